@@ -0,0 +1,46 @@
+use anyhow::Result;
+use colored::Colorize;
+use gitent_core::Storage;
+use std::path::PathBuf;
+
+/// Show the audit log of writes made through the API, newest first.
+pub fn run(limit: Option<usize>, db: Option<PathBuf>) -> Result<()> {
+    let db_path = super::get_db_path(db)?;
+
+    if !db_path.exists() {
+        anyhow::bail!("No active gitent session found. Run 'gitent start' first.");
+    }
+
+    let storage = Storage::new(&db_path)?;
+    let entries = storage.get_audit_log(limit)?;
+
+    if entries.is_empty() {
+        println!("{}", "No API writes recorded".yellow());
+        return Ok(());
+    }
+
+    for entry in entries {
+        let status = entry.status.to_string();
+        let status = if entry.status < 400 {
+            status.green()
+        } else {
+            status.red()
+        };
+        println!(
+            "{} {} {} {} {} {} {}",
+            entry
+                .timestamp
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+                .bright_black(),
+            status,
+            entry.method.bold(),
+            entry.path,
+            entry.claimed_agent_id.as_deref().unwrap_or("-").cyan(),
+            entry.target.as_deref().unwrap_or("-"),
+            entry.source_ip.as_deref().unwrap_or("-").bright_black()
+        );
+    }
+
+    Ok(())
+}
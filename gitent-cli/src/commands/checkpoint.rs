@@ -0,0 +1,42 @@
+use anyhow::Result;
+use colored::Colorize;
+use std::path::PathBuf;
+
+/// Create a checkpoint named `label`, or list the session's checkpoints when
+/// no label is given.
+pub fn run(label: Option<String>, db: Option<PathBuf>) -> Result<()> {
+    let storage = super::open_storage_at(db)?;
+    let session = storage.get_active_session()?;
+
+    let Some(label) = label else {
+        let checkpoints = storage.list_checkpoints(&session.id)?;
+        if checkpoints.is_empty() {
+            println!("{}", "No checkpoints".yellow());
+            return Ok(());
+        }
+        for checkpoint in checkpoints {
+            println!(
+                "{} {}",
+                checkpoint
+                    .created_at
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+                    .bright_black(),
+                checkpoint.label.bold()
+            );
+        }
+        return Ok(());
+    };
+
+    storage.create_checkpoint(&session.id, &label)?;
+
+    println!("{}", "✓ Checkpoint created".green().bold());
+    println!("  {}: {}", "Label".bold(), label);
+    println!();
+    println!(
+        "Run {} to see what changed since",
+        format!("gitent diff --since-checkpoint {:?}", label).cyan()
+    );
+
+    Ok(())
+}
@@ -1,41 +1,400 @@
 use anyhow::{Context, Result};
 use colored::Colorize;
-use gitent_core::{Commit, Storage};
+use dialoguer::Confirm;
+use gitent_core::diff::{apply_hunks, hunks};
+use gitent_core::ignore::IgnoreMatcher;
+use gitent_core::{Change, ChangeType, Commit, Config, Storage};
 use std::path::PathBuf;
+use std::process::ExitCode;
+use uuid::Uuid;
 
-pub fn run(message: String, agent_id: String, db: Option<PathBuf>) -> Result<()> {
-    let db_path = super::get_db_path(db);
+const HUNK_CONTEXT: usize = 3;
 
-    if !db_path.exists() {
-        anyhow::bail!("No active gitent session found. Run 'gitent start' first.");
+/// What `gitent commit` ended up doing, so scripts can tell the cases apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitOutcome {
+    Committed(Uuid),
+    NothingToCommit,
+}
+
+impl CommitOutcome {
+    /// Exit with 2 when there was nothing to commit, 0 otherwise.
+    pub fn exit_code(self) -> ExitCode {
+        match self {
+            CommitOutcome::Committed(_) => ExitCode::SUCCESS,
+            CommitOutcome::NothingToCommit => ExitCode::from(2),
+        }
+    }
+}
+
+/// The issue and pull request a commit is linked to, whether it's a
+/// milestone, and any other metadata to record on it.
+#[derive(Debug, Clone, Default)]
+pub struct Links {
+    pub issue: Option<String>,
+    pub pr_url: Option<String>,
+    pub milestone: bool,
+    pub metadata: Vec<(String, String)>,
+}
+
+/// Parse a `KEY=VALUE` metadata pair.
+pub fn parse_metadata(pair: &str) -> Result<(String, String), String> {
+    match pair.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", pair)),
     }
+}
 
-    let storage = Storage::new(&db_path)?;
+/// Commit the uncommitted changes within `commit_scope`, which overrides the
+/// configured scope when set; an empty scope includes every change.
+pub fn run(
+    message: String,
+    agent: Option<String>,
+    interactive: bool,
+    allow_empty: bool,
+    links: Links,
+    commit_scope: Option<Vec<String>>,
+    db: Option<PathBuf>,
+) -> Result<CommitOutcome> {
+    let settings = super::load_settings(Config {
+        agent_id: agent,
+        db,
+        commit_scope,
+        ..Default::default()
+    })?;
+    let storage = super::open_storage(&settings)?;
+    let agent_id = settings.agent_id;
     let session = storage
         .get_active_session()
         .context("No active session found")?;
 
-    let changes = storage.get_uncommitted_changes(&session.id)?;
+    let mut changes = storage.get_uncommitted_changes(&session.id)?;
+    let mut out_of_scope = 0;
+    if !settings.commit_scope.is_empty() {
+        let scope = IgnoreMatcher::new(&settings.commit_scope)?;
+        let total = changes.len();
+        changes.retain(|change| in_scope(&scope, change));
+        out_of_scope = total - changes.len();
+    }
 
-    if changes.is_empty() {
+    if changes.is_empty() && !allow_empty {
         println!("{}", "No changes to commit".yellow());
-        return Ok(());
+        if out_of_scope > 0 {
+            println!(
+                "  {} change(s) outside commit_scope; use --all to include them",
+                out_of_scope
+            );
+        }
+        return Ok(CommitOutcome::NothingToCommit);
+    }
+
+    let change_ids: Vec<Uuid> = if interactive && !changes.is_empty() {
+        select_changes(&storage, changes)?
+    } else {
+        changes.iter().map(|c| c.id).collect()
+    };
+
+    if change_ids.is_empty() && !allow_empty {
+        println!("{}", "No changes selected".yellow());
+        return Ok(CommitOutcome::NothingToCommit);
     }
 
     println!("{}", "Creating commit...".bold());
-    println!("  {}: {}", "Changes".bold(), changes.len());
+    println!("  {}: {}", "Changes".bold(), change_ids.len());
+    if out_of_scope > 0 {
+        println!(
+            "  {}: {} (left uncommitted, use --all to include)",
+            "Outside commit_scope".bold(),
+            out_of_scope
+        );
+    }
     println!();
 
-    let change_ids: Vec<_> = changes.iter().map(|c| c.id).collect();
-    let commit = Commit::new(message.clone(), agent_id.clone(), change_ids, session.id);
+    let mut commit = Commit::new(message, agent_id.clone(), change_ids, session.id);
+    // Build on the latest commit, as the API does, so history can be walked
+    if let Some(head) = storage.get_head_commit(&session.id)? {
+        commit = commit.with_parent(head.id);
+    }
+    if let Some(issue) = links.issue {
+        commit = commit.with_issue(issue);
+    }
+    if let Some(pr_url) = links.pr_url {
+        commit = commit.with_pr_url(pr_url);
+    }
+    if links.milestone {
+        commit = commit.with_milestone();
+    }
+    for (key, value) in links.metadata {
+        commit = commit.with_metadata(key, value);
+    }
 
     storage.create_commit(&commit)?;
 
     println!("{}", "✓ Commit created successfully!".green().bold());
     println!("  {}: {}", "Commit ID".bold(), commit.id);
-    println!("  {}: {}", "Message".bold(), message);
+    println!("  {}: {}", "Message".bold(), commit.summary());
     println!("  {}: {}", "Agent".bold(), agent_id);
-    println!("  {}: {}", "Files changed".bold(), changes.len());
+    println!("  {}: {}", "Files changed".bold(), commit.changes.len());
+    for trailer in commit.link_trailers() {
+        println!("  {}", trailer);
+    }
+    if commit.is_milestone() {
+        println!("  {}", "★ Milestone".magenta().bold());
+    }
+
+    Ok(CommitOutcome::Committed(commit.id))
+}
+
+/// Build the full commit message from a summary and an optional body, where
+/// a body of `-` is read from stdin.
+pub fn compose_message(summary: &str, body: Option<String>) -> Result<String> {
+    let body = match body.as_deref() {
+        Some("-") => Some(std::io::read_to_string(std::io::stdin())?),
+        _ => body,
+    };
+    Ok(Commit::compose_message(summary, body.as_deref()))
+}
+
+/// Whether a change touches a file matching the commit scope; a rename is
+/// in scope if either of its paths is.
+fn in_scope(scope: &IgnoreMatcher, change: &Change) -> bool {
+    std::iter::once(&change.path)
+        .chain(&change.old_path)
+        .any(|path| scope.is_ignored(path))
+}
+
+/// Ask which changes to commit, hunk by hunk for modified text files.
+///
+/// When only some hunks of a change are picked, the change is split and the
+/// unpicked hunks stay uncommitted.
+fn select_changes(storage: &Storage, changes: Vec<Change>) -> Result<Vec<Uuid>> {
+    let mut selected = Vec::new();
+
+    for change in changes {
+        let text = match (&change.content_before, &change.content_after) {
+            (Some(old), Some(new)) if change.change_type == ChangeType::Modify => {
+                std::str::from_utf8(old)
+                    .ok()
+                    .zip(std::str::from_utf8(new).ok())
+            }
+            _ => None,
+        };
+        let file_hunks = text
+            .map(|(old, new)| hunks(old, new, HUNK_CONTEXT))
+            .unwrap_or_default();
+
+        let (Some((old, new)), false) = (text, file_hunks.is_empty()) else {
+            let prompt = format!(
+                "Include {} {}?",
+                change.change_type.as_str(),
+                change.path.display()
+            );
+            if Confirm::new()
+                .with_prompt(prompt)
+                .default(true)
+                .interact()?
+            {
+                selected.push(change.id);
+            }
+            continue;
+        };
+
+        println!("{}", change.path.display().to_string().bold());
+        let mut picked = Vec::new();
+        for (index, hunk) in file_hunks.iter().enumerate() {
+            print_hunk(hunk);
+            let prompt = format!("Include hunk {}/{}?", index + 1, file_hunks.len());
+            if Confirm::new()
+                .with_prompt(prompt)
+                .default(true)
+                .interact()?
+            {
+                picked.push(index);
+            }
+        }
+        println!();
 
-    Ok(())
+        if picked.len() == file_hunks.len() {
+            selected.push(change.id);
+        } else if !picked.is_empty() {
+            let partial = apply_hunks(old, new, HUNK_CONTEXT, &picked);
+            match storage.split_change(&change, partial.into_bytes()) {
+                Ok((first, _)) => selected.push(first.id),
+                Err(e) => println!(
+                    "{} {}: {}",
+                    "Leaving uncommitted".yellow(),
+                    change.path.display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    Ok(selected)
+}
+
+fn print_hunk(hunk: &str) {
+    for line in hunk.lines() {
+        if line.starts_with("@@") {
+            println!("{}", line.cyan());
+        } else if line.starts_with('+') {
+            println!("{}", line.green());
+        } else if line.starts_with('-') {
+            println!("{}", line.red());
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gitent_core::Session;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn setup() -> (TempDir, PathBuf, Session) {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("gitent.db");
+        let storage = Storage::new(&db_path).unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        storage.create_session(&session).unwrap();
+        (temp_dir, db_path, session)
+    }
+
+    fn commit(db_path: &Path, allow_empty: bool) -> CommitOutcome {
+        run(
+            "test".to_string(),
+            None,
+            false,
+            allow_empty,
+            Links::default(),
+            None,
+            Some(db_path.to_path_buf()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_nothing_to_commit_exits_nonzero() {
+        let (_temp_dir, db_path, _session) = setup();
+
+        let outcome = commit(&db_path, false);
+        assert_eq!(outcome, CommitOutcome::NothingToCommit);
+        assert_eq!(outcome.exit_code(), ExitCode::from(2));
+    }
+
+    #[test]
+    fn test_commit_exits_zero() {
+        let (_temp_dir, db_path, session) = setup();
+        let storage = Storage::new(&db_path).unwrap();
+        let change = Change::new(ChangeType::Create, "file.txt".into(), session.id)
+            .with_content_after(b"hello".to_vec());
+        storage.create_change(&change).unwrap();
+
+        let outcome = commit(&db_path, false);
+        assert!(matches!(outcome, CommitOutcome::Committed(_)));
+        assert_eq!(outcome.exit_code(), ExitCode::SUCCESS);
+        assert!(storage
+            .get_uncommitted_changes(&session.id)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_commit_with_issue() {
+        let (_temp_dir, db_path, _session) = setup();
+
+        let outcome = run(
+            "Fix crash".to_string(),
+            None,
+            false,
+            true,
+            Links {
+                issue: Some("GH-123".to_string()),
+                pr_url: Some("https://github.com/org/repo/pull/7".to_string()),
+                milestone: true,
+                metadata: vec![("ticket".to_string(), "GE-7".to_string())],
+            },
+            None,
+            Some(db_path.clone()),
+        )
+        .unwrap();
+        let CommitOutcome::Committed(id) = outcome else {
+            panic!("expected a commit");
+        };
+
+        let storage = Storage::new(&db_path).unwrap();
+        let commits = storage.get_commits_for_issue("GH-123").unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].id, id);
+        assert_eq!(
+            commits[0].pr_url(),
+            Some("https://github.com/org/repo/pull/7")
+        );
+        assert!(commits[0].is_milestone());
+        assert_eq!(commits[0].metadata["ticket"], "GE-7");
+    }
+
+    #[test]
+    fn test_allow_empty_creates_commit() {
+        let (_temp_dir, db_path, session) = setup();
+
+        let CommitOutcome::Committed(id) = commit(&db_path, true) else {
+            panic!("expected an empty commit");
+        };
+        let storage = Storage::new(&db_path).unwrap();
+        assert!(storage.get_commit(&id).unwrap().changes.is_empty());
+        assert_eq!(
+            storage.get_commits_for_session(&session.id).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_commit_scope() {
+        let (_temp_dir, db_path, session) = setup();
+        let storage = Storage::new(&db_path).unwrap();
+        let source = Change::new(ChangeType::Create, "src/lib.rs".into(), session.id)
+            .with_content_after(b"pub fn f() {}".to_vec());
+        let generated = Change::new(ChangeType::Create, "gen/schema.rs".into(), session.id)
+            .with_content_after(b"// generated".to_vec());
+        storage.create_change(&source).unwrap();
+        storage.create_change(&generated).unwrap();
+
+        let scoped = |scope: Vec<&str>| {
+            run(
+                "test".to_string(),
+                None,
+                false,
+                false,
+                Links::default(),
+                Some(scope.into_iter().map(String::from).collect()),
+                Some(db_path.clone()),
+            )
+            .unwrap()
+        };
+
+        let CommitOutcome::Committed(id) = scoped(vec!["src/"]) else {
+            panic!("expected a commit");
+        };
+        assert_eq!(storage.get_commit(&id).unwrap().changes, vec![source.id]);
+        let left: Vec<_> = storage
+            .get_uncommitted_changes(&session.id)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(left, vec![generated.id]);
+
+        // Nothing in scope is left, but out-of-scope changes stay tracked
+        assert_eq!(scoped(vec!["src/"]), CommitOutcome::NothingToCommit);
+
+        // An empty scope, as with --all, includes them
+        let CommitOutcome::Committed(id) = scoped(vec![]) else {
+            panic!("expected a commit");
+        };
+        assert_eq!(storage.get_commit(&id).unwrap().changes, vec![generated.id]);
+    }
 }
@@ -1,11 +1,59 @@
 use anyhow::Result;
 use colored::Colorize;
-use gitent_core::{diff::FileDiff, Storage};
-use std::path::PathBuf;
+use gitent_core::{
+    diff::{
+        collapse_renames, for_each_collapsed,
+        semantic::{self, FilePatch},
+        FileDiff,
+    },
+    ignore::IgnoreMatcher,
+    worktree, Change, ChangeType, Config, Storage,
+};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
-pub fn run(commit_id: Option<String>, db: Option<PathBuf>) -> Result<()> {
-    let db_path = super::get_db_path(db);
+/// An earlier point in the session to show changes since.
+pub enum Since {
+    /// A snapshot id from `gitent snapshot`
+    Snapshot(String),
+    /// A checkpoint label from `gitent checkpoint`
+    Checkpoint(String),
+}
+
+/// How each changed file is shown.
+pub enum Format {
+    /// Every hunk, up to `max_lines` lines per file
+    Full {
+        max_lines: Option<usize>,
+        highlight: bool,
+        /// Compare structured files such as JSON by content where possible
+        semantic: bool,
+        /// Show files matching `diff_suppress` in full too
+        show_suppressed: bool,
+    },
+    /// One line per file with its status letter and path
+    NameStatus,
+    /// An RFC 6902 JSON Patch per JSON file, as a JSON array
+    JsonPatch,
+}
+
+pub fn run(
+    commit_id: Option<String>,
+    since: Option<Since>,
+    worktree: bool,
+    output: Option<PathBuf>,
+    format: Format,
+    group_by_dir: bool,
+    db: Option<PathBuf>,
+) -> Result<()> {
+    let settings = super::load_settings(Config {
+        db,
+        ..Default::default()
+    })?;
+    let db_path = settings.db_path;
 
     if !db_path.exists() {
         anyhow::bail!("No active gitent session found. Run 'gitent start' first.");
@@ -13,20 +61,55 @@ pub fn run(commit_id: Option<String>, db: Option<PathBuf>) -> Result<()> {
 
     let storage = Storage::new(&db_path)?;
     let session = storage.get_active_session()?;
+    // Name-status output is meant for scanning and scripts, so has no headings
+    let show_header = output.is_none() && matches!(format, Format::Full { .. });
 
     let changes = if let Some(id_str) = commit_id {
-        let commit_id = Uuid::parse_str(&id_str)?;
-        let commit = storage.get_commit(&commit_id)?;
-
-        println!("{}", format!("Diff for commit {}", commit.id).bold().cyan());
-        println!("{}: {}", "Message".bold(), commit.message);
-        println!();
-
-        commit
-            .changes
-            .iter()
-            .filter_map(|id| storage.get_change(id).ok())
-            .collect()
+        let commit = storage.resolve_commit_prefix(&session.id, &id_str)?;
+
+        if show_header {
+            println!("{}", format!("Diff for commit {}", commit.id).bold().cyan());
+            println!("{}: {}", "Message".bold(), commit.message);
+            println!();
+        }
+
+        Changes::Commit(commit.changes)
+    } else if worktree {
+        let changes = worktree::changes(&storage, &session)?;
+
+        if changes.is_empty() {
+            println!("{}", "Working tree matches the last commit".green());
+            return Ok(());
+        }
+
+        if show_header {
+            println!("{}", "Working tree vs. last commit".bold().cyan());
+            println!();
+        }
+        Changes::Loaded(changes)
+    } else if let Some(since) = since {
+        let (changes, description) = match since {
+            Since::Snapshot(id_str) => {
+                let snapshot_id = Uuid::parse_str(&id_str)?;
+                let changes = storage.diff_since_snapshot(&snapshot_id)?;
+                (changes, format!("snapshot {}", snapshot_id))
+            }
+            Since::Checkpoint(label) => {
+                let changes = storage.changes_since_checkpoint(&session.id, &label)?;
+                (changes, format!("checkpoint {:?}", label))
+            }
+        };
+
+        if changes.is_empty() {
+            println!("{}", format!("No changes since {}", description).green());
+            return Ok(());
+        }
+
+        if show_header {
+            println!("{}", format!("Changes since {}", description).bold().cyan());
+            println!();
+        }
+        Changes::Loaded(changes)
     } else {
         let changes = storage.get_uncommitted_changes(&session.id)?;
 
@@ -35,46 +118,576 @@ pub fn run(commit_id: Option<String>, db: Option<PathBuf>) -> Result<()> {
             return Ok(());
         }
 
-        println!("{}", "Uncommitted changes".bold().cyan());
-        println!();
-        changes
+        if show_header {
+            println!("{}", "Uncommitted changes".bold().cyan());
+            println!();
+        }
+        Changes::Loaded(changes)
     };
 
-    for change in changes {
-        println!("{}", "━".repeat(80).bright_black());
+    let mut out = match (output, format) {
+        (Some(path), _) => Output::Patch {
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+        },
+        (None, Format::NameStatus) => Output::NameStatus {
+            writer: Box::new(std::io::stdout()),
+        },
+        (None, Format::JsonPatch) => Output::JsonPatch {
+            patches: Vec::new(),
+        },
+        (
+            None,
+            Format::Full {
+                max_lines,
+                highlight,
+                semantic,
+                show_suppressed,
+            },
+        ) => {
+            // Highlighting is pointless, and its escapes unwanted, when output isn't a terminal
+            let highlight = highlight && std::io::stdout().is_terminal();
+            #[cfg(not(feature = "syntax"))]
+            if highlight {
+                eprintln!(
+                    "{}",
+                    "Highlighting is unavailable: gitent was built without the `syntax` feature"
+                        .dimmed()
+                );
+            }
+            Output::Full {
+                max_lines,
+                semantic,
+                suppress: suppress_matcher(&settings.diff_suppress, show_suppressed)?,
+                #[cfg(feature = "syntax")]
+                highlighter: highlight.then(|| Box::new(crate::highlight::Highlighter::new())),
+            }
+        }
+    };
 
-        let status = match change.change_type {
-            gitent_core::ChangeType::Create => "NEW".green(),
-            gitent_core::ChangeType::Modify => "MOD".yellow(),
-            gitent_core::ChangeType::Delete => "DEL".red(),
-            gitent_core::ChangeType::Rename => "REN".blue(),
-        };
+    match changes {
+        // Fetched and shown one at a time, as a large commit's content may
+        // not fit in memory all at once
+        Changes::Commit(ids) if !group_by_dir => for_each_collapsed(
+            &ids,
+            settings.rename_threshold,
+            |id| storage.get_change(id).ok(),
+            |change, similarity| out.show(&change, similarity),
+        )?,
+        Changes::Commit(ids) => {
+            out.show_commit_grouped(&storage, &ids, settings.rename_threshold)?
+        }
+        Changes::Loaded(changes) => {
+            let shown = collapse_renames(changes, settings.rename_threshold);
+            if group_by_dir {
+                out.show_grouped(group_by_directory(shown))?;
+            } else {
+                for (change, similarity) in shown {
+                    out.show(&change, similarity)?;
+                }
+            }
+        }
+    }
 
-        println!(
-            "{} {}",
-            status,
-            change.path.display().to_string().white().bold()
-        );
-        println!();
-
-        match FileDiff::from_change(&change) {
-            Ok(diff) => {
-                for line in &diff.diff_lines {
-                    let (prefix, color): (&str, fn(&str) -> colored::ColoredString) =
-                        match line.line_type {
-                            gitent_core::diff::DiffLineType::Addition => ("+", |s| s.green()),
-                            gitent_core::diff::DiffLineType::Deletion => ("-", |s| s.red()),
-                            gitent_core::diff::DiffLineType::Context => (" ", |s| s.normal()),
-                        };
-                    print!("{}", color(&format!("{}{}", prefix, line.content)));
+    match out {
+        Output::Patch { mut writer, path } => {
+            writer.flush()?;
+            println!(
+                "{} {}",
+                "✓ Diff written to".green(),
+                path.display().to_string().bold()
+            );
+        }
+        Output::JsonPatch { mut patches } => print_json_patches(&mut patches)?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// The changes to show.
+enum Changes {
+    /// A commit's changes, by id
+    Commit(Vec<Uuid>),
+    Loaded(Vec<Change>),
+}
+
+/// Where and how changes are shown.
+enum Output {
+    /// A color-free unified diff, suitable for saving as a patch
+    Patch {
+        writer: BufWriter<File>,
+        path: PathBuf,
+    },
+    NameStatus {
+        writer: Box<dyn Write>,
+    },
+    /// JSON Patches gathered with the sequence number of their change, to
+    /// print together once every change has been seen
+    JsonPatch {
+        patches: Vec<(Option<i64>, FilePatch)>,
+    },
+    Full {
+        max_lines: Option<usize>,
+        semantic: bool,
+        /// Files shown as a one-line note instead of a diff
+        suppress: Option<IgnoreMatcher>,
+        #[cfg(feature = "syntax")]
+        highlighter: Option<Box<crate::highlight::Highlighter>>,
+    },
+}
+
+impl Output {
+    /// Show each directory's changes under a heading naming it. A patch file
+    /// gets the same order without the headings, so it still applies.
+    fn show_grouped(&mut self, groups: Vec<DirGroup>) -> gitent_core::Result<()> {
+        for (i, (dir, changes)) in groups.into_iter().enumerate() {
+            self.show_heading(&dir, i == 0)?;
+            for (change, similarity) in changes {
+                self.show(&change, similarity)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`Output::show_grouped`] for a commit's changes, which are
+    /// ordered by their paths alone and then fetched and shown one at a
+    /// time, as a large commit's content may not fit in memory all at once.
+    fn show_commit_grouped(
+        &mut self,
+        storage: &Storage,
+        ids: &[Uuid],
+        rename_threshold: f32,
+    ) -> gitent_core::Result<()> {
+        let paths = storage.get_change_paths(ids)?;
+        let mut ids = ids.to_vec();
+        // Stable, so a file changed more than once keeps its capture order
+        ids.sort_by_key(|id| paths.get(id).map(|path| (directory_of(path), path.clone())));
+
+        let mut current: Option<String> = None;
+        for_each_collapsed(
+            &ids,
+            rename_threshold,
+            |id| storage.get_change(id).ok(),
+            |change, similarity| {
+                // A rename sorts by its new path, so stays in its directory
+                let dir = directory_of(&change.path);
+                if current.as_ref() != Some(&dir) {
+                    self.show_heading(&dir, current.is_none())?;
+                    current = Some(dir);
                 }
+                self.show(&change, similarity)
+            },
+        )
+    }
+
+    /// Print the heading ahead of `dir`'s changes.
+    fn show_heading(&mut self, dir: &str, first: bool) -> gitent_core::Result<()> {
+        match self {
+            Output::Full { .. } => {
+                println!("{}", "═".repeat(80).bright_black());
+                println!("{}", format!("{}/", dir).cyan().bold());
             }
-            Err(_) => {
-                println!("  {}", "[Binary file or unable to generate diff]".dimmed());
+            Output::NameStatus { writer } => {
+                if !first {
+                    writeln!(writer)?;
+                }
+                writeln!(writer, "{}/", dir)?;
             }
+            Output::Patch { .. } | Output::JsonPatch { .. } => {}
         }
-        println!();
+        Ok(())
     }
 
+    fn show(&mut self, change: &Change, similarity: Option<f32>) -> gitent_core::Result<()> {
+        match self {
+            Output::Patch { writer, .. } => {
+                let patch = FileDiff::from_change(change)?.format_unified(3);
+                writer.write_all(patch.as_bytes())?;
+            }
+            Output::NameStatus { writer } => writeln!(writer, "{}", name_status(change))?,
+            Output::JsonPatch { patches } => {
+                patches.push((change.seq, FilePatch::for_change(change)))
+            }
+            Output::Full {
+                max_lines,
+                semantic,
+                suppress,
+                #[cfg(feature = "syntax")]
+                highlighter,
+            } => {
+                #[cfg(feature = "syntax")]
+                let mut file_highlighter = highlighter.as_ref().and_then(|h| h.for_change(change));
+
+                println!("{}", "━".repeat(80).bright_black());
+
+                let status = match change.change_type {
+                    ChangeType::Create => "NEW".green(),
+                    ChangeType::Modify => "MOD".yellow(),
+                    ChangeType::Delete => "DEL".red(),
+                    ChangeType::Rename => "REN".blue(),
+                };
+
+                println!(
+                    "{} {}",
+                    status,
+                    super::describe_path(change, similarity).white().bold()
+                );
+                println!();
+
+                if is_suppressed(suppress.as_ref(), change) {
+                    println!(
+                        "  {}",
+                        "[Generated file changed (suppressed); use --show-suppressed to see it]"
+                            .dimmed()
+                    );
+                    println!();
+                    return Ok(());
+                }
+
+                if let Some(entries) = semantic.then(|| semantic::diff_change(change)).flatten() {
+                    print_semantic(&entries);
+                    println!();
+                    return Ok(());
+                }
+
+                let limit = max_lines.unwrap_or(usize::MAX);
+                let mut lines = FileDiff::iter_lines(change);
+
+                for line in lines.by_ref().take(limit) {
+                    match line {
+                        Ok(line) => {
+                            #[cfg(feature = "syntax")]
+                            if let Some(highlighted) =
+                                file_highlighter.as_mut().and_then(|h| h.line(&line))
+                            {
+                                print!("{}", highlighted);
+                                continue;
+                            }
+
+                            let (prefix, color): (&str, fn(&str) -> colored::ColoredString) =
+                                match line.line_type {
+                                    gitent_core::diff::DiffLineType::Addition => {
+                                        ("+", |s| s.green())
+                                    }
+                                    gitent_core::diff::DiffLineType::Deletion => ("-", |s| s.red()),
+                                    gitent_core::diff::DiffLineType::Context => {
+                                        (" ", |s| s.normal())
+                                    }
+                                };
+                            print!("{}", color(&format!("{}{}", prefix, line.content)));
+                        }
+                        Err(_) => {
+                            println!("  {}", "[Binary file or unable to generate diff]".dimmed());
+                        }
+                    }
+                }
+
+                if lines.next().is_some() {
+                    println!(
+                        "{}",
+                        format!("... diff truncated at {} lines", limit).dimmed()
+                    );
+                }
+                println!();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A directory and the changes to files directly inside it.
+type DirGroup = (String, Vec<(Change, Option<f32>)>);
+
+/// Group changes by the directory their file is in, with the directories
+/// and the files within each sorted by path. Files at the top level are
+/// grouped under `.`.
+fn group_by_directory(changes: Vec<(Change, Option<f32>)>) -> Vec<DirGroup> {
+    let mut groups: BTreeMap<String, Vec<(Change, Option<f32>)>> = BTreeMap::new();
+    for (change, similarity) in changes {
+        groups
+            .entry(directory_of(&change.path))
+            .or_default()
+            .push((change, similarity));
+    }
+    groups
+        .into_iter()
+        .map(|(dir, mut changes)| {
+            // Stable, so a file changed more than once keeps its capture order
+            changes.sort_by(|(a, _), (b, _)| a.path.cmp(&b.path));
+            (dir, changes)
+        })
+        .collect()
+}
+
+/// The directory `path` is in, or `.` at the top level.
+fn directory_of(path: &Path) -> String {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.display().to_string(),
+        _ => ".".to_string(),
+    }
+}
+
+/// The matcher for files whose diffs are collapsed, or `None` when
+/// `show_suppressed` asks for every diff in full.
+fn suppress_matcher(patterns: &[String], show_suppressed: bool) -> Result<Option<IgnoreMatcher>> {
+    if show_suppressed || patterns.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(IgnoreMatcher::new(patterns)?))
+}
+
+/// Whether `change`'s diff is collapsed to a note.
+fn is_suppressed(suppress: Option<&IgnoreMatcher>, change: &Change) -> bool {
+    suppress.is_some_and(|matcher| matcher.is_ignored(&change.path))
+}
+
+/// Print the JSON Patches as one JSON array, oldest change first, with the
+/// files that have none listed on stderr. Fails if no file has one.
+fn print_json_patches(patches: &mut [(Option<i64>, FilePatch)]) -> Result<()> {
+    patches.sort_by_key(|(seq, _)| *seq);
+    let (made, failed): (Vec<&FilePatch>, Vec<&FilePatch>) = patches
+        .iter()
+        .map(|(_, patch)| patch)
+        .partition(|patch| patch.patch.is_some());
+
+    for patch in &failed {
+        eprintln!(
+            "{} {}",
+            "Skipped:".yellow(),
+            patch.error.as_deref().unwrap_or_default()
+        );
+    }
+    if made.is_empty() {
+        anyhow::bail!("None of the changed files has a JSON Patch");
+    }
+    println!("{}", serde_json::to_string_pretty(&made)?);
     Ok(())
 }
+
+/// Print a semantic diff, one added, removed or changed value per line.
+fn print_semantic(entries: &[semantic::SemanticChange]) {
+    if entries.is_empty() {
+        println!("  {}", "[No changes in content, only formatting]".dimmed());
+    }
+    for entry in entries {
+        let line = entry.to_string();
+        match entry {
+            semantic::SemanticChange::Added { .. } => println!("{}", line.green()),
+            semantic::SemanticChange::Removed { .. } => println!("{}", line.red()),
+            semantic::SemanticChange::Changed { .. } => println!("{}", line.yellow()),
+        }
+    }
+}
+
+/// A change as `git diff --name-status` shows it: a status letter and the
+/// path, with renames as `R old -> new`.
+fn name_status(change: &Change) -> String {
+    let status = match change.change_type {
+        ChangeType::Create => 'A',
+        ChangeType::Modify => 'M',
+        ChangeType::Delete => 'D',
+        ChangeType::Rename => 'R',
+    };
+    format!("{}\t{}", status, super::describe_path(change, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_name_status() {
+        let session_id = Uuid::new_v4();
+        let changes = [
+            Change::new(ChangeType::Create, "new.rs".into(), session_id),
+            Change::new(ChangeType::Modify, "src/lib.rs".into(), session_id),
+            Change::new(ChangeType::Delete, "old.rs".into(), session_id),
+            Change::new(ChangeType::Rename, "b.rs".into(), session_id).with_old_path("a.rs".into()),
+        ];
+
+        let lines: Vec<String> = changes.iter().map(name_status).collect();
+        assert_eq!(
+            lines,
+            ["A\tnew.rs", "M\tsrc/lib.rs", "D\told.rs", "R\ta.rs -> b.rs"]
+        );
+    }
+
+    #[test]
+    fn test_group_by_directory() {
+        let session_id = Uuid::new_v4();
+        let change = |path: &str| {
+            (
+                Change::new(ChangeType::Modify, path.into(), session_id),
+                None,
+            )
+        };
+        let changes = vec![
+            change("src/main.rs"),
+            change("tests/api.rs"),
+            change("README.md"),
+            change("src/commands/diff.rs"),
+            change("src/lib.rs"),
+            change("Cargo.toml"),
+        ];
+
+        let groups: Vec<(String, Vec<String>)> = group_by_directory(changes)
+            .into_iter()
+            .map(|(dir, changes)| {
+                let paths = changes
+                    .iter()
+                    .map(|(change, _)| change.path.display().to_string())
+                    .collect();
+                (dir, paths)
+            })
+            .collect();
+        let dirs: Vec<&str> = groups.iter().map(|(dir, _)| dir.as_str()).collect();
+        assert_eq!(dirs, [".", "src", "src/commands", "tests"]);
+        assert_eq!(groups[0].1, ["Cargo.toml", "README.md"]);
+        assert_eq!(groups[1].1, ["src/lib.rs", "src/main.rs"]);
+        assert_eq!(groups[2].1, ["src/commands/diff.rs"]);
+        assert_eq!(groups[3].1, ["tests/api.rs"]);
+    }
+
+    #[test]
+    fn test_commit_grouped_headings_are_sorted() {
+        let temp_dir = TempDir::new().unwrap();
+        let output_path = temp_dir.path().join("name-status.txt");
+        let storage = Storage::new(temp_dir.path().join("gitent.db")).unwrap();
+        let session = gitent_core::Session::new(temp_dir.path().to_path_buf());
+        storage.create_session(&session).unwrap();
+
+        let ids: Vec<Uuid> = [
+            "tests/api.rs",
+            "src/main.rs",
+            "README.md",
+            "src/cli/args.rs",
+        ]
+        .into_iter()
+        .map(|path| {
+            let change = Change::new(ChangeType::Create, path.into(), session.id)
+                .with_content_after(format!("// {}\n", path).into_bytes());
+            storage.create_change(&change).unwrap();
+            change.id
+        })
+        .collect();
+
+        let mut out = Output::NameStatus {
+            writer: Box::new(File::create(&output_path).unwrap()),
+        };
+        out.show_commit_grouped(&storage, &ids, 0.5).unwrap();
+        drop(out);
+
+        let printed = std::fs::read_to_string(&output_path).unwrap();
+        let lines: Vec<&str> = printed.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "./",
+                "A\tREADME.md",
+                "",
+                "src/",
+                "A\tsrc/main.rs",
+                "",
+                "src/cli/",
+                "A\tsrc/cli/args.rs",
+                "",
+                "tests/",
+                "A\ttests/api.rs",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lockfiles_suppressed() {
+        let patterns = Config::default().resolve(Path::new("/test")).diff_suppress;
+        let session_id = Uuid::new_v4();
+        let lockfile = Change::new(ChangeType::Modify, "Cargo.lock".into(), session_id);
+        let bundle = Change::new(ChangeType::Create, "web/app.min.js".into(), session_id);
+        let source = Change::new(ChangeType::Modify, "src/lib.rs".into(), session_id);
+
+        let suppress = suppress_matcher(&patterns, false).unwrap();
+        assert!(is_suppressed(suppress.as_ref(), &lockfile));
+        assert!(is_suppressed(suppress.as_ref(), &bundle));
+        assert!(!is_suppressed(suppress.as_ref(), &source));
+
+        let suppress = suppress_matcher(&patterns, true).unwrap();
+        assert!(!is_suppressed(suppress.as_ref(), &lockfile));
+        assert!(!is_suppressed(suppress.as_ref(), &bundle));
+    }
+
+    #[test]
+    fn test_write_patch_has_no_ansi_codes() {
+        colored::control::set_override(true);
+
+        let temp_dir = TempDir::new().unwrap();
+        let output_path = temp_dir.path().join("changes.patch");
+
+        let change = Change::new(
+            ChangeType::Modify,
+            PathBuf::from("test.txt"),
+            Uuid::new_v4(),
+        )
+        .with_content_before(b"Hello\nWorld\n".to_vec())
+        .with_content_after(b"Hello\nRust\nWorld\n".to_vec());
+
+        let mut out = Output::Patch {
+            writer: BufWriter::new(File::create(&output_path).unwrap()),
+            path: output_path.clone(),
+        };
+        out.show(&change, None).unwrap();
+        drop(out);
+
+        let patch = std::fs::read_to_string(&output_path).unwrap();
+        assert!(patch.contains("--- test.txt"));
+        assert!(patch.contains("+Rust"));
+        assert!(!patch.contains('\x1b'));
+    }
+
+    #[test]
+    fn test_large_commit_is_streamed() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("gitent.db");
+        let output_path = temp_dir.path().join("commit.patch");
+        let storage = Storage::new(&db_path).unwrap();
+        let session = gitent_core::Session::new(temp_dir.path().to_path_buf());
+        storage.create_session(&session).unwrap();
+
+        // 64 files of 256 KiB each
+        let ids: Vec<Uuid> = (0..64)
+            .map(|i| {
+                let content = format!("file {} line\n", i).repeat(256 * 1024 / 16);
+                let change =
+                    Change::new(ChangeType::Create, format!("{}.txt", i).into(), session.id)
+                        .with_content_after(content.into_bytes());
+                storage.create_change(&change).unwrap();
+                change.id
+            })
+            .collect();
+        let commit = gitent_core::Commit::new(
+            "Add files".to_string(),
+            "agent".to_string(),
+            ids,
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+        drop(storage);
+
+        run(
+            Some(commit.id.to_string()),
+            None,
+            false,
+            Some(output_path.clone()),
+            Format::NameStatus,
+            false,
+            Some(db_path),
+        )
+        .unwrap();
+
+        let patch = std::fs::read_to_string(&output_path).unwrap();
+        for i in 0..64 {
+            assert!(patch.contains(&format!("+++ {}.txt", i)));
+        }
+    }
+}
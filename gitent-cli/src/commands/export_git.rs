@@ -0,0 +1,54 @@
+use anyhow::Result;
+use colored::Colorize;
+use gitent_core::{git, Config, Storage};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::path::PathBuf;
+
+pub fn run(dir: PathBuf, branch: String, db: Option<PathBuf>) -> Result<()> {
+    let settings = super::load_settings(Config {
+        db,
+        ..Default::default()
+    })?;
+
+    if !settings.db_path.exists() {
+        anyhow::bail!("No active gitent session found. Run 'gitent start' first.");
+    }
+
+    let storage = Storage::new(&settings.db_path)?;
+    let session = storage.get_active_session()?;
+
+    let bar = ProgressBar::new(0).with_style(
+        ProgressStyle::with_template("Exporting to git {bar:30} {pos}/{len} commits")
+            .expect("valid progress template"),
+    );
+    let report = git::export_history(
+        &storage,
+        &session,
+        &dir,
+        &branch,
+        &settings.authors,
+        |progress| {
+            bar.set_length(progress.total as u64);
+            bar.set_position(progress.done as u64);
+        },
+    )?;
+    bar.finish_and_clear();
+
+    match report.head {
+        Some(head) => println!(
+            "{}",
+            format!(
+                "✓ Exported {} commit(s) to {} on branch {} ({})",
+                report.commits,
+                dir.display(),
+                branch,
+                &head.to_string()[..7]
+            )
+            .green()
+            .bold()
+        ),
+        None => println!("{}", "No commits to export".yellow()),
+    }
+
+    Ok(())
+}
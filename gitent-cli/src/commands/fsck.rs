@@ -0,0 +1,90 @@
+use anyhow::Result;
+use colored::Colorize;
+use gitent_core::Storage;
+use std::path::PathBuf;
+
+pub fn run(repair: bool, deep: bool, db: Option<PathBuf>) -> Result<()> {
+    let db_path = super::get_db_path(db)?;
+
+    if !db_path.exists() {
+        anyhow::bail!("No active gitent session found. Run 'gitent start' first.");
+    }
+
+    let storage = Storage::new(&db_path)?;
+    let report = if deep {
+        storage.check_integrity_deep()?
+    } else {
+        storage.check_integrity()?
+    };
+
+    println!("{}", "Checking database integrity...".bold().cyan());
+    println!();
+
+    if report.is_clean() {
+        println!("{}", "✓ No problems found".green().bold());
+        return Ok(());
+    }
+
+    for link in &report.missing_changes {
+        println!(
+            "  {} commit {} references missing change {}",
+            "✗".red(),
+            link.commit_id,
+            link.change_id
+        );
+    }
+    for link in &report.dangling_links {
+        println!(
+            "  {} link to change {} from missing commit {}",
+            "✗".red(),
+            link.change_id,
+            link.commit_id
+        );
+    }
+    for change_id in &report.orphaned_changes {
+        println!(
+            "  {} change {} belongs to a missing session",
+            "✗".red(),
+            change_id
+        );
+    }
+    for change_id in &report.corrupted_changes {
+        println!(
+            "  {} change {} has content that doesn't match its hash",
+            "✗".red(),
+            change_id
+        );
+    }
+    for commit_id in &report.altered_commits {
+        println!(
+            "  {} commit {} no longer matches its digest",
+            "✗".red(),
+            commit_id
+        );
+    }
+    println!();
+
+    if !repair {
+        println!("Run with {} to remove broken links", "--repair".cyan());
+        return Ok(());
+    }
+
+    let removed = storage.repair_integrity(&report)?;
+    println!(
+        "{}",
+        format!("✓ Removed {} broken link(s)", removed)
+            .green()
+            .bold()
+    );
+    if !report.orphaned_changes.is_empty() || !report.corrupted_changes.is_empty() {
+        println!(
+            "{}",
+            "Orphaned and corrupted changes were left in place for manual recovery".yellow()
+        );
+    }
+    if !report.altered_commits.is_empty() {
+        println!("{}", "Altered commits were left as they are".yellow());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use anyhow::Result;
+use colored::Colorize;
+use std::path::PathBuf;
+
+pub fn run(blobs: bool, dry_run: bool, db: Option<PathBuf>) -> Result<()> {
+    let storage = super::open_storage_at(db)?;
+
+    if !blobs {
+        storage.vacuum()?;
+        let swept = storage.sweep_objects()?;
+        println!("{}", "✓ Database compacted".green().bold());
+        if !swept.is_empty() {
+            println!("  Removed {} unreferenced object(s)", swept.len());
+        }
+        return Ok(());
+    }
+
+    let orphans = storage.find_orphan_blobs()?;
+    if orphans.is_empty() && dry_run {
+        println!("{}", "✓ No unreferenced content found".green().bold());
+        return Ok(());
+    }
+
+    if dry_run {
+        for orphan in &orphans {
+            println!(
+                "  {} change {} ({} bytes)",
+                "-".red(),
+                orphan.change_id,
+                orphan.bytes
+            );
+        }
+        let bytes: u64 = orphans.iter().map(|o| o.bytes).sum();
+        println!();
+        println!(
+            "Would remove {} change(s), {} bytes of content",
+            orphans.len(),
+            bytes
+        );
+        return Ok(());
+    }
+
+    let report = storage.collect_orphan_blobs()?;
+    if report.changes_removed == 0 && report.objects_removed == 0 {
+        println!("{}", "✓ No unreferenced content found".green().bold());
+        return Ok(());
+    }
+    println!(
+        "{}",
+        format!(
+            "✓ Removed {} unreferenced change(s) and {} object(s), reclaiming {} bytes",
+            report.changes_removed, report.objects_removed, report.bytes_reclaimed
+        )
+        .green()
+        .bold()
+    );
+
+    Ok(())
+}
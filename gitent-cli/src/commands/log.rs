@@ -1,10 +1,25 @@
 use anyhow::Result;
 use colored::Colorize;
-use gitent_core::Storage;
+use gitent_core::{Change, Commit, Config, Storage};
+use std::collections::HashSet;
 use std::path::PathBuf;
 
-pub fn run(limit: Option<usize>, db: Option<PathBuf>) -> Result<()> {
-    let db_path = super::get_db_path(db);
+/// Files or changes listed under each commit before the rest are summarized.
+const LISTED: usize = 5;
+
+pub fn run(
+    limit: Option<usize>,
+    tool: Option<String>,
+    milestones_only: bool,
+    full: bool,
+    show_changes: bool,
+    db: Option<PathBuf>,
+) -> Result<()> {
+    let settings = super::load_settings(Config {
+        db,
+        ..Default::default()
+    })?;
+    let db_path = settings.db_path;
 
     if !db_path.exists() {
         anyhow::bail!("No active gitent session found. Run 'gitent start' first.");
@@ -12,10 +27,31 @@ pub fn run(limit: Option<usize>, db: Option<PathBuf>) -> Result<()> {
 
     let storage = Storage::new(&db_path)?;
     let session = storage.get_active_session()?;
-    let commits = storage.get_commits_for_session(&session.id)?;
+    let mut commits = storage.get_commits_for_session(&session.id)?;
+    if let Some(tool) = &tool {
+        let matching: HashSet<_> = storage
+            .get_commits_by_tool(&session.id, tool)?
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        commits.retain(|info| matching.contains(&info.commit.id));
+    }
+    if milestones_only {
+        let milestones: HashSet<_> = storage
+            .get_milestones(&session.id)?
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        commits.retain(|info| milestones.contains(&info.commit.id));
+    }
 
     if commits.is_empty() {
-        println!("{}", "No commits yet".yellow());
+        let message = if milestones_only {
+            "No milestones yet"
+        } else {
+            "No commits yet"
+        };
+        println!("{}", message.yellow());
         return Ok(());
     }
 
@@ -27,34 +63,80 @@ pub fn run(limit: Option<usize>, db: Option<PathBuf>) -> Result<()> {
     for commit_info in commits.iter().take(to_show) {
         let commit = &commit_info.commit;
 
+        if commit.is_milestone() {
+            println!(
+                "{} {} {}",
+                "commit".magenta().bold(),
+                commit.id.to_string().magenta().bold(),
+                "★ milestone".magenta().bold()
+            );
+        } else {
+            println!(
+                "{} {}",
+                "commit".yellow().bold(),
+                commit.id.to_string().yellow()
+            );
+        }
         println!(
-            "{} {}",
-            "commit".yellow().bold(),
-            commit.id.to_string().yellow()
+            "{}: {}",
+            "Agent".bold(),
+            settings.authors.author_for(&commit.agent_id)
         );
-        println!("{}: {}", "Agent".bold(), commit.agent_id);
+        for co_author in &commit_info.co_authors {
+            println!(
+                "{}: {}",
+                "Co-authored-by".bold(),
+                settings.authors.author_for(co_author)
+            );
+        }
+        if let Some(issue) = commit.issue() {
+            println!("{}: {}", "Issue".bold(), issue);
+        }
+        if let Some(pr_url) = commit.pr_url() {
+            println!("{}: {}", "PR".bold(), pr_url);
+        }
         println!(
             "{}: {}",
             "Date".bold(),
             commit.timestamp.format("%Y-%m-%d %H:%M:%S")
         );
         println!();
-        println!("    {}", commit.message);
+        for line in message_lines(commit, full) {
+            println!("    {}", line);
+        }
         println!();
         println!(
             "    {} file(s) changed",
             commit_info.change_count.to_string().cyan()
         );
 
-        if !commit_info.files_affected.is_empty() {
-            for path in commit_info.files_affected.iter().take(5) {
+        if show_changes {
+            let changes = commit_changes(&storage, commit);
+            for change in changes.iter().take(LISTED) {
+                println!(
+                    "      {} {}",
+                    super::change_icon(change.change_type),
+                    super::describe_path(change, None).dimmed()
+                );
+            }
+            if changes.len() > LISTED {
+                println!(
+                    "      {} and {} more...",
+                    "...".dimmed(),
+                    (changes.len() - LISTED).to_string().dimmed()
+                );
+            }
+        } else if !commit_info.files_affected.is_empty() {
+            for path in commit_info.files_affected.iter().take(LISTED) {
                 println!("      • {}", path.display().to_string().dimmed());
             }
-            if commit_info.files_affected.len() > 5 {
+            if commit_info.files_affected.len() > LISTED {
                 println!(
                     "      {} and {} more...",
                     "...".dimmed(),
-                    (commit_info.files_affected.len() - 5).to_string().dimmed()
+                    (commit_info.files_affected.len() - LISTED)
+                        .to_string()
+                        .dimmed()
                 );
             }
         }
@@ -72,3 +154,81 @@ pub fn run(limit: Option<usize>, db: Option<PathBuf>) -> Result<()> {
 
     Ok(())
 }
+
+/// The changes in a commit, in canonical order. Changes that can no longer be
+/// loaded are skipped.
+fn commit_changes(storage: &Storage, commit: &Commit) -> Vec<Change> {
+    commit
+        .changes
+        .iter()
+        .filter_map(|id| storage.get_change(id).ok())
+        .collect()
+}
+
+/// The lines of a commit message to show: just the summary, or the whole
+/// message when `full` is set.
+fn message_lines(commit: &Commit, full: bool) -> Vec<&str> {
+    if full {
+        commit.message.lines().collect()
+    } else {
+        vec![commit.summary()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gitent_core::{ChangeType, Session};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_commit_changes_markers() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let changes = [
+            Change::new(ChangeType::Create, PathBuf::from("new.rs"), session.id),
+            Change::new(ChangeType::Modify, PathBuf::from("lib.rs"), session.id),
+            Change::new(ChangeType::Delete, PathBuf::from("old.rs"), session.id),
+        ];
+        for change in &changes {
+            storage.create_change(change).unwrap();
+        }
+        let commit = Commit::new(
+            "Rework modules".to_string(),
+            "test-agent".to_string(),
+            changes.iter().map(|c| c.id).collect(),
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+
+        let listed: Vec<String> = commit_changes(&storage, &commit)
+            .iter()
+            .map(|change| {
+                format!(
+                    "{} {}",
+                    crate::commands::change_marker(change.change_type),
+                    change.path.display()
+                )
+            })
+            .collect();
+        assert_eq!(listed, vec!["+ new.rs", "~ lib.rs", "- old.rs"]);
+    }
+
+    #[test]
+    fn test_message_lines() {
+        let commit = Commit::new(
+            Commit::compose_message("Add parser", Some("Handles nested lists.")),
+            "test-agent".to_string(),
+            vec![],
+            Uuid::new_v4(),
+        );
+
+        assert_eq!(message_lines(&commit, false), vec!["Add parser"]);
+        assert_eq!(
+            message_lines(&commit, true),
+            vec!["Add parser", "", "Handles nested lists."]
+        );
+    }
+}
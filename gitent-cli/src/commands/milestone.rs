@@ -0,0 +1,44 @@
+use anyhow::Result;
+use colored::Colorize;
+use std::path::PathBuf;
+
+/// Mark `commit_id` as a milestone, or unmark it with `unset`, or list the
+/// session's milestones when no commit is given.
+pub fn run(commit_id: Option<String>, unset: bool, db: Option<PathBuf>) -> Result<()> {
+    let storage = super::open_storage_at(db)?;
+    let session = storage.get_active_session()?;
+
+    let Some(commit_id) = commit_id else {
+        let milestones = storage.get_milestones(&session.id)?;
+        if milestones.is_empty() {
+            println!("{}", "No milestones".yellow());
+            return Ok(());
+        }
+        for commit in milestones {
+            println!(
+                "{} {} {}",
+                commit
+                    .timestamp
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+                    .bright_black(),
+                commit.id.to_string().magenta(),
+                commit.summary().bold()
+            );
+        }
+        return Ok(());
+    };
+
+    let commit = storage.resolve_commit_prefix(&session.id, &commit_id)?;
+    let commit = storage.set_milestone(&commit.id, !unset)?;
+
+    if unset {
+        println!("{}", "✓ Milestone removed".green().bold());
+    } else {
+        println!("{}", "✓ Marked as milestone".green().bold());
+    }
+    println!("  {}: {}", "Commit ID".bold(), commit.id);
+    println!("  {}: {}", "Message".bold(), commit.summary());
+
+    Ok(())
+}
@@ -1,17 +1,96 @@
+pub mod audit;
+pub mod checkpoint;
 pub mod commit;
 pub mod diff;
+#[cfg(feature = "git")]
+pub mod export_git;
+pub mod fsck;
+pub mod gc;
 pub mod log;
+pub mod milestone;
+pub mod rebuild_db;
+pub mod repair;
+pub mod report;
+pub mod reset;
+pub mod revert_change;
 pub mod rollback;
+pub mod sessions;
+pub mod snapshot;
 pub mod start;
 pub mod status;
 
+use anyhow::Result;
+use colored::{ColoredString, Colorize};
+use gitent_core::{Change, ChangeType, Config, Settings, Storage};
 use std::path::PathBuf;
 
-pub fn get_db_path(custom_path: Option<PathBuf>) -> PathBuf {
-    custom_path.unwrap_or_else(|| {
-        std::env::current_dir()
-            .unwrap()
-            .join(".gitent")
-            .join("gitent.db")
-    })
+/// Resolve settings for the current directory, with CLI flags taking precedence
+/// over `.gitent/config.toml`.
+pub fn load_settings(overrides: Config) -> Result<Settings> {
+    let root = std::env::current_dir()?;
+    Ok(Settings::load(&root, overrides)?)
+}
+
+pub fn get_db_path(custom_path: Option<PathBuf>) -> Result<PathBuf> {
+    let settings = load_settings(Config {
+        db: custom_path,
+        ..Default::default()
+    })?;
+    Ok(settings.db_path)
+}
+
+/// Open the database to write to it, with the settings that decide how
+/// content is stored.
+pub fn open_storage(settings: &Settings) -> Result<Storage> {
+    if !settings.db_path.exists() {
+        anyhow::bail!("No active gitent session found. Run 'gitent start' first.");
+    }
+    Ok(Storage::open(settings)?)
+}
+
+/// [`open_storage`] for commands whose only setting is the database path.
+pub fn open_storage_at(custom_path: Option<PathBuf>) -> Result<Storage> {
+    open_storage(&load_settings(Config {
+        db: custom_path,
+        ..Default::default()
+    })?)
+}
+
+/// The marker shown next to a change of each type.
+pub fn change_marker(change_type: ChangeType) -> &'static str {
+    match change_type {
+        ChangeType::Create => "+",
+        ChangeType::Modify => "~",
+        ChangeType::Delete => "-",
+        ChangeType::Rename => "→",
+    }
+}
+
+/// [`change_marker`] in the color for its change type.
+pub fn change_icon(change_type: ChangeType) -> ColoredString {
+    let marker = change_marker(change_type);
+    match change_type {
+        ChangeType::Create => marker.green(),
+        ChangeType::Modify => marker.yellow(),
+        ChangeType::Delete => marker.red(),
+        ChangeType::Rename => marker.blue(),
+    }
+}
+
+/// Format a change's path for display, as `old -> new (92%)` for renames.
+pub fn describe_path(change: &Change, similarity: Option<f32>) -> String {
+    match &change.old_path {
+        Some(old_path) => {
+            let similarity = similarity
+                .map(|s| format!(" ({:.0}%)", s * 100.0))
+                .unwrap_or_default();
+            format!(
+                "{} -> {}{}",
+                old_path.display(),
+                change.path.display(),
+                similarity
+            )
+        }
+        None => change.path.display().to_string(),
+    }
 }
@@ -0,0 +1,76 @@
+use anyhow::Result;
+use colored::Colorize;
+use gitent_core::event_log;
+use gitent_core::{Config, Storage};
+use std::path::{Path, PathBuf};
+
+/// Files SQLite keeps next to a database, which belong with it.
+const DB_SUFFIXES: [&str; 3] = ["", "-wal", "-shm"];
+
+pub fn run(log: Option<PathBuf>, force: bool, db: Option<PathBuf>) -> Result<()> {
+    let settings = super::load_settings(Config {
+        db,
+        ..Default::default()
+    })?;
+    let db_path = settings.db_path;
+    let log_path = log.unwrap_or(settings.event_log_path);
+    let external_threshold = settings.external_threshold;
+
+    if !log_path.exists() {
+        anyhow::bail!(
+            "No event log at {}. Start gitent with --event-log to keep one.",
+            log_path.display()
+        );
+    }
+    if db_path.exists() {
+        if !force {
+            anyhow::bail!(
+                "{} already exists. Pass --force to replace it.",
+                db_path.display()
+            );
+        }
+        let backup = set_aside(&db_path)?;
+        println!("Moved the existing database to {}", backup.display());
+    }
+
+    // Not Storage::open, as the log being replayed mustn't be appended to
+    let mut storage = Storage::new(&db_path)?;
+    if let Some(threshold) = external_threshold {
+        storage = storage.with_external_store(threshold);
+    }
+    let report = event_log::replay(&log_path, &storage)?;
+
+    if report.torn_tail {
+        println!(
+            "{}",
+            "The last event was cut short and was skipped".yellow()
+        );
+    }
+    println!(
+        "{}",
+        format!(
+            "✓ Rebuilt {} from {} event(s): {} session(s), {} change(s), {} commit(s)",
+            db_path.display(),
+            report.events,
+            report.sessions,
+            report.changes,
+            report.commits
+        )
+        .green()
+        .bold()
+    );
+
+    Ok(())
+}
+
+/// Rename the database at `db_path` and its journal files to `*.bak`,
+/// returning the database's new path.
+fn set_aside(db_path: &Path) -> Result<PathBuf> {
+    for suffix in DB_SUFFIXES {
+        let path = PathBuf::from(format!("{}{}", db_path.display(), suffix));
+        if path.exists() {
+            std::fs::rename(&path, format!("{}.bak", path.display()))?;
+        }
+    }
+    Ok(PathBuf::from(format!("{}.bak", db_path.display())))
+}
@@ -0,0 +1,36 @@
+use anyhow::Result;
+use colored::Colorize;
+use std::path::PathBuf;
+
+pub fn run(db: Option<PathBuf>) -> Result<()> {
+    let storage = super::open_storage_at(db)?;
+    let ended = storage.repair_active_sessions()?;
+
+    if ended.is_empty() {
+        println!("{}", "✓ Only one session is active".green().bold());
+        return Ok(());
+    }
+
+    for session in &ended {
+        println!(
+            "  {} ended session {} (started {})",
+            "-".red(),
+            session.id,
+            session.started.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+    let active = storage.get_active_session()?;
+    println!();
+    println!(
+        "{}",
+        format!(
+            "✓ Ended {} stale session(s); {} is the active session",
+            ended.len(),
+            active.id
+        )
+        .green()
+        .bold()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use anyhow::Result;
+use colored::Colorize;
+use gitent_core::Storage;
+use std::path::PathBuf;
+
+pub fn run(by_language: bool, db: Option<PathBuf>) -> Result<()> {
+    let db_path = super::get_db_path(db)?;
+
+    if !db_path.exists() {
+        anyhow::bail!("No active gitent session found. Run 'gitent start' first.");
+    }
+
+    let storage = Storage::new(&db_path)?;
+    let session = storage.get_active_session()?;
+    let commits = storage.get_commits_for_session(&session.id)?;
+    let uncommitted = storage.get_uncommitted_changes(&session.id)?;
+
+    println!("{}", "Session Report".bold().cyan());
+    println!("  {}: {}", "Commits".bold(), commits.len());
+    println!("  {}: {}", "Uncommitted changes".bold(), uncommitted.len());
+
+    if by_language {
+        let counts = storage.count_changes_by_language(&session.id)?;
+        let total: usize = counts.iter().map(|(_, count)| count).sum();
+
+        println!();
+        println!("{}", "Changes by language".bold());
+        for (language, count) in &counts {
+            let percent = *count as f64 * 100.0 / total as f64;
+            println!(
+                "  {:<16} {:>6}  {}",
+                language.as_deref().unwrap_or("Other"),
+                count.to_string().yellow(),
+                format!("{:.1}%", percent).dimmed()
+            );
+        }
+    }
+
+    Ok(())
+}
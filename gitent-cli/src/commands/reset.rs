@@ -0,0 +1,29 @@
+use anyhow::Result;
+use colored::Colorize;
+use std::path::PathBuf;
+
+pub fn run(soft: bool, db: Option<PathBuf>) -> Result<()> {
+    if !soft {
+        anyhow::bail!("Only soft resets are supported. Run 'gitent reset --soft'.");
+    }
+
+    let storage = super::open_storage_at(db)?;
+    let session = storage.get_active_session()?;
+    let commit = storage.reset_head(&session.id)?;
+
+    println!("{}", "✓ Reset latest commit".green().bold());
+    println!("  {}: {}", "Commit ID".bold(), commit.id);
+    println!("  {}: {}", "Message".bold(), commit.message);
+    println!(
+        "  {}: {}",
+        "Changes restored".bold(),
+        commit.changes.len().to_string().yellow()
+    );
+
+    match storage.get_head_commit(&session.id)? {
+        Some(head) => println!("  {}: {}", "Head is now".bold(), head.id),
+        None => println!("  {}", "No commits remain".dimmed()),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use anyhow::Result;
+use colored::Colorize;
+use gitent_core::rollback::{self, RollbackOutcome};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+pub fn run(
+    change_id: String,
+    force: bool,
+    verify: bool,
+    record: bool,
+    db: Option<PathBuf>,
+) -> Result<()> {
+    let storage = super::open_storage_at(db)?;
+    let change = storage.get_change(&Uuid::parse_str(&change_id)?)?;
+    let commit = storage.get_commit_for_change(&change.id)?;
+    let session = storage.get_active_session()?;
+    // Another session's files may live under a different root
+    if change.session_id != session.id {
+        anyhow::bail!("Change {} is not in the active session", change.id);
+    }
+    let root = session.content_root();
+
+    println!("{}", "Reverting change".bold().cyan());
+    println!("  {}: {}", "Change".bold(), change.id);
+    println!(
+        "  {}: {} ({})",
+        "Commit".bold(),
+        commit.id,
+        commit.message.lines().next().unwrap_or("")
+    );
+    println!(
+        "  {}: {}",
+        "File".bold(),
+        super::describe_path(&change, None)
+    );
+    println!();
+
+    let plan = rollback::plan_change(&commit, &change, root)?;
+    // Read before the file is touched, as it's what the revert replaces
+    let revert = record.then(|| rollback::revert_record(&change, root));
+    let result = rollback::execute(plan, std::slice::from_ref(&change), root, force, verify);
+    let step = &result.steps[0];
+
+    match step.outcome {
+        RollbackOutcome::Applied => {
+            println!("  {} {}", "✓".green(), step.path.display());
+        }
+        RollbackOutcome::Skipped => {
+            anyhow::bail!(
+                "{} was modified after this commit; pass --force to revert it anyway",
+                step.path.display()
+            );
+        }
+        RollbackOutcome::Failed | RollbackOutcome::Planned => {
+            anyhow::bail!(
+                "Couldn't revert {}: {}",
+                step.path.display(),
+                step.error.as_deref().unwrap_or("unknown error")
+            );
+        }
+    }
+
+    if let Some(revert) = revert {
+        storage.create_change(&revert)?;
+        println!("  {}: {}", "Recorded as".bold(), revert.id);
+    }
+
+    println!();
+    println!("{}", "✓ Change reverted".green().bold());
+    Ok(())
+}
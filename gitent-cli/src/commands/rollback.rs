@@ -1,91 +1,198 @@
 use anyhow::Result;
 use colored::Colorize;
-use gitent_core::Storage;
-use std::path::PathBuf;
+use gitent_core::rollback::{self, RollbackAction, RollbackOutcome, RollbackPlan};
+use gitent_core::{Change, Storage};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
-pub fn run(commit_id: String, execute: bool, db: Option<PathBuf>) -> Result<()> {
-    let db_path = super::get_db_path(db);
+/// Characters of the commit id that have to be typed to confirm a rollback.
+const SHORT_ID_LEN: usize = 8;
 
-    if !db_path.exists() {
-        anyhow::bail!("No active gitent session found. Run 'gitent start' first.");
-    }
+/// What to roll back.
+pub enum Target {
+    Commit(String),
+    /// Every commit from the first to the second, inclusive
+    Range {
+        from: String,
+        to: String,
+    },
+}
 
-    let storage = Storage::new(&db_path)?;
+/// What to do once the preview is shown.
+pub enum Mode {
+    Preview,
+    /// Check the rollback could be done in full, without doing it
+    Check,
+    Execute {
+        /// Skip the confirmation prompt
+        yes: bool,
+    },
+}
+
+pub fn run(
+    target: Target,
+    mode: Mode,
+    force: bool,
+    verify: bool,
+    db: Option<PathBuf>,
+) -> Result<()> {
+    let storage = super::open_storage_at(db)?;
     let session = storage.get_active_session()?;
-    let commit_uuid = Uuid::parse_str(&commit_id)?;
-    let commit = storage.get_commit(&commit_uuid)?;
 
-    println!("{}", "Rollback Preview".bold().cyan());
-    println!("  {}: {}", "Target Commit".bold(), commit.id);
-    println!("  {}: {}", "Message".bold(), commit.message);
-    println!("  {}: {}", "Agent".bold(), commit.agent_id);
-    println!(
-        "  {}: {}",
-        "Date".bold(),
-        commit.timestamp.format("%Y-%m-%d %H:%M:%S")
-    );
-    println!();
+    let root = session.content_root();
+    let (plan, source) = match target {
+        Target::Commit(commit_id) => {
+            let commit = storage.resolve_commit_prefix(&session.id, &commit_id)?;
+            // Another session's files may live under a different root
+            if commit.session_id != session.id {
+                anyhow::bail!("Commit {} is not in the active session", commit.id);
+            }
 
-    // Get all changes from this commit
-    let changes: Vec<_> = commit
-        .changes
-        .iter()
-        .filter_map(|id| storage.get_change(id).ok())
-        .collect();
+            println!("{}", "Rollback Preview".bold().cyan());
+            println!("  {}: {}", "Target Commit".bold(), commit.id);
+            println!("  {}: {}", "Message".bold(), commit.message);
+            println!("  {}: {}", "Agent".bold(), commit.agent_id);
+            println!(
+                "  {}: {}",
+                "Date".bold(),
+                commit.timestamp.format("%Y-%m-%d %H:%M:%S")
+            );
+            println!();
 
-    if changes.is_empty() {
-        println!("{}", "No changes in this commit to rollback".yellow());
+            // Planned one change at a time, and fetched again as each is
+            // rolled back, as a large commit's content may not fit in memory
+            let steps = commit
+                .changes
+                .iter()
+                .filter_map(|id| storage.get_change(id).ok())
+                .map(|change| rollback::plan_step(&change, root))
+                .collect();
+            let plan = RollbackPlan {
+                commit_id: commit.id,
+                executed: false,
+                steps,
+            };
+            (plan, Source::Stored(&storage))
+        }
+        Target::Range { from, to } => {
+            let from = storage.resolve_commit_prefix(&session.id, &from)?;
+            let to = storage.resolve_commit_prefix(&session.id, &to)?;
+            if from.session_id != session.id {
+                anyhow::bail!("Commit {} is not in the active session", from.id);
+            }
+            let mut commits = storage.commit_range(&from.id, &to.id)?;
+
+            println!("{}", "Rollback Preview".bold().cyan());
+            println!("  {}: {}", "Target Commits".bold(), commits.len());
+            for commit in commits.iter().rev() {
+                println!(
+                    "    {} {}",
+                    commit.id.to_string().yellow(),
+                    commit.message.lines().next().unwrap_or("")
+                );
+            }
+            println!();
+
+            // Only the net effect on each file is undone
+            let mut changes: Vec<_> = commits
+                .iter()
+                .flat_map(|commit| &commit.changes)
+                .filter_map(|id| storage.get_change(id).ok())
+                .collect();
+            changes.sort_by_key(|change| change.seq);
+            let newest = commits.pop().expect("commit ranges are never empty");
+            let changes = rollback::net_changes(&changes);
+            let plan = rollback::plan(&newest, &changes, root);
+            (plan, Source::Combined(changes))
+        }
+    };
+
+    if plan.steps.is_empty() {
+        println!("{}", "No changes to rollback".yellow());
         return Ok(());
     }
 
     println!("{}", "Files to be restored:".bold());
-    for change in &changes {
-        let status = match change.change_type {
-            gitent_core::ChangeType::Create => "will be removed".red(),
-            gitent_core::ChangeType::Modify => "will be restored".yellow(),
-            gitent_core::ChangeType::Delete => "will be recreated".green(),
-            gitent_core::ChangeType::Rename => "will be renamed back".blue(),
+    for step in &plan.steps {
+        let status = match step.action {
+            RollbackAction::Remove => "will be removed".red(),
+            RollbackAction::Restore => "will be restored".yellow(),
+            RollbackAction::Recreate => "will be recreated".green(),
+            RollbackAction::RenameBack => "will be renamed back".blue(),
         };
-        println!("  {} {}", change.path.display(), status);
+        println!("  {} {}", step.path.display(), status);
+        if let Some(conflict) = &step.conflict {
+            println!("    {} {}", "⚠ conflict:".red().bold(), conflict);
+        }
     }
     println!();
 
-    if !execute {
-        println!("{}", "This is a preview only.".yellow());
-        println!(
-            "Run with {} to actually perform the rollback",
-            "--execute".cyan()
-        );
-        return Ok(());
+    let yes = match mode {
+        Mode::Preview => {
+            println!("{}", "This is a preview only.".yellow());
+            println!(
+                "Run with {} to actually perform the rollback, or {} to make sure it can be",
+                "--execute".cyan(),
+                "--check".cyan()
+            );
+            if plan.has_conflicts() {
+                println!(
+                    "Conflicting files are skipped unless {} is given",
+                    "--force".cyan()
+                );
+            }
+            return Ok(());
+        }
+        Mode::Check => return check(plan, &source, root, force, verify),
+        Mode::Execute { yes } => yes,
+    };
+
+    if !yes {
+        let stdin = std::io::stdin();
+        let interactive = stdin.is_terminal();
+        confirm(&plan.commit_id, &mut stdin.lock(), interactive)?;
+        println!();
     }
 
     // Perform the rollback
     println!("{}", "Performing rollback...".bold());
 
-    let mut errors = Vec::new();
-    let mut success_count = 0;
+    let mut result = plan;
+    for step in &mut result.steps {
+        let change = source.get(&step.change_id);
+        rollback::execute_step(step, change.as_ref(), root, force, verify);
+    }
+    result.executed = true;
 
-    for change in &changes {
-        match perform_rollback_for_change(change, &session.root_path) {
-            Ok(_) => {
-                success_count += 1;
-                println!("  {} {}", "✓".green(), change.path.display());
+    for step in &result.steps {
+        match step.outcome {
+            RollbackOutcome::Applied => {
+                println!("  {} {}", "✓".green(), step.path.display());
+            }
+            RollbackOutcome::Skipped => {
+                println!(
+                    "  {} {} - {}",
+                    "-".yellow(),
+                    step.path.display(),
+                    "skipped (conflict)".yellow()
+                );
             }
-            Err(e) => {
-                errors.push((change.path.clone(), e));
+            RollbackOutcome::Failed | RollbackOutcome::Planned => {
                 println!(
                     "  {} {} - {}",
                     "✗".red(),
-                    change.path.display(),
+                    step.path.display(),
                     "failed".red()
                 );
             }
         }
     }
 
+    let success_count = result.count(RollbackOutcome::Applied);
+
     println!();
-    if errors.is_empty() {
+    if success_count == result.steps.len() {
         println!(
             "{}",
             format!("✓ Successfully rolled back {} file(s)", success_count)
@@ -95,58 +202,133 @@ pub fn run(commit_id: String, execute: bool, db: Option<PathBuf>) -> Result<()>
     } else {
         println!(
             "{}",
-            format!("⚠ Rolled back {}/{} files", success_count, changes.len())
-                .yellow()
-                .bold()
+            format!(
+                "⚠ Rolled back {}/{} files",
+                success_count,
+                result.steps.len()
+            )
+            .yellow()
+            .bold()
         );
-        println!();
-        println!("{}", "Errors:".red().bold());
-        for (path, error) in errors {
-            println!("  {}: {}", path.display(), error);
+
+        let errors: Vec<_> = result
+            .steps
+            .iter()
+            .filter_map(|step| step.error.as_ref().map(|e| (&step.path, e)))
+            .collect();
+        if !errors.is_empty() {
+            println!();
+            println!("{}", "Errors:".red().bold());
+            for (path, error) in errors {
+                println!("  {}: {}", path.display(), error);
+            }
         }
     }
 
     Ok(())
 }
 
-fn perform_rollback_for_change(
-    change: &gitent_core::Change,
-    root_path: &std::path::Path,
-) -> Result<()> {
-    let full_path = root_path.join(&change.path);
+/// Where the changes being rolled back come from.
+enum Source<'a> {
+    /// Fetched from storage as needed
+    Stored(&'a Storage),
+    /// Combined from a range of commits, so only held in memory
+    Combined(Vec<Change>),
+}
 
-    match change.change_type {
-        gitent_core::ChangeType::Create => {
-            // Remove the created file
-            if full_path.exists() {
-                std::fs::remove_file(&full_path)?;
-            }
+impl Source<'_> {
+    fn get(&self, id: &Uuid) -> Option<Change> {
+        match self {
+            Source::Stored(storage) => storage.get_change(id).ok(),
+            Source::Combined(changes) => changes.iter().find(|c| c.id == *id).cloned(),
         }
-        gitent_core::ChangeType::Modify => {
-            // Restore previous content
-            if let Some(content_before) = &change.content_before {
-                std::fs::write(&full_path, content_before)?;
-            }
-        }
-        gitent_core::ChangeType::Delete => {
-            // Recreate the deleted file
-            if let Some(content_before) = &change.content_before {
-                if let Some(parent) = full_path.parent() {
-                    std::fs::create_dir_all(parent)?;
-                }
-                std::fs::write(&full_path, content_before)?;
-            }
-        }
-        gitent_core::ChangeType::Rename => {
-            // Rename back to old path
-            if let Some(old_path) = &change.old_path {
-                let old_full_path = root_path.join(old_path);
-                if full_path.exists() {
-                    std::fs::rename(&full_path, &old_full_path)?;
-                }
-            }
+    }
+}
+
+/// Report whether each file in `plan` could be rolled back, failing if any
+/// couldn't.
+fn check(
+    mut result: RollbackPlan,
+    source: &Source,
+    root: &Path,
+    force: bool,
+    verify: bool,
+) -> Result<()> {
+    println!("{}", "Checking rollback...".bold());
+    for step in &mut result.steps {
+        let change = source.get(&step.change_id);
+        rollback::check_step(step, change.as_ref(), root, force, verify);
+    }
+
+    for step in &result.steps {
+        match &step.error {
+            None => println!("  {} {}", "✓".green(), step.path.display()),
+            Some(error) => println!("  {} {} - {}", "✗".red(), step.path.display(), error),
         }
     }
 
+    let blocked = result.count(RollbackOutcome::Failed);
+    println!();
+    if blocked > 0 {
+        anyhow::bail!(
+            "Rollback would fail for {}/{} file(s)",
+            blocked,
+            result.steps.len()
+        );
+    }
+    println!(
+        "{}",
+        format!("✓ All {} file(s) can be rolled back", result.steps.len())
+            .green()
+            .bold()
+    );
+    Ok(())
+}
+
+/// Ask for the commit's short id before overwriting files, failing unless it's
+/// typed back. Without a terminal to ask on, fail rather than wait for input.
+fn confirm(commit_id: &Uuid, input: &mut impl BufRead, interactive: bool) -> Result<()> {
+    if !interactive {
+        anyhow::bail!("Not prompting for confirmation without a terminal; pass --yes to roll back");
+    }
+
+    let short_id = &commit_id.to_string()[..SHORT_ID_LEN];
+    print!(
+        "Type {} to roll back, anything else aborts: ",
+        short_id.cyan()
+    );
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    if answer.trim() != short_id {
+        anyhow::bail!("Rollback aborted");
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_confirm() {
+        let id = Uuid::new_v4();
+        let short_id = &id.to_string()[..SHORT_ID_LEN];
+
+        let mut matching = format!("{}\n", short_id).into_bytes();
+        assert!(confirm(&id, &mut &matching[..], true).is_ok());
+
+        // The full id, a typo or no answer at all abort
+        for answer in [id.to_string(), format!("{}x", short_id), String::new()] {
+            let answer = answer.into_bytes();
+            let err = confirm(&id, &mut &answer[..], true).unwrap_err();
+            assert_eq!(err.to_string(), "Rollback aborted");
+        }
+
+        // Without a terminal even the right answer isn't read
+        matching.clear();
+        let err = confirm(&id, &mut &matching[..], false).unwrap_err();
+        assert!(err.to_string().contains("--yes"));
+    }
+}
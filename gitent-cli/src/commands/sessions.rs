@@ -0,0 +1,56 @@
+use anyhow::Result;
+use colored::Colorize;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// List every session, oldest first.
+pub fn list(db: Option<PathBuf>) -> Result<()> {
+    let storage = super::open_storage_at(db)?;
+
+    for session in storage.get_sessions()? {
+        let marker = if session.active {
+            "active".green().bold()
+        } else {
+            "ended".bright_black()
+        };
+        println!(
+            "{} {} {} {}",
+            session.id.to_string().cyan(),
+            session
+                .started
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+                .bright_black(),
+            marker,
+            session.root_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Fold session `from` into session `into`.
+pub fn merge(from: String, into: String, db: Option<PathBuf>) -> Result<()> {
+    let storage = super::open_storage_at(db)?;
+    let report = storage.merge_sessions(&Uuid::parse_str(&into)?, &Uuid::parse_str(&from)?)?;
+
+    println!("{}", "✓ Sessions merged".green().bold());
+    println!("  {}: {}", "Into".bold(), into);
+    println!("  {}: {}", "Changes moved".bold(), report.changes_moved);
+    println!("  {}: {}", "Commits moved".bold(), report.commits_moved);
+
+    if !report.overlapping_paths.is_empty() {
+        println!();
+        println!(
+            "{}",
+            "⚠ Files changed in both sessions; review their history:"
+                .yellow()
+                .bold()
+        );
+        for path in &report.overlapping_paths {
+            println!("  {}", path.display());
+        }
+    }
+
+    Ok(())
+}
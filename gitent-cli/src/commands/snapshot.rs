@@ -0,0 +1,19 @@
+use anyhow::Result;
+use colored::Colorize;
+use std::path::PathBuf;
+
+pub fn run(db: Option<PathBuf>) -> Result<()> {
+    let storage = super::open_storage_at(db)?;
+    let session = storage.get_active_session()?;
+    let snapshot_id = storage.snapshot_uncommitted(&session.id)?;
+
+    println!("{}", "✓ Snapshot taken".green().bold());
+    println!("  {}: {}", "Snapshot ID".bold(), snapshot_id);
+    println!();
+    println!(
+        "Run {} to see what changed since",
+        format!("gitent diff --since-snapshot {}", snapshot_id).cyan()
+    );
+
+    Ok(())
+}
@@ -1,12 +1,55 @@
 use anyhow::Result;
 use colored::Colorize;
-use gitent_server::GitentServer;
+use gitent_core::{Config, Settings};
+use gitent_server::{CorsPolicy, GitentServer};
+#[cfg(feature = "git")]
+use indicatif::{ProgressBar, ProgressStyle};
 use std::path::PathBuf;
 
-pub async fn run(path: PathBuf, port: u16, db: Option<PathBuf>) -> Result<()> {
+/// Git history to seed a new session with.
+#[cfg_attr(not(feature = "git"), allow(dead_code))]
+pub struct GitImport {
+    /// Import only this many of the newest commits
+    pub max_depth: Option<usize>,
+}
+
+/// Start the server for `path`, with `overrides` holding the settings given as flags.
+pub async fn run(
+    path: PathBuf,
+    overrides: Config,
+    cors_origins: Vec<String>,
+    cors_any: bool,
+    metrics: bool,
+    import_git: Option<GitImport>,
+) -> Result<()> {
+    #[cfg(not(feature = "git"))]
+    if import_git.is_some() {
+        anyhow::bail!("--import-git is unavailable: gitent was built without the `git` feature");
+    }
     let abs_path = std::fs::canonicalize(&path)?;
 
-    let db_path = db.unwrap_or_else(|| abs_path.join(".gitent").join("gitent.db"));
+    let settings = Settings::load(
+        &abs_path,
+        Config {
+            // Paths given on the command line are relative to the cwd, not the project
+            db: overrides.db.map(std::path::absolute).transpose()?,
+            content_root: overrides
+                .content_root
+                .map(std::path::absolute)
+                .transpose()?,
+            ..overrides
+        },
+    )?;
+    let db_path = settings.db_path.clone();
+    let port = settings.port;
+
+    let cors = if cors_any {
+        CorsPolicy::Any
+    } else if !cors_origins.is_empty() {
+        CorsPolicy::from_origins(&cors_origins)?
+    } else {
+        CorsPolicy::Disabled
+    };
 
     // Create .gitent directory if it doesn't exist
     if let Some(parent) = db_path.parent() {
@@ -17,14 +60,98 @@ pub async fn run(path: PathBuf, port: u16, db: Option<PathBuf>) -> Result<()> {
     println!("   {}: {:?}", "Watching".bold(), abs_path);
     println!("   {}: {:?}", "Database".bold(), db_path);
 
-    let server = GitentServer::new(abs_path.clone(), db_path)?;
+    let server = GitentServer::with_settings(abs_path.clone(), &settings)?
+        .with_cors(cors.clone())
+        .with_metrics(metrics);
 
     println!("   {}: {}", "Session ID".bold(), server.session_id());
+    #[cfg(feature = "git")]
+    if let Some(import) = import_git {
+        let bar = ProgressBar::new(0).with_style(
+            ProgressStyle::with_template("   Importing from git {bar:30} {pos}/{len} commits")
+                .expect("valid progress template"),
+        );
+        let report = server
+            .import_git(import.max_depth, {
+                let bar = bar.clone();
+                move |progress| {
+                    bar.set_length(progress.total as u64);
+                    bar.set_position(progress.done as u64);
+                }
+            })
+            .await?;
+        bar.finish_and_clear();
+        println!(
+            "   {}: {} commit(s), {} change(s)",
+            "Imported from git".bold(),
+            report.commits,
+            report.changes
+        );
+        if report.shallow {
+            println!(
+                "   {}",
+                "Shallow clone: history before its oldest commit was not imported".yellow()
+            );
+        }
+    }
     println!(
         "   {}: {}",
         "API Server".bold(),
         format!("http://localhost:{}", port).green()
     );
+    match &cors {
+        CorsPolicy::Disabled => {}
+        CorsPolicy::Any => println!("   {}: {}", "CORS".bold(), "any origin".yellow()),
+        CorsPolicy::Origins(_) => println!("   {}: {}", "CORS".bold(), cors_origins.join(", ")),
+    }
+    if metrics {
+        println!(
+            "   {}: {}",
+            "Metrics".bold(),
+            format!("http://localhost:{}/metrics", port).green()
+        );
+    }
+    if let Some(interval) = settings.poll_interval {
+        println!("   {}: every {} ms", "Polling".bold(), interval.as_millis());
+    }
+    if let Some(limit) = settings.max_concurrency {
+        println!("   {}: {} requests", "Concurrency limit".bold(), limit);
+    }
+    if let Some(limit) = settings.max_body_bytes {
+        println!("   {}: {} bytes", "Body limit".bold(), limit);
+    }
+    if !settings.redact_patterns.is_empty() {
+        println!(
+            "   {}: {}",
+            "Redacting".bold(),
+            settings.redact_patterns.join(", ")
+        );
+    }
+    if !settings.include_patterns.is_empty() {
+        println!(
+            "   {}: {}",
+            "Including only".bold(),
+            settings.include_patterns.join(", ")
+        );
+    }
+    if !settings.webhooks.is_empty() {
+        println!("   {}: {}", "Webhooks".bold(), settings.webhooks.join(", "));
+    }
+    if let Some(content_root) = &settings.content_root {
+        println!("   {}: {:?}", "Content root".bold(), content_root);
+    }
+    if settings.file_index {
+        println!("   {}: {}", "File index".bold(), "enabled".green());
+    }
+    if settings.event_log {
+        println!("   {}: {:?}", "Event log".bold(), settings.event_log_path);
+    }
+    if let Some(n) = settings.retention.keep_commits {
+        println!("   {}: newest {} commits", "Retention".bold(), n);
+    }
+    if let Some(days) = settings.retention.keep_days {
+        println!("   {}: {} days", "Retention".bold(), days);
+    }
     println!();
     println!("{}", "Press Ctrl+C to stop".dimmed());
     println!();
@@ -1,10 +1,28 @@
 use anyhow::Result;
 use colored::Colorize;
-use gitent_core::Storage;
+use gitent_core::{diff::collapse_renames, rollback::net_changes, Change, Config, Storage};
+use std::fmt::Write as _;
+use std::io::Write as _;
 use std::path::PathBuf;
+use std::time::Duration;
 
-pub fn run(db: Option<PathBuf>) -> Result<()> {
-    let db_path = super::get_db_path(db);
+/// How often `--follow` checks the database for new changes.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Uncommitted changes listed before the rest are summarized.
+const LISTED: usize = 10;
+
+pub fn run(
+    tool: Option<String>,
+    follow: bool,
+    suggest_compact: bool,
+    db: Option<PathBuf>,
+) -> Result<()> {
+    let settings = super::load_settings(Config {
+        db,
+        ..Default::default()
+    })?;
+    let db_path = settings.db_path;
 
     if !db_path.exists() {
         println!("{}", "No active gitent session found".red());
@@ -13,55 +31,308 @@ pub fn run(db: Option<PathBuf>) -> Result<()> {
     }
 
     let storage = Storage::new(&db_path)?;
+    let options = RenderOptions {
+        tool,
+        rename_threshold: settings.rename_threshold,
+        suggest_compact,
+    };
+    if !follow {
+        print!("{}", render(&storage, &options)?);
+        return Ok(());
+    }
+
+    // Runs until interrupted with Ctrl+C
+    let mut follower = Follower::default();
+    loop {
+        if let Some(frame) = follower.refresh(&storage, &options)? {
+            // Clear the screen and redraw from the top
+            print!("\x1b[2J\x1b[H{}", frame);
+            println!("{}", "Watching for changes; press Ctrl+C to stop".dimmed());
+            std::io::stdout().flush()?;
+        }
+        std::thread::sleep(FOLLOW_INTERVAL);
+    }
+}
+
+/// Renders the status for `--follow`, keeping the last frame so the screen
+/// is only redrawn when something changed.
+#[derive(Debug, Default)]
+struct Follower {
+    last: Option<String>,
+}
+
+impl Follower {
+    /// The new status, or `None` if it's the same as last time.
+    fn refresh(&mut self, storage: &Storage, options: &RenderOptions) -> Result<Option<String>> {
+        let frame = render(storage, options)?;
+        if self.last.as_ref() == Some(&frame) {
+            return Ok(None);
+        }
+        self.last = Some(frame.clone());
+        Ok(Some(frame))
+    }
+}
+
+/// What `gitent status` shows.
+#[derive(Debug, Clone, Default)]
+struct RenderOptions {
+    /// Only show changes made by this tool
+    tool: Option<String>,
+    rename_threshold: f32,
+    /// Report what compacting the changes would remove
+    suggest_compact: bool,
+}
+
+/// What coalescing uncommitted changes into one per file would remove.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Compaction {
+    /// Changes that would be dropped
+    removable: usize,
+    /// Stored content that would go with them
+    bytes_saved: usize,
+}
+
+/// Work out what compaction would remove from `changes`, without changing
+/// anything. Files are coalesced the same way a combined rollback does.
+fn suggest_compaction(changes: &[Change]) -> Compaction {
+    let mut oldest_first = changes.to_vec();
+    oldest_first.sort_by_key(|change| change.seq);
+    let compacted = net_changes(&oldest_first);
+
+    let content_size = |changes: &[Change]| -> usize {
+        changes
+            .iter()
+            .map(|change| {
+                change.content_before.as_ref().map_or(0, Vec::len)
+                    + change.content_after.as_ref().map_or(0, Vec::len)
+            })
+            .sum()
+    };
+    Compaction {
+        removable: changes.len() - compacted.len(),
+        bytes_saved: content_size(changes).saturating_sub(content_size(&compacted)),
+    }
+}
+
+/// The active session and its uncommitted changes, as printed by `gitent status`.
+fn render(storage: &Storage, options: &RenderOptions) -> Result<String> {
     let session = storage.get_active_session()?;
-    let changes = storage.get_uncommitted_changes(&session.id)?;
+    let (changes, total, compaction) = if options.tool.is_none() && !options.suggest_compact {
+        // Only the changes listed need decoding; the rest are just counted
+        let (changes, total) = storage.get_uncommitted_changes_limited(&session.id, LISTED)?;
+        let changes = collapse_renames(changes, options.rename_threshold);
+        (changes, total, None)
+    } else {
+        let mut changes = storage.get_uncommitted_changes(&session.id)?;
+        if let Some(tool) = &options.tool {
+            changes.retain(|c| c.tool.as_deref() == Some(tool.as_str()));
+        }
+        let compaction = options
+            .suggest_compact
+            .then(|| suggest_compaction(&changes));
+        let changes = collapse_renames(changes, options.rename_threshold);
+        let total = changes.len();
+        (changes, total, compaction)
+    };
 
-    println!("{}", "Session Status".bold().cyan());
-    println!("  {}: {}", "Root".bold(), session.root_path.display());
-    println!("  {}: {}", "Session ID".bold(), session.id);
-    println!(
+    let mut out = String::new();
+    writeln!(out, "{}", "Session Status".bold().cyan())?;
+    writeln!(out, "  {}: {}", "Root".bold(), session.root_path.display())?;
+    writeln!(out, "  {}: {}", "Session ID".bold(), session.id)?;
+    writeln!(
+        out,
         "  {}: {}",
         "Started".bold(),
         session.started.format("%Y-%m-%d %H:%M:%S")
-    );
-    println!();
+    )?;
+    writeln!(out)?;
 
     if changes.is_empty() {
-        println!("{}", "No uncommitted changes".green());
-    } else {
-        println!(
-            "{} {}",
-            "Uncommitted changes:".bold(),
-            format!("({})", changes.len()).yellow()
-        );
-        println!();
+        writeln!(out, "{}", "No uncommitted changes".green())?;
+        return Ok(out);
+    }
 
-        for change in changes.iter().take(10) {
-            let icon = match change.change_type {
-                gitent_core::ChangeType::Create => "+".green(),
-                gitent_core::ChangeType::Modify => "~".yellow(),
-                gitent_core::ChangeType::Delete => "-".red(),
-                gitent_core::ChangeType::Rename => "→".blue(),
-            };
+    writeln!(
+        out,
+        "{} {}",
+        "Uncommitted changes:".bold(),
+        format!("({})", total).yellow()
+    )?;
+    writeln!(out)?;
 
-            println!("  {} {}", icon, change.path.display());
-        }
+    for (change, similarity) in changes.iter().take(LISTED) {
+        let icon = super::change_icon(change.change_type);
+
+        let tool = change
+            .tool
+            .as_ref()
+            .map(|t| format!(" [{}]", t).dimmed().to_string())
+            .unwrap_or_default();
+        writeln!(
+            out,
+            "  {} {}{}",
+            icon,
+            super::describe_path(change, *similarity),
+            tool
+        )?;
+    }
 
-        if changes.len() > 10 {
-            println!();
-            println!(
-                "  {} and {} more...",
-                "...".dimmed(),
-                (changes.len() - 10).to_string().yellow()
-            );
+    let listed = changes.len().min(LISTED);
+    if total > listed {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "  {} and {} more...",
+            "...".dimmed(),
+            (total - listed).to_string().yellow()
+        )?;
+    }
+
+    if let Some(compaction) = compaction {
+        writeln!(out)?;
+        if compaction.removable == 0 {
+            writeln!(out, "{}", "Nothing to compact".green())?;
+        } else {
+            writeln!(
+                out,
+                "{} {} change(s) could be compacted away, saving {} byte(s)",
+                "Suggestion:".bold(),
+                compaction.removable.to_string().yellow(),
+                compaction.bytes_saved.to_string().yellow()
+            )?;
         }
+    }
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "Run {} to commit these changes",
+        "gitent commit \"message\"".cyan()
+    )?;
+
+    Ok(out)
+}
 
-        println!();
-        println!(
-            "Run {} to commit these changes",
-            "gitent commit \"message\"".cyan()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gitent_core::diff::DEFAULT_RENAME_THRESHOLD;
+    use gitent_core::{Change, ChangeType, Session};
+
+    #[test]
+    fn test_follow_redraws_only_on_change() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        let mut follower = Follower::default();
+        let options = RenderOptions {
+            rename_threshold: DEFAULT_RENAME_THRESHOLD,
+            ..Default::default()
+        };
+
+        let frame = follower
+            .refresh(&storage, &options)
+            .unwrap()
+            .expect("first refresh draws");
+        assert!(frame.contains("No uncommitted changes"));
+
+        let change = Change::new(ChangeType::Create, PathBuf::from("src/lib.rs"), session.id)
+            .with_content_after(b"fn main() {}".to_vec());
+        storage.create_change(&change).unwrap();
+        let frame = follower
+            .refresh(&storage, &options)
+            .unwrap()
+            .expect("a new change redraws");
+        assert!(frame.contains("(1)"));
+        assert!(frame.contains("src/lib.rs"));
+        assert!(!frame.contains("No uncommitted changes"));
+
+        assert!(follower.refresh(&storage, &options).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_suggest_compaction() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        let record = |change_type, path: &str, before: Option<&str>, after: Option<&str>| {
+            let mut change = Change::new(change_type, PathBuf::from(path), session.id);
+            if let Some(before) = before {
+                change = change.with_content_before(before.as_bytes().to_vec());
+            }
+            if let Some(after) = after {
+                change = change.with_content_after(after.as_bytes().to_vec());
+            }
+            storage.create_change(&change).unwrap();
+        };
+        // Three changes to one file compact to a single create
+        record(ChangeType::Create, "a.txt", None, Some("1"));
+        record(ChangeType::Modify, "a.txt", Some("1"), Some("12"));
+        record(ChangeType::Modify, "a.txt", Some("12"), Some("123"));
+        // A file created and then deleted leaves nothing
+        record(ChangeType::Create, "tmp.txt", None, Some("scratch"));
+        record(ChangeType::Delete, "tmp.txt", Some("scratch"), None);
+        // A lone change has nothing to compact
+        record(ChangeType::Modify, "b.txt", Some("old"), Some("new"));
+
+        let changes = storage.get_uncommitted_changes(&session.id).unwrap();
+        assert_eq!(
+            suggest_compaction(&changes),
+            Compaction {
+                removable: 4,
+                bytes_saved: 20,
+            }
         );
+
+        let frame = render(
+            &storage,
+            &RenderOptions {
+                rename_threshold: DEFAULT_RENAME_THRESHOLD,
+                suggest_compact: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(frame.contains("could be compacted away"));
+        assert_eq!(storage.get_uncommitted_count(&session.id).unwrap(), 6);
     }
 
-    Ok(())
+    #[test]
+    fn test_lists_newest_changes_with_total() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        for i in 0..LISTED + 5 {
+            let change = Change::new(
+                ChangeType::Create,
+                PathBuf::from(format!("file{}.txt", i)),
+                session.id,
+            )
+            .with_content_after(format!("{}", i).into_bytes());
+            storage.create_change(&change).unwrap();
+        }
+
+        let frame = render(
+            &storage,
+            &RenderOptions {
+                rename_threshold: DEFAULT_RENAME_THRESHOLD,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(frame.contains(&format!("({})", LISTED + 5)));
+        assert!(frame.contains(&format!("file{}.txt", LISTED + 4)));
+        assert!(!frame.contains("file4.txt"));
+        // Other tests may have turned colors on
+        let mut plain = String::new();
+        let mut chars = frame.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|&c| c == 'm');
+            } else {
+                plain.push(c);
+            }
+        }
+        assert!(plain.contains("and 5 more"));
+    }
 }
@@ -0,0 +1,138 @@
+//! Syntax highlighting for `gitent diff --highlight`, built with the `syntax` feature.
+
+use gitent_core::diff::{DiffLine, DiffLineType};
+use gitent_core::Change;
+use std::fmt::Write;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Color, Style, Theme, ThemeSet};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+
+const THEME: &str = "base16-ocean.dark";
+
+/// Background marking added lines under the token colors.
+const ADDITION_BACKGROUND: (u8, u8, u8) = (0x1e, 0x3a, 0x22);
+
+pub struct Highlighter {
+    syntaxes: SyntaxSet,
+    theme: Theme,
+}
+
+impl Highlighter {
+    pub fn new() -> Self {
+        let mut themes = ThemeSet::load_defaults();
+        Self {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            theme: themes.themes.remove(THEME).unwrap_or_default(),
+        }
+    }
+
+    /// Start highlighting a change's diff, or `None` if its language isn't known.
+    pub fn for_change(&self, change: &Change) -> Option<FileHighlighter<'_>> {
+        let syntax = self.syntax_for(change)?;
+        Some(FileHighlighter {
+            lines: HighlightLines::new(syntax, &self.theme),
+            syntaxes: &self.syntaxes,
+        })
+    }
+
+    fn syntax_for(&self, change: &Change) -> Option<&SyntaxReference> {
+        let by_language = change
+            .language
+            .as_deref()
+            .and_then(|language| self.syntaxes.find_syntax_by_token(language));
+        by_language.or_else(|| {
+            let extension = change.path.extension()?.to_str()?;
+            self.syntaxes.find_syntax_by_extension(extension)
+        })
+    }
+}
+
+/// Highlighting state for one file's diff; lines must be fed in order.
+pub struct FileHighlighter<'a> {
+    lines: HighlightLines<'a>,
+    syntaxes: &'a SyntaxSet,
+}
+
+impl FileHighlighter<'_> {
+    /// Render a diff line with ANSI colors, or `None` to fall back to plain
+    /// coloring.
+    ///
+    /// Tokens are highlighted as they appear in the new file, so removed lines
+    /// are left to the plain coloring and don't disturb the parser state.
+    pub fn line(&mut self, line: &DiffLine) -> Option<String> {
+        let (prefix, background) = match line.line_type {
+            DiffLineType::Addition => ("+", Some(ADDITION_BACKGROUND)),
+            DiffLineType::Context => (" ", None),
+            DiffLineType::Deletion => return None,
+        };
+
+        let ranges = self
+            .lines
+            .highlight_line(&line.content, self.syntaxes)
+            .ok()?;
+
+        let mut out = String::new();
+        set_background(&mut out, background);
+        out.push_str(prefix);
+        for (style, text) in ranges {
+            set_style(&mut out, style, background);
+            out.push_str(text.trim_end_matches('\n'));
+        }
+        out.push_str("\x1b[0m");
+        if line.content.ends_with('\n') {
+            out.push('\n');
+        }
+
+        Some(out)
+    }
+}
+
+fn set_style(out: &mut String, style: Style, background: Option<(u8, u8, u8)>) {
+    let Color { r, g, b, .. } = style.foreground;
+    let _ = write!(out, "\x1b[0;38;2;{};{};{}m", r, g, b);
+    set_background(out, background);
+}
+
+fn set_background(out: &mut String, background: Option<(u8, u8, u8)>) {
+    if let Some((r, g, b)) = background {
+        let _ = write!(out, "\x1b[48;2;{};{};{}m", r, g, b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gitent_core::diff::FileDiff;
+    use gitent_core::ChangeType;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_highlight_rust_diff() {
+        let change = Change::new(ChangeType::Modify, "src/main.rs".into(), Uuid::new_v4())
+            .with_content_before(b"fn main() {\n    let x = 1;\n}\n".to_vec())
+            .with_content_after(b"fn main() {\n    let x = \"two\";\n}\n".to_vec());
+
+        let highlighter = Highlighter::new();
+        let mut file = highlighter.for_change(&change).unwrap();
+
+        let rendered: Vec<Option<String>> = FileDiff::iter_lines(&change)
+            .map(|line| file.line(&line.unwrap()))
+            .collect();
+
+        // The removed line is left to plain coloring
+        assert_eq!(rendered.iter().filter(|line| line.is_none()).count(), 1);
+        let added = rendered
+            .iter()
+            .flatten()
+            .find(|line| line.contains("two"))
+            .unwrap();
+        assert!(added.contains(";38;2;"));
+        assert!(added.ends_with("\x1b[0m\n"));
+    }
+
+    #[test]
+    fn test_unknown_language_is_not_highlighted() {
+        let change = Change::new(ChangeType::Modify, "notes.xyz".into(), Uuid::new_v4());
+        assert!(Highlighter::new().for_change(&change).is_none());
+    }
+}
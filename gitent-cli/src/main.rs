@@ -1,11 +1,21 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use gitent_core::Config;
+use std::io::IsTerminal;
 use std::path::PathBuf;
+use std::process::ExitCode;
 
 mod commands;
 mod display;
+#[cfg(feature = "syntax")]
+mod highlight;
 
-use commands::{commit, diff, log, rollback, start, status};
+#[cfg(feature = "git")]
+use commands::export_git;
+use commands::{
+    audit, checkpoint, commit, diff, fsck, gc, log, milestone, rebuild_db, repair, report, reset,
+    revert_change, rollback, sessions, snapshot, start, status,
+};
 
 #[derive(Parser)]
 #[command(name = "gitent")]
@@ -23,23 +33,143 @@ enum Commands {
         #[arg(default_value = ".")]
         path: PathBuf,
 
-        /// Port for the API server
-        #[arg(short, long, default_value = "3030")]
-        port: u16,
+        /// Port for the API server [default: 3030]
+        #[arg(short, long)]
+        port: Option<u16>,
 
         /// Database path
         #[arg(short, long)]
         db: Option<PathBuf>,
+
+        /// Allow browser requests from this origin (repeatable)
+        #[arg(long = "cors-origin", value_name = "ORIGIN")]
+        cors_origins: Vec<String>,
+
+        /// Allow browser requests from any origin (development only)
+        #[arg(long, conflicts_with = "cors_origins")]
+        cors_any: bool,
+
+        /// Prune all but the newest N commits
+        #[arg(long, value_name = "N")]
+        keep_commits: Option<usize>,
+
+        /// Prune commits older than D days
+        #[arg(long, value_name = "D")]
+        keep_days: Option<u32>,
+
+        /// Serve Prometheus metrics at /metrics
+        #[arg(long)]
+        metrics: bool,
+
+        /// Poll for changes every MS milliseconds instead of using OS
+        /// notifications (for NFS, SMB and some container mounts)
+        #[arg(long, value_name = "MS")]
+        poll: Option<u64>,
+
+        /// Remember file states between runs and record changes made while
+        /// the server was stopped
+        #[arg(long)]
+        file_index: bool,
+
+        /// Also journal every change and commit to .gitent/events.log, so the
+        /// database can be rebuilt with `gitent rebuild-db`
+        #[arg(long)]
+        event_log: bool,
+
+        /// Handle at most N API requests at once, answering the rest with 503
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        max_concurrency: Option<u32>,
+
+        /// Answer API requests with bodies larger than N bytes with 413
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+        max_body_bytes: Option<u64>,
+
+        /// Store paths relative to DIR instead of the watched directory
+        #[arg(long, value_name = "DIR")]
+        content_root: Option<PathBuf>,
+
+        /// Track files matching these patterns without storing their content
+        #[arg(long, value_name = "PATTERNS", value_delimiter = ',')]
+        redact: Option<Vec<String>>,
+
+        /// Track only files matching these patterns, ignoring everything else
+        #[arg(long, value_name = "PATTERNS", value_delimiter = ',')]
+        include: Option<Vec<String>>,
+
+        /// Seed the session with the history of the git repository being watched
+        /// (requires the `git` feature)
+        #[arg(long)]
+        import_git: bool,
+
+        /// Import only the newest N git commits
+        #[arg(long, value_name = "N", requires = "import_git")]
+        import_depth: Option<usize>,
+
+        /// POST each commit made through the API to this URL (repeatable)
+        #[arg(long = "webhook", value_name = "URL")]
+        webhooks: Vec<String>,
+
+        /// Record files larger than N bytes without their content, or with
+        /// --external-store, keep their content outside the database
+        #[arg(long, value_name = "N")]
+        max_file_size: Option<u64>,
+
+        /// Keep content over the size limit in .gitent/objects, referenced by
+        /// hash from the database [default limit: 10 MiB]
+        #[arg(long)]
+        external_store: bool,
     },
 
     /// Commit changes with a message
     Commit {
-        /// Commit message
-        message: String,
+        /// Commit message summary
+        #[arg(required_unless_present = "summary")]
+        message: Option<String>,
+
+        /// Commit message summary, as an alternative to the positional argument
+        #[arg(
+            short = 'm',
+            long = "message",
+            value_name = "SUMMARY",
+            conflicts_with = "message"
+        )]
+        summary: Option<String>,
+
+        /// Detailed commit message body; use - to read it from stdin
+        #[arg(long)]
+        body: Option<String>,
 
-        /// Agent ID
-        #[arg(short, long, default_value = "cli-user")]
-        agent: String,
+        /// Agent ID [default: cli-user]
+        #[arg(short, long)]
+        agent: Option<String>,
+
+        /// Choose which changes and hunks to commit
+        #[arg(short, long)]
+        interactive: bool,
+
+        /// Create the commit even when there are no changes
+        #[arg(long)]
+        allow_empty: bool,
+
+        /// Issue the commit addresses, such as GH-123
+        #[arg(long)]
+        issue: Option<String>,
+
+        /// URL of the pull request the commit belongs to
+        #[arg(long = "pr", value_name = "URL")]
+        pr_url: Option<String>,
+
+        /// Include changes outside the configured commit_scope
+        #[arg(long)]
+        all: bool,
+
+        /// Mark the commit as a milestone
+        #[arg(long)]
+        milestone: bool,
+
+        /// Record KEY=VALUE metadata on the commit; may be repeated
+        #[arg(long = "meta", value_name = "KEY=VALUE", value_parser = commit::parse_metadata)]
+        metadata: Vec<(String, String)>,
 
         /// Database path
         #[arg(short, long)]
@@ -52,6 +182,53 @@ enum Commands {
         #[arg(short, long)]
         limit: Option<usize>,
 
+        /// Show full commit messages instead of just the summary line
+        #[arg(long)]
+        full: bool,
+
+        /// List each change in a commit with its type instead of just the files
+        #[arg(long)]
+        show_changes: bool,
+
+        /// Only show commits with changes made by this tool
+        #[arg(long)]
+        tool: Option<String>,
+
+        /// Only show milestone commits
+        #[arg(long)]
+        milestones_only: bool,
+
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// Show writes made through the API, newest first
+    Audit {
+        /// Number of entries to show
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// List or merge sessions
+    Sessions {
+        #[command(subcommand)]
+        action: SessionsAction,
+    },
+
+    /// Mark a commit as a milestone, or list milestones
+    Milestone {
+        /// Commit ID or a unique prefix of it (lists milestones if omitted)
+        commit_id: Option<String>,
+
+        /// Remove the milestone mark instead
+        #[arg(long, requires = "commit_id")]
+        unset: bool,
+
         /// Database path
         #[arg(short, long)]
         db: Option<PathBuf>,
@@ -59,6 +236,18 @@ enum Commands {
 
     /// Show current status
     Status {
+        /// Only show changes made by this tool
+        #[arg(long)]
+        tool: Option<String>,
+
+        /// Keep the status on screen, redrawing it as changes arrive
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Report how many changes compaction would remove, without compacting
+        #[arg(long)]
+        suggest_compact: bool,
+
         /// Database path
         #[arg(short, long)]
         db: Option<PathBuf>,
@@ -66,9 +255,54 @@ enum Commands {
 
     /// Show diff for a commit or uncommitted changes
     Diff {
-        /// Commit ID (if not provided, shows uncommitted changes)
+        /// Commit ID or a unique prefix of it (if not provided, shows
+        /// uncommitted changes)
         commit_id: Option<String>,
 
+        /// Write a color-free unified diff to this file instead of the terminal
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Maximum number of diff lines to show per file
+        #[arg(long)]
+        max_lines: Option<usize>,
+
+        /// Syntax-highlight code (requires the `syntax` feature)
+        #[arg(long)]
+        highlight: bool,
+
+        /// Show changes recorded since a snapshot from `gitent snapshot`
+        #[arg(long, value_name = "ID", conflicts_with = "commit_id")]
+        since_snapshot: Option<String>,
+
+        /// Show changes recorded since a checkpoint from `gitent checkpoint`
+        #[arg(long, value_name = "LABEL", conflicts_with_all = ["commit_id", "since_snapshot"])]
+        since_checkpoint: Option<String>,
+
+        /// Diff JSON files by content, ignoring formatting and key order
+        #[arg(long, conflicts_with = "output")]
+        semantic: bool,
+
+        /// Compare the files on disk with the last commit, ignoring recorded changes
+        #[arg(long, conflicts_with_all = ["commit_id", "since_snapshot", "since_checkpoint"])]
+        worktree: bool,
+
+        /// List each changed file's status (A/M/D/R) and path instead of its diff
+        #[arg(long, conflicts_with_all = ["output", "max_lines", "highlight", "semantic"])]
+        name_status: bool,
+
+        /// Print each JSON file's change as an RFC 6902 JSON Patch
+        #[arg(long, conflicts_with_all = ["output", "max_lines", "highlight", "semantic", "name_status"])]
+        json_patch: bool,
+
+        /// Show the diffs of lockfiles and other files matching diff_suppress
+        #[arg(long)]
+        show_suppressed: bool,
+
+        /// Group files by directory, with a heading for each, in path order
+        #[arg(long, conflicts_with = "json_patch")]
+        group_by_dir: bool,
+
         /// Database path
         #[arg(short, long)]
         db: Option<PathBuf>,
@@ -76,13 +310,198 @@ enum Commands {
 
     /// Rollback to a specific commit
     Rollback {
-        /// Commit ID to rollback to
-        commit_id: String,
+        /// Commit ID, or a unique prefix of it, to rollback to
+        #[arg(required_unless_present = "from")]
+        commit_id: Option<String>,
+
+        /// Roll back every commit from this one...
+        #[arg(
+            long,
+            value_name = "COMMIT",
+            requires = "to",
+            conflicts_with = "commit_id"
+        )]
+        from: Option<String>,
+
+        /// ...up to and including this one, undoing only the net effect on each file
+        #[arg(long, value_name = "COMMIT", requires = "from")]
+        to: Option<String>,
 
         /// Actually perform the rollback (without this, just shows preview)
         #[arg(long)]
         execute: bool,
 
+        /// Check every file could be rolled back without changing anything,
+        /// failing if any couldn't
+        #[arg(long, conflicts_with = "execute")]
+        check: bool,
+
+        /// Also roll back files that were modified after the commit
+        #[arg(long)]
+        force: bool,
+
+        /// Refuse to restore content that doesn't match its recorded hash
+        #[arg(long)]
+        verify: bool,
+
+        /// Don't ask for confirmation before overwriting files
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// Undo one committed change on disk, leaving the rest of its commit alone
+    RevertChange {
+        /// ID of the change to revert
+        change_id: String,
+
+        /// Also revert a file that was modified after the commit
+        #[arg(long)]
+        force: bool,
+
+        /// Refuse to restore content that doesn't match its recorded hash
+        #[arg(long)]
+        verify: bool,
+
+        /// Store the revert as a change of its own, for when no server is
+        /// watching the file to record it
+        #[arg(long)]
+        record: bool,
+
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// Mark the current state to diff against later with `diff --since-snapshot`
+    Snapshot {
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// Mark a named point to diff against later with `diff --since-checkpoint`
+    Checkpoint {
+        /// Name of the checkpoint (lists checkpoints if omitted)
+        label: Option<String>,
+
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// Undo the latest commit, keeping its changes as uncommitted
+    Reset {
+        /// Keep the commit's changes as uncommitted changes
+        #[arg(long)]
+        soft: bool,
+
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// Summarize the session's history
+    Report {
+        /// Break changes down by file language
+        #[arg(long)]
+        by_language: bool,
+
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// Check the database for broken commit/change links
+    Fsck {
+        /// Remove broken links that were found
+        #[arg(long)]
+        repair: bool,
+
+        /// Also check every change's content against its hash
+        #[arg(long)]
+        deep: bool,
+
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// Rebuild the database from the event log kept with `start --event-log`
+    RebuildDb {
+        /// Event log to read [default: .gitent/events.log]
+        #[arg(long)]
+        log: Option<PathBuf>,
+
+        /// Replace an existing database, keeping it as <DB>.bak
+        #[arg(long)]
+        force: bool,
+
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// Write the session's commits to a git repository, authored as the
+    /// `authors` map says
+    #[cfg(feature = "git")]
+    ExportGit {
+        /// Repository to write to, created if it doesn't exist
+        dir: PathBuf,
+
+        /// Branch to point at the newest commit, replacing it if it exists
+        #[arg(long, default_value = "gitent")]
+        branch: String,
+
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// End stale active sessions, keeping only the most recently started one
+    Repair {
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// Compact the database, optionally removing content of deleted sessions
+    Gc {
+        /// Delete uncommitted changes that a later commit superseded, or whose
+        /// session is gone
+        #[arg(long)]
+        blobs: bool,
+
+        /// List what --blobs would delete without deleting it
+        #[arg(long, requires = "blobs")]
+        dry_run: bool,
+
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SessionsAction {
+    /// List every session, oldest first
+    List {
+        /// Database path
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    /// Fold one session's changes and commits into another
+    Merge {
+        /// Session to merge and remove
+        from: String,
+
+        /// Session to merge into
+        into: String,
+
         /// Database path
         #[arg(short, long)]
         db: Option<PathBuf>,
@@ -90,35 +509,219 @@ enum Commands {
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     tracing_subscriber::fmt::init();
 
     let cli = Cli::parse();
 
+    // Don't embed ANSI escapes when output is piped or redirected
+    if !std::io::stdout().is_terminal() {
+        colored::control::set_override(false);
+    }
+
     match cli.command {
-        Commands::Start { path, port, db } => {
-            start::run(path, port, db).await?;
+        Commands::Start {
+            path,
+            port,
+            db,
+            cors_origins,
+            cors_any,
+            keep_commits,
+            keep_days,
+            metrics,
+            poll,
+            file_index,
+            event_log,
+            max_concurrency,
+            max_body_bytes,
+            content_root,
+            redact,
+            include,
+            import_git,
+            import_depth,
+            webhooks,
+            max_file_size,
+            external_store,
+        } => {
+            let overrides = Config {
+                port,
+                db,
+                keep_commits,
+                keep_days,
+                poll_interval_ms: poll,
+                file_index: file_index.then_some(true),
+                event_log: event_log.then_some(true),
+                max_concurrency: max_concurrency.map(|n| n as usize),
+                max_body_bytes: max_body_bytes.map(|n| n as usize),
+                content_root,
+                redact_patterns: redact,
+                include_patterns: include,
+                webhooks: (!webhooks.is_empty()).then_some(webhooks),
+                max_file_size,
+                external_store: external_store.then_some(true),
+                ..Default::default()
+            };
+            let import_git = import_git.then_some(start::GitImport {
+                max_depth: import_depth,
+            });
+            start::run(path, overrides, cors_origins, cors_any, metrics, import_git).await?;
         }
-        Commands::Commit { message, agent, db } => {
-            commit::run(message, agent, db)?;
+        Commands::Commit {
+            message,
+            summary,
+            body,
+            agent,
+            interactive,
+            allow_empty,
+            issue,
+            pr_url,
+            all,
+            milestone,
+            metadata,
+            db,
+        } => {
+            let summary = message.or(summary).unwrap_or_default();
+            let message = commit::compose_message(&summary, body)?;
+            let links = commit::Links {
+                issue,
+                pr_url,
+                milestone,
+                metadata,
+            };
+            // An empty scope takes in every change
+            let scope = all.then(Vec::new);
+            return Ok(
+                commit::run(message, agent, interactive, allow_empty, links, scope, db)?
+                    .exit_code(),
+            );
         }
-        Commands::Log { limit, db } => {
-            log::run(limit, db)?;
+        Commands::Log {
+            limit,
+            full,
+            show_changes,
+            tool,
+            milestones_only,
+            db,
+        } => {
+            log::run(limit, tool, milestones_only, full, show_changes, db)?;
         }
-        Commands::Status { db } => {
-            status::run(db)?;
+        Commands::Sessions { action } => match action {
+            SessionsAction::List { db } => sessions::list(db)?,
+            SessionsAction::Merge { from, into, db } => sessions::merge(from, into, db)?,
+        },
+        Commands::Audit { limit, db } => {
+            audit::run(limit, db)?;
         }
-        Commands::Diff { commit_id, db } => {
-            diff::run(commit_id, db)?;
+        Commands::Milestone {
+            commit_id,
+            unset,
+            db,
+        } => {
+            milestone::run(commit_id, unset, db)?;
+        }
+        Commands::Status {
+            tool,
+            follow,
+            suggest_compact,
+            db,
+        } => {
+            status::run(tool, follow, suggest_compact, db)?;
+        }
+        Commands::Diff {
+            commit_id,
+            output,
+            max_lines,
+            highlight,
+            semantic,
+            since_snapshot,
+            since_checkpoint,
+            worktree,
+            name_status,
+            json_patch,
+            show_suppressed,
+            group_by_dir,
+            db,
+        } => {
+            let since = since_snapshot
+                .map(diff::Since::Snapshot)
+                .or(since_checkpoint.map(diff::Since::Checkpoint));
+            let format = if name_status {
+                diff::Format::NameStatus
+            } else if json_patch {
+                diff::Format::JsonPatch
+            } else {
+                diff::Format::Full {
+                    max_lines,
+                    highlight,
+                    semantic,
+                    show_suppressed,
+                }
+            };
+            diff::run(commit_id, since, worktree, output, format, group_by_dir, db)?;
         }
         Commands::Rollback {
             commit_id,
+            from,
+            to,
             execute,
+            check,
+            force,
+            verify,
+            yes,
+            db,
+        } => {
+            let target = match (commit_id, from, to) {
+                (_, Some(from), Some(to)) => rollback::Target::Range { from, to },
+                (Some(commit_id), _, _) => rollback::Target::Commit(commit_id),
+                _ => unreachable!("clap requires a commit or a range"),
+            };
+            let mode = if execute {
+                rollback::Mode::Execute { yes }
+            } else if check {
+                rollback::Mode::Check
+            } else {
+                rollback::Mode::Preview
+            };
+            rollback::run(target, mode, force, verify, db)?;
+        }
+        Commands::RevertChange {
+            change_id,
+            force,
+            verify,
+            record,
             db,
         } => {
-            rollback::run(commit_id, execute, db)?;
+            revert_change::run(change_id, force, verify, record, db)?;
+        }
+        Commands::Snapshot { db } => {
+            snapshot::run(db)?;
+        }
+        Commands::Checkpoint { label, db } => {
+            checkpoint::run(label, db)?;
+        }
+        Commands::Reset { soft, db } => {
+            reset::run(soft, db)?;
+        }
+        Commands::Report { by_language, db } => {
+            report::run(by_language, db)?;
+        }
+        Commands::Fsck { repair, deep, db } => {
+            fsck::run(repair, deep, db)?;
+        }
+        Commands::RebuildDb { log, force, db } => {
+            rebuild_db::run(log, force, db)?;
+        }
+        #[cfg(feature = "git")]
+        Commands::ExportGit { dir, branch, db } => {
+            export_git::run(dir, branch, db)?;
+        }
+        Commands::Repair { db } => {
+            repair::run(db)?;
+        }
+        Commands::Gc { blobs, dry_run, db } => {
+            gc::run(blobs, dry_run, db)?;
         }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,90 @@
+//! Cancelling long storage operations.
+//!
+//! Expensive [`Storage`](crate::Storage) methods check the token passed to
+//! [`Storage::cancellable`](crate::Storage::cancellable) as they go, and stop
+//! with [`Error::Cancelled`] or [`Error::Timeout`] once it fires.
+
+use crate::error::{Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A shared flag for cancelling an operation, with an optional deadline.
+///
+/// Clones share the flag, so any of them can cancel the operation.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up with [`Error::Timeout`] once `deadline` has passed.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Give up with [`Error::Timeout`] after `timeout` from now.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Fail if the token was cancelled or its deadline has passed.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Error::Timeout),
+            _ => Ok(()),
+        }
+    }
+
+    /// A guard that cancels the token when dropped, such as when the future
+    /// serving a request is dropped because its client went away.
+    pub fn cancel_on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop(self.clone())
+    }
+}
+
+/// Cancels its token when dropped; see [`CancelToken::cancel_on_drop`].
+#[derive(Debug)]
+pub struct CancelOnDrop(CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_and_deadline() {
+        let token = CancelToken::new();
+        assert!(token.check().is_ok());
+        drop(token.cancel_on_drop());
+        assert!(matches!(token.check(), Err(Error::Cancelled)));
+
+        let expired = CancelToken::new().with_deadline(Instant::now());
+        assert!(matches!(expired.check(), Err(Error::Timeout)));
+
+        let later = CancelToken::new().with_timeout(Duration::from_secs(60));
+        assert!(later.check().is_ok());
+    }
+}
@@ -0,0 +1,453 @@
+//! Project-level configuration loaded from `.gitent/config.toml`.
+//!
+//! Settings are resolved with the following precedence, highest first:
+//!
+//! 1. Command-line flags
+//! 2. Values from `.gitent/config.toml` in the project root
+//! 3. Built-in defaults
+//!
+//! Relative paths in the config file are resolved against the project root.
+
+use crate::diff::DEFAULT_RENAME_THRESHOLD;
+use crate::error::{Error, Result};
+use crate::event_log::EVENT_LOG_FILE;
+use crate::hash::HashAlgorithm;
+use crate::models::{MetadataSchema, RetentionPolicy, Session};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const CONFIG_DIR: &str = ".gitent";
+pub const CONFIG_FILE: &str = "config.toml";
+pub const DEFAULT_DB_FILE: &str = "gitent.db";
+pub const DEFAULT_PORT: u16 = 3030;
+pub const DEFAULT_AGENT_ID: &str = "cli-user";
+pub const DEFAULT_DEBOUNCE_MS: u64 = 500;
+pub const DEFAULT_WATCH_QUEUE_CAPACITY: usize = 100;
+pub const DEFAULT_DIFF_CACHE_SIZE: usize = 128;
+pub const DEFAULT_AUDIT_KEEP_DAYS: u32 = 90;
+/// Size over which content goes to the object store, if `max_file_size` isn't set
+pub const DEFAULT_EXTERNAL_THRESHOLD: u64 = 10 * 1024 * 1024;
+
+/// Files whose diffs are collapsed to a note unless `diff_suppress` says
+/// otherwise: lockfiles and minified bundles.
+pub const DEFAULT_DIFF_SUPPRESS: &[&str] = &[
+    "Cargo.lock",
+    "package-lock.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "poetry.lock",
+    "Gemfile.lock",
+    "composer.lock",
+    "*.min.js",
+    "*.min.css",
+];
+
+/// Domain of the email given to agents missing from the author map.
+pub const FALLBACK_EMAIL_DOMAIN: &str = "gitent.local";
+
+/// What the watcher does with new events when its queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// Hold up event delivery until the queue drains, losing nothing
+    #[default]
+    Block,
+    /// Drop the events with a warning and count them
+    Drop,
+}
+
+/// The person an agent's work is credited to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Author {
+    pub name: String,
+    pub email: String,
+}
+
+impl fmt::Display for Author {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} <{}>", self.name, self.email)
+    }
+}
+
+/// Real names and emails for agent ids, for git authorship and display.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AuthorMap(pub HashMap<String, Author>);
+
+impl AuthorMap {
+    /// The author for `agent_id`, or `agent_id <agent_id@gitent.local>` for
+    /// an agent that isn't mapped.
+    pub fn author_for(&self, agent_id: &str) -> Author {
+        self.0.get(agent_id).cloned().unwrap_or_else(|| Author {
+            name: agent_id.to_string(),
+            email: format!("{}@{}", agent_id, FALLBACK_EMAIL_DOMAIN),
+        })
+    }
+}
+
+/// Optional settings, as read from a config file or supplied on the command line.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub port: Option<u16>,
+    pub db: Option<PathBuf>,
+    pub ignore_patterns: Option<Vec<String>>,
+    pub redact_patterns: Option<Vec<String>>,
+    pub include_patterns: Option<Vec<String>>,
+    pub metadata_schema: Option<MetadataSchema>,
+    pub commit_scope: Option<Vec<String>>,
+    pub diff_suppress: Option<Vec<String>>,
+    pub agent_id: Option<String>,
+    pub debounce_ms: Option<u64>,
+    pub poll_interval_ms: Option<u64>,
+    pub watch_queue_capacity: Option<usize>,
+    pub watch_overflow: Option<OverflowPolicy>,
+    pub file_index: Option<bool>,
+    pub event_log: Option<bool>,
+    pub max_concurrency: Option<usize>,
+    pub max_body_bytes: Option<usize>,
+    pub storage_timeout_ms: Option<u64>,
+    pub admin_token: Option<String>,
+    pub webhooks: Option<Vec<String>>,
+    pub diff_cache_size: Option<usize>,
+    pub max_file_size: Option<u64>,
+    pub external_store: Option<bool>,
+    pub store_deltas: Option<bool>,
+    pub hash_algorithm: Option<HashAlgorithm>,
+    pub content_root: Option<PathBuf>,
+    pub rename_threshold: Option<f32>,
+    pub keep_commits: Option<usize>,
+    pub keep_days: Option<u32>,
+    pub audit_keep_days: Option<u32>,
+    pub authors: Option<AuthorMap>,
+}
+
+/// Fully resolved settings with every default filled in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub port: u16,
+    pub db_path: PathBuf,
+    pub ignore_patterns: Vec<String>,
+    /// Files tracked without storing their content
+    pub redact_patterns: Vec<String>,
+    /// Only files matching these are tracked; empty means every file
+    pub include_patterns: Vec<String>,
+    /// Metadata new changes and commits are checked against
+    pub metadata_schema: Option<MetadataSchema>,
+    /// Files `gitent commit` includes by default; empty means every file
+    pub commit_scope: Vec<String>,
+    /// Files `gitent diff` shows a one-line note for instead of their diff
+    pub diff_suppress: Vec<String>,
+    pub agent_id: String,
+    pub debounce: Duration,
+    /// Scan for changes at this interval instead of using OS notifications
+    pub poll_interval: Option<Duration>,
+    /// Batches of events the watcher queues before `watch_overflow` applies
+    pub watch_queue_capacity: usize,
+    pub watch_overflow: OverflowPolicy,
+    /// Keep an index of file states to catch up on offline changes at startup
+    pub file_index: bool,
+    /// Journal every write to `event_log_path` as well as the database
+    pub event_log: bool,
+    pub event_log_path: PathBuf,
+    /// Requests the API handles at once before turning more away with a 503
+    pub max_concurrency: Option<usize>,
+    /// Largest request body the API reads before answering with a 413
+    pub max_body_bytes: Option<usize>,
+    /// How long the API lets an expensive storage operation run, counting
+    /// time spent queued behind others, before answering with a 504
+    pub storage_timeout: Option<Duration>,
+    /// Bearer token for admin endpoints such as `GET /audit` and
+    /// `POST /session`; they're disabled without one
+    pub admin_token: Option<String>,
+    /// URLs posted to after each commit made through the API
+    pub webhooks: Vec<String>,
+    /// Commit diffs the API keeps rendered; 0 disables the cache
+    pub diff_cache_size: usize,
+    pub max_file_size: Option<u64>,
+    /// Content larger than this is kept in `.gitent/objects` rather than the
+    /// database; files over `max_file_size` are stored this way instead of
+    /// without their content
+    pub external_threshold: Option<u64>,
+    pub store_deltas: bool,
+    /// How new sessions hash file content
+    pub hash_algorithm: HashAlgorithm,
+    /// Directory stored paths are relative to, if not the watched root
+    pub content_root: Option<PathBuf>,
+    pub rename_threshold: f32,
+    pub retention: RetentionPolicy,
+    /// Days of audit log kept; `None` keeps it all
+    pub audit_keep_days: Option<u32>,
+    pub authors: AuthorMap,
+}
+
+impl Config {
+    /// Path of the config file for a project rooted at `root`.
+    pub fn path_for(root: &Path) -> PathBuf {
+        root.join(CONFIG_DIR).join(CONFIG_FILE)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let contents = std::fs::read_to_string(path.as_ref())?;
+        toml::from_str(&contents)
+            .map_err(|e| Error::Config(format!("{}: {}", path.as_ref().display(), e)))
+    }
+
+    /// Load `.gitent/config.toml` under `root`, or an empty config if there is none.
+    pub fn discover(root: &Path) -> Result<Self> {
+        let path = Self::path_for(root);
+        if path.exists() {
+            Self::load(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    /// Layer `overrides` on top of this config; any value set in `overrides` wins.
+    pub fn merge(self, overrides: Config) -> Self {
+        Self {
+            port: overrides.port.or(self.port),
+            db: overrides.db.or(self.db),
+            ignore_patterns: overrides.ignore_patterns.or(self.ignore_patterns),
+            redact_patterns: overrides.redact_patterns.or(self.redact_patterns),
+            include_patterns: overrides.include_patterns.or(self.include_patterns),
+            metadata_schema: overrides.metadata_schema.or(self.metadata_schema),
+            commit_scope: overrides.commit_scope.or(self.commit_scope),
+            diff_suppress: overrides.diff_suppress.or(self.diff_suppress),
+            agent_id: overrides.agent_id.or(self.agent_id),
+            debounce_ms: overrides.debounce_ms.or(self.debounce_ms),
+            poll_interval_ms: overrides.poll_interval_ms.or(self.poll_interval_ms),
+            watch_queue_capacity: overrides.watch_queue_capacity.or(self.watch_queue_capacity),
+            watch_overflow: overrides.watch_overflow.or(self.watch_overflow),
+            file_index: overrides.file_index.or(self.file_index),
+            event_log: overrides.event_log.or(self.event_log),
+            max_concurrency: overrides.max_concurrency.or(self.max_concurrency),
+            max_body_bytes: overrides.max_body_bytes.or(self.max_body_bytes),
+            storage_timeout_ms: overrides.storage_timeout_ms.or(self.storage_timeout_ms),
+            admin_token: overrides.admin_token.or(self.admin_token),
+            webhooks: overrides.webhooks.or(self.webhooks),
+            diff_cache_size: overrides.diff_cache_size.or(self.diff_cache_size),
+            max_file_size: overrides.max_file_size.or(self.max_file_size),
+            external_store: overrides.external_store.or(self.external_store),
+            store_deltas: overrides.store_deltas.or(self.store_deltas),
+            hash_algorithm: overrides.hash_algorithm.or(self.hash_algorithm),
+            content_root: overrides.content_root.or(self.content_root),
+            rename_threshold: overrides.rename_threshold.or(self.rename_threshold),
+            keep_commits: overrides.keep_commits.or(self.keep_commits),
+            keep_days: overrides.keep_days.or(self.keep_days),
+            audit_keep_days: overrides.audit_keep_days.or(self.audit_keep_days),
+            authors: overrides.authors.or(self.authors),
+        }
+    }
+
+    /// Fill in defaults for anything left unset, resolving paths against `root`.
+    pub fn resolve(self, root: &Path) -> Settings {
+        // With an external store, the size limit is where it starts rather
+        // than where content stops being kept
+        let (max_file_size, external_threshold) = match self.external_store {
+            Some(true) => (
+                None,
+                Some(self.max_file_size.unwrap_or(DEFAULT_EXTERNAL_THRESHOLD)),
+            ),
+            _ => (self.max_file_size, None),
+        };
+        let db_path = match self.db {
+            Some(db) => root.join(db),
+            None => root.join(CONFIG_DIR).join(DEFAULT_DB_FILE),
+        };
+
+        Settings {
+            port: self.port.unwrap_or(DEFAULT_PORT),
+            db_path,
+            ignore_patterns: self
+                .ignore_patterns
+                .unwrap_or_else(Session::default_ignore_patterns),
+            redact_patterns: self.redact_patterns.unwrap_or_default(),
+            include_patterns: self.include_patterns.unwrap_or_default(),
+            metadata_schema: self.metadata_schema,
+            commit_scope: self.commit_scope.unwrap_or_default(),
+            diff_suppress: self.diff_suppress.unwrap_or_else(|| {
+                DEFAULT_DIFF_SUPPRESS
+                    .iter()
+                    .map(|pattern| pattern.to_string())
+                    .collect()
+            }),
+            agent_id: self
+                .agent_id
+                .unwrap_or_else(|| DEFAULT_AGENT_ID.to_string()),
+            debounce: Duration::from_millis(self.debounce_ms.unwrap_or(DEFAULT_DEBOUNCE_MS)),
+            poll_interval: self.poll_interval_ms.map(Duration::from_millis),
+            // A zero-capacity queue would never accept anything
+            watch_queue_capacity: self
+                .watch_queue_capacity
+                .unwrap_or(DEFAULT_WATCH_QUEUE_CAPACITY)
+                .max(1),
+            watch_overflow: self.watch_overflow.unwrap_or_default(),
+            file_index: self.file_index.unwrap_or(false),
+            event_log: self.event_log.unwrap_or(false),
+            event_log_path: root.join(CONFIG_DIR).join(EVENT_LOG_FILE),
+            max_concurrency: self.max_concurrency,
+            max_body_bytes: self.max_body_bytes,
+            storage_timeout: self.storage_timeout_ms.map(Duration::from_millis),
+            admin_token: self.admin_token,
+            webhooks: self.webhooks.unwrap_or_default(),
+            diff_cache_size: self.diff_cache_size.unwrap_or(DEFAULT_DIFF_CACHE_SIZE),
+            max_file_size,
+            external_threshold,
+            store_deltas: self.store_deltas.unwrap_or(false),
+            hash_algorithm: self.hash_algorithm.unwrap_or_default(),
+            content_root: self
+                .content_root
+                .map(|content_root| root.join(content_root)),
+            rename_threshold: self.rename_threshold.unwrap_or(DEFAULT_RENAME_THRESHOLD),
+            retention: RetentionPolicy {
+                keep_commits: self.keep_commits,
+                keep_days: self.keep_days,
+            },
+            // 0 keeps the audit log forever
+            audit_keep_days: Some(self.audit_keep_days.unwrap_or(DEFAULT_AUDIT_KEEP_DAYS))
+                .filter(|&days| days > 0),
+            authors: self.authors.unwrap_or_default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Discover the config file under `root` and resolve it with `overrides` applied.
+    pub fn load(root: &Path, overrides: Config) -> Result<Self> {
+        Ok(Config::discover(root)?.merge(overrides).resolve(root))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_defaults_without_config_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let settings = Settings::load(temp_dir.path(), Config::default()).unwrap();
+
+        assert_eq!(settings.port, DEFAULT_PORT);
+        assert_eq!(settings.agent_id, DEFAULT_AGENT_ID);
+        assert_eq!(
+            settings.db_path,
+            temp_dir.path().join(".gitent").join("gitent.db")
+        );
+        assert_eq!(settings.ignore_patterns, Session::default_ignore_patterns());
+        assert_eq!(settings.poll_interval, None);
+        assert_eq!(settings.watch_queue_capacity, DEFAULT_WATCH_QUEUE_CAPACITY);
+        assert_eq!(settings.watch_overflow, OverflowPolicy::Block);
+        assert_eq!(settings.audit_keep_days, Some(DEFAULT_AUDIT_KEEP_DAYS));
+    }
+
+    #[test]
+    fn test_config_file_with_overrides() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join(".gitent")).unwrap();
+        std::fs::write(
+            Config::path_for(temp_dir.path()),
+            r#"
+            port = 8080
+            db = "data/history.db"
+            ignore_patterns = ["build"]
+            agent_id = "config-agent"
+            debounce_ms = 250
+            poll_interval_ms = 2000
+            watch_queue_capacity = 500
+            watch_overflow = "drop"
+            max_file_size = 1048576
+            hash_algorithm = "blake3"
+            "#,
+        )
+        .unwrap();
+
+        let overrides = Config {
+            port: Some(9090),
+            agent_id: Some("flag-agent".to_string()),
+            ..Default::default()
+        };
+        let settings = Settings::load(temp_dir.path(), overrides).unwrap();
+
+        assert_eq!(settings.port, 9090);
+        assert_eq!(settings.agent_id, "flag-agent");
+        assert_eq!(settings.db_path, temp_dir.path().join("data/history.db"));
+        assert_eq!(settings.ignore_patterns, vec!["build".to_string()]);
+        assert_eq!(settings.debounce, Duration::from_millis(250));
+        assert_eq!(settings.poll_interval, Some(Duration::from_secs(2)));
+        assert_eq!(settings.max_file_size, Some(1048576));
+        assert_eq!(settings.watch_queue_capacity, 500);
+        assert_eq!(settings.watch_overflow, OverflowPolicy::Drop);
+        assert_eq!(settings.hash_algorithm, HashAlgorithm::Blake3);
+    }
+
+    #[test]
+    fn test_author_map() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join(".gitent")).unwrap();
+        std::fs::write(
+            Config::path_for(temp_dir.path()),
+            r#"
+            [authors.claude-1]
+            name = "Ada Lovelace"
+            email = "ada@example.com"
+            "#,
+        )
+        .unwrap();
+
+        let settings = Settings::load(temp_dir.path(), Config::default()).unwrap();
+        assert_eq!(
+            settings.authors.author_for("claude-1").to_string(),
+            "Ada Lovelace <ada@example.com>"
+        );
+        assert_eq!(
+            settings.authors.author_for("cli-user").to_string(),
+            "cli-user <cli-user@gitent.local>"
+        );
+    }
+
+    #[test]
+    fn test_metadata_schema() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join(".gitent")).unwrap();
+        std::fs::write(
+            Config::path_for(temp_dir.path()),
+            r#"
+            [metadata_schema]
+            strict = true
+
+            [metadata_schema.commits.ticket]
+            required = true
+
+            [metadata_schema.changes.attempt]
+            type = "integer"
+            "#,
+        )
+        .unwrap();
+
+        let settings = Settings::load(temp_dir.path(), Config::default()).unwrap();
+        let schema = settings.metadata_schema.unwrap();
+        assert!(schema.strict);
+        assert!(schema.commits["ticket"].required);
+        assert_eq!(
+            schema.changes["attempt"].value_type,
+            crate::models::MetadataType::Integer
+        );
+    }
+
+    #[test]
+    fn test_invalid_config_file() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join(".gitent")).unwrap();
+        std::fs::write(Config::path_for(temp_dir.path()), "prot = 8080").unwrap();
+
+        assert!(matches!(
+            Config::discover(temp_dir.path()),
+            Err(Error::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,166 @@
+//! Compact line-based deltas between two versions of a file.
+//!
+//! A delta is a sequence of instructions that rebuild the target from the
+//! base: copy a byte range out of the base, or insert literal bytes. Lines
+//! are diffed with `similar`, so a small edit to a large file produces a
+//! delta a fraction of the file's size.
+
+use crate::error::{Error, Result};
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+
+enum Instruction {
+    Copy { offset: usize, len: usize },
+    Insert { start: usize, end: usize },
+}
+
+/// Encode `target` as a delta against `base`.
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let base_lines = split_lines(base);
+    let target_lines = split_lines(target);
+    let base_offsets = line_offsets(&base_lines);
+    let target_offsets = line_offsets(&target_lines);
+
+    let mut instructions: Vec<Instruction> = Vec::new();
+    for op in capture_diff_slices(Algorithm::Myers, &base_lines, &target_lines) {
+        let next = match op {
+            DiffOp::Equal { old_index, len, .. } => Instruction::Copy {
+                offset: base_offsets[old_index],
+                len: base_offsets[old_index + len] - base_offsets[old_index],
+            },
+            DiffOp::Insert {
+                new_index, new_len, ..
+            }
+            | DiffOp::Replace {
+                new_index, new_len, ..
+            } => Instruction::Insert {
+                start: target_offsets[new_index],
+                end: target_offsets[new_index + new_len],
+            },
+            DiffOp::Delete { .. } => continue,
+        };
+
+        // Coalesce with the previous instruction where the ranges are contiguous
+        match (instructions.last_mut(), &next) {
+            (Some(Instruction::Copy { offset, len }), Instruction::Copy { offset: o, len: l })
+                if *offset + *len == *o =>
+            {
+                *len += l;
+            }
+            (Some(Instruction::Insert { end, .. }), Instruction::Insert { start: s, end: e })
+                if *end == *s =>
+            {
+                *end = *e;
+            }
+            _ => instructions.push(next),
+        }
+    }
+
+    let mut delta = Vec::new();
+    for instruction in instructions {
+        match instruction {
+            Instruction::Copy { offset, len } => {
+                delta.push(OP_COPY);
+                delta.extend_from_slice(&(offset as u64).to_le_bytes());
+                delta.extend_from_slice(&(len as u64).to_le_bytes());
+            }
+            Instruction::Insert { start, end } => {
+                delta.push(OP_INSERT);
+                delta.extend_from_slice(&((end - start) as u64).to_le_bytes());
+                delta.extend_from_slice(&target[start..end]);
+            }
+        }
+    }
+
+    delta
+}
+
+/// Rebuild the target from `base` and a delta produced by [`encode`].
+pub fn apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut pos = 0;
+
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+
+        match op {
+            OP_COPY => {
+                let offset = read_u64(delta, &mut pos)?;
+                let len = read_u64(delta, &mut pos)?;
+                let range = base
+                    .get(offset..offset.saturating_add(len))
+                    .ok_or_else(|| corrupt("copy range outside base"))?;
+                output.extend_from_slice(range);
+            }
+            OP_INSERT => {
+                let len = read_u64(delta, &mut pos)?;
+                let bytes = delta
+                    .get(pos..pos.saturating_add(len))
+                    .ok_or_else(|| corrupt("truncated insert"))?;
+                output.extend_from_slice(bytes);
+                pos += len;
+            }
+            _ => return Err(corrupt("unknown instruction")),
+        }
+    }
+
+    Ok(output)
+}
+
+fn split_lines(content: &[u8]) -> Vec<&[u8]> {
+    content.split_inclusive(|&b| b == b'\n').collect()
+}
+
+/// Byte offset of the start of each line, plus a final entry for the end.
+fn line_offsets(lines: &[&[u8]]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(lines.len() + 1);
+    let mut offset = 0;
+    offsets.push(offset);
+    for line in lines {
+        offset += line.len();
+        offsets.push(offset);
+    }
+    offsets
+}
+
+fn read_u64(delta: &[u8], pos: &mut usize) -> Result<usize> {
+    let bytes = delta
+        .get(*pos..*pos + 8)
+        .ok_or_else(|| corrupt("truncated length"))?;
+    *pos += 8;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+fn corrupt(reason: &str) -> Error {
+    Error::DiffFailed(format!("corrupt delta: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let base = b"one\ntwo\nthree\nfour\n".to_vec();
+        let target = b"zero\none\n2\nthree\nfour\nfive".to_vec();
+
+        let delta = encode(&base, &target);
+        assert_eq!(apply(&base, &delta).unwrap(), target);
+
+        assert_eq!(apply(&base, &encode(&base, b"")).unwrap(), b"");
+        assert_eq!(apply(b"", &encode(b"", &target)).unwrap(), target);
+    }
+
+    #[test]
+    fn test_apply_rejects_corrupt_delta() {
+        let base = b"hello\n".to_vec();
+        let mut delta = encode(&base, b"hello\nworld\n");
+        delta.truncate(delta.len() - 3);
+
+        assert!(apply(&base, &delta).is_err());
+        assert!(apply(&base, &[42]).is_err());
+    }
+}
@@ -1,16 +1,28 @@
-use crate::error::Result;
-use crate::models::Change;
-use similar::{ChangeTag, TextDiff};
+use crate::error::{Error, Result};
+use crate::models::{Change, ChangeType};
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, DiffOp, DiffTag, TextDiff};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use uuid::Uuid;
 
-#[derive(Debug, Clone)]
+pub mod semantic;
+
+/// Minimum content similarity for a delete/create pair to count as a rename.
+pub const DEFAULT_RENAME_THRESHOLD: f32 = 0.5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileDiff {
     pub path: String,
+    pub change_type: ChangeType,
+    /// Where the file was before, for renames
+    pub old_path: Option<String>,
     pub old_content: Option<String>,
     pub new_content: Option<String>,
     pub diff_lines: Vec<DiffLine>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DiffLine {
     pub line_type: DiffLineType,
     pub content: String,
@@ -18,7 +30,8 @@ pub struct DiffLine {
     pub new_line_number: Option<usize>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum DiffLineType {
     Context,
     Addition,
@@ -45,12 +58,41 @@ impl FileDiff {
 
         Ok(FileDiff {
             path: change.path.to_string_lossy().to_string(),
+            change_type: change.change_type,
+            old_path: change
+                .old_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string()),
             old_content,
             new_content,
             diff_lines,
         })
     }
 
+    /// Lazily produce the diff lines for a change.
+    ///
+    /// Unlike [`FileDiff::from_change`], this neither copies the file contents
+    /// nor builds the full line list up front, so callers rendering only part
+    /// of a large diff can stop early. Yields nothing if either side of the
+    /// change has no content, and a single error if the content isn't UTF-8.
+    pub fn iter_lines(change: &Change) -> impl Iterator<Item = Result<DiffLine>> + '_ {
+        let decoded = match (&change.content_before, &change.content_after) {
+            (Some(old), Some(new)) => Some(
+                std::str::from_utf8(old)
+                    .and_then(|old| std::str::from_utf8(new).map(|new| (old, new))),
+            ),
+            _ => None,
+        };
+
+        let (lines, error) = match decoded {
+            Some(Ok((old, new))) => (Some(DiffLineIter::new(old, new)), None),
+            Some(Err(e)) => (None, Some(Err(Error::DiffFailed(e.to_string())))),
+            None => (None, None),
+        };
+
+        error.into_iter().chain(lines.into_iter().flatten().map(Ok))
+    }
+
     fn compute_diff(old_text: &str, new_text: &str) -> Vec<DiffLine> {
         let diff = TextDiff::from_lines(old_text, new_text);
         let mut lines = Vec::new();
@@ -89,50 +131,358 @@ impl FileDiff {
         lines
     }
 
+    /// Render the diff in unified format with `context_lines` of context
+    /// around each hunk. A missing side is diffed as empty, and labelled
+    /// `/dev/null` only for a created or deleted file, so a modification
+    /// whose old content wasn't captured isn't mistaken for a new file.
     pub fn format_unified(&self, context_lines: usize) -> String {
-        let mut output = String::new();
+        if self.old_content.is_none() && self.new_content.is_none() {
+            return String::new();
+        }
 
-        output.push_str(&format!("--- {}\n", self.path));
-        output.push_str(&format!("+++ {}\n", self.path));
+        let label = |absent: ChangeType, path: &str| {
+            if self.change_type == absent {
+                "/dev/null".to_string()
+            } else {
+                path.to_string()
+            }
+        };
+        let old_path = self.old_path.as_deref().unwrap_or(&self.path);
+
+        TextDiff::from_lines(
+            self.old_content.as_deref().unwrap_or(""),
+            self.new_content.as_deref().unwrap_or(""),
+        )
+        .unified_diff()
+        .context_radius(context_lines)
+        .header(
+            &label(ChangeType::Create, old_path),
+            &label(ChangeType::Delete, &self.path),
+        )
+        .to_string()
+    }
+}
 
-        let mut in_hunk = false;
-        let mut hunk_start = 0;
-        let mut hunk_lines = Vec::new();
+/// A single unified diff covering every change, in order.
+pub fn unified_patch(changes: &[Change], context_lines: usize) -> Result<String> {
+    let mut patch = String::new();
+    for change in changes {
+        patch.push_str(&FileDiff::from_change(change)?.format_unified(context_lines));
+    }
+    Ok(patch)
+}
+
+/// The hunks of a line diff between `old` and `new`, each rendered in
+/// unified format. Hunk `i` here is hunk `i` for [`apply_hunks`].
+pub fn hunks(old: &str, new: &str, context_lines: usize) -> Vec<String> {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(context_lines)
+        .iter_hunks()
+        .map(|hunk| hunk.to_string())
+        .collect()
+}
 
-        for (i, line) in self.diff_lines.iter().enumerate() {
-            if line.line_type != DiffLineType::Context || in_hunk {
-                if !in_hunk {
-                    in_hunk = true;
-                    hunk_start = i.saturating_sub(context_lines);
+/// Apply only the `selected` hunks of the diff from `old` to `new`.
+///
+/// Returns `old` with those hunks applied, which sits part way between the
+/// two; the unselected hunks make up the rest of the way to `new`.
+pub fn apply_hunks(old: &str, new: &str, context_lines: usize, selected: &[usize]) -> String {
+    let diff = TextDiff::from_lines(old, new);
+    let old_lines = diff.old_slices();
+    let new_lines = diff.new_slices();
+
+    let mut output = String::with_capacity(old.len().max(new.len()));
+    let mut pos = 0;
+    for (index, group) in diff.grouped_ops(context_lines).iter().enumerate() {
+        for op in group.iter().filter(|op| op.tag() != DiffTag::Equal) {
+            let old_range = op.old_range();
+            output.extend(old_lines[pos..old_range.start].iter().copied());
+            if selected.contains(&index) {
+                output.extend(new_lines[op.new_range()].iter().copied());
+            } else {
+                output.extend(old_lines[old_range.clone()].iter().copied());
+            }
+            pos = old_range.end;
+        }
+    }
+    output.extend(old_lines[pos..].iter().copied());
+
+    output
+}
+
+/// Pair deleted and created files whose contents are similar enough to be
+/// a rename, using [`DEFAULT_RENAME_THRESHOLD`].
+pub fn detect_renames(deletes: &[Change], creates: &[Change]) -> Vec<(Change, Change, f32)> {
+    detect_renames_with_threshold(deletes, creates, DEFAULT_RENAME_THRESHOLD)
+}
+
+/// Pair deleted and created files by content similarity.
+///
+/// Returns `(delete, create, similarity)` tuples, best matches first. Each
+/// file is paired at most once, and pairs scoring below `threshold` (0.0 to
+/// 1.0) are dropped. Deletes without recorded content can't be matched.
+pub fn detect_renames_with_threshold(
+    deletes: &[Change],
+    creates: &[Change],
+    threshold: f32,
+) -> Vec<(Change, Change, f32)> {
+    let mut candidates = Vec::new();
+
+    for (i, delete) in deletes.iter().enumerate() {
+        let Some(old) = &delete.content_before else {
+            continue;
+        };
+        for (j, create) in creates.iter().enumerate() {
+            let Some(new) = &create.content_after else {
+                continue;
+            };
+            let score = similarity(old, new);
+            if score >= threshold {
+                candidates.push((i, j, score));
+            }
+        }
+    }
+
+    pick_renames(candidates, deletes.len(), creates.len())
+        .into_iter()
+        .map(|(i, j, score)| (deletes[i].clone(), creates[j].clone(), score))
+        .collect()
+}
+
+/// Choose pairs from `(delete, create, similarity)` index candidates, best
+/// first and each file at most once.
+fn pick_renames(
+    mut candidates: Vec<(usize, usize, f32)>,
+    deletes: usize,
+    creates: usize,
+) -> Vec<(usize, usize, f32)> {
+    candidates.sort_by(|a, b| b.2.total_cmp(&a.2).then((a.0, a.1).cmp(&(b.0, b.1))));
+
+    let mut used_deletes = vec![false; deletes];
+    let mut used_creates = vec![false; creates];
+    let mut renames = Vec::new();
+
+    for (i, j, score) in candidates {
+        if used_deletes[i] || used_creates[j] {
+            continue;
+        }
+        used_deletes[i] = true;
+        used_creates[j] = true;
+        renames.push((i, j, score));
+    }
+
+    renames
+}
+
+/// The synthetic rename standing in for a paired delete and create.
+fn rename_of(delete: &Change, create: &Change) -> Change {
+    let mut rename = Change::new(ChangeType::Rename, create.path.clone(), create.session_id)
+        .with_old_path(delete.path.clone());
+    rename.id = create.id;
+    rename.timestamp = create.timestamp;
+    rename.agent_id = create.agent_id.clone();
+    if let Some(content) = &delete.content_before {
+        rename = rename.with_content_before(content.clone());
+    }
+    if let Some(content) = &create.content_after {
+        rename = rename.with_content_after(content.clone());
+    }
+    rename
+}
+
+/// Collapse delete/create pairs in `changes` into synthetic `Rename` changes
+/// carrying both contents, keeping the original order otherwise.
+///
+/// Each entry is paired with its similarity score if it was detected as a rename.
+pub fn collapse_renames(changes: Vec<Change>, threshold: f32) -> Vec<(Change, Option<f32>)> {
+    let deletes: Vec<Change> = changes
+        .iter()
+        .filter(|c| c.change_type == ChangeType::Delete)
+        .cloned()
+        .collect();
+    let creates: Vec<Change> = changes
+        .iter()
+        .filter(|c| c.change_type == ChangeType::Create)
+        .cloned()
+        .collect();
+
+    let renames = detect_renames_with_threshold(&deletes, &creates, threshold);
+    if renames.is_empty() {
+        return changes.into_iter().map(|c| (c, None)).collect();
+    }
+
+    changes
+        .into_iter()
+        .filter_map(|change| {
+            if renames.iter().any(|(delete, _, _)| delete.id == change.id) {
+                return None;
+            }
+
+            match renames.iter().find(|(_, create, _)| create.id == change.id) {
+                Some((delete, create, score)) => Some((rename_of(delete, create), Some(*score))),
+                None => Some((change, None)),
+            }
+        })
+        .collect()
+}
+
+/// Like [`collapse_renames`], but for changes fetched one at a time by
+/// `load` and handed to `f` in the order of `ids`, for commits too large to
+/// hold in memory at once.
+///
+/// Only deleted files are kept, as any of them may pair with any created
+/// file, so each created file is loaded again when there are some. Changes
+/// `load` can't find are left out.
+pub fn for_each_collapsed(
+    ids: &[Uuid],
+    threshold: f32,
+    mut load: impl FnMut(&Uuid) -> Option<Change>,
+    mut f: impl FnMut(Change, Option<f32>) -> Result<()>,
+) -> Result<()> {
+    let mut deletes = Vec::new();
+    let mut creates = Vec::new();
+    for id in ids {
+        match load(id) {
+            Some(change)
+                if change.change_type == ChangeType::Delete && change.content_before.is_some() =>
+            {
+                deletes.push(change)
+            }
+            Some(change) if change.change_type == ChangeType::Create => creates.push(*id),
+            _ => {}
+        }
+    }
+
+    let mut candidates = Vec::new();
+    if !deletes.is_empty() {
+        for (j, id) in creates.iter().enumerate() {
+            let Some(new) = load(id).and_then(|create| create.content_after) else {
+                continue;
+            };
+            for (i, delete) in deletes.iter().enumerate() {
+                let old = delete.content_before.as_deref().unwrap_or_default();
+                let score = similarity(old, &new);
+                if score >= threshold {
+                    candidates.push((i, j, score));
                 }
+            }
+        }
+    }
+    let renames = pick_renames(candidates, deletes.len(), creates.len());
+    let renamed: HashMap<Uuid, (usize, f32)> = renames
+        .iter()
+        .map(|&(i, j, score)| (creates[j], (i, score)))
+        .collect();
+    let paired: HashSet<Uuid> = renames.iter().map(|&(i, _, _)| deletes[i].id).collect();
+
+    for id in ids {
+        if paired.contains(id) {
+            continue;
+        }
+        let Some(change) = load(id) else {
+            continue;
+        };
+        match renamed.get(id) {
+            Some(&(i, score)) => f(rename_of(&deletes[i], &change), Some(score))?,
+            None => f(change, None)?,
+        }
+    }
 
-                let prefix = match line.line_type {
-                    DiffLineType::Addition => "+",
-                    DiffLineType::Deletion => "-",
-                    DiffLineType::Context => " ",
+    Ok(())
+}
+
+/// Content similarity from 0.0 to 1.0, line-based for text.
+fn similarity(old: &[u8], new: &[u8]) -> f32 {
+    if old == new {
+        return 1.0;
+    }
+
+    match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+        (Ok(old), Ok(new)) => TextDiff::from_lines(old, new).ratio(),
+        _ => 0.0,
+    }
+}
+
+/// Walks the diff ops, materializing one `DiffLine` at a time.
+struct DiffLineIter<'a> {
+    old_lines: Vec<&'a str>,
+    new_lines: Vec<&'a str>,
+    ops: std::vec::IntoIter<DiffOp>,
+    current: Option<(DiffTag, Range<usize>, Range<usize>)>,
+}
+
+impl<'a> DiffLineIter<'a> {
+    fn new(old_text: &'a str, new_text: &'a str) -> Self {
+        let ops = TextDiff::from_lines(old_text, new_text).ops().to_vec();
+
+        Self {
+            old_lines: old_text.split_inclusive('\n').collect(),
+            new_lines: new_text.split_inclusive('\n').collect(),
+            ops: ops.into_iter(),
+            current: None,
+        }
+    }
+
+    fn deletion(&self, index: usize) -> DiffLine {
+        DiffLine {
+            line_type: DiffLineType::Deletion,
+            content: line_content(self.old_lines[index]),
+            old_line_number: Some(index + 1),
+            new_line_number: None,
+        }
+    }
+
+    fn addition(&self, index: usize) -> DiffLine {
+        DiffLine {
+            line_type: DiffLineType::Addition,
+            content: line_content(self.new_lines[index]),
+            old_line_number: None,
+            new_line_number: Some(index + 1),
+        }
+    }
+}
+
+/// Mirrors `similar`'s rendering, which terminates a final unterminated line.
+fn line_content(line: &str) -> String {
+    if line.ends_with('\n') {
+        line.to_string()
+    } else {
+        format!("{}\n", line)
+    }
+}
+
+impl Iterator for DiffLineIter<'_> {
+    type Item = DiffLine;
+
+    fn next(&mut self) -> Option<DiffLine> {
+        loop {
+            if let Some((tag, old_range, new_range)) = &mut self.current {
+                let line = match tag {
+                    DiffTag::Equal => match (old_range.next(), new_range.next()) {
+                        (Some(old_index), Some(new_index)) => Some(DiffLine {
+                            line_type: DiffLineType::Context,
+                            content: line_content(self.old_lines[old_index]),
+                            old_line_number: Some(old_index + 1),
+                            new_line_number: Some(new_index + 1),
+                        }),
+                        _ => None,
+                    },
+                    DiffTag::Delete => old_range.next().map(|i| self.deletion(i)),
+                    DiffTag::Insert => new_range.next().map(|i| self.addition(i)),
+                    DiffTag::Replace => match old_range.next() {
+                        Some(i) => Some(self.deletion(i)),
+                        None => new_range.next().map(|i| self.addition(i)),
+                    },
                 };
 
-                hunk_lines.push(format!("{}{}", prefix, line.content));
-
-                // Check if we should close the hunk
-                if i + context_lines >= self.diff_lines.len() - 1 {
-                    if !hunk_lines.is_empty() {
-                        output.push_str(&format!(
-                            "@@ -{},{} +{},{} @@\n",
-                            self.diff_lines[hunk_start].old_line_number.unwrap_or(0),
-                            hunk_lines.len(),
-                            self.diff_lines[hunk_start].new_line_number.unwrap_or(0),
-                            hunk_lines.len()
-                        ));
-                        output.push_str(&hunk_lines.join(""));
-                        hunk_lines.clear();
-                    }
-                    in_hunk = false;
+                if line.is_some() {
+                    return line;
                 }
             }
-        }
 
-        output
+            self.current = Some(self.ops.next()?.as_tag_tuple());
+        }
     }
 }
 
@@ -143,6 +493,56 @@ mod tests {
     use std::path::PathBuf;
     use uuid::Uuid;
 
+    #[test]
+    fn test_apply_selected_hunks() {
+        let old: String = (1..=20).map(|i| format!("line {}\n", i)).collect();
+        let new = old
+            .replace("line 2\n", "line two\n")
+            .replace("line 18\n", "line eighteen\n");
+
+        assert_eq!(hunks(&old, &new, 3).len(), 2);
+
+        let partial = apply_hunks(&old, &new, 3, &[1]);
+        assert_eq!(partial, old.replace("line 18\n", "line eighteen\n"));
+
+        // The rest of the hunks take the partial content the rest of the way
+        let remaining = hunks(&partial, &new, 3);
+        assert_eq!(remaining.len(), 1);
+        assert!(remaining[0].contains("+line two"));
+
+        assert_eq!(apply_hunks(&old, &new, 3, &[]), old);
+        assert_eq!(apply_hunks(&old, &new, 3, &[0, 1]), new);
+    }
+
+    #[test]
+    fn test_format_unified_hunks() {
+        let old: String = (1..=20).map(|i| format!("line {}\n", i)).collect();
+        let new = old
+            .replace("line 2\n", "line two\n")
+            .replace("line 18\n", "line eighteen\n");
+        let change = Change::new(ChangeType::Modify, PathBuf::from("f.txt"), Uuid::new_v4())
+            .with_content_before(old.into_bytes())
+            .with_content_after(new.into_bytes());
+
+        let patch = FileDiff::from_change(&change).unwrap().format_unified(1);
+        assert!(patch.starts_with("--- f.txt\n+++ f.txt\n"));
+        assert!(patch.contains("@@ -1,3 +1,3 @@\n line 1\n-line 2\n+line two\n line 3\n"));
+        assert!(patch.contains("@@ -17,3 +17,3 @@\n"));
+
+        let create = Change::new(ChangeType::Create, PathBuf::from("new.txt"), Uuid::new_v4())
+            .with_content_after(b"hello\n".to_vec());
+        let patch = FileDiff::from_change(&create).unwrap().format_unified(3);
+        assert_eq!(patch, "--- /dev/null\n+++ new.txt\n@@ -0,0 +1 @@\n+hello\n");
+
+        // Without its old content, a modification is still labelled as one
+        let uncaptured = Change::new(ChangeType::Modify, PathBuf::from("f.txt"), Uuid::new_v4())
+            .with_content_after(b"hello\n".to_vec());
+        let patch = FileDiff::from_change(&uncaptured)
+            .unwrap()
+            .format_unified(3);
+        assert!(patch.starts_with("--- f.txt\n+++ f.txt\n"));
+    }
+
     #[test]
     fn test_diff_computation() {
         let old_text = "line 1\nline 2\nline 3\n";
@@ -173,4 +573,119 @@ mod tests {
         assert!(file_diff.new_content.is_some());
         assert!(!file_diff.diff_lines.is_empty());
     }
+
+    #[test]
+    fn test_iter_lines_matches_eager_diff() {
+        let session_id = Uuid::new_v4();
+        let change = Change::new(ChangeType::Modify, PathBuf::from("test.txt"), session_id)
+            .with_content_before(b"one\ntwo\nthree\nfour\nfive\n".to_vec())
+            .with_content_after(b"zero\none\n2\nthree\nfive\nsix".to_vec());
+
+        let eager = FileDiff::from_change(&change).unwrap().diff_lines;
+        let streamed: Vec<DiffLine> = FileDiff::iter_lines(&change)
+            .collect::<Result<_>>()
+            .unwrap();
+
+        assert!(!streamed.is_empty());
+        assert_eq!(eager, streamed);
+    }
+
+    #[test]
+    fn test_detect_renames() {
+        let session_id = Uuid::new_v4();
+        let original = "fn main() {\n    println!(\"hello\");\n}\n\nfn helper() {}\n";
+        let edited = "fn main() {\n    println!(\"hello, world\");\n}\n\nfn helper() {}\n";
+
+        let delete = Change::new(ChangeType::Delete, PathBuf::from("a.rs"), session_id)
+            .with_content_before(original.as_bytes().to_vec());
+        let unrelated_delete = Change::new(ChangeType::Delete, PathBuf::from("c.rs"), session_id)
+            .with_content_before(b"completely\ndifferent\n".to_vec());
+        let create = Change::new(ChangeType::Create, PathBuf::from("b.rs"), session_id)
+            .with_content_after(edited.as_bytes().to_vec());
+
+        let creates = [create.clone()];
+        let renames = detect_renames(&[unrelated_delete, delete.clone()], &creates);
+
+        assert_eq!(1, renames.len());
+        let (old, new, score) = &renames[0];
+        assert_eq!(old.id, delete.id);
+        assert_eq!(new.id, create.id);
+        assert!(*score >= DEFAULT_RENAME_THRESHOLD && *score < 1.0);
+
+        assert!(detect_renames_with_threshold(&[delete], &[create], 0.99).is_empty());
+    }
+
+    #[test]
+    fn test_collapse_renames() {
+        let session_id = Uuid::new_v4();
+        let delete = Change::new(ChangeType::Delete, PathBuf::from("a.txt"), session_id)
+            .with_content_before(b"same\n".to_vec());
+        let create = Change::new(ChangeType::Create, PathBuf::from("b.txt"), session_id)
+            .with_content_after(b"same\n".to_vec());
+        let modify = Change::new(ChangeType::Modify, PathBuf::from("c.txt"), session_id);
+
+        let collapsed = collapse_renames(vec![delete, create, modify], DEFAULT_RENAME_THRESHOLD);
+
+        assert_eq!(2, collapsed.len());
+        let (rename, score) = &collapsed[0];
+        assert_eq!(rename.change_type, ChangeType::Rename);
+        assert_eq!(rename.old_path, Some(PathBuf::from("a.txt")));
+        assert_eq!(rename.path, PathBuf::from("b.txt"));
+        assert_eq!(*score, Some(1.0));
+        assert_eq!(collapsed[1].1, None);
+    }
+
+    #[test]
+    fn test_for_each_collapsed_matches_collapse_renames() {
+        let session_id = Uuid::new_v4();
+        let content = |name: &str| format!("{}\n", name).repeat(50).into_bytes();
+        let mut changes = Vec::new();
+        for i in 0..20 {
+            let path = PathBuf::from(format!("{}.txt", i));
+            changes.push(match i % 4 {
+                0 => Change::new(ChangeType::Delete, path, session_id)
+                    .with_content_before(content(&format!("moved {}", i))),
+                1 => Change::new(ChangeType::Create, path, session_id)
+                    .with_content_after(content(&format!("moved {}", i - 1))),
+                2 => Change::new(ChangeType::Create, path, session_id)
+                    .with_content_after(content(&format!("new {}", i))),
+                _ => Change::new(ChangeType::Modify, path, session_id)
+                    .with_content_before(content("old"))
+                    .with_content_after(content("new")),
+            });
+        }
+        let ids: Vec<Uuid> = changes.iter().map(|c| c.id).collect();
+
+        let mut loads = 0;
+        let mut streamed = Vec::new();
+        for_each_collapsed(
+            &ids,
+            DEFAULT_RENAME_THRESHOLD,
+            |id| {
+                loads += 1;
+                changes.iter().find(|c| c.id == *id).cloned()
+            },
+            |change, score| {
+                streamed.push((change.id, change.change_type, change.old_path, score));
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        let eager: Vec<_> = collapse_renames(changes.clone(), DEFAULT_RENAME_THRESHOLD)
+            .into_iter()
+            .map(|(change, score)| (change.id, change.change_type, change.old_path, score))
+            .collect();
+        assert_eq!(streamed, eager);
+        assert_eq!(
+            streamed
+                .iter()
+                .filter(|(_, kind, _, _)| *kind == ChangeType::Rename)
+                .count(),
+            5
+        );
+        // Each change is loaded once to sort it and once to hand it on, with
+        // created files loaded once more to compare against deleted ones
+        assert_eq!(loads, 20 + 10 + 15);
+    }
 }
@@ -0,0 +1,442 @@
+//! Diffs of structured files by what they contain rather than by line, so
+//! reformatting or reordering keys isn't reported as a change.
+//!
+//! Each supported format has a handler that parses both sides of a change.
+//! When a file isn't in a supported format, or either side doesn't parse,
+//! there is no semantic diff and callers fall back to the line diff.
+//!
+//! JSON changes can also be written as an RFC 6902 JSON Patch with
+//! [`json_patch_change`].
+
+use crate::error::{Error, Result};
+use crate::models::{Change, ChangeType};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::path::Path;
+
+/// A structured file format with a semantic diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+}
+
+impl Format {
+    /// The format of the file at `path`, judging by its extension.
+    pub fn detect(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    /// The differences between two documents, or `None` if either doesn't
+    /// parse.
+    pub fn diff(self, old: &str, new: &str) -> Option<Vec<SemanticChange>> {
+        match self {
+            Format::Json => diff_json(old, new),
+        }
+    }
+}
+
+/// One difference between two documents, at a path such as
+/// `$.dependencies.serde` or `$.items[2]`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SemanticChange {
+    Added {
+        path: String,
+        value: Value,
+    },
+    Removed {
+        path: String,
+        value: Value,
+    },
+    Changed {
+        path: String,
+        old: Value,
+        new: Value,
+    },
+}
+
+impl fmt::Display for SemanticChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SemanticChange::Added { path, value } => write!(f, "+ {}: {}", path, value),
+            SemanticChange::Removed { path, value } => write!(f, "- {}: {}", path, value),
+            SemanticChange::Changed { path, old, new } => {
+                write!(f, "~ {}: {} -> {}", path, old, new)
+            }
+        }
+    }
+}
+
+/// The semantic diff of a change, if its file is in a supported format and
+/// both sides were recorded and parse.
+pub fn diff_change(change: &Change) -> Option<Vec<SemanticChange>> {
+    let format = Format::detect(&change.path)?;
+    let old = std::str::from_utf8(change.content_before.as_deref()?).ok()?;
+    let new = std::str::from_utf8(change.content_after.as_deref()?).ok()?;
+    format.diff(old, new)
+}
+
+/// Compare two JSON documents value by value.
+///
+/// Object keys are matched by name, so their order doesn't matter; array
+/// elements are matched by position.
+pub fn diff_json(old: &str, new: &str) -> Option<Vec<SemanticChange>> {
+    let old: Value = serde_json::from_str(old).ok()?;
+    let new: Value = serde_json::from_str(new).ok()?;
+    let mut changes = Vec::new();
+    walk(&mut Vec::new(), &old, &new, &mut |path, edit| {
+        let path = dollar_path(path);
+        changes.push(match edit {
+            Edit::Add(value) => SemanticChange::Added {
+                path,
+                value: value.clone(),
+            },
+            Edit::Remove(value) => SemanticChange::Removed {
+                path,
+                value: value.clone(),
+            },
+            Edit::Replace(old, new) => SemanticChange::Changed {
+                path,
+                old: old.clone(),
+                new: new.clone(),
+            },
+        });
+    });
+    Some(changes)
+}
+
+/// The RFC 6902 JSON Patch turning the old side of a change into the new
+/// one, for a `.json` file with both sides recorded.
+///
+/// The differences are found as by [`diff_json`] and written as `add`,
+/// `remove` and `replace` operations, in an order that applies cleanly. A
+/// created file is a single `add`, and a deleted one a single `remove`, of
+/// the whole document.
+pub fn json_patch_change(change: &Change) -> Result<Value> {
+    let path = change.path.display();
+    if Format::detect(&change.path) != Some(Format::Json) {
+        return Err(Error::DiffFailed(format!(
+            "{} isn't a JSON file, so has no JSON Patch",
+            path
+        )));
+    }
+    let side = |content: Option<&[u8]>, which: &str| {
+        let content = content.ok_or_else(|| {
+            Error::DiffFailed(format!("{} has no content {} the change", path, which))
+        })?;
+        std::str::from_utf8(content)
+            .ok()
+            .and_then(|text| serde_json::from_str::<Value>(text).ok())
+            .ok_or_else(|| {
+                Error::DiffFailed(format!(
+                    "{} doesn't parse as JSON {} the change",
+                    path, which
+                ))
+            })
+    };
+    match change.change_type {
+        ChangeType::Create => {
+            let new = side(change.content_after.as_deref(), "after")?;
+            Ok(json!([{"op": "add", "path": "", "value": new}]))
+        }
+        ChangeType::Delete => {
+            side(change.content_before.as_deref(), "before")?;
+            Ok(json!([{"op": "remove", "path": ""}]))
+        }
+        ChangeType::Modify | ChangeType::Rename => {
+            let old = side(change.content_before.as_deref(), "before")?;
+            let new = side(change.content_after.as_deref(), "after")?;
+            Ok(json_patch(&old, &new))
+        }
+    }
+}
+
+/// A JSON Patch for one change, or why it has none.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilePatch {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl FilePatch {
+    pub fn for_change(change: &Change) -> Self {
+        let (patch, error) = match json_patch_change(change) {
+            Ok(patch) => (Some(patch), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        Self {
+            path: change.path.to_string_lossy().to_string(),
+            patch,
+            error,
+        }
+    }
+}
+
+/// A [`FilePatch`] for each change, in the order given. Pass changes oldest
+/// first so patches to the same file apply one after another.
+pub fn json_patches<'a>(changes: impl IntoIterator<Item = &'a Change>) -> Vec<FilePatch> {
+    changes.into_iter().map(FilePatch::for_change).collect()
+}
+
+/// The RFC 6902 JSON Patch turning `old` into `new`.
+pub fn json_patch(old: &Value, new: &Value) -> Value {
+    let mut operations = Vec::new();
+    walk(&mut Vec::new(), old, new, &mut |path, edit| {
+        let path = pointer(path);
+        operations.push(match edit {
+            Edit::Add(value) => json!({"op": "add", "path": path, "value": value}),
+            Edit::Remove(_) => json!({"op": "remove", "path": path}),
+            Edit::Replace(_, new) => json!({"op": "replace", "path": path, "value": new}),
+        });
+    });
+    Value::Array(operations)
+}
+
+/// One step into a document.
+enum Segment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+/// A difference found by [`walk`].
+enum Edit<'a> {
+    Add(&'a Value),
+    Remove(&'a Value),
+    Replace(&'a Value, &'a Value),
+}
+
+/// Report every difference between `old` and `new` to `emit`, with the path
+/// to where it is.
+///
+/// Surplus array elements are removed from the end backwards, so the edits
+/// can be applied in the order they're reported.
+fn walk<'a>(
+    path: &mut Vec<Segment<'a>>,
+    old: &'a Value,
+    new: &'a Value,
+    emit: &mut impl FnMut(&[Segment<'a>], Edit<'a>),
+) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_value) in old {
+                path.push(Segment::Key(key));
+                match new.get(key) {
+                    Some(new_value) => walk(path, old_value, new_value, emit),
+                    None => emit(path, Edit::Remove(old_value)),
+                }
+                path.pop();
+            }
+            for (key, new_value) in new {
+                if !old.contains_key(key) {
+                    path.push(Segment::Key(key));
+                    emit(path, Edit::Add(new_value));
+                    path.pop();
+                }
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            for (index, (old, new)) in old.iter().zip(new).enumerate() {
+                path.push(Segment::Index(index));
+                walk(path, old, new, emit);
+                path.pop();
+            }
+            for (index, old) in old.iter().enumerate().skip(new.len()).rev() {
+                path.push(Segment::Index(index));
+                emit(path, Edit::Remove(old));
+                path.pop();
+            }
+            for (index, new) in new.iter().enumerate().skip(old.len()) {
+                path.push(Segment::Index(index));
+                emit(path, Edit::Add(new));
+                path.pop();
+            }
+        }
+        (old, new) if old != new => emit(path, Edit::Replace(old, new)),
+        _ => {}
+    }
+}
+
+/// A path as shown in semantic diffs, such as `$.items[2].name`.
+fn dollar_path(path: &[Segment]) -> String {
+    path.iter()
+        .fold("$".to_string(), |parent, segment| match segment {
+            Segment::Key(key) => member_path(&parent, key),
+            Segment::Index(index) => format!("{}[{}]", parent, index),
+        })
+}
+
+/// A path as an RFC 6901 JSON Pointer, such as `/items/2/name`.
+fn pointer(path: &[Segment]) -> String {
+    path.iter()
+        .map(|segment| match segment {
+            Segment::Key(key) => format!("/{}", key.replace('~', "~0").replace('/', "~1")),
+            Segment::Index(index) => format!("/{}", index),
+        })
+        .collect()
+}
+
+/// `parent.key`, or `parent["key"]` when the key isn't a plain identifier.
+fn member_path(parent: &str, key: &str) -> String {
+    let plain = !key.is_empty()
+        && !key.starts_with(|c: char| c.is_ascii_digit())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if plain {
+        format!("{}.{}", parent, key)
+    } else {
+        format!("{}[{}]", parent, Value::from(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_reformatted_json_has_no_changes() {
+        let minified =
+            r#"{"name":"gitent","tags":["vcs","agents"],"deps":{"serde":"1","uuid":"1"}}"#;
+        let pretty = r#"{
+            "deps": {
+                "uuid": "1",
+                "serde": "1"
+            },
+            "name": "gitent",
+            "tags": [
+                "vcs",
+                "agents"
+            ]
+        }"#;
+        assert_eq!(diff_json(minified, pretty), Some(vec![]));
+
+        // A line diff of the same change touches every line
+        let change = Change::new(
+            ChangeType::Modify,
+            PathBuf::from("package.json"),
+            Uuid::new_v4(),
+        )
+        .with_content_before(minified.as_bytes().to_vec())
+        .with_content_after(pretty.as_bytes().to_vec());
+        assert_eq!(diff_change(&change), Some(vec![]));
+    }
+
+    #[test]
+    fn test_json_changes_and_fallback() {
+        let old = r#"{"version": 1, "tags": ["a", "b"], "old key": true}"#;
+        let new = r#"{"version": 2, "tags": ["a"], "added": {"x": null}}"#;
+        let changes = diff_json(old, new).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                SemanticChange::Removed {
+                    path: r#"$["old key"]"#.to_string(),
+                    value: json!(true),
+                },
+                SemanticChange::Removed {
+                    path: "$.tags[1]".to_string(),
+                    value: json!("b"),
+                },
+                SemanticChange::Changed {
+                    path: "$.version".to_string(),
+                    old: json!(1),
+                    new: json!(2),
+                },
+                SemanticChange::Added {
+                    path: "$.added".to_string(),
+                    value: json!({"x": null}),
+                },
+            ]
+        );
+        assert_eq!(changes[2].to_string(), "~ $.version: 1 -> 2");
+
+        // Anything that doesn't parse, or isn't JSON, gets the line diff
+        assert_eq!(diff_json("{", "{}"), None);
+        let text = Change::new(
+            ChangeType::Modify,
+            PathBuf::from("notes.txt"),
+            Uuid::new_v4(),
+        )
+        .with_content_before(b"{}".to_vec())
+        .with_content_after(b"{\"a\": 1}".to_vec());
+        assert_eq!(diff_change(&text), None);
+    }
+
+    #[test]
+    fn test_json_patch_applies() {
+        let old = r#"{"name": "app", "version": 1, "tags": ["a", "b", "c"],
+                      "a/b": {"x~y": 1}, "scripts": {"lint": "eslint", "test": "jest"}}"#;
+        let new = r#"{"name": "app", "version": 2, "tags": ["a"],
+                      "a/b": {"x~y": 2}, "scripts": {"test": "jest"}, "private": true}"#;
+        let change = Change::new(
+            ChangeType::Modify,
+            PathBuf::from("package.json"),
+            Uuid::new_v4(),
+        )
+        .with_content_before(old.as_bytes().to_vec())
+        .with_content_after(new.as_bytes().to_vec());
+
+        let patch = json_patch_change(&change).unwrap();
+        assert!(patch.as_array().unwrap().contains(&json!(
+            {"op": "replace", "path": "/a~1b/x~0y", "value": 2}
+        )));
+
+        let mut document: Value = serde_json::from_str(old).unwrap();
+        let patch: json_patch::Patch = serde_json::from_value(patch).unwrap();
+        json_patch::patch(&mut document, &patch).unwrap();
+        assert_eq!(document, serde_json::from_str::<Value>(new).unwrap());
+
+        // Anything else gets a clear error rather than a line diff
+        let text = Change::new(
+            ChangeType::Modify,
+            PathBuf::from("notes.txt"),
+            Uuid::new_v4(),
+        )
+        .with_content_before(b"{}".to_vec())
+        .with_content_after(b"{}".to_vec());
+        assert!(matches!(
+            json_patch_change(&text),
+            Err(Error::DiffFailed(_))
+        ));
+        let broken = change.with_content_after(b"{".to_vec());
+        assert!(matches!(
+            json_patch_change(&broken),
+            Err(Error::DiffFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_json_patch_of_created_and_deleted_files() {
+        let session_id = Uuid::new_v4();
+        let created = Change::new(ChangeType::Create, PathBuf::from("new.json"), session_id)
+            .with_content_after(br#"{"a": [1, 2]}"#.to_vec());
+        let patch = json_patch_change(&created).unwrap();
+        assert_eq!(
+            patch,
+            json!([{"op": "add", "path": "", "value": {"a": [1, 2]}}])
+        );
+        let mut document = Value::Null;
+        let patch: json_patch::Patch = serde_json::from_value(patch).unwrap();
+        json_patch::patch(&mut document, &patch).unwrap();
+        assert_eq!(document, json!({"a": [1, 2]}));
+
+        let deleted = Change::new(ChangeType::Delete, PathBuf::from("old.json"), session_id)
+            .with_content_before(b"[]".to_vec());
+        assert_eq!(
+            json_patch_change(&deleted).unwrap(),
+            json!([{"op": "remove", "path": ""}])
+        );
+        let broken = deleted.with_content_before(b"[".to_vec());
+        assert!(matches!(
+            json_patch_change(&broken),
+            Err(Error::DiffFailed(_))
+        ));
+    }
+}
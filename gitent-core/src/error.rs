@@ -5,10 +5,10 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Database error: {0}")]
-    Database(#[from] rusqlite::Error),
+    Database(#[source] rusqlite::Error),
 
     #[error("IO error: {0}")]
-    Io(#[from] std::io::Error),
+    Io(#[source] std::io::Error),
 
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
@@ -19,9 +19,18 @@ pub enum Error {
     #[error("Commit not found: {0}")]
     CommitNotFound(String),
 
+    #[error("Ambiguous commit id: {0}")]
+    AmbiguousRef(String),
+
     #[error("Session not found: {0}")]
     SessionNotFound(String),
 
+    #[error("Snapshot not found: {0}")]
+    SnapshotNotFound(String),
+
+    #[error("Checkpoint not found: {0}")]
+    CheckpointNotFound(String),
+
     #[error("Invalid path: {0}")]
     InvalidPath(String),
 
@@ -39,4 +48,87 @@ pub enum Error {
 
     #[error("Invalid operation: {0}")]
     InvalidOperation(String),
+
+    #[error("Invalid metadata: {0}")]
+    InvalidMetadata(String),
+
+    #[error("Invalid config: {0}")]
+    Config(String),
+
+    #[error("Content does not match its hash for change: {0}")]
+    CorruptContent(String),
+
+    #[error("Storage is full: {0}")]
+    StorageFull(String),
+
+    #[error("Event log is corrupt: {0}")]
+    EventLog(String),
+
+    #[error("Concurrent modification: {0}")]
+    ConcurrentModification(String),
+
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    #[error("Operation timed out")]
+    Timeout,
+
+    #[cfg(feature = "git")]
+    #[error("Git error: {0}")]
+    Git(#[from] git2::Error),
+}
+
+impl Error {
+    /// Whether this is [`Error::Cancelled`] or [`Error::Timeout`].
+    pub fn is_cancellation(&self) -> bool {
+        matches!(self, Error::Cancelled | Error::Timeout)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            // Cancellation noticed while reading a row comes back wrapped
+            rusqlite::Error::FromSqlConversionFailure(_, _, inner)
+                if inner
+                    .downcast_ref::<Error>()
+                    .is_some_and(Error::is_cancellation) =>
+            {
+                *inner.downcast::<Error>().unwrap()
+            }
+            e => match e.sqlite_error_code() {
+                Some(rusqlite::ErrorCode::DiskFull) => Error::StorageFull(e.to_string()),
+                _ => Error::Database(e),
+            },
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::StorageFull => Error::StorageFull(e.to_string()),
+            _ => Error::Io(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_full_errors() {
+        let io = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        assert!(matches!(Error::from(io), Error::StorageFull(_)));
+
+        let sqlite = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_FULL),
+            None,
+        );
+        assert!(matches!(Error::from(sqlite), Error::StorageFull(_)));
+
+        let io = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert!(matches!(Error::from(io), Error::Io(_)));
+    }
 }
@@ -0,0 +1,445 @@
+//! An append-only log of everything written to storage, kept alongside the
+//! database so history can be rebuilt if the database is lost.
+//!
+//! Each line of the log is one JSON [`LogEvent`]. Events are appended once
+//! the write they describe has succeeded, and flushed to disk before the
+//! write returns.
+
+use crate::error::{Error, Result};
+use crate::models::{Change, Checkpoint, Commit, Session};
+use crate::objects::ObjectNames;
+use crate::storage::Storage;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use uuid::Uuid;
+
+/// Name of the event log inside the `.gitent` directory.
+pub const EVENT_LOG_FILE: &str = "events.log";
+
+/// One write to storage, as recorded in the event log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LogEvent {
+    SessionCreated {
+        session: Session,
+    },
+    SessionUpdated {
+        session: Session,
+    },
+    /// A change was stored. Content kept in the object store is logged by
+    /// name in `objects` and left out of `change`.
+    ChangeCreated {
+        change: Change,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        objects: Option<ObjectNames>,
+    },
+    /// The after-content of an uncommitted change was replaced, with
+    /// `content`, or the content stored as `object` in the object store
+    ContentReplaced {
+        change_id: Uuid,
+        #[serde(default)]
+        content: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        object: Option<String>,
+    },
+    CommitCreated {
+        commit: Commit,
+    },
+    /// The latest commit of a session was undone
+    HeadReset {
+        session_id: Uuid,
+    },
+    MilestoneSet {
+        commit_id: Uuid,
+        milestone: bool,
+    },
+    /// One session's history was folded into another's
+    SessionsMerged {
+        into: Uuid,
+        from: Uuid,
+    },
+    /// Every active session but the newest was ended, as they were left
+    ActiveSessionsRepaired {
+        ended: Vec<Session>,
+    },
+    /// Commits outside the retention policy were removed, with the changes
+    /// only they referred to
+    HistoryPruned {
+        commit_ids: Vec<Uuid>,
+    },
+    /// Changes nothing could reach anymore were collected
+    OrphansCollected {
+        change_ids: Vec<Uuid>,
+    },
+    CheckpointCreated {
+        checkpoint: Checkpoint,
+    },
+    SnapshotTaken {
+        snapshot_id: Uuid,
+        session_id: Uuid,
+        seq: i64,
+        created_at: DateTime<Utc>,
+    },
+}
+
+/// An open event log, appended to as storage is written.
+#[derive(Debug)]
+pub struct EventLog {
+    file: File,
+}
+
+impl EventLog {
+    /// Open the log at `path` for appending, creating it if need be.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Append `event` and wait until it's on disk.
+    pub fn append(&self, event: &LogEvent) -> Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        // One write per line, so a crash can only leave the last line torn
+        (&self.file).write_all(&line)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// What [`replay`] restored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub events: usize,
+    pub sessions: usize,
+    pub changes: usize,
+    pub commits: usize,
+    /// The last line was cut short, most likely by a crash while writing it,
+    /// and was skipped
+    pub torn_tail: bool,
+}
+
+/// Apply every event in the log at `path` to `storage`, in order.
+///
+/// `storage` should be empty and have no event log of its own. A torn last
+/// line is skipped; any other line that can't be read is an error.
+pub fn replay(path: &Path, storage: &Storage) -> Result<ReplayReport> {
+    let mut report = ReplayReport::default();
+    let mut lines = BufReader::new(File::open(path)?)
+        .lines()
+        .enumerate()
+        .peekable();
+
+    while let Some((index, line)) = lines.next() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = match serde_json::from_str::<LogEvent>(&line) {
+            Ok(event) => event,
+            Err(_) if lines.peek().is_none() => {
+                report.torn_tail = true;
+                break;
+            }
+            Err(e) => {
+                return Err(Error::EventLog(format!(
+                    "{} line {}: {}",
+                    path.display(),
+                    index + 1,
+                    e
+                )))
+            }
+        };
+
+        match event {
+            LogEvent::SessionCreated { session } => {
+                storage.create_session(&session)?;
+                report.sessions += 1;
+            }
+            LogEvent::SessionUpdated { session } => storage.update_session(&session)?,
+            LogEvent::ChangeCreated {
+                mut change,
+                objects,
+            } => {
+                if let Some(objects) = objects {
+                    change.content_before = storage.load_object_named(objects.before)?;
+                    change.content_after = storage.load_object_named(objects.after)?;
+                }
+                // Metadata was checked when the change was first stored
+                storage.insert_change(&change)?;
+                report.changes += 1;
+            }
+            LogEvent::ContentReplaced {
+                change_id,
+                content,
+                object,
+            } => {
+                let content = match object {
+                    Some(name) => storage.load_object(name.as_bytes())?,
+                    None => content,
+                };
+                storage.set_content_after(&change_id, content)?;
+            }
+            LogEvent::CommitCreated { commit } => {
+                storage.create_commit(&commit)?;
+                report.commits += 1;
+            }
+            LogEvent::HeadReset { session_id } => {
+                storage.reset_head(&session_id)?;
+                report.commits = report.commits.saturating_sub(1);
+            }
+            LogEvent::MilestoneSet {
+                commit_id,
+                milestone,
+            } => {
+                storage.set_milestone(&commit_id, milestone)?;
+            }
+            LogEvent::SessionsMerged { into, from } => {
+                storage.merge_sessions(&into, &from)?;
+                report.sessions = report.sessions.saturating_sub(1);
+            }
+            LogEvent::ActiveSessionsRepaired { ended } => {
+                for session in &ended {
+                    storage.update_session(session)?;
+                }
+            }
+            LogEvent::HistoryPruned { commit_ids } => {
+                let changes = storage.remove_commits(&commit_ids)?;
+                report.commits = report.commits.saturating_sub(commit_ids.len());
+                report.changes = report.changes.saturating_sub(changes);
+            }
+            LogEvent::OrphansCollected { change_ids } => {
+                storage.remove_orphans(&change_ids)?;
+                report.changes = report.changes.saturating_sub(change_ids.len());
+            }
+            LogEvent::CheckpointCreated { checkpoint } => {
+                storage.restore_checkpoint(&checkpoint)?;
+            }
+            LogEvent::SnapshotTaken {
+                snapshot_id,
+                session_id,
+                seq,
+                created_at,
+            } => {
+                storage.restore_snapshot(&snapshot_id, &session_id, seq, &created_at)?;
+            }
+        }
+        report.events += 1;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ChangeType, RetentionPolicy};
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_rebuild_from_event_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("gitent.db");
+        let log_path = temp_dir.path().join(EVENT_LOG_FILE);
+
+        let storage = Storage::new(&db_path)
+            .unwrap()
+            .with_event_log(EventLog::open(&log_path).unwrap());
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let create = Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id)
+            .with_content_after(b"one\n".to_vec());
+        storage.create_change(&create).unwrap();
+        let first = Commit::new(
+            "Add a".to_string(),
+            "agent".to_string(),
+            vec![create.id],
+            session.id,
+        );
+        storage.create_commit(&first).unwrap();
+
+        let modify = Change::new(ChangeType::Modify, PathBuf::from("a.txt"), session.id)
+            .with_content_before(b"one\n".to_vec())
+            .with_content_after(b"draft\n".to_vec());
+        storage.create_change(&modify).unwrap();
+        storage
+            .set_content_after(&modify.id, b"two\n".to_vec())
+            .unwrap();
+        let undone = Commit::new(
+            "Edit a".to_string(),
+            "agent".to_string(),
+            vec![modify.id],
+            session.id,
+        )
+        .with_parent(first.id);
+        storage.create_commit(&undone).unwrap();
+        storage.reset_head(&session.id).unwrap();
+        let second = Commit::new(
+            "Edit a again".to_string(),
+            "agent".to_string(),
+            vec![modify.id],
+            session.id,
+        )
+        .with_parent(first.id);
+        storage.create_commit(&second).unwrap();
+
+        let history = |storage: &Storage| {
+            let commits: Vec<_> = storage
+                .get_commits_for_session(&session.id)
+                .unwrap()
+                .into_iter()
+                .map(|info| (info.commit.id, info.commit.parent, info.commit.changes))
+                .collect();
+            let changes: Vec<_> = storage
+                .query_changes(&session.id)
+                .fetch()
+                .unwrap()
+                .into_iter()
+                .map(|c| (c.id, c.seq, c.content_before, c.content_after))
+                .collect();
+            (commits, changes)
+        };
+        let before = history(&storage);
+        drop(storage);
+        std::fs::remove_file(&db_path).unwrap();
+
+        let rebuilt = Storage::new(&db_path).unwrap();
+        let report = replay(&log_path, &rebuilt).unwrap();
+        assert_eq!(
+            report,
+            ReplayReport {
+                events: 8,
+                sessions: 1,
+                changes: 2,
+                commits: 2,
+                torn_tail: false,
+            }
+        );
+        assert_eq!(rebuilt.get_active_session().unwrap().id, session.id);
+        assert_eq!(history(&rebuilt), before);
+    }
+
+    #[test]
+    fn test_rebuild_replays_maintenance() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("gitent.db");
+        let log_path = temp_dir.path().join(EVENT_LOG_FILE);
+
+        let storage = Storage::new(&db_path)
+            .unwrap()
+            .with_event_log(EventLog::open(&log_path).unwrap());
+        let stale = Session::new(PathBuf::from("/stale"));
+        storage.create_session(&stale).unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        storage.repair_active_sessions().unwrap();
+
+        let mut parent = None;
+        for content in ["one\n", "two\n", "three\n"] {
+            let change = Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id)
+                .with_content_after(content.as_bytes().to_vec());
+            storage.create_change(&change).unwrap();
+            let mut commit = Commit::new(
+                content.to_string(),
+                "agent".to_string(),
+                vec![change.id],
+                session.id,
+            );
+            if let Some(parent) = parent {
+                commit = commit.with_parent(parent);
+            }
+            storage.create_commit(&commit).unwrap();
+            parent = Some(commit.id);
+        }
+        let policy = RetentionPolicy {
+            keep_commits: Some(1),
+            keep_days: None,
+        };
+        storage.prune_history(&session.id, &policy).unwrap();
+        let checkpoint = storage.create_checkpoint(&session.id, "pruned").unwrap();
+        let snapshot = storage.snapshot_uncommitted(&session.id).unwrap();
+
+        let commits = |storage: &Storage| storage.get_commits_for_session(&session.id).unwrap();
+        let before = commits(&storage);
+        assert_eq!(before.len(), 1);
+        drop(storage);
+        std::fs::remove_file(&db_path).unwrap();
+
+        let rebuilt = Storage::new(&db_path).unwrap();
+        let report = replay(&log_path, &rebuilt).unwrap();
+        assert_eq!((report.commits, report.changes), (1, 1));
+        assert!(!rebuilt.get_session(&stale.id).unwrap().active);
+        assert_eq!(
+            commits(&rebuilt)
+                .iter()
+                .map(|info| info.commit.id)
+                .collect::<Vec<_>>(),
+            before.iter().map(|info| info.commit.id).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            rebuilt.get_checkpoint(&session.id, "pruned").unwrap(),
+            checkpoint
+        );
+        assert!(rebuilt.diff_since_snapshot(&snapshot).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_external_content_logged_by_name() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("gitent.db");
+        let log_path = temp_dir.path().join(EVENT_LOG_FILE);
+
+        let storage = Storage::new(&db_path)
+            .unwrap()
+            .with_external_store(16)
+            .with_event_log(EventLog::open(&log_path).unwrap());
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let big = b"far more than sixteen bytes\n".repeat(64);
+        let create = Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id)
+            .with_content_after(big.clone());
+        storage.create_change(&create).unwrap();
+        let edited = [big.as_slice(), b"and then some\n"].concat();
+        storage
+            .set_content_after(&create.id, edited.clone())
+            .unwrap();
+
+        // Neither version of the content is repeated in the log
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        assert!(log.len() < big.len());
+        drop(storage);
+        std::fs::remove_file(&db_path).unwrap();
+
+        let rebuilt = Storage::new(&db_path).unwrap();
+        replay(&log_path, &rebuilt).unwrap();
+        let change = rebuilt.get_change(&create.id).unwrap();
+        assert_eq!(change.content_after, Some(edited));
+    }
+
+    #[test]
+    fn test_torn_tail_is_skipped() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join(EVENT_LOG_FILE);
+        let session = Session::new(PathBuf::from("/test"));
+        EventLog::open(&log_path)
+            .unwrap()
+            .append(&LogEvent::SessionCreated {
+                session: session.clone(),
+            })
+            .unwrap();
+        let mut file = OpenOptions::new().append(true).open(&log_path).unwrap();
+        file.write_all(br#"{"event":"change_created","chan"#)
+            .unwrap();
+
+        let storage = Storage::in_memory().unwrap();
+        let report = replay(&log_path, &storage).unwrap();
+        assert_eq!(report.sessions, 1);
+        assert!(report.torn_tail);
+        assert!(storage.get_session(&session.id).is_ok());
+    }
+}
@@ -0,0 +1,679 @@
+//! Seeding a session from an existing git repository's history, and writing
+//! a session's history out as git commits credited to git authors.
+
+use crate::config::AuthorMap;
+use crate::error::{Error, Result};
+use crate::models::{Change, ChangeType, Commit, Progress, Session};
+use crate::storage::Storage;
+use chrono::{DateTime, Utc};
+use git2::{
+    Delta, DiffFindOptions, FileMode, Index, IndexEntry, IndexTime, Oid, Repository, Signature,
+    Sort, Time,
+};
+use std::collections::HashMap;
+use std::path::{Component, Path};
+use uuid::Uuid;
+
+/// Commit metadata key holding the git commit an imported commit came from.
+pub const GIT_SHA_KEY: &str = "git_sha";
+
+/// What an import brought in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub commits: usize,
+    pub changes: usize,
+    /// Commits left alone because an earlier, interrupted import already
+    /// brought them in
+    pub skipped: usize,
+    /// The repository is a shallow clone, so history before its oldest
+    /// commit wasn't available; that commit is imported as if it were the first
+    pub shallow: bool,
+}
+
+/// What an export wrote.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportReport {
+    pub commits: usize,
+    /// The git commit the branch was pointed at, if there was anything to
+    /// export
+    pub head: Option<Oid>,
+}
+
+/// Record the history of the git repository at `repo_path` in `session`, one
+/// gitent commit per git commit along `HEAD`, oldest first.
+///
+/// Each commit's diff against its first parent becomes its changes, with
+/// renames detected. Only the newest `max_depth` commits are imported when
+/// it is set. Files outside the session's content root are skipped, and a
+/// rename into or out of it is recorded as a create or delete.
+///
+/// Each commit is imported in one transaction, and commits the session
+/// already has are skipped, so an interrupted import can be run again to
+/// finish it. `progress` is called after each commit.
+pub fn import_history(
+    storage: &Storage,
+    session: &Session,
+    repo_path: &Path,
+    max_depth: Option<usize>,
+    mut progress: impl FnMut(Progress),
+) -> Result<ImportReport> {
+    let repo = Repository::open(repo_path)?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| Error::InvalidOperation("Cannot import a bare repository".to_string()))?
+        .to_path_buf();
+    let mut report = ImportReport {
+        shallow: repo.is_shallow(),
+        ..Default::default()
+    };
+
+    // Nothing has been committed yet
+    if repo.is_empty()? {
+        return Ok(report);
+    }
+
+    let mut walk = repo.revwalk()?;
+    walk.push_head()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    let mut oids = walk
+        .take(max_depth.unwrap_or(usize::MAX))
+        .collect::<std::result::Result<Vec<Oid>, _>>()?;
+    oids.reverse();
+
+    let mut imported: HashMap<Oid, Uuid> = storage
+        .commits_by_metadata(&session.id, GIT_SHA_KEY)?
+        .into_iter()
+        .filter_map(|(sha, id)| Some((Oid::from_str(&sha).ok()?, id)))
+        .collect();
+    let total = oids.len();
+    for (done, oid) in oids.into_iter().enumerate() {
+        if imported.contains_key(&oid) {
+            report.skipped += 1;
+        } else {
+            let (commit_id, changes) = storage.batch(|storage| {
+                import_commit(storage, session, &repo, &workdir, oid, &imported)
+            })?;
+            report.commits += 1;
+            report.changes += changes;
+            imported.insert(oid, commit_id);
+        }
+        progress(Progress {
+            done: done + 1,
+            total,
+        });
+    }
+
+    Ok(report)
+}
+
+/// Record one git commit, returning the new commit's id and how many
+/// changes it has.
+fn import_commit(
+    storage: &Storage,
+    session: &Session,
+    repo: &Repository,
+    workdir: &Path,
+    oid: Oid,
+    imported: &HashMap<Oid, Uuid>,
+) -> Result<(Uuid, usize)> {
+    let git_commit = repo.find_commit(oid)?;
+    // Missing past the depth of a shallow clone
+    let parent = git_commit.parent(0).ok();
+    let old_tree = parent.as_ref().map(|p| p.tree()).transpose()?;
+    let mut diff = repo.diff_tree_to_tree(old_tree.as_ref(), Some(&git_commit.tree()?), None)?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+
+    let timestamp =
+        DateTime::from_timestamp(git_commit.time().seconds(), 0).unwrap_or_else(Utc::now);
+    let author = git_commit.author().name().unwrap_or("git").to_string();
+
+    let mut change_ids = Vec::new();
+    for delta in diff.deltas() {
+        let mut change_type = match delta.status() {
+            Delta::Added | Delta::Copied => ChangeType::Create,
+            Delta::Deleted => ChangeType::Delete,
+            Delta::Modified | Delta::Typechange => ChangeType::Modify,
+            Delta::Renamed => ChangeType::Rename,
+            _ => continue,
+        };
+        // Submodules have no content of their own
+        if delta.new_file().mode() == FileMode::Commit
+            || delta.old_file().mode() == FileMode::Commit
+        {
+            continue;
+        }
+        let stored_path = |file: git2::DiffFile| {
+            file.path()
+                .and_then(|path| session.relative_path(&workdir.join(path)).ok())
+        };
+        let new_path = stored_path(delta.new_file());
+        let old_path = stored_path(delta.old_file());
+        // As with the watcher, a move into or out of what's tracked is a
+        // create or delete
+        let (path, old_path) = match (change_type, new_path, old_path) {
+            (ChangeType::Rename, Some(path), Some(old_path)) => (path, Some(old_path)),
+            (ChangeType::Rename, Some(path), None) => {
+                change_type = ChangeType::Create;
+                (path, None)
+            }
+            (ChangeType::Rename, None, Some(old_path)) => {
+                change_type = ChangeType::Delete;
+                (old_path, None)
+            }
+            (_, Some(path), _) => (path, None),
+            _ => continue,
+        };
+
+        let mut change = Change::new(change_type, path, session.id).with_agent_id(author.clone());
+        change.timestamp = timestamp;
+        if let Some(old_path) = old_path {
+            change = change.with_old_path(old_path);
+        }
+        if change_type != ChangeType::Create {
+            let blob = repo.find_blob(delta.old_file().id())?;
+            change =
+                change.with_content_before_hashed(blob.content().to_vec(), session.hash_algorithm);
+        }
+        if change_type != ChangeType::Delete {
+            let blob = repo.find_blob(delta.new_file().id())?;
+            change =
+                change.with_content_after_hashed(blob.content().to_vec(), session.hash_algorithm);
+        }
+
+        storage.create_change(&change)?;
+        change_ids.push(change.id);
+    }
+
+    let message = git_commit.message().unwrap_or_default().trim().to_string();
+    let mut commit = Commit::new(message, author, change_ids, session.id)
+        .with_metadata(GIT_SHA_KEY.to_string(), oid.to_string());
+    commit.timestamp = timestamp;
+    if let Some(parent_id) = parent.and_then(|p| imported.get(&p.id()).copied()) {
+        commit = commit.with_parent(parent_id);
+    }
+    storage.create_commit(&commit)?;
+
+    Ok((commit.id, commit.changes.len()))
+}
+
+/// Write the commits of `session` into the git repository at `repo_path`,
+/// one git commit each, oldest first, and point `branch` at the newest.
+///
+/// Each commit's tree is its parent's with the commit's changes applied, and
+/// it's credited to its agent as `authors` maps it. Files whose content
+/// wasn't recorded or was redacted are left out, as in archives. The
+/// repository is created if it doesn't exist and `branch` is overwritten if
+/// it does; nothing else in it, the work tree included, is touched.
+/// `progress` is called after each commit.
+pub fn export_history(
+    storage: &Storage,
+    session: &Session,
+    repo_path: &Path,
+    branch: &str,
+    authors: &AuthorMap,
+    mut progress: impl FnMut(Progress),
+) -> Result<ExportReport> {
+    let repo = match Repository::open(repo_path) {
+        Ok(repo) => repo,
+        Err(_) => Repository::init(repo_path)?,
+    };
+    let mut commits: Vec<Commit> = storage
+        .get_commits_for_session(&session.id)?
+        .into_iter()
+        .map(|info| info.commit)
+        .collect();
+    commits.reverse();
+
+    let mut report = ExportReport::default();
+    let mut exported: HashMap<Uuid, Oid> = HashMap::new();
+    let total = commits.len();
+    for (done, commit) in commits.iter().enumerate() {
+        let parent = commit
+            .parent
+            .and_then(|id| exported.get(&id))
+            .map(|oid| repo.find_commit(*oid))
+            .transpose()?;
+        let mut index = Index::new()?;
+        if let Some(parent) = &parent {
+            index.read_tree(&parent.tree()?)?;
+        }
+        // Fetched one at a time, as a large commit's content may not fit in
+        // memory all at once
+        for id in &commit.changes {
+            apply_change(&repo, &mut index, &storage.get_change(id)?)?;
+        }
+        let tree = repo.find_tree(index.write_tree_to(&repo)?)?;
+
+        let signature = signature_for(commit, authors)?;
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        let oid = repo.commit(
+            None,
+            &signature,
+            &signature,
+            &commit.message,
+            &tree,
+            &parents,
+        )?;
+        exported.insert(commit.id, oid);
+        report.commits += 1;
+        report.head = Some(oid);
+        progress(Progress {
+            done: done + 1,
+            total,
+        });
+    }
+
+    if let Some(head) = report.head {
+        repo.reference(
+            &format!("refs/heads/{}", branch),
+            head,
+            true,
+            "gitent export",
+        )?;
+    }
+    Ok(report)
+}
+
+/// Update `index` for one change: its old path, if renamed, and its path
+/// are cleared, and its content is written back under its path.
+fn apply_change(repo: &Repository, index: &mut Index, change: &Change) -> Result<()> {
+    let Some(path) = index_path(&change.path) else {
+        return Ok(());
+    };
+    if change.change_type == ChangeType::Rename {
+        if let Some(old_path) = change.old_path.as_deref().and_then(index_path) {
+            remove_entry(index, &old_path)?;
+        }
+    }
+    remove_entry(index, &path)?;
+
+    if change.change_type == ChangeType::Delete || change.is_redacted() {
+        return Ok(());
+    }
+    let Some(content) = change.content_after.as_deref() else {
+        return Ok(());
+    };
+    index.add(&IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode: 0o100644,
+        uid: 0,
+        gid: 0,
+        file_size: content.len() as u32,
+        id: repo.blob(content)?,
+        flags: 0,
+        flags_extended: 0,
+        path: path.into_bytes(),
+    })?;
+    Ok(())
+}
+
+fn remove_entry(index: &mut Index, path: &str) -> Result<()> {
+    if index.get_path(Path::new(path), 0).is_some() {
+        index.remove(Path::new(path), 0)?;
+    }
+    Ok(())
+}
+
+/// A stored path as git writes it, or `None` for one that reaches outside
+/// the content root.
+fn index_path(path: &Path) -> Option<String> {
+    let parts = path
+        .components()
+        .map(|component| match component {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+/// The git signature a gitent commit is written with: its agent's mapped
+/// author, at the time it was committed.
+pub fn signature_for(commit: &Commit, authors: &AuthorMap) -> Result<Signature<'static>> {
+    let author = authors.author_for(&commit.agent_id);
+    let time = Time::new(commit.timestamp.timestamp(), 0);
+    Ok(Signature::new(&author.name, &author.email, &time)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Author;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    /// Write `files` into the work tree and commit them all.
+    fn git_commit(repo: &Repository, files: &[(&str, Option<&str>)], message: &str) -> Oid {
+        let workdir = repo.workdir().unwrap();
+        let mut index = repo.index().unwrap();
+        for (path, content) in files {
+            match content {
+                Some(content) => {
+                    std::fs::write(workdir.join(path), content).unwrap();
+                    index.add_path(Path::new(path)).unwrap();
+                }
+                None => {
+                    std::fs::remove_file(workdir.join(path)).unwrap();
+                    index.remove_path(Path::new(path)).unwrap();
+                }
+            }
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("Ada", "ada@example.com").unwrap();
+        let parents: Vec<_> = repo
+            .head()
+            .ok()
+            .map(|head| head.peel_to_commit().unwrap())
+            .into_iter()
+            .collect();
+        let parents: Vec<_> = parents.iter().collect();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    fn setup() -> (TempDir, Repository, Storage, Session) {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = Repository::init(temp_dir.path()).unwrap();
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        storage.create_session(&session).unwrap();
+        (temp_dir, repo, storage, session)
+    }
+
+    #[test]
+    fn test_import_history() {
+        let (temp_dir, repo, storage, session) = setup();
+        let first = git_commit(
+            &repo,
+            &[("README.md", Some("hello\n")), ("old.txt", Some("bye\n"))],
+            "Initial commit\n",
+        );
+        let second = git_commit(
+            &repo,
+            &[("README.md", Some("hello world\n")), ("old.txt", None)],
+            "Update readme\n\nAnd drop old.txt.\n",
+        );
+
+        let report = import_history(&storage, &session, temp_dir.path(), None, |_| {}).unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                commits: 2,
+                changes: 4,
+                skipped: 0,
+                shallow: false,
+            }
+        );
+
+        let commits = storage.get_commits_for_session(&session.id).unwrap();
+        let [newest, oldest] = &commits[..] else {
+            panic!("expected two commits, got {}", commits.len());
+        };
+        assert_eq!(oldest.commit.message, "Initial commit");
+        assert_eq!(oldest.commit.agent_id, "Ada");
+        assert_eq!(oldest.commit.metadata[GIT_SHA_KEY], first.to_string());
+        assert_eq!(newest.commit.message, "Update readme\n\nAnd drop old.txt.");
+        assert_eq!(newest.commit.metadata[GIT_SHA_KEY], second.to_string());
+        assert_eq!(newest.commit.parent, Some(oldest.commit.id));
+
+        let mut changes: Vec<_> = newest
+            .commit
+            .changes
+            .iter()
+            .map(|id| storage.get_change(id).unwrap())
+            .collect();
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(changes[0].path, PathBuf::from("README.md"));
+        assert_eq!(changes[0].change_type, ChangeType::Modify);
+        assert_eq!(changes[0].content_before.as_deref(), Some(&b"hello\n"[..]));
+        assert_eq!(
+            changes[0].content_after.as_deref(),
+            Some(&b"hello world\n"[..])
+        );
+        assert_eq!(changes[1].path, PathBuf::from("old.txt"));
+        assert_eq!(changes[1].change_type, ChangeType::Delete);
+    }
+
+    #[test]
+    fn test_import_depth() {
+        let (temp_dir, repo, storage, session) = setup();
+        git_commit(&repo, &[("a.txt", Some("1"))], "One");
+        git_commit(&repo, &[("a.txt", Some("2"))], "Two");
+        git_commit(&repo, &[("a.txt", Some("3"))], "Three");
+
+        let report = import_history(&storage, &session, temp_dir.path(), Some(2), |_| {}).unwrap();
+        assert_eq!(report.commits, 2);
+
+        let commits = storage.get_commits_for_session(&session.id).unwrap();
+        let messages: Vec<_> = commits.iter().map(|c| c.commit.message.as_str()).collect();
+        assert_eq!(messages, vec!["Three", "Two"]);
+        // The oldest imported commit has no gitent parent to point at
+        assert_eq!(commits[1].commit.parent, None);
+    }
+
+    #[test]
+    fn test_import_resumes() {
+        let (temp_dir, repo, storage, session) = setup();
+        git_commit(&repo, &[("a.txt", Some("1"))], "One");
+        git_commit(&repo, &[("a.txt", Some("2"))], "Two");
+        import_history(&storage, &session, temp_dir.path(), None, |_| {}).unwrap();
+        git_commit(&repo, &[("a.txt", Some("3"))], "Three");
+
+        let mut seen = Vec::new();
+        let report = import_history(&storage, &session, temp_dir.path(), None, |progress| {
+            seen.push(progress)
+        })
+        .unwrap();
+        assert_eq!((report.commits, report.skipped), (1, 2));
+        assert_eq!(
+            seen,
+            (1..=3)
+                .map(|done| Progress { done, total: 3 })
+                .collect::<Vec<_>>()
+        );
+
+        let commits = storage.get_commits_for_session(&session.id).unwrap();
+        let messages: Vec<_> = commits.iter().map(|c| c.commit.message.as_str()).collect();
+        assert_eq!(messages, vec!["Three", "Two", "One"]);
+        assert_eq!(commits[0].commit.parent, Some(commits[1].commit.id));
+    }
+
+    #[test]
+    fn test_import_renames_across_content_root() {
+        let (temp_dir, repo, storage, _) = setup();
+        let session = Session::new(temp_dir.path().join("src"));
+        storage.create_session(&session).unwrap();
+        std::fs::create_dir(temp_dir.path().join("src")).unwrap();
+        std::fs::create_dir(temp_dir.path().join("docs")).unwrap();
+        let main = "fn main() {}\n".repeat(10);
+        let lib = "pub fn lib() {}\n".repeat(10);
+        git_commit(
+            &repo,
+            &[("docs/main.rs", Some(&main)), ("src/lib.rs", Some(&lib))],
+            "Add files",
+        );
+        git_commit(
+            &repo,
+            &[
+                ("docs/main.rs", None),
+                ("src/main.rs", Some(&main)),
+                ("src/lib.rs", None),
+                ("docs/lib.rs", Some(&lib)),
+            ],
+            "Move files",
+        );
+
+        import_history(&storage, &session, temp_dir.path(), None, |_| {}).unwrap();
+
+        let commits = storage.get_commits_for_session(&session.id).unwrap();
+        let mut changes: Vec<_> = commits[0]
+            .commit
+            .changes
+            .iter()
+            .map(|id| storage.get_change(id).unwrap())
+            .collect();
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        let [moved_out, moved_in] = &changes[..] else {
+            panic!("expected two changes, got {}", changes.len());
+        };
+        assert_eq!(moved_in.path, PathBuf::from("main.rs"));
+        assert_eq!(moved_in.change_type, ChangeType::Create);
+        assert_eq!(moved_in.old_path, None);
+        assert_eq!(moved_in.content_before, None);
+        assert_eq!(moved_in.content_after.as_deref(), Some(main.as_bytes()));
+        assert_eq!(moved_out.path, PathBuf::from("lib.rs"));
+        assert_eq!(moved_out.change_type, ChangeType::Delete);
+        assert_eq!(moved_out.content_before.as_deref(), Some(lib.as_bytes()));
+        assert_eq!(moved_out.content_after, None);
+    }
+
+    #[test]
+    fn test_import_empty_repository() {
+        let (temp_dir, _repo, storage, session) = setup();
+
+        let report = import_history(&storage, &session, temp_dir.path(), None, |_| {}).unwrap();
+        assert_eq!(report, ImportReport::default());
+    }
+
+    #[test]
+    fn test_export_history() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        let authors = AuthorMap(HashMap::from([(
+            "claude-1".to_string(),
+            Author {
+                name: "Ada Lovelace".to_string(),
+                email: "ada@example.com".to_string(),
+            },
+        )]));
+
+        let commit = |agent: &str, changes: Vec<Change>, parent: Option<Uuid>| {
+            for change in &changes {
+                storage.create_change(change).unwrap();
+            }
+            let ids = changes.iter().map(|c| c.id).collect();
+            let mut commit = Commit::new(format!("By {}", agent), agent.into(), ids, session.id);
+            if let Some(parent) = parent {
+                commit = commit.with_parent(parent);
+            }
+            storage.create_commit(&commit).unwrap();
+            commit.id
+        };
+        let change = |change_type, path: &str, content: Option<&str>| {
+            let change = Change::new(change_type, PathBuf::from(path), session.id);
+            match content {
+                Some(content) => change.with_content_after(content.as_bytes().to_vec()),
+                None => change,
+            }
+        };
+        let first = commit(
+            "claude-1",
+            vec![
+                change(ChangeType::Create, "a.txt", Some("a")),
+                change(ChangeType::Create, "src/b.rs", Some("b")),
+                change(ChangeType::Create, "gone.txt", Some("gone")),
+            ],
+            None,
+        );
+        commit(
+            "other",
+            vec![
+                change(ChangeType::Modify, "a.txt", Some("a2")),
+                change(ChangeType::Rename, "src/c.rs", Some("b"))
+                    .with_old_path(PathBuf::from("src/b.rs")),
+                change(ChangeType::Delete, "gone.txt", None),
+                change(ChangeType::Create, "secret.env", Some("key")).redact(),
+            ],
+            Some(first),
+        );
+
+        let mut seen = Vec::new();
+        let report = export_history(
+            &storage,
+            &session,
+            temp_dir.path(),
+            "gitent",
+            &authors,
+            |progress| seen.push(progress.done),
+        )
+        .unwrap();
+        assert_eq!(report.commits, 2);
+        assert_eq!(seen, vec![1, 2]);
+
+        let repo = Repository::open(temp_dir.path()).unwrap();
+        let head = repo
+            .find_reference("refs/heads/gitent")
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(Some(head.id()), report.head);
+        assert_eq!(head.message(), Some("By other"));
+        assert_eq!(head.author().email(), Some("other@gitent.local"));
+        let parent = head.parent(0).unwrap();
+        assert_eq!(parent.author().name(), Some("Ada Lovelace"));
+        assert_eq!(parent.author().email(), Some("ada@example.com"));
+        assert_eq!(parent.parent_count(), 0);
+
+        let files = |commit: &git2::Commit| {
+            let tree = commit.tree().unwrap();
+            let mut files = Vec::new();
+            tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+                if let Some(blob) = entry.to_object(&repo).ok().and_then(|o| o.into_blob().ok()) {
+                    let content = String::from_utf8(blob.content().to_vec()).unwrap();
+                    files.push((format!("{}{}", dir, entry.name().unwrap()), content));
+                }
+                git2::TreeWalkResult::Ok
+            })
+            .unwrap();
+            files
+        };
+        let file = |path: &str, content: &str| (path.to_string(), content.to_string());
+        assert_eq!(
+            files(&parent),
+            vec![
+                file("a.txt", "a"),
+                file("gone.txt", "gone"),
+                file("src/b.rs", "b")
+            ]
+        );
+        assert_eq!(
+            files(&head),
+            vec![file("a.txt", "a2"), file("src/c.rs", "b")]
+        );
+    }
+
+    #[test]
+    fn test_signature_uses_mapped_author() {
+        let session_id = Uuid::new_v4();
+        let authors = AuthorMap(HashMap::from([(
+            "claude-1".to_string(),
+            Author {
+                name: "Ada Lovelace".to_string(),
+                email: "ada@example.com".to_string(),
+            },
+        )]));
+
+        let commit = Commit::new("Add a".into(), "claude-1".into(), vec![], session_id);
+        let signature = signature_for(&commit, &authors).unwrap();
+        assert_eq!(signature.name(), Some("Ada Lovelace"));
+        assert_eq!(signature.email(), Some("ada@example.com"));
+        assert_eq!(signature.when().seconds(), commit.timestamp.timestamp());
+
+        let commit = Commit::new("Add b".into(), "other".into(), vec![], session_id);
+        let signature = signature_for(&commit, &authors).unwrap();
+        assert_eq!(signature.email(), Some("other@gitent.local"));
+    }
+}
@@ -0,0 +1,94 @@
+//! Content hashing, with each hash saying which algorithm made it.
+
+use serde::{Deserialize, Serialize};
+
+/// How a session hashes file content.
+///
+/// SHA-256 hashes are plain hex, as they always have been. Other algorithms
+/// prefix the hex with their name and a colon, such as `blake3:…`, so a
+/// stored hash can be checked without knowing which session it came from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    /// Much faster on large files
+    Blake3,
+    /// The algorithm behind git's object ids
+    Sha1,
+}
+
+impl HashAlgorithm {
+    pub fn as_str(&self) -> &str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha1 => "sha1",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            "sha1" => Some(HashAlgorithm::Sha1),
+            _ => None,
+        }
+    }
+
+    /// The algorithm that made `hash`.
+    pub fn of(hash: &str) -> Self {
+        hash.split_once(':')
+            .and_then(|(name, _)| Self::parse(name))
+            .unwrap_or_default()
+    }
+
+    /// Hash `content`, tagged with this algorithm.
+    pub fn hash(&self, content: &[u8]) -> String {
+        use sha2::Digest;
+        match self {
+            HashAlgorithm::Sha256 => hex::encode(sha2::Sha256::digest(content)),
+            HashAlgorithm::Blake3 => format!("blake3:{}", blake3::hash(content).to_hex()),
+            HashAlgorithm::Sha1 => format!("sha1:{}", hex::encode(sha1::Sha1::digest(content))),
+        }
+    }
+}
+
+/// Whether `content` hashes to `hash` under the algorithm that made it.
+pub fn matches(hash: &str, content: &[u8]) -> bool {
+    HashAlgorithm::of(hash).hash(content) == hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_algorithms_tag_their_hashes() {
+        let content = b"fn main() {}";
+
+        let sha256 = HashAlgorithm::Sha256.hash(content);
+        let blake3 = HashAlgorithm::Blake3.hash(content);
+        let sha1 = HashAlgorithm::Sha1.hash(content);
+        assert_eq!(sha256.len(), 64);
+        assert!(blake3.starts_with("blake3:"));
+        assert_eq!(
+            HashAlgorithm::Sha1.hash(b""),
+            "sha1:da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        assert_ne!(sha256, blake3);
+        assert_ne!(blake3, sha1);
+
+        for (algorithm, hash) in [
+            (HashAlgorithm::Sha256, &sha256),
+            (HashAlgorithm::Blake3, &blake3),
+            (HashAlgorithm::Sha1, &sha1),
+        ] {
+            // Hashing again gives the same result
+            assert_eq!(algorithm.hash(content), *hash);
+            assert_eq!(HashAlgorithm::of(hash), algorithm);
+            assert!(matches(hash, content));
+            assert!(!matches(hash, b"fn main() { }"));
+        }
+    }
+}
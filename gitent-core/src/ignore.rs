@@ -0,0 +1,186 @@
+//! Matching paths against a session's ignore and include patterns.
+//!
+//! Patterns follow a small subset of `.gitignore` syntax:
+//!
+//! - A plain name such as `target` matches any path containing it, which is
+//!   how patterns have always behaved.
+//! - `*`, `?` and `[...]` are wildcards that don't cross `/`; `**` matches any
+//!   number of directories.
+//! - A leading `/` anchors the pattern to the session root; otherwise it can
+//!   match at any depth.
+//! - A trailing `/` is allowed for directories. A pattern matching a
+//!   directory also matches everything inside it.
+//!
+//! Include patterns use the same syntax. When there are any, files that match
+//! none of them are ignored too.
+
+use crate::error::{Error, Result};
+use crate::models::Session;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct IgnoreMatcher {
+    ignore: Patterns,
+    include: Option<Patterns>,
+}
+
+impl IgnoreMatcher {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        Ok(Self {
+            ignore: Patterns::new(patterns, "ignore")?,
+            include: None,
+        })
+    }
+
+    /// The matcher for a session's ignore and include patterns.
+    pub fn for_session(session: &Session) -> Result<Self> {
+        Self::new(&session.ignore_patterns)?.with_includes(&session.include_patterns)
+    }
+
+    /// Ignore every file that matches none of `patterns`, unless there are none.
+    pub fn with_includes(mut self, patterns: &[String]) -> Result<Self> {
+        self.include = if patterns.is_empty() {
+            None
+        } else {
+            Some(Patterns::new(patterns, "include")?)
+        };
+        Ok(self)
+    }
+
+    /// Whether the file at `relative_path`, relative to the session root, is
+    /// ignored.
+    pub fn is_ignored(&self, relative_path: &Path) -> bool {
+        self.is_ignored_dir(relative_path) || !self.is_included(relative_path)
+    }
+
+    /// Whether the file at `relative_path` matches an include pattern, or
+    /// there are none.
+    pub fn is_included(&self, relative_path: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(relative_path))
+    }
+
+    /// Whether the directory at `relative_path` is ignored. Include patterns
+    /// name files, so only the ignore patterns apply.
+    pub fn is_ignored_dir(&self, relative_path: &Path) -> bool {
+        self.ignore.is_match(relative_path)
+    }
+}
+
+/// Compiled patterns of either kind.
+#[derive(Debug, Clone)]
+struct Patterns {
+    substrings: Vec<String>,
+    globs: GlobSet,
+}
+
+impl Patterns {
+    /// Compile `patterns`, naming them as `kind` patterns in errors.
+    fn new(patterns: &[String], kind: &str) -> Result<Self> {
+        let mut substrings = Vec::new();
+        let mut globs = GlobSetBuilder::new();
+
+        for pattern in patterns {
+            if !pattern.contains(['/', '*', '?', '[']) {
+                substrings.push(pattern.clone());
+                continue;
+            }
+
+            let trimmed = pattern.trim_end_matches('/');
+            let glob = match trimmed.strip_prefix('/') {
+                Some(anchored) => anchored.to_string(),
+                None => format!("**/{}", trimmed),
+            };
+
+            for glob in [glob.clone(), format!("{}/**", glob)] {
+                let compiled = GlobBuilder::new(&glob)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|e| {
+                        Error::Config(format!("invalid {} pattern {:?}: {}", kind, pattern, e))
+                    })?;
+                globs.add(compiled);
+            }
+        }
+
+        let globs = globs
+            .build()
+            .map_err(|e| Error::Config(format!("invalid {} patterns: {}", kind, e)))?;
+
+        Ok(Self { substrings, globs })
+    }
+
+    fn is_match(&self, relative_path: &Path) -> bool {
+        let path_str = relative_path.to_string_lossy();
+        self.substrings
+            .iter()
+            .any(|pattern| path_str.contains(pattern.as_str()))
+            || self.globs.is_match(relative_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(patterns: &[&str]) -> IgnoreMatcher {
+        IgnoreMatcher::new(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn test_anchored_and_directory_patterns() {
+        let root_only = matcher(&["/build/"]);
+        assert!(root_only.is_ignored(Path::new("build")));
+        assert!(root_only.is_ignored(Path::new("build/out.o")));
+        assert!(!root_only.is_ignored(Path::new("src/build/helpers.rs")));
+
+        let any_depth = matcher(&["build/"]);
+        assert!(any_depth.is_ignored(Path::new("build/out.o")));
+        assert!(any_depth.is_ignored(Path::new("src/build/helpers.rs")));
+        assert!(!any_depth.is_ignored(Path::new("src/builder.rs")));
+    }
+
+    #[test]
+    fn test_wildcard_patterns() {
+        let m = matcher(&["*.log", "/docs/**/*.tmp"]);
+        assert!(m.is_ignored(Path::new("server.log")));
+        assert!(m.is_ignored(Path::new("logs/2024/server.log")));
+        assert!(!m.is_ignored(Path::new("server.log.rs")));
+        assert!(m.is_ignored(Path::new("docs/a/b/draft.tmp")));
+        assert!(!m.is_ignored(Path::new("src/draft.tmp")));
+    }
+
+    #[test]
+    fn test_plain_patterns_match_substrings() {
+        let m = matcher(&["target", ".git"]);
+        assert!(m.is_ignored(Path::new("target/debug/gitent")));
+        assert!(m.is_ignored(Path::new("crates/x/target/foo")));
+        assert!(m.is_ignored(Path::new(".gitignore")));
+        assert!(!m.is_ignored(Path::new("src/main.rs")));
+
+        assert!(IgnoreMatcher::new(&["[".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_include_patterns() {
+        let m = matcher(&["target"])
+            .with_includes(&["/src/**/*.rs".to_string()])
+            .unwrap();
+        assert!(!m.is_ignored(Path::new("src/main.rs")));
+        assert!(!m.is_ignored(Path::new("src/commands/log.rs")));
+        assert!(m.is_ignored(Path::new("README.md")));
+        assert!(m.is_ignored(Path::new("src/notes.txt")));
+        assert!(m.is_ignored(Path::new("tests/cli.rs")));
+        // Ignore patterns still apply to included files
+        assert!(m.is_ignored(Path::new("src/target/gen.rs")));
+
+        // Directories are walked into unless ignored
+        assert!(!m.is_ignored_dir(Path::new("src/commands")));
+        assert!(m.is_ignored_dir(Path::new("target")));
+
+        let everything = matcher(&[]).with_includes(&[]).unwrap();
+        assert!(!everything.is_ignored(Path::new("README.md")));
+    }
+}
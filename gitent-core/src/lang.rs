@@ -0,0 +1,104 @@
+//! Guessing a file's language from its name or shebang line.
+
+use std::path::Path;
+
+/// Extensions, without the dot and lowercased, and the language they map to.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("c", "C"),
+    ("h", "C"),
+    ("cc", "C++"),
+    ("cpp", "C++"),
+    ("cxx", "C++"),
+    ("hpp", "C++"),
+    ("cs", "C#"),
+    ("css", "CSS"),
+    ("go", "Go"),
+    ("html", "HTML"),
+    ("htm", "HTML"),
+    ("java", "Java"),
+    ("js", "JavaScript"),
+    ("mjs", "JavaScript"),
+    ("cjs", "JavaScript"),
+    ("jsx", "JavaScript"),
+    ("json", "JSON"),
+    ("kt", "Kotlin"),
+    ("lua", "Lua"),
+    ("md", "Markdown"),
+    ("php", "PHP"),
+    ("py", "Python"),
+    ("rb", "Ruby"),
+    ("rs", "Rust"),
+    ("scala", "Scala"),
+    ("sh", "Shell"),
+    ("bash", "Shell"),
+    ("zsh", "Shell"),
+    ("sql", "SQL"),
+    ("swift", "Swift"),
+    ("toml", "TOML"),
+    ("ts", "TypeScript"),
+    ("tsx", "TypeScript"),
+    ("yaml", "YAML"),
+    ("yml", "YAML"),
+];
+
+/// Interpreters named in a shebang line and the language they run.
+const INTERPRETERS: &[(&str, &str)] = &[
+    ("bash", "Shell"),
+    ("sh", "Shell"),
+    ("zsh", "Shell"),
+    ("node", "JavaScript"),
+    ("perl", "Perl"),
+    ("python", "Python"),
+    ("python3", "Python"),
+    ("ruby", "Ruby"),
+];
+
+/// Guess the language of `path` from its extension.
+pub fn from_path(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    EXTENSIONS
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, language)| *language)
+}
+
+/// Guess the language of a script from a `#!` line at the start of `content`.
+pub fn from_shebang(content: &[u8]) -> Option<&'static str> {
+    let first_line = content.split(|&b| b == b'\n').next()?;
+    let shebang = std::str::from_utf8(first_line).ok()?.strip_prefix("#!")?;
+
+    // `#!/usr/bin/env python3` names the interpreter as an argument
+    let mut words = shebang.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-'))?;
+    }
+
+    INTERPRETERS
+        .iter()
+        .find(|(name, _)| *name == program)
+        .map(|(_, language)| *language)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(from_path(Path::new("src/main.rs")), Some("Rust"));
+        assert_eq!(from_path(Path::new("App.TSX")), Some("TypeScript"));
+        assert_eq!(from_path(Path::new("data.xyz")), None);
+        assert_eq!(from_path(Path::new("Makefile")), None);
+    }
+
+    #[test]
+    fn test_from_shebang() {
+        assert_eq!(from_shebang(b"#!/bin/bash\necho hi\n"), Some("Shell"));
+        assert_eq!(
+            from_shebang(b"#!/usr/bin/env -S python3 -u\nprint()\n"),
+            Some("Python")
+        );
+        assert_eq!(from_shebang(b"echo hi\n"), None);
+    }
+}
@@ -5,11 +5,34 @@
 //! This crate provides the fundamental data structures and database operations
 //! for tracking file system changes, commits, and rollbacks.
 
+pub mod cancel;
+pub mod clock;
+pub mod config;
+pub mod delta;
 pub mod diff;
 pub mod error;
+pub mod event_log;
+#[cfg(feature = "git")]
+pub mod git;
+pub mod hash;
+pub mod ignore;
+pub mod lang;
 pub mod models;
+pub mod objects;
+pub mod paths;
+pub mod rollback;
 pub mod storage;
+pub mod worktree;
 
+pub use cancel::CancelToken;
+pub use clock::{Clock, FixedClock, SystemClock};
+pub use config::{Author, AuthorMap, Config, Settings};
 pub use error::{Error, Result};
-pub use models::{Change, ChangeType, Commit, CommitInfo, Session};
-pub use storage::Storage;
+pub use hash::HashAlgorithm;
+pub use models::{
+    ActivityEvent, AuditEntry, Change, ChangeType, Checkpoint, Commit, CommitChangeLink,
+    CommitInfo, FileIndexEntry, FileVersion, GcReport, IntegrityReport, MergeReport, MetadataField,
+    MetadataSchema, MetadataType, OrphanBlob, PathStatus, Progress, PruneReport, RetentionPolicy,
+    Session, TrackedPath,
+};
+pub use storage::{ChangeQuery, SortOrder, Storage, StoredContent};
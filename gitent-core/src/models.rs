@@ -1,7 +1,10 @@
+use crate::clock::{Clock, SystemClock};
+use crate::hash::{self, HashAlgorithm};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -45,15 +48,41 @@ pub struct Change {
     pub content_hash_before: Option<String>,
     pub content_hash_after: Option<String>,
     pub agent_id: Option<String>,
+    /// The tool that produced the change, e.g. an editor, formatter or the watcher
+    #[serde(default)]
+    pub tool: Option<String>,
+    /// Language of the file, guessed from its extension or shebang
+    #[serde(default)]
+    pub language: Option<String>,
+    /// When the file was last modified before the change, restored on rollback
+    #[serde(default)]
+    pub mtime_before: Option<DateTime<Utc>>,
+    /// When the file was last modified after the change
+    #[serde(default)]
+    pub mtime_after: Option<DateTime<Utc>>,
     pub metadata: HashMap<String, String>,
     pub session_id: Uuid,
+    /// Position in the order changes were recorded, assigned when stored
+    #[serde(default)]
+    pub seq: Option<i64>,
 }
 
 impl Change {
     pub fn new(change_type: ChangeType, path: PathBuf, session_id: Uuid) -> Self {
+        Self::new_with_clock(change_type, path, session_id, &SystemClock)
+    }
+
+    /// [`Change::new`], timestamped by `clock`.
+    pub fn new_with_clock(
+        change_type: ChangeType,
+        path: PathBuf,
+        session_id: Uuid,
+        clock: &dyn Clock,
+    ) -> Self {
+        let language = crate::lang::from_path(&path).map(str::to_string);
         Self {
             id: Uuid::new_v4(),
-            timestamp: Utc::now(),
+            timestamp: clock.now(),
             change_type,
             path,
             old_path: None,
@@ -62,28 +91,75 @@ impl Change {
             content_hash_before: None,
             content_hash_after: None,
             agent_id: None,
+            tool: None,
+            language,
+            mtime_before: None,
+            mtime_after: None,
             metadata: HashMap::new(),
             session_id,
+            seq: None,
         }
     }
 
-    pub fn with_content_before(mut self, content: Vec<u8>) -> Self {
-        self.content_hash_before = Some(Self::hash_content(&content));
+    pub fn with_content_before(self, content: Vec<u8>) -> Self {
+        self.with_content_before_hashed(content, HashAlgorithm::default())
+    }
+
+    /// [`Change::with_content_before`], hashing the content with `algorithm`,
+    /// such as the session's, so it needn't be hashed again when stored.
+    pub fn with_content_before_hashed(
+        mut self,
+        content: Vec<u8>,
+        algorithm: HashAlgorithm,
+    ) -> Self {
+        self.content_hash_before = Some(algorithm.hash(&content));
         self.content_before = Some(content);
         self
     }
 
-    pub fn with_content_after(mut self, content: Vec<u8>) -> Self {
-        self.content_hash_after = Some(Self::hash_content(&content));
+    pub fn with_content_after(self, content: Vec<u8>) -> Self {
+        self.with_content_after_hashed(content, HashAlgorithm::default())
+    }
+
+    /// [`Change::with_content_after`], hashing the content with `algorithm`.
+    pub fn with_content_after_hashed(mut self, content: Vec<u8>, algorithm: HashAlgorithm) -> Self {
+        if self.language.is_none() {
+            self.language = crate::lang::from_shebang(&content).map(str::to_string);
+        }
+        self.content_hash_after = Some(algorithm.hash(&content));
         self.content_after = Some(content);
         self
     }
 
+    /// A copy of the change with its content left out, keeping the hashes.
+    pub fn without_content(&self) -> Self {
+        Self {
+            content_before: None,
+            content_after: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn with_mtime_before(mut self, mtime: DateTime<Utc>) -> Self {
+        self.mtime_before = Some(mtime);
+        self
+    }
+
+    pub fn with_mtime_after(mut self, mtime: DateTime<Utc>) -> Self {
+        self.mtime_after = Some(mtime);
+        self
+    }
+
     pub fn with_agent_id(mut self, agent_id: String) -> Self {
         self.agent_id = Some(agent_id);
         self
     }
 
+    pub fn with_tool(mut self, tool: String) -> Self {
+        self.tool = Some(tool);
+        self
+    }
+
     pub fn with_metadata(mut self, key: String, value: String) -> Self {
         self.metadata.insert(key, value);
         self
@@ -94,11 +170,134 @@ impl Change {
         self
     }
 
-    fn hash_content(content: &[u8]) -> String {
-        use sha2::{Digest, Sha256};
-        let mut hasher = Sha256::new();
-        hasher.update(content);
-        hex::encode(hasher.finalize())
+    /// Check that the change makes sense for its type, so diff and rollback
+    /// never see a create with prior content or a rename without a source.
+    ///
+    /// A modification may carry no content at all, as the watcher records for
+    /// files over its size limit, but not a before without an after.
+    pub fn validate(&self) -> crate::Result<()> {
+        let has_before = self.content_before.is_some() || self.content_hash_before.is_some();
+        let has_after = self.content_after.is_some() || self.content_hash_after.is_some();
+
+        let problem = match self.change_type {
+            ChangeType::Create if has_before => Some("a create can't have content before"),
+            ChangeType::Delete if has_after => Some("a delete can't have content after"),
+            ChangeType::Rename if self.old_path.is_none() => Some("a rename needs an old path"),
+            ChangeType::Modify if has_before && !has_after => {
+                Some("a modification with content before needs content after")
+            }
+            _ => None,
+        };
+
+        match problem {
+            Some(problem) => Err(crate::Error::InvalidOperation(format!(
+                "Invalid change to {}: {}",
+                self.path.display(),
+                problem
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that the stored content hashes to the stored hashes.
+    ///
+    /// Content without a recorded hash, or a hash without content, can't be
+    /// checked and is treated as intact, as is redacted content.
+    pub fn verify_content_integrity(&self) -> bool {
+        if self.is_redacted() {
+            return true;
+        }
+
+        let matches = |content: &Option<Vec<u8>>, hash: &Option<String>| match (content, hash) {
+            (Some(content), Some(hash)) => hash::matches(hash, content),
+            _ => true,
+        };
+
+        matches(&self.content_before, &self.content_hash_before)
+            && matches(&self.content_after, &self.content_hash_after)
+    }
+
+    /// Whether every hash that can be recomputed from the change's content
+    /// was made with `algorithm`.
+    pub fn hashed_with(&self, algorithm: HashAlgorithm) -> bool {
+        self.is_redacted()
+            || [
+                (&self.content_before, &self.content_hash_before),
+                (&self.content_after, &self.content_hash_after),
+            ]
+            .into_iter()
+            .all(|(content, hash)| {
+                content.is_none()
+                    || hash
+                        .as_deref()
+                        .is_some_and(|hash| HashAlgorithm::of(hash) == algorithm)
+            })
+    }
+
+    /// Hash the change's content with `algorithm` instead. Hashes without
+    /// content to recompute them from are kept as they are, as are those of
+    /// redacted content.
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        if self.hashed_with(algorithm) {
+            return self;
+        }
+        for (content, hash) in [
+            (&self.content_before, &mut self.content_hash_before),
+            (&self.content_after, &mut self.content_hash_after),
+        ] {
+            if let Some(content) = content {
+                *hash = Some(algorithm.hash(content));
+            }
+        }
+        self
+    }
+
+    /// Replace any content with [`REDACTED_CONTENT`]. The hashes of the real
+    /// content are kept, so later changes to the file are still noticed.
+    pub fn redact(mut self) -> Self {
+        for content in [&mut self.content_before, &mut self.content_after]
+            .into_iter()
+            .flatten()
+        {
+            *content = REDACTED_CONTENT.to_vec();
+        }
+        self.metadata
+            .insert(REDACTED_KEY.to_string(), "true".to_string());
+        self
+    }
+
+    /// Whether the change's content was replaced by [`Change::redact`].
+    pub fn is_redacted(&self) -> bool {
+        self.metadata.contains_key(REDACTED_KEY)
+    }
+
+    /// Note why the change has no content, such as the file being unreadable.
+    pub fn with_content_missing(self, reason: impl Into<String>) -> Self {
+        self.with_metadata(CONTENT_MISSING_KEY.to_string(), reason.into())
+    }
+
+    /// Why the change has no content, if that was noted.
+    pub fn content_missing(&self) -> Option<&str> {
+        self.metadata.get(CONTENT_MISSING_KEY).map(String::as_str)
+    }
+
+    /// Whether either side's content isn't text, so it has no line diff.
+    pub fn is_binary(&self) -> bool {
+        [&self.content_before, &self.content_after]
+            .into_iter()
+            .flatten()
+            .any(|content| content.contains(&0) || std::str::from_utf8(content).is_err())
+    }
+
+    /// The order changes are kept in within a commit: by path, then by when
+    /// they were recorded, so the same history always lists them the same way.
+    /// Paths compare byte by byte, as the database sorts them.
+    pub fn canonical_cmp(&self, other: &Change) -> Ordering {
+        (self.path.as_os_str(), self.seq, self.id).cmp(&(
+            other.path.as_os_str(),
+            other.seq,
+            other.id,
+        ))
     }
 }
 
@@ -116,10 +315,21 @@ pub struct Commit {
 
 impl Commit {
     pub fn new(message: String, agent_id: String, changes: Vec<Uuid>, session_id: Uuid) -> Self {
+        Self::new_with_clock(message, agent_id, changes, session_id, &SystemClock)
+    }
+
+    /// [`Commit::new`], timestamped by `clock`.
+    pub fn new_with_clock(
+        message: String,
+        agent_id: String,
+        changes: Vec<Uuid>,
+        session_id: Uuid,
+        clock: &dyn Clock,
+    ) -> Self {
         Self {
             id: Uuid::new_v4(),
             parent: None,
-            timestamp: Utc::now(),
+            timestamp: clock.now(),
             message,
             agent_id,
             changes,
@@ -128,6 +338,64 @@ impl Commit {
         }
     }
 
+    /// A hash of what this commit records, given its `changes`.
+    ///
+    /// Ids are left out and the changes are hashed in canonical order, so
+    /// identical histories hash the same however they were put together.
+    /// Storage keeps each commit's digest from when it was made, for
+    /// [`Storage::check_integrity_deep`](crate::Storage::check_integrity_deep)
+    /// to check against.
+    pub fn digest(&self, changes: &[Change]) -> String {
+        use sha2::{Digest, Sha256};
+
+        let mut changes: Vec<&Change> = changes.iter().collect();
+        changes.sort_by(|a, b| a.canonical_cmp(b));
+
+        let mut hasher = Sha256::new();
+        let mut field = |value: &str| {
+            hasher.update(value.as_bytes());
+            hasher.update([0]);
+        };
+        field(&self.timestamp.to_rfc3339());
+        field(&self.agent_id);
+        field(&self.message);
+        for change in changes {
+            field(change.change_type.as_str());
+            field(&change.path.to_string_lossy());
+            field(
+                &change
+                    .old_path
+                    .as_deref()
+                    .unwrap_or(Path::new(""))
+                    .to_string_lossy(),
+            );
+            field(change.content_hash_before.as_deref().unwrap_or(""));
+            field(change.content_hash_after.as_deref().unwrap_or(""));
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    /// Join a summary line and an optional body into a message, separated by
+    /// a blank line as in git.
+    pub fn compose_message(summary: &str, body: Option<&str>) -> String {
+        match body.map(str::trim).filter(|body| !body.is_empty()) {
+            Some(body) => format!("{}\n\n{}", summary.trim(), body),
+            None => summary.trim().to_string(),
+        }
+    }
+
+    /// The first line of the message.
+    pub fn summary(&self) -> &str {
+        self.message.lines().next().unwrap_or_default()
+    }
+
+    /// Everything after the summary line, if there is anything.
+    pub fn body(&self) -> Option<&str> {
+        let (_, body) = self.message.split_once('\n')?;
+        let body = body.trim();
+        (!body.is_empty()).then_some(body)
+    }
+
     pub fn with_parent(mut self, parent: Uuid) -> Self {
         self.parent = Some(parent);
         self
@@ -137,8 +405,61 @@ impl Commit {
         self.metadata.insert(key, value);
         self
     }
+
+    /// Link the commit to an issue, such as `GH-123`.
+    pub fn with_issue(self, issue: String) -> Self {
+        self.with_metadata(ISSUE_KEY.to_string(), issue)
+    }
+
+    /// Link the commit to a pull request by its URL.
+    pub fn with_pr_url(self, url: String) -> Self {
+        self.with_metadata(PR_URL_KEY.to_string(), url)
+    }
+
+    /// Mark the commit as a milestone, a point worth finding again in a
+    /// long history.
+    pub fn with_milestone(self) -> Self {
+        self.with_metadata(MILESTONE_KEY.to_string(), "true".to_string())
+    }
+
+    pub fn is_milestone(&self) -> bool {
+        self.metadata
+            .get(MILESTONE_KEY)
+            .is_some_and(|v| v == "true")
+    }
+
+    /// The issue the commit is linked to, if any.
+    pub fn issue(&self) -> Option<&str> {
+        self.metadata.get(ISSUE_KEY).map(String::as_str)
+    }
+
+    /// The pull request the commit is linked to, if any.
+    pub fn pr_url(&self) -> Option<&str> {
+        self.metadata.get(PR_URL_KEY).map(String::as_str)
+    }
+
+    /// Git-style `Issue` and `PR` trailer lines for the commit's links.
+    pub fn link_trailers(&self) -> Vec<String> {
+        let issue = self.issue().map(|issue| format!("Issue: {}", issue));
+        let pr = self.pr_url().map(|url| format!("PR: {}", url));
+        issue.into_iter().chain(pr).collect()
+    }
 }
 
+/// Stored in place of the content of redacted files.
+pub const REDACTED_CONTENT: &[u8] = b"[REDACTED]";
+/// Change metadata key marking redacted content.
+const REDACTED_KEY: &str = "redacted";
+/// Change metadata key holding why content couldn't be recorded.
+const CONTENT_MISSING_KEY: &str = "content_missing";
+
+/// Commit metadata key holding the linked issue.
+const ISSUE_KEY: &str = "issue";
+/// Commit metadata key holding the linked pull request URL.
+const PR_URL_KEY: &str = "pr_url";
+/// Commit metadata key marking milestones.
+pub(crate) const MILESTONE_KEY: &str = "milestone";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Session {
     pub id: Uuid,
@@ -147,33 +468,217 @@ pub struct Session {
     pub ended: Option<DateTime<Utc>>,
     pub active: bool,
     pub ignore_patterns: Vec<String>,
+    /// Store modifies as deltas against the previous version of the file
+    #[serde(default)]
+    pub store_deltas: bool,
+    /// Directory stored paths are relative to, when it differs from the
+    /// watched `root_path`
+    #[serde(default)]
+    pub content_root: Option<PathBuf>,
+    /// Files whose content is replaced with a placeholder before storage,
+    /// in the same syntax as `ignore_patterns`
+    #[serde(default)]
+    pub redact_patterns: Vec<String>,
+    /// When set, only files matching one of these are tracked, in the same
+    /// syntax as `ignore_patterns`
+    #[serde(default)]
+    pub include_patterns: Vec<String>,
+    /// Metadata changes and commits are expected to carry
+    #[serde(default)]
+    pub metadata_schema: Option<MetadataSchema>,
+    /// How content is hashed
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
 }
 
 impl Session {
     pub fn new(root_path: PathBuf) -> Self {
+        Self::new_with_clock(root_path, &SystemClock)
+    }
+
+    /// [`Session::new`], started at `clock`'s time.
+    pub fn new_with_clock(root_path: PathBuf, clock: &dyn Clock) -> Self {
         Self {
             id: Uuid::new_v4(),
             root_path,
-            started: Utc::now(),
+            started: clock.now(),
             ended: None,
             active: true,
-            ignore_patterns: vec![
-                ".git".to_string(),
-                "target".to_string(),
-                "node_modules".to_string(),
-                ".gitent".to_string(),
-            ],
+            ignore_patterns: Self::default_ignore_patterns(),
+            store_deltas: false,
+            content_root: None,
+            redact_patterns: Vec::new(),
+            include_patterns: Vec::new(),
+            metadata_schema: None,
+            hash_algorithm: HashAlgorithm::default(),
         }
     }
 
+    pub fn default_ignore_patterns() -> Vec<String> {
+        vec![
+            ".git".to_string(),
+            "target".to_string(),
+            "node_modules".to_string(),
+            ".gitent".to_string(),
+        ]
+    }
+
     pub fn with_ignore_patterns(mut self, patterns: Vec<String>) -> Self {
         self.ignore_patterns = patterns;
         self
     }
 
+    /// Track files matching `patterns` without storing their content.
+    pub fn with_redact_patterns(mut self, patterns: Vec<String>) -> Self {
+        self.redact_patterns = patterns;
+        self
+    }
+
+    /// Track only files matching `patterns`; empty tracks everything.
+    pub fn with_include_patterns(mut self, patterns: Vec<String>) -> Self {
+        self.include_patterns = patterns;
+        self
+    }
+
+    /// Check the metadata of new changes and commits against `schema`.
+    pub fn with_metadata_schema(mut self, schema: MetadataSchema) -> Self {
+        self.metadata_schema = Some(schema);
+        self
+    }
+
+    /// Hash the content of new changes with `algorithm`.
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = algorithm;
+        self
+    }
+
+    pub fn with_delta_storage(mut self, store_deltas: bool) -> Self {
+        self.store_deltas = store_deltas;
+        self
+    }
+
+    /// Express stored paths relative to `content_root` instead of the watched
+    /// root, for example the repository root while watching a build directory.
+    pub fn with_content_root(mut self, content_root: PathBuf) -> Self {
+        self.content_root = Some(content_root);
+        self
+    }
+
+    /// The directory stored paths are relative to.
+    pub fn content_root(&self) -> &Path {
+        self.content_root.as_deref().unwrap_or(&self.root_path)
+    }
+
+    /// Resolve `path` to the form changes are stored in, relative to the
+    /// content root, rejecting paths outside it.
+    pub fn relative_path(&self, path: &Path) -> crate::Result<PathBuf> {
+        crate::paths::relative_path(self.content_root(), path)
+    }
+
+    /// Where a stored path is on disk.
+    pub fn absolute_path(&self, path: &Path) -> PathBuf {
+        self.content_root().join(path)
+    }
+
     pub fn end(&mut self) {
+        self.end_with_clock(&SystemClock);
+    }
+
+    /// [`Session::end`], ended at `clock`'s time.
+    pub fn end_with_clock(&mut self, clock: &dyn Clock) {
         self.active = false;
-        self.ended = Some(Utc::now());
+        self.ended = Some(clock.now());
+    }
+}
+
+/// The kind of value a metadata key holds. Metadata values are always
+/// strings; this is what they must parse as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataType {
+    #[default]
+    String,
+    Integer,
+    Number,
+    Boolean,
+}
+
+impl MetadataType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            MetadataType::String => "string",
+            MetadataType::Integer => "integer",
+            MetadataType::Number => "number",
+            MetadataType::Boolean => "boolean",
+        }
+    }
+
+    /// Whether `value` is a value of this type.
+    pub fn accepts(&self, value: &str) -> bool {
+        match self {
+            MetadataType::String => true,
+            MetadataType::Integer => value.parse::<i64>().is_ok(),
+            MetadataType::Number => value.parse::<f64>().is_ok(),
+            MetadataType::Boolean => matches!(value, "true" | "false"),
+        }
+    }
+}
+
+/// One key in a [`MetadataSchema`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetadataField {
+    pub required: bool,
+    #[serde(rename = "type")]
+    pub value_type: MetadataType,
+}
+
+/// The metadata keys a session's changes and commits are expected to carry.
+///
+/// Keys not in the schema are allowed. In strict mode metadata that breaks
+/// the schema is rejected with [`crate::Error::InvalidMetadata`]; otherwise
+/// it is stored with a warning.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetadataSchema {
+    pub strict: bool,
+    pub changes: BTreeMap<String, MetadataField>,
+    pub commits: BTreeMap<String, MetadataField>,
+}
+
+impl MetadataSchema {
+    /// What's wrong with a change's metadata, if anything.
+    pub fn change_problems(&self, metadata: &HashMap<String, String>) -> Vec<String> {
+        Self::problems(&self.changes, metadata)
+    }
+
+    /// What's wrong with a commit's metadata, if anything.
+    pub fn commit_problems(&self, metadata: &HashMap<String, String>) -> Vec<String> {
+        Self::problems(&self.commits, metadata)
+    }
+
+    fn problems(
+        fields: &BTreeMap<String, MetadataField>,
+        metadata: &HashMap<String, String>,
+    ) -> Vec<String> {
+        fields
+            .iter()
+            .filter_map(|(key, field)| match metadata.get(key) {
+                None if field.required => Some(format!("missing required key '{}'", key)),
+                Some(value) if !field.value_type.accepts(value) => Some(format!(
+                    "'{}' should be {} {}, not '{}'",
+                    key,
+                    if field.value_type == MetadataType::Integer {
+                        "an"
+                    } else {
+                        "a"
+                    },
+                    field.value_type.as_str(),
+                    value
+                )),
+                _ => None,
+            })
+            .collect()
     }
 }
 
@@ -182,12 +687,246 @@ pub struct CommitInfo {
     pub commit: Commit,
     pub change_count: usize,
     pub files_affected: Vec<PathBuf>,
+    /// Other agents whose changes are included in the commit, sorted
+    #[serde(default)]
+    pub co_authors: Vec<String>,
+}
+
+impl CommitInfo {
+    /// Git-style `Co-authored-by` trailer lines for the commit's co-authors.
+    pub fn co_author_trailers(&self) -> Vec<String> {
+        self.co_authors
+            .iter()
+            .map(|agent| format!("Co-authored-by: {}", agent))
+            .collect()
+    }
+}
+
+/// A link from a commit to a change, as stored in `commit_changes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitChangeLink {
+    pub commit_id: Uuid,
+    pub change_id: Uuid,
+}
+
+/// Inconsistencies found by `Storage::check_integrity`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IntegrityReport {
+    /// Links from an existing commit to a change that doesn't exist
+    pub missing_changes: Vec<CommitChangeLink>,
+    /// Links whose commit doesn't exist
+    pub dangling_links: Vec<CommitChangeLink>,
+    /// Changes belonging to a session that doesn't exist
+    pub orphaned_changes: Vec<Uuid>,
+    /// Changes whose content doesn't match its hash; only filled in by a deep check
+    #[serde(default)]
+    pub corrupted_changes: Vec<Uuid>,
+    /// Commits whose changes no longer match the digest taken when they were
+    /// made; only filled in by a deep check
+    #[serde(default)]
+    pub altered_commits: Vec<Uuid>,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing_changes.is_empty()
+            && self.dangling_links.is_empty()
+            && self.orphaned_changes.is_empty()
+            && self.corrupted_changes.is_empty()
+            && self.altered_commits.is_empty()
+    }
+}
+
+/// How much commit history to keep when pruning.
+///
+/// A commit survives if it is among the newest `keep_commits` or is younger
+/// than `keep_days`. The latest commit is always kept, and an empty policy
+/// keeps everything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub keep_commits: Option<usize>,
+    pub keep_days: Option<u32>,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_commits.is_none() && self.keep_days.is_none()
+    }
+}
+
+/// What a prune removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PruneReport {
+    pub commits_removed: usize,
+    pub changes_removed: usize,
+    /// Objects deleted from the object store as nothing refers to them anymore
+    #[serde(default)]
+    pub objects_removed: usize,
+}
+
+/// What [`Storage::merge_sessions`](crate::Storage::merge_sessions) moved.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeReport {
+    pub changes_moved: usize,
+    pub commits_moved: usize,
+    /// Files changed in both sessions, whose histories now interleave
+    pub overlapping_paths: Vec<PathBuf>,
+}
+
+/// Stored content nothing needs anymore: a change no commit refers to that a
+/// later committed change superseded, or whose session is gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrphanBlob {
+    pub change_id: Uuid,
+    /// Bytes of content stored for the change
+    pub bytes: u64,
+}
+
+/// What a garbage collection removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GcReport {
+    pub changes_removed: usize,
+    /// Objects deleted from the object store as nothing refers to them anymore
+    #[serde(default)]
+    pub objects_removed: usize,
+    /// Bytes of content removed from the database, before it was vacuumed,
+    /// and from the object store
+    pub bytes_reclaimed: u64,
+}
+
+/// A named point in a session's stream of changes. Unlike a snapshot, it is
+/// looked up by label and kept as part of the session's history.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub id: Uuid,
+    pub session_id: Uuid,
+    pub label: String,
+    /// `seq` of the last change recorded before the checkpoint
+    pub seq: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A write made through the API, as recorded in the audit log. Unlike
+/// change history, this includes requests that were rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub method: String,
+    pub path: String,
+    /// HTTP status the request was answered with
+    pub status: u16,
+    /// The agent the client said it was acting for. Nothing checks this, so
+    /// any client can name any agent
+    pub claimed_agent_id: Option<String>,
+    /// The change or commit written, if any
+    pub target: Option<String>,
+    pub source_ip: Option<String>,
+    pub request_id: Option<String>,
+}
+
+/// Whether a tracked path still exists as of its latest change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathStatus {
+    Live,
+    /// Deleted, or renamed to another path
+    Deleted,
+}
+
+/// A path touched by some change in a session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackedPath {
+    pub path: PathBuf,
+    /// Set when asked for
+    pub status: Option<PathStatus>,
+}
+
+/// One recorded version of a file, for playing back its history.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub change_id: Uuid,
+    /// `None` while the change is uncommitted
+    pub commit_id: Option<Uuid>,
+    pub timestamp: DateTime<Utc>,
+    pub change_type: ChangeType,
+    /// Where the file was at this version, which differs before a rename
+    pub path: PathBuf,
+    /// The file's content after this version; `None` once deleted, when it
+    /// wasn't stored, or when not asked for
+    pub content: Option<Vec<u8>>,
+}
+
+/// How far a long operation has got, as passed to its progress callback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Items handled so far, counting ones skipped as already done
+    pub done: usize,
+    pub total: usize,
+}
+
+/// One entry in a session's activity feed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ActivityEvent {
+    Change(Change),
+    Commit(Commit),
+}
+
+impl ActivityEvent {
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            ActivityEvent::Change(change) => change.timestamp,
+            ActivityEvent::Commit(commit) => commit.timestamp,
+        }
+    }
+}
+
+/// What the watcher last saw of a file, used to catch up on changes made
+/// while the server was down.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileIndexEntry {
+    pub path: PathBuf,
+    /// Modification time in nanoseconds since the Unix epoch
+    pub mtime_ns: i64,
+    /// Content hash, if the content was read
+    pub hash: Option<String>,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_constructors_use_clock() {
+        use crate::clock::FixedClock;
+        use chrono::TimeZone;
+
+        let at = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let clock = FixedClock::new(at);
+
+        let mut session = Session::new_with_clock(PathBuf::from("/test"), &clock);
+        let change = Change::new_with_clock(
+            ChangeType::Create,
+            PathBuf::from("test.txt"),
+            session.id,
+            &clock,
+        );
+        clock.advance(chrono::Duration::seconds(30));
+        let commit = Commit::new_with_clock(
+            "Add test".to_string(),
+            "agent".to_string(),
+            vec![change.id],
+            session.id,
+            &clock,
+        );
+        session.end_with_clock(&clock);
+
+        assert_eq!(session.started, at);
+        assert_eq!(change.timestamp, at);
+        assert_eq!(commit.timestamp, at + chrono::Duration::seconds(30));
+        assert_eq!(session.ended, Some(at + chrono::Duration::seconds(30)));
+    }
+
     #[test]
     fn test_change_creation() {
         let session_id = Uuid::new_v4();
@@ -198,6 +937,82 @@ mod tests {
         assert_eq!(change.session_id, session_id);
     }
 
+    #[test]
+    fn test_metadata_schema_problems() {
+        let schema = MetadataSchema {
+            commits: BTreeMap::from([
+                (
+                    "ticket".to_string(),
+                    MetadataField {
+                        required: true,
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "attempt".to_string(),
+                    MetadataField {
+                        required: false,
+                        value_type: MetadataType::Integer,
+                    },
+                ),
+            ]),
+            ..Default::default()
+        };
+        let metadata = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+
+        assert!(schema
+            .commit_problems(&metadata(&[("ticket", "GE-1"), ("attempt", "2")]))
+            .is_empty());
+        assert_eq!(
+            schema.commit_problems(&metadata(&[("attempt", "two")])),
+            vec![
+                "'attempt' should be an integer, not 'two'",
+                "missing required key 'ticket'",
+            ]
+        );
+        // The commit keys don't apply to changes
+        assert!(schema.change_problems(&metadata(&[])).is_empty());
+    }
+
+    #[test]
+    fn test_validate_rejects_inconsistent_changes() {
+        let session_id = Uuid::new_v4();
+        let change = |change_type| Change::new(change_type, PathBuf::from("a.txt"), session_id);
+
+        let invalid = [
+            change(ChangeType::Create).with_content_before(b"old".to_vec()),
+            change(ChangeType::Delete).with_content_after(b"new".to_vec()),
+            change(ChangeType::Rename),
+            change(ChangeType::Modify).with_content_before(b"old".to_vec()),
+        ];
+        for change in invalid {
+            assert!(
+                matches!(change.validate(), Err(crate::Error::InvalidOperation(_))),
+                "{:?} should be rejected",
+                change.change_type
+            );
+        }
+
+        let valid = [
+            change(ChangeType::Create).with_content_after(b"new".to_vec()),
+            change(ChangeType::Delete).with_content_before(b"old".to_vec()),
+            change(ChangeType::Rename).with_old_path(PathBuf::from("b.txt")),
+            change(ChangeType::Modify)
+                .with_content_before(b"old".to_vec())
+                .with_content_after(b"new".to_vec()),
+            // Content over the watcher's size limit isn't captured
+            change(ChangeType::Modify),
+        ];
+        for change in valid {
+            assert!(change.validate().is_ok());
+        }
+    }
+
     #[test]
     fn test_change_with_content() {
         let session_id = Uuid::new_v4();
@@ -210,6 +1025,33 @@ mod tests {
         assert_eq!(change.content_after.unwrap(), content);
     }
 
+    #[test]
+    fn test_change_language() {
+        let session_id = Uuid::new_v4();
+
+        let change = Change::new(ChangeType::Create, PathBuf::from("lib.rs"), session_id);
+        assert_eq!(change.language.as_deref(), Some("Rust"));
+
+        let change = Change::new(ChangeType::Create, PathBuf::from("data.xyz"), session_id);
+        assert_eq!(change.language, None);
+
+        let change = Change::new(ChangeType::Create, PathBuf::from("bin/deploy"), session_id)
+            .with_content_after(b"#!/usr/bin/env python3\n".to_vec());
+        assert_eq!(change.language.as_deref(), Some("Python"));
+    }
+
+    #[test]
+    fn test_verify_content_integrity() {
+        let session_id = Uuid::new_v4();
+        let mut change = Change::new(ChangeType::Modify, PathBuf::from("test.txt"), session_id)
+            .with_content_before(b"before".to_vec())
+            .with_content_after(b"after".to_vec());
+        assert!(change.verify_content_integrity());
+
+        change.content_after = Some(b"tampered".to_vec());
+        assert!(!change.verify_content_integrity());
+    }
+
     #[test]
     fn test_commit_creation() {
         let session_id = Uuid::new_v4();
@@ -226,6 +1068,56 @@ mod tests {
         assert_eq!(commit.changes, change_ids);
     }
 
+    #[test]
+    fn test_commit_summary_and_body() {
+        let message =
+            Commit::compose_message("Add parser", Some("Handles nested lists.\n\nSee #12."));
+        assert_eq!(message, "Add parser\n\nHandles nested lists.\n\nSee #12.");
+
+        let commit = Commit::new(message, "test-agent".to_string(), vec![], Uuid::new_v4());
+        assert_eq!(commit.summary(), "Add parser");
+        assert_eq!(commit.body(), Some("Handles nested lists.\n\nSee #12."));
+
+        let commit = Commit::new(
+            Commit::compose_message("Fix typo", Some("  \n")),
+            "test-agent".to_string(),
+            vec![],
+            Uuid::new_v4(),
+        );
+        assert_eq!(commit.message, "Fix typo");
+        assert_eq!(commit.body(), None);
+    }
+
+    #[test]
+    fn test_is_binary() {
+        let change = |content: &[u8]| {
+            Change::new(ChangeType::Create, PathBuf::from("f"), Uuid::new_v4())
+                .with_content_after(content.to_vec())
+        };
+        assert!(!change(b"fn main() {}\n").is_binary());
+        assert!(change(b"\x89PNG\r\n\x1a\n\0\0").is_binary());
+        assert!(change(&[0xff, 0xfe, 0x41]).is_binary());
+    }
+
+    #[test]
+    fn test_commit_link_trailers() {
+        let commit = Commit::new(
+            "Fix crash".to_string(),
+            "test-agent".to_string(),
+            vec![],
+            Uuid::new_v4(),
+        );
+        assert!(commit.link_trailers().is_empty());
+
+        let commit = commit
+            .with_issue("GH-123".to_string())
+            .with_pr_url("https://github.com/org/repo/pull/7".to_string());
+        assert_eq!(
+            commit.link_trailers(),
+            vec!["Issue: GH-123", "PR: https://github.com/org/repo/pull/7"]
+        );
+    }
+
     #[test]
     fn test_session_creation() {
         let session = Session::new(PathBuf::from("/test/path"));
@@ -0,0 +1,217 @@
+//! Content kept outside the database, for files too large to store in it.
+//!
+//! Objects are named by the SHA-256 of their content and live in an
+//! `objects` directory next to the database, so identical content is stored
+//! once. The database keeps only the name.
+
+use crate::error::{Error, Result};
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Name of the object directory, next to the database.
+pub const OBJECTS_DIR: &str = "objects";
+
+/// How recently an object must have been stored for [`ObjectStore::sweep`]
+/// to leave it alone: long enough for the row naming it to be written, even
+/// by another process.
+pub const SWEEP_GRACE: Duration = Duration::from_secs(10 * 60);
+
+/// The objects a change's content is stored as, in place of the content.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectNames {
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// A stored object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredObject {
+    pub name: String,
+    pub bytes: u64,
+    /// When it was last stored
+    pub stored: SystemTime,
+}
+
+/// A content-addressed directory of objects.
+#[derive(Debug, Clone)]
+pub struct ObjectStore {
+    dir: PathBuf,
+}
+
+impl ObjectStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The store kept alongside the database at `db_path`.
+    pub fn for_database(db_path: &Path) -> Self {
+        let parent = db_path.parent().unwrap_or(Path::new("."));
+        Self::new(parent.join(OBJECTS_DIR))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Store `content`, returning its name. Content that's already stored
+    /// isn't written again.
+    pub fn put(&self, content: &[u8]) -> Result<String> {
+        let name = hex::encode(Sha256::digest(content));
+        let path = self.dir.join(&name);
+        if path.exists() {
+            // Counts as newly stored, so a sweep can't take it before the row
+            // naming it is written
+            filetime::set_file_mtime(&path, FileTime::now())?;
+            return Ok(name);
+        }
+
+        // Written under a temporary name first, so a crash can't leave a
+        // truncated object behind the real name
+        std::fs::create_dir_all(&self.dir)?;
+        let temp = self
+            .dir
+            .join(format!("{}.tmp-{}", name, std::process::id()));
+        let mut file = std::fs::File::create(&temp)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &path)?;
+        Ok(name)
+    }
+
+    /// The content stored under `name`.
+    pub fn get(&self, name: &str) -> Result<Vec<u8>> {
+        std::fs::read(self.path(name)?).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Error::InvalidOperation(format!(
+                "Object {} is missing from {}",
+                name,
+                self.dir.display()
+            )),
+            _ => e.into(),
+        })
+    }
+
+    /// The object stored under `name`, opened to be read as it's needed
+    /// rather than loaded whole.
+    pub fn open(&self, name: &str) -> Result<std::fs::File> {
+        std::fs::File::open(self.path(name)?).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Error::InvalidOperation(format!(
+                "Object {} is missing from {}",
+                name,
+                self.dir.display()
+            )),
+            _ => e.into(),
+        })
+    }
+
+    /// The size of the object stored under `name`.
+    pub fn size(&self, name: &str) -> Result<u64> {
+        Ok(std::fs::metadata(self.path(name)?)?.len())
+    }
+
+    /// Every stored object. Writes left unfinished aren't included.
+    pub fn list(&self) -> Result<Vec<StoredObject>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut objects = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if !is_object_name(&name) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            objects.push(StoredObject {
+                name,
+                bytes: metadata.len(),
+                stored: metadata.modified()?,
+            });
+        }
+        objects.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(objects)
+    }
+
+    /// Delete the objects `in_use` doesn't accept, except ones stored within
+    /// [`SWEEP_GRACE`] of `now`. Returns the objects deleted.
+    pub fn sweep(
+        &self,
+        now: SystemTime,
+        mut in_use: impl FnMut(&str) -> bool,
+    ) -> Result<Vec<StoredObject>> {
+        let mut removed = Vec::new();
+        for object in self.list()? {
+            let recent = now
+                .duration_since(object.stored)
+                .map_or(true, |age| age < SWEEP_GRACE);
+            if recent || in_use(&object.name) {
+                continue;
+            }
+            std::fs::remove_file(self.dir.join(&object.name))?;
+            removed.push(object);
+        }
+        Ok(removed)
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        // Names are hex digests; anything else could point outside the store
+        if !is_object_name(name) {
+            return Err(Error::InvalidOperation(format!(
+                "Invalid object name {:?}",
+                name
+            )));
+        }
+        Ok(self.dir.join(name))
+    }
+}
+
+fn is_object_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_put_and_get() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = ObjectStore::for_database(&temp_dir.path().join("gitent.db"));
+
+        let name = store.put(b"large content").unwrap();
+        assert_eq!(store.put(b"large content").unwrap(), name);
+        assert!(temp_dir.path().join(OBJECTS_DIR).join(&name).exists());
+        assert_eq!(store.get(&name).unwrap(), b"large content");
+
+        assert!(store.get("0123abcd").is_err());
+        assert!(store.get("../gitent.db").is_err());
+    }
+
+    #[test]
+    fn test_sweep_keeps_objects_in_use_and_recent() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = ObjectStore::new(temp_dir.path());
+        let kept = store.put(b"still referenced").unwrap();
+        let unused = store.put(b"no longer referenced").unwrap();
+
+        // Nothing is old enough yet
+        let removed = store.sweep(SystemTime::now(), |_| false).unwrap();
+        assert!(removed.is_empty());
+
+        let later = SystemTime::now() + SWEEP_GRACE * 2;
+        let removed = store.sweep(later, |name| name == kept).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].name, unused);
+        assert_eq!(removed[0].bytes, 20);
+        assert!(store.get(&unused).is_err());
+        assert_eq!(store.get(&kept).unwrap(), b"still referenced");
+    }
+}
@@ -0,0 +1,112 @@
+//! Normalizing change paths so each file is tracked under one name.
+
+use crate::error::{Error, Result};
+use std::path::{Component, Path, PathBuf};
+
+/// Resolve `path` to a path relative to `root`, the form changes are stored in.
+///
+/// Relative paths are taken as relative to `root`. `.` and `..` are resolved
+/// without touching the filesystem, so this works for deleted files too.
+/// Paths outside `root`, including the root itself, are rejected with
+/// [`Error::InvalidPath`].
+pub fn relative_path(root: &Path, path: &Path) -> Result<PathBuf> {
+    let outside =
+        || Error::InvalidPath(format!("{} is outside {}", path.display(), root.display()));
+
+    let relative = if path.is_absolute() {
+        path.strip_prefix(root).map_err(|_| outside())?
+    } else {
+        path
+    };
+
+    let mut normalized = PathBuf::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return Err(outside());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(outside()),
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        return Err(Error::InvalidPath(format!(
+            "{} does not name a file",
+            path.display()
+        )));
+    }
+
+    Ok(normalized)
+}
+
+/// Where a stored change path is on disk under `root`, whether it was stored
+/// relative to `root` or, as in older databases, as an absolute path.
+///
+/// Paths that resolve outside `root` are rejected with [`Error::InvalidPath`]
+/// rather than followed.
+pub fn resolve(root: &Path, path: &Path) -> Result<PathBuf> {
+    Ok(root.join(relative_path(root, path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_path() {
+        let root = Path::new("/work/project");
+        let expected = PathBuf::from("src/main.rs");
+
+        assert_eq!(
+            relative_path(root, Path::new("src/main.rs")).unwrap(),
+            expected
+        );
+        assert_eq!(
+            relative_path(root, Path::new("/work/project/src/main.rs")).unwrap(),
+            expected
+        );
+        assert_eq!(
+            relative_path(root, Path::new("./src/../src/main.rs")).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_resolve_relative_and_absolute_alike() {
+        let root = Path::new("/work/project");
+        let expected = PathBuf::from("/work/project/src/main.rs");
+
+        assert_eq!(resolve(root, Path::new("src/main.rs")).unwrap(), expected);
+        assert_eq!(
+            resolve(root, Path::new("/work/project/src/main.rs")).unwrap(),
+            expected
+        );
+        assert!(resolve(root, Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn test_paths_outside_root_are_rejected() {
+        let root = Path::new("/work/project");
+
+        for path in [
+            "../../etc/passwd",
+            "src/../../other",
+            "/etc/passwd",
+            ".",
+            "",
+        ] {
+            assert!(
+                matches!(
+                    relative_path(root, Path::new(path)),
+                    Err(Error::InvalidPath(_))
+                ),
+                "{} should be rejected",
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,955 @@
+//! Undoing the changes recorded in a commit.
+//!
+//! Rolling back is done in two steps: [`plan`] works out what would happen to
+//! each file and flags conflicts, and [`execute`] applies the plan to disk.
+//! In between, [`check`] can confirm that every file could be rolled back.
+//! A conflict means the file on disk no longer matches what the commit left
+//! behind, so rolling back would discard newer work; such files are skipped
+//! unless forced. [`plan_change`] plans undoing just one of a commit's
+//! changes the same way.
+
+use crate::error::{Error, Result};
+use crate::hash;
+use crate::models::{Change, ChangeType, Commit};
+use crate::paths::resolve;
+use chrono::{DateTime, Utc};
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RollbackAction {
+    /// A created file is removed
+    Remove,
+    /// A modified file gets its previous content back
+    Restore,
+    /// A deleted file is written again
+    Recreate,
+    /// A renamed file is moved back to its old path
+    RenameBack,
+}
+
+impl RollbackAction {
+    pub fn for_change(change_type: ChangeType) -> Self {
+        match change_type {
+            ChangeType::Create => RollbackAction::Remove,
+            ChangeType::Modify => RollbackAction::Restore,
+            ChangeType::Delete => RollbackAction::Recreate,
+            ChangeType::Rename => RollbackAction::RenameBack,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RollbackOutcome {
+    Planned,
+    Applied,
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RollbackStep {
+    pub change_id: Uuid,
+    pub path: PathBuf,
+    pub action: RollbackAction,
+    /// Why rolling this file back would overwrite newer work, if it would
+    pub conflict: Option<String>,
+    pub outcome: RollbackOutcome,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RollbackPlan {
+    pub commit_id: Uuid,
+    pub executed: bool,
+    pub steps: Vec<RollbackStep>,
+}
+
+impl RollbackPlan {
+    pub fn has_conflicts(&self) -> bool {
+        self.steps.iter().any(|step| step.conflict.is_some())
+    }
+
+    pub fn count(&self, outcome: RollbackOutcome) -> usize {
+        self.steps
+            .iter()
+            .filter(|step| step.outcome == outcome)
+            .count()
+    }
+}
+
+/// Work out what rolling back `changes` from `commit` would do under `root`.
+pub fn plan(commit: &Commit, changes: &[Change], root: &Path) -> RollbackPlan {
+    RollbackPlan {
+        commit_id: commit.id,
+        executed: false,
+        steps: changes
+            .iter()
+            .map(|change| plan_step(change, root))
+            .collect(),
+    }
+}
+
+/// Work out what undoing just `change`, one of `commit`'s changes, would do
+/// under `root`, leaving the rest of the commit alone.
+pub fn plan_change(commit: &Commit, change: &Change, root: &Path) -> Result<RollbackPlan> {
+    if !commit.changes.contains(&change.id) {
+        return Err(Error::InvalidOperation(format!(
+            "Change {} isn't in commit {}",
+            change.id, commit.id
+        )));
+    }
+    Ok(RollbackPlan {
+        commit_id: commit.id,
+        executed: false,
+        steps: vec![plan_step(change, root)],
+    })
+}
+
+/// Work out what rolling back a single change would do under `root`. Steps
+/// don't hold content, so a plan can be built one change at a time.
+pub fn plan_step(change: &Change, root: &Path) -> RollbackStep {
+    RollbackStep {
+        change_id: change.id,
+        path: change.path.clone(),
+        action: RollbackAction::for_change(change.change_type),
+        conflict: detect_conflict(change, root),
+        outcome: RollbackOutcome::Planned,
+        error: None,
+    }
+}
+
+/// Apply a plan produced by [`plan`] for the same `changes`.
+///
+/// Conflicting files are skipped unless `force` is set. With `verify`, a
+/// change whose stored content doesn't match its hash is refused rather than
+/// written to disk. Failures are recorded per step rather than aborting the
+/// rest of the rollback.
+pub fn execute(
+    mut plan: RollbackPlan,
+    changes: &[Change],
+    root: &Path,
+    force: bool,
+    verify: bool,
+) -> RollbackPlan {
+    for step in &mut plan.steps {
+        let change = changes.iter().find(|c| c.id == step.change_id);
+        execute_step(step, change, root, force, verify);
+    }
+
+    plan.executed = true;
+    plan
+}
+
+/// Apply a single step of a plan, given the change it was planned for, or
+/// `None` if that can't be found.
+pub fn execute_step(
+    step: &mut RollbackStep,
+    change: Option<&Change>,
+    root: &Path,
+    force: bool,
+    verify: bool,
+) {
+    if step.conflict.is_some() && !force {
+        step.outcome = RollbackOutcome::Skipped;
+        return;
+    }
+
+    match change_for(step, change, verify).and_then(|change| rollback_change(change, root)) {
+        Ok(()) => step.outcome = RollbackOutcome::Applied,
+        Err(e) => {
+            step.outcome = RollbackOutcome::Failed;
+            step.error = Some(e.to_string());
+        }
+    }
+}
+
+/// Run every check [`execute`] would make on a plan without touching disk.
+///
+/// Steps that would fail are marked [`RollbackOutcome::Failed`] with the
+/// reason; the rest stay planned. A conflict counts as a failure unless
+/// `force` is set, since its file would be skipped.
+pub fn check(
+    mut plan: RollbackPlan,
+    changes: &[Change],
+    root: &Path,
+    force: bool,
+    verify: bool,
+) -> RollbackPlan {
+    for step in &mut plan.steps {
+        let change = changes.iter().find(|c| c.id == step.change_id);
+        check_step(step, change, root, force, verify);
+    }
+
+    plan
+}
+
+/// Check a single step of a plan, as [`check`] does.
+pub fn check_step(
+    step: &mut RollbackStep,
+    change: Option<&Change>,
+    root: &Path,
+    force: bool,
+    verify: bool,
+) {
+    let result = match &step.conflict {
+        Some(conflict) if !force => Err(Error::RollbackFailed(format!("conflict: {}", conflict))),
+        _ => change_for(step, change, verify).and_then(|change| check_change(change, root)),
+    };
+    if let Err(e) = result {
+        step.outcome = RollbackOutcome::Failed;
+        step.error = Some(e.to_string());
+    }
+}
+
+/// The change a step rolls back, refused if `verify` is set and its content
+/// doesn't match its hash.
+fn change_for<'a>(
+    step: &RollbackStep,
+    change: Option<&'a Change>,
+    verify: bool,
+) -> Result<&'a Change> {
+    let change = change.ok_or_else(|| Error::ChangeNotFound(step.change_id.to_string()))?;
+    if verify && !change.verify_content_integrity() {
+        return Err(Error::CorruptContent(change.id.to_string()));
+    }
+    Ok(change)
+}
+
+/// The change that undoes `change`, to record a revert with: the same file
+/// going back the other way, with a `reverts` metadata entry naming the
+/// change it undoes.
+///
+/// What the file holds beforehand is read from disk under `root`, so call
+/// this before reverting. A forced revert then records the newer content it
+/// overwrote, not what the commit left behind.
+pub fn revert_record(change: &Change, root: &Path) -> Change {
+    let (change_type, path, old_path) = match (change.change_type, &change.old_path) {
+        (ChangeType::Create, _) => (ChangeType::Delete, change.path.clone(), None),
+        (ChangeType::Delete, _) => (ChangeType::Create, change.path.clone(), None),
+        (ChangeType::Rename, Some(old_path)) => (
+            ChangeType::Rename,
+            old_path.clone(),
+            Some(change.path.clone()),
+        ),
+        (change_type, _) => (change_type, change.path.clone(), None),
+    };
+
+    let mut revert = Change::new(change_type, path, change.session_id)
+        .with_metadata("reverts".to_string(), change.id.to_string());
+    revert.old_path = old_path;
+    if change_type != ChangeType::Create {
+        let current = resolve(root, &change.path).ok();
+        if let Some(content) = current.as_ref().and_then(|path| std::fs::read(path).ok()) {
+            revert = revert.with_content_before(content);
+        }
+        revert.mtime_before = current
+            .and_then(|path| std::fs::metadata(path).ok())
+            .and_then(|metadata| metadata.modified().ok())
+            .map(DateTime::from);
+    }
+    if change_type != ChangeType::Delete {
+        revert.content_after = change.content_before.clone();
+        revert.content_hash_after = change.content_hash_before.clone();
+        revert.mtime_after = change.mtime_before;
+    }
+    revert
+}
+
+/// Coalesce `changes`, oldest first, into one change per file with their net
+/// effect, so rolling each back undoes the whole sequence.
+///
+/// A file is followed through renames. Files that end as they started, such
+/// as one created and then deleted, are left out. The result is ordered by
+/// when each file was last changed, newest first, and each change keeps the
+/// id of the last change to its file.
+pub fn net_changes(changes: &[Change]) -> Vec<Change> {
+    // For each file: its first and last change, and its path after the last
+    let mut files: Vec<(&Change, &Change, usize)> = Vec::new();
+
+    for (index, change) in changes.iter().enumerate() {
+        let current = match (change.change_type, &change.old_path) {
+            (ChangeType::Rename, Some(old_path)) => old_path,
+            _ => &change.path,
+        };
+        match files.iter_mut().find(|(_, last, _)| last.path == *current) {
+            Some(file) => {
+                file.1 = change;
+                file.2 = index;
+            }
+            None => files.push((change, change, index)),
+        }
+    }
+    files.sort_by_key(|(_, _, last_index)| std::cmp::Reverse(*last_index));
+
+    files
+        .into_iter()
+        .filter_map(|(first, last, _)| net_change(first, last))
+        .collect()
+}
+
+/// The single change taking a file from before `first` to after `last`.
+fn net_change(first: &Change, last: &Change) -> Option<Change> {
+    let origin = match (first.change_type, &first.old_path) {
+        (ChangeType::Rename, Some(old_path)) => old_path.clone(),
+        _ => first.path.clone(),
+    };
+    let existed_before = first.change_type != ChangeType::Create;
+    let exists_after = last.change_type != ChangeType::Delete;
+
+    let mut net = last.clone();
+    net.old_path = None;
+    net.content_before = first.content_before.clone();
+    net.content_hash_before = first.content_hash_before.clone();
+    net.mtime_before = first.mtime_before;
+
+    match (existed_before, exists_after) {
+        (false, false) => return None,
+        (false, true) => {
+            net.change_type = ChangeType::Create;
+            net.content_before = None;
+            net.content_hash_before = None;
+            net.mtime_before = None;
+        }
+        (true, false) => {
+            net.change_type = ChangeType::Delete;
+            net.path = origin;
+        }
+        (true, true) if origin != net.path => {
+            net.change_type = ChangeType::Rename;
+            net.old_path = Some(origin);
+        }
+        (true, true) => {
+            let unchanged = net.content_hash_before.is_some()
+                && net.content_hash_before == net.content_hash_after;
+            if unchanged {
+                return None;
+            }
+            net.change_type = ChangeType::Modify;
+        }
+    }
+
+    Some(net)
+}
+
+/// Undo a single change on disk.
+///
+/// The change's paths may be stored relative to `root` or absolute; either
+/// way they must be inside `root`.
+pub fn rollback_change(change: &Change, root: &Path) -> Result<()> {
+    check_change(change, root)?;
+    let full_path = resolve(root, &change.path)?;
+
+    match change.change_type {
+        ChangeType::Create => {
+            // Remove the created file
+            if full_path.exists() {
+                std::fs::remove_file(&full_path)?;
+            }
+        }
+        ChangeType::Modify => {
+            // Restore previous content
+            if let Some(content_before) = &change.content_before {
+                std::fs::write(&full_path, content_before)?;
+                restore_mtime(&full_path, change.mtime_before)?;
+            }
+        }
+        ChangeType::Delete => {
+            // Recreate the deleted file
+            if let Some(content_before) = &change.content_before {
+                if let Some(parent) = full_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&full_path, content_before)?;
+                restore_mtime(&full_path, change.mtime_before)?;
+            }
+        }
+        ChangeType::Rename => {
+            // Rename back to old path
+            if let Some(old_path) = &change.old_path {
+                let old_full_path = resolve(root, old_path)?;
+                if full_path.exists() {
+                    std::fs::rename(&full_path, &old_full_path)?;
+                }
+                // Restore content that changed along with the name
+                if let Some(content_before) = &change.content_before {
+                    std::fs::write(&old_full_path, content_before)?;
+                }
+                if old_full_path.exists() {
+                    restore_mtime(&old_full_path, change.mtime_before)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that undoing a single change could succeed: its paths are inside
+/// `root`, its content can be written back, and every file and directory it
+/// would write to or remove from can be written.
+pub fn check_change(change: &Change, root: &Path) -> Result<()> {
+    let full_path = resolve(root, &change.path)?;
+
+    // Writing the placeholder back would destroy the real file
+    if change.is_redacted() && change.content_before.is_some() {
+        return Err(Error::RollbackFailed(format!(
+            "content of {} was redacted",
+            change.path.display()
+        )));
+    }
+
+    match change.change_type {
+        ChangeType::Create => {
+            if full_path.exists() {
+                writable(parent(&full_path))?;
+            }
+        }
+        ChangeType::Modify => {
+            if change.content_before.is_some() {
+                if full_path.exists() {
+                    writable(&full_path)?;
+                } else {
+                    writable(parent(&full_path))?;
+                }
+            }
+        }
+        ChangeType::Delete => {
+            if change.content_before.is_some() {
+                if full_path.exists() {
+                    writable(&full_path)?;
+                } else {
+                    creatable(&full_path)?;
+                }
+            }
+        }
+        ChangeType::Rename => {
+            if let Some(old_path) = &change.old_path {
+                let old_full_path = resolve(root, old_path)?;
+                if full_path.exists() {
+                    writable(parent(&full_path))?;
+                }
+                if old_full_path.exists() {
+                    writable(&old_full_path)?;
+                } else {
+                    writable(parent(&old_full_path))?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parent(path: &Path) -> &Path {
+    path.parent().unwrap_or(path)
+}
+
+/// Fail if `path` is missing or this process can't write to it.
+fn writable(path: &Path) -> Result<()> {
+    std::fs::metadata(path)
+        .map_err(|e| Error::RollbackFailed(format!("can't access {}: {}", path.display(), e)))?;
+    if !can_write(path) {
+        return Err(Error::RollbackFailed(format!(
+            "{} is read-only",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Ask the OS rather than reading mode bits, which are wrong for root, ACLs
+/// and read-only mounts.
+#[cfg(unix)]
+fn can_write(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is a NUL-terminated string that outlives the call
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// Files are opened for writing, without truncating them. Windows ignores
+/// the read-only attribute on directories, so those are always writable.
+#[cfg(not(unix))]
+fn can_write(path: &Path) -> bool {
+    path.is_dir() || std::fs::OpenOptions::new().write(true).open(path).is_ok()
+}
+
+/// Fail if the directories leading to `path` couldn't be created: the
+/// nearest one that exists must be a writable directory.
+fn creatable(path: &Path) -> Result<()> {
+    let Some(existing) = path.ancestors().skip(1).find(|dir| dir.exists()) else {
+        return Ok(());
+    };
+    if !existing.is_dir() {
+        return Err(Error::RollbackFailed(format!(
+            "{} is not a directory",
+            existing.display()
+        )));
+    }
+    writable(existing)
+}
+
+/// Give a restored file back its recorded modification time, if there is one.
+fn restore_mtime(path: &Path, mtime: Option<DateTime<Utc>>) -> Result<()> {
+    if let Some(mtime) = mtime {
+        filetime::set_file_mtime(path, FileTime::from_system_time(mtime.into()))?;
+    }
+    Ok(())
+}
+
+fn detect_conflict(change: &Change, root: &Path) -> Option<String> {
+    let full_path = match resolve(root, &change.path) {
+        Ok(full_path) => full_path,
+        Err(e) => return Some(e.to_string()),
+    };
+
+    match change.change_type {
+        ChangeType::Create | ChangeType::Modify => {
+            let expected = change.content_hash_after.as_ref()?;
+            let current = std::fs::read(&full_path).ok()?;
+            if !hash::matches(expected, &current) {
+                Some("file was modified after this commit".to_string())
+            } else {
+                None
+            }
+        }
+        ChangeType::Delete => full_path
+            .exists()
+            .then(|| "file was recreated after this commit".to_string()),
+        ChangeType::Rename => {
+            let old_path = change.old_path.as_ref()?;
+            match resolve(root, old_path) {
+                Ok(old_full_path) => old_full_path
+                    .exists()
+                    .then(|| format!("{} already exists", old_path.display())),
+                Err(e) => Some(e.to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn modify_commit(root: &Path) -> (Commit, Vec<Change>) {
+        let session_id = Uuid::new_v4();
+        let change = Change::new(ChangeType::Modify, PathBuf::from("file.txt"), session_id)
+            .with_content_before(b"before".to_vec())
+            .with_content_after(b"after".to_vec());
+        std::fs::write(root.join("file.txt"), b"after").unwrap();
+
+        let commit = Commit::new(
+            "Edit".to_string(),
+            "test-agent".to_string(),
+            vec![change.id],
+            session_id,
+        );
+        (commit, vec![change])
+    }
+
+    #[test]
+    fn test_execute_restores_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let (commit, changes) = modify_commit(temp_dir.path());
+
+        let plan = plan(&commit, &changes, temp_dir.path());
+        assert!(!plan.has_conflicts());
+        assert_eq!(plan.steps[0].action, RollbackAction::Restore);
+
+        let result = execute(plan, &changes, temp_dir.path(), false, false);
+        assert_eq!(result.count(RollbackOutcome::Applied), 1);
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("file.txt")).unwrap(),
+            b"before"
+        );
+    }
+
+    #[test]
+    fn test_rollback_restores_mtime() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = crate::Storage::in_memory().unwrap();
+        let session = crate::Session::new(temp_dir.path().to_path_buf());
+        storage.create_session(&session).unwrap();
+
+        let file_path = temp_dir.path().join("file.txt");
+        let mtime_before: DateTime<Utc> = "2021-03-04T05:06:07.123456789Z".parse().unwrap();
+        std::fs::write(&file_path, b"after").unwrap();
+        let change = Change::new(ChangeType::Modify, PathBuf::from("file.txt"), session.id)
+            .with_content_before(b"before".to_vec())
+            .with_content_after(b"after".to_vec())
+            .with_mtime_before(mtime_before)
+            .with_mtime_after(
+                std::fs::metadata(&file_path)
+                    .unwrap()
+                    .modified()
+                    .unwrap()
+                    .into(),
+            );
+        storage.create_change(&change).unwrap();
+
+        // Survives storage at full precision
+        let stored = storage.get_change(&change.id).unwrap();
+        assert_eq!(stored.mtime_before, Some(mtime_before));
+        assert_eq!(stored.mtime_after, change.mtime_after);
+
+        rollback_change(&stored, temp_dir.path()).unwrap();
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"before");
+        let restored: DateTime<Utc> = std::fs::metadata(&file_path)
+            .unwrap()
+            .modified()
+            .unwrap()
+            .into();
+        assert_eq!(restored, mtime_before);
+
+        // Without a recorded mtime the file keeps the time it was written at
+        let untimed = Change {
+            mtime_before: None,
+            ..stored
+        };
+        std::fs::write(&file_path, b"after").unwrap();
+        rollback_change(&untimed, temp_dir.path()).unwrap();
+        let written: DateTime<Utc> = std::fs::metadata(&file_path)
+            .unwrap()
+            .modified()
+            .unwrap()
+            .into();
+        assert!(written > mtime_before);
+    }
+
+    #[test]
+    fn test_check_reports_read_only_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let (commit, changes) = modify_commit(temp_dir.path());
+        let file_path = temp_dir.path().join("file.txt");
+
+        let checked = check(
+            plan(&commit, &changes, temp_dir.path()),
+            &changes,
+            temp_dir.path(),
+            false,
+            false,
+        );
+        assert_eq!(checked.count(RollbackOutcome::Planned), 1);
+
+        let mut permissions = std::fs::metadata(&file_path).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&file_path, permissions).unwrap();
+        let writes_anyway = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&file_path)
+            .is_ok();
+
+        let checked = check(
+            plan(&commit, &changes, temp_dir.path()),
+            &changes,
+            temp_dir.path(),
+            false,
+            false,
+        );
+        if writes_anyway {
+            // Root, for one, can write to it regardless
+            assert_eq!(checked.count(RollbackOutcome::Planned), 1);
+            return;
+        }
+        assert!(!checked.executed);
+        assert_eq!(checked.steps[0].outcome, RollbackOutcome::Failed);
+        assert!(checked.steps[0]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("read-only"));
+        // Nothing was touched
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"after");
+
+        // Executing fails the same way
+        let result = execute(
+            plan(&commit, &changes, temp_dir.path()),
+            &changes,
+            temp_dir.path(),
+            false,
+            false,
+        );
+        assert_eq!(result.count(RollbackOutcome::Failed), 1);
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"after");
+    }
+
+    #[test]
+    fn test_check_reports_conflicts_and_missing_directories() {
+        let temp_dir = TempDir::new().unwrap();
+        let (commit, changes) = modify_commit(temp_dir.path());
+        std::fs::write(temp_dir.path().join("file.txt"), b"newer work").unwrap();
+
+        let checked = check(
+            plan(&commit, &changes, temp_dir.path()),
+            &changes,
+            temp_dir.path(),
+            false,
+            false,
+        );
+        assert!(checked.steps[0]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("conflict"));
+        let checked = check(
+            plan(&commit, &changes, temp_dir.path()),
+            &changes,
+            temp_dir.path(),
+            true,
+            false,
+        );
+        assert_eq!(checked.count(RollbackOutcome::Planned), 1);
+
+        // A deleted file can't be recreated under a path blocked by a file
+        std::fs::write(temp_dir.path().join("blocker"), b"").unwrap();
+        let delete = Change::new(
+            ChangeType::Delete,
+            PathBuf::from("blocker/deep/gone.txt"),
+            commit.session_id,
+        )
+        .with_content_before(b"gone".to_vec());
+        let err = check_change(&delete, temp_dir.path()).unwrap_err();
+        assert!(err.to_string().contains("is not a directory"));
+
+        let delete = Change {
+            path: PathBuf::from("new/deep/gone.txt"),
+            ..delete
+        };
+        check_change(&delete, temp_dir.path()).unwrap();
+    }
+
+    #[test]
+    fn test_conflict_is_skipped_unless_forced() {
+        let temp_dir = TempDir::new().unwrap();
+        let (commit, changes) = modify_commit(temp_dir.path());
+        std::fs::write(temp_dir.path().join("file.txt"), b"newer work").unwrap();
+
+        let plan = plan(&commit, &changes, temp_dir.path());
+        assert!(plan.has_conflicts());
+
+        let result = execute(plan.clone(), &changes, temp_dir.path(), false, false);
+        assert_eq!(result.count(RollbackOutcome::Skipped), 1);
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("file.txt")).unwrap(),
+            b"newer work"
+        );
+
+        let result = execute(plan, &changes, temp_dir.path(), true, false);
+        assert_eq!(result.count(RollbackOutcome::Applied), 1);
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("file.txt")).unwrap(),
+            b"before"
+        );
+    }
+
+    #[test]
+    fn test_revert_single_change() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let storage = crate::Storage::in_memory().unwrap();
+        let session = crate::Session::new(root.to_path_buf());
+        storage.create_session(&session).unwrap();
+
+        let edit = |path: &str| {
+            std::fs::write(root.join(path), b"after").unwrap();
+            let change = Change::new(ChangeType::Modify, PathBuf::from(path), session.id)
+                .with_content_before(b"before".to_vec())
+                .with_content_after(b"after".to_vec());
+            storage.create_change(&change).unwrap();
+            change
+        };
+        let good = edit("good.txt");
+        let bad = edit("bad.txt");
+        let commit = Commit::new(
+            "Edit both".to_string(),
+            "test-agent".to_string(),
+            vec![good.id, bad.id],
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+
+        let found = storage.get_commit_for_change(&bad.id).unwrap();
+        assert_eq!(found.id, commit.id);
+        let plan = plan_change(&found, &bad, root).unwrap();
+        assert_eq!(plan.steps.len(), 1);
+        // Edited since the commit, so the revert has to be forced
+        std::fs::write(root.join("bad.txt"), b"newer").unwrap();
+        let revert = revert_record(&bad, root);
+        let result = execute(plan, std::slice::from_ref(&bad), root, true, false);
+        assert_eq!(result.count(RollbackOutcome::Applied), 1);
+
+        assert_eq!(std::fs::read(root.join("bad.txt")).unwrap(), b"before");
+        assert_eq!(std::fs::read(root.join("good.txt")).unwrap(), b"after");
+
+        // The revert records what it overwrote, not what the commit left
+        assert_eq!(revert.change_type, ChangeType::Modify);
+        assert_eq!(revert.content_before, Some(b"newer".to_vec()));
+        assert_ne!(revert.content_hash_before, bad.content_hash_after);
+        assert_eq!(revert.content_after, Some(b"before".to_vec()));
+        assert_eq!(revert.metadata["reverts"], bad.id.to_string());
+
+        // Only committed changes can be reverted on their own
+        let uncommitted = edit("other.txt");
+        assert!(matches!(
+            storage.get_commit_for_change(&uncommitted.id),
+            Err(Error::InvalidOperation(_))
+        ));
+        assert!(plan_change(&commit, &uncommitted, root).is_err());
+    }
+
+    #[test]
+    fn test_net_rollback_of_overlapping_commits() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let session_id = Uuid::new_v4();
+        let change = |change_type, path: &str| Change::new(change_type, path.into(), session_id);
+
+        // First commit: edit a.txt, create b.txt, rename c.txt to d.txt
+        // Second commit: edit a.txt and b.txt again, delete e.txt
+        let changes = vec![
+            change(ChangeType::Modify, "a.txt")
+                .with_content_before(b"a1".to_vec())
+                .with_content_after(b"a2".to_vec()),
+            change(ChangeType::Create, "b.txt").with_content_after(b"b1".to_vec()),
+            change(ChangeType::Rename, "d.txt").with_old_path("c.txt".into()),
+            change(ChangeType::Modify, "a.txt")
+                .with_content_before(b"a2".to_vec())
+                .with_content_after(b"a3".to_vec()),
+            change(ChangeType::Modify, "b.txt")
+                .with_content_before(b"b1".to_vec())
+                .with_content_after(b"b2".to_vec()),
+            change(ChangeType::Delete, "e.txt").with_content_before(b"e1".to_vec()),
+        ];
+        for (path, content) in [("a.txt", "a3"), ("b.txt", "b2"), ("d.txt", "c1")] {
+            std::fs::write(root.join(path), content).unwrap();
+        }
+        let commit = Commit::new("Range".into(), "agent".into(), vec![], session_id);
+
+        let net = net_changes(&changes);
+        let actions: Vec<(&str, RollbackAction)> = net
+            .iter()
+            .map(|c| {
+                (
+                    c.path.to_str().unwrap(),
+                    RollbackAction::for_change(c.change_type),
+                )
+            })
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                ("e.txt", RollbackAction::Recreate),
+                ("b.txt", RollbackAction::Remove),
+                ("a.txt", RollbackAction::Restore),
+                ("d.txt", RollbackAction::RenameBack),
+            ]
+        );
+
+        let plan = plan(&commit, &net, root);
+        assert!(!plan.has_conflicts());
+        let result = execute(plan, &net, root, false, false);
+        assert_eq!(result.count(RollbackOutcome::Applied), 4);
+
+        assert_eq!(std::fs::read(root.join("a.txt")).unwrap(), b"a1");
+        assert!(!root.join("b.txt").exists());
+        assert_eq!(std::fs::read(root.join("c.txt")).unwrap(), b"c1");
+        assert!(!root.join("d.txt").exists());
+        assert_eq!(std::fs::read(root.join("e.txt")).unwrap(), b"e1");
+    }
+
+    #[test]
+    fn test_net_changes_drop_files_that_end_as_they_started() {
+        let session_id = Uuid::new_v4();
+        let changes = vec![
+            Change::new(ChangeType::Create, "tmp.txt".into(), session_id)
+                .with_content_after(b"scratch".to_vec()),
+            Change::new(ChangeType::Delete, "tmp.txt".into(), session_id)
+                .with_content_before(b"scratch".to_vec()),
+            Change::new(ChangeType::Modify, "same.txt".into(), session_id)
+                .with_content_before(b"one".to_vec())
+                .with_content_after(b"two".to_vec()),
+            Change::new(ChangeType::Modify, "same.txt".into(), session_id)
+                .with_content_before(b"two".to_vec())
+                .with_content_after(b"one".to_vec()),
+        ];
+        assert!(net_changes(&changes).is_empty());
+    }
+
+    #[test]
+    fn test_absolute_and_relative_paths_target_the_same_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let session_id = Uuid::new_v4();
+        let file = root.join("src").join("lib.rs");
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+
+        for path in [PathBuf::from("src/lib.rs"), file.clone()] {
+            std::fs::write(&file, b"after").unwrap();
+            let change = Change::new(ChangeType::Modify, path, session_id)
+                .with_content_before(b"before".to_vec())
+                .with_content_after(b"after".to_vec());
+            let commit = Commit::new("Edit".into(), "agent".into(), vec![change.id], session_id);
+            let changes = vec![change];
+
+            let plan = plan(&commit, &changes, root);
+            assert!(!plan.has_conflicts());
+            let result = execute(plan, &changes, root, false, false);
+            assert_eq!(result.count(RollbackOutcome::Applied), 1);
+            assert_eq!(std::fs::read(&file).unwrap(), b"before");
+        }
+
+        // Nothing outside the root is touched, even when forced
+        let outside = TempDir::new().unwrap();
+        let outside_file = outside.path().join("lib.rs");
+        std::fs::write(&outside_file, b"after").unwrap();
+        let change = Change::new(ChangeType::Modify, outside_file.clone(), session_id)
+            .with_content_before(b"before".to_vec());
+        let commit = Commit::new("Edit".into(), "agent".into(), vec![change.id], session_id);
+        let changes = vec![change];
+        let plan = plan(&commit, &changes, root);
+        assert!(plan.has_conflicts());
+        let result = execute(plan, &changes, root, true, false);
+        assert_eq!(result.count(RollbackOutcome::Failed), 1);
+        assert_eq!(std::fs::read(&outside_file).unwrap(), b"after");
+    }
+
+    #[test]
+    fn test_redacted_content_is_not_restored() {
+        let temp_dir = TempDir::new().unwrap();
+        let (commit, changes) = modify_commit(temp_dir.path());
+        let changes: Vec<_> = changes.into_iter().map(Change::redact).collect();
+
+        let plan = plan(&commit, &changes, temp_dir.path());
+        let result = execute(plan, &changes, temp_dir.path(), false, false);
+        assert_eq!(result.count(RollbackOutcome::Failed), 1);
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("file.txt")).unwrap(),
+            b"after"
+        );
+    }
+
+    #[test]
+    fn test_verify_refuses_corrupted_content() {
+        let temp_dir = TempDir::new().unwrap();
+        let (commit, mut changes) = modify_commit(temp_dir.path());
+        changes[0].content_before = Some(b"corrupted".to_vec());
+
+        let plan = plan(&commit, &changes, temp_dir.path());
+        let result = execute(plan, &changes, temp_dir.path(), false, true);
+        assert_eq!(result.count(RollbackOutcome::Failed), 1);
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("file.txt")).unwrap(),
+            b"after"
+        );
+    }
+}
@@ -1,31 +1,400 @@
+use crate::cancel::CancelToken;
+use crate::clock::{Clock, SystemClock};
+use crate::config::Settings;
+use crate::delta;
 use crate::error::{Error, Result};
-use crate::models::{Change, ChangeType, Commit, CommitInfo, Session};
-use chrono::DateTime;
-use rusqlite::{params, Connection, OptionalExtension, Row};
+use crate::event_log::{EventLog, LogEvent};
+use crate::hash::HashAlgorithm;
+use crate::ignore::IgnoreMatcher;
+use crate::models::{
+    ActivityEvent, AuditEntry, Change, ChangeType, Checkpoint, Commit, CommitChangeLink,
+    CommitInfo, FileIndexEntry, FileVersion, GcReport, IntegrityReport, MergeReport,
+    MetadataSchema, OrphanBlob, PathStatus, PruneReport, RetentionPolicy, Session, TrackedPath,
+    MILESTONE_KEY,
+};
+use crate::objects::{ObjectNames, ObjectStore, StoredObject};
+use chrono::{DateTime, SecondsFormat, Utc};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use rusqlite::types::{Type, Value};
+use rusqlite::{
+    params, params_from_iter, Connection, OptionalExtension, Row, Transaction, TransactionBehavior,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
 use uuid::Uuid;
 
-const SCHEMA_VERSION: i32 = 1;
+const SCHEMA_VERSION: i32 = 20;
+
+/// SQL run to upgrade a database one schema version; entry `n` upgrades
+/// version `n + 1` to `n + 2`.
+const MIGRATIONS: &[&str] = &[
+    // Delta storage for modifies
+    "ALTER TABLE sessions ADD COLUMN store_deltas INTEGER NOT NULL DEFAULT 0;
+     ALTER TABLE changes ADD COLUMN delta_base TEXT REFERENCES changes(id);",
+    // Which tool produced a change
+    "ALTER TABLE changes ADD COLUMN tool TEXT;",
+    // Tie-free ordering of changes, seeded from insertion order
+    "ALTER TABLE changes ADD COLUMN seq INTEGER;
+     UPDATE changes SET seq = rowid;
+     CREATE UNIQUE INDEX idx_changes_seq ON changes(seq);",
+    // Language of the changed file
+    "ALTER TABLE changes ADD COLUMN language TEXT;",
+    // Last seen state of each file, for catching up after a restart
+    "CREATE TABLE file_index (
+         root_path TEXT NOT NULL,
+         path TEXT NOT NULL,
+         mtime_ns INTEGER NOT NULL,
+         hash TEXT,
+         PRIMARY KEY (root_path, path)
+     );",
+    // Markers for comparing against an earlier point without committing
+    "CREATE TABLE snapshots (
+         id TEXT PRIMARY KEY,
+         session_id TEXT NOT NULL REFERENCES sessions(id),
+         seq INTEGER NOT NULL,
+         created_at TEXT NOT NULL
+     );",
+    // Store paths under the session root relative to it, as new changes are
+    "UPDATE changes SET path = substr(path, length(s.root_path) + 2)
+     FROM sessions s
+     WHERE s.id = changes.session_id
+       AND substr(changes.path, 1, length(s.root_path) + 1) = s.root_path || '/';
+     UPDATE changes SET old_path = substr(old_path, length(s.root_path) + 2)
+     FROM sessions s
+     WHERE s.id = changes.session_id
+       AND substr(changes.old_path, 1, length(s.root_path) + 1) = s.root_path || '/';",
+    // Named markers in the change stream
+    "CREATE TABLE checkpoints (
+         id TEXT PRIMARY KEY,
+         session_id TEXT NOT NULL REFERENCES sessions(id),
+         label TEXT NOT NULL,
+         seq INTEGER NOT NULL,
+         created_at TEXT NOT NULL,
+         UNIQUE (session_id, label)
+     );",
+    // Directory stored paths are relative to, if not the watched root
+    "ALTER TABLE sessions ADD COLUMN content_root TEXT;",
+    // Looking up commits by linked issue
+    "CREATE INDEX idx_commits_issue ON commits(json_extract(metadata, '$.issue'));",
+    // Files tracked without storing their content
+    "ALTER TABLE sessions ADD COLUMN redact_patterns TEXT NOT NULL DEFAULT '[]';",
+    // Writes made through the API, including rejected ones
+    "CREATE TABLE audit (
+         id INTEGER PRIMARY KEY AUTOINCREMENT,
+         timestamp TEXT NOT NULL,
+         method TEXT NOT NULL,
+         path TEXT NOT NULL,
+         status INTEGER NOT NULL,
+         agent_id TEXT,
+         target TEXT,
+         source_ip TEXT,
+         request_id TEXT
+     );",
+    // File modification times around a change
+    "ALTER TABLE changes ADD COLUMN mtime_before TEXT;
+     ALTER TABLE changes ADD COLUMN mtime_after TEXT;",
+    // Binary content stored deflated
+    "ALTER TABLE changes ADD COLUMN compressed INTEGER NOT NULL DEFAULT 0;",
+    // Only files matching these are tracked, when there are any
+    "ALTER TABLE sessions ADD COLUMN include_patterns TEXT NOT NULL DEFAULT '[]';",
+    // Metadata keys changes and commits are checked against
+    "ALTER TABLE sessions ADD COLUMN metadata_schema TEXT;",
+    // How each session hashes content
+    "ALTER TABLE sessions ADD COLUMN hash_algorithm TEXT NOT NULL DEFAULT 'sha256';",
+    // Content kept in the object store, with only its name in the row
+    "ALTER TABLE changes ADD COLUMN external INTEGER NOT NULL DEFAULT 0;",
+    // What each commit recorded, hashed when it was made
+    "ALTER TABLE commits ADD COLUMN digest TEXT;",
+];
+
+/// Before and after content of a change.
+type ContentPair = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// Which of a session's changes [`Storage::latest_changes`] counts.
+enum TreeScope<'a> {
+    All,
+    Committed,
+    /// Changes in this commit and its ancestors
+    UpTo(&'a Uuid),
+}
+
+/// A comma-separated list of `n` SQL parameter placeholders.
+fn placeholders(n: usize) -> String {
+    vec!["?"; n].join(", ")
+}
+
+/// A modification time as stored, keeping its full precision so it can be
+/// restored exactly.
+fn mtime_to_sql(mtime: &DateTime<Utc>) -> String {
+    mtime.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+/// Deflate binary content, which deltas do little for.
+fn compress(content: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    // Writing to a Vec can't fail
+    encoder.write_all(content).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn decompress(content: &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    DeflateDecoder::new(content).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+/// The columns a change's content is stored in when it isn't a delta: the
+/// names of its objects if it went to the object store, or the content itself.
+fn full_content<'a>(
+    change: &'a Change,
+    external: Option<&'a ObjectNames>,
+) -> (Option<&'a [u8]>, Option<&'a [u8]>) {
+    match external {
+        Some(names) => (
+            names.before.as_deref().map(str::as_bytes),
+            names.after.as_deref().map(str::as_bytes),
+        ),
+        None => (
+            change.content_before.as_deref(),
+            change.content_after.as_deref(),
+        ),
+    }
+}
+
+/// The event for new content given to `change`, naming its object rather than
+/// repeating content kept in the object store.
+fn content_replaced(change: &Change, external: Option<&ObjectNames>) -> LogEvent {
+    match external.and_then(|names| names.after.clone()) {
+        Some(name) => LogEvent::ContentReplaced {
+            change_id: change.id,
+            content: Vec::new(),
+            object: Some(name),
+        },
+        None => LogEvent::ContentReplaced {
+            change_id: change.id,
+            content: change.content_after.clone().unwrap_or_default(),
+            object: None,
+        },
+    }
+}
+
+/// Reject metadata that breaks a strict schema, or warn about it otherwise.
+fn check_metadata(schema: &MetadataSchema, what: &str, problems: Vec<String>) -> Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let problems = format!("{}: {}", what, problems.join(", "));
+    if schema.strict {
+        return Err(Error::InvalidMetadata(problems));
+    }
+    tracing::warn!(
+        "Metadata doesn't match the session's schema for {}",
+        problems
+    );
+    Ok(())
+}
+
+/// Longest chain of deltas allowed before a full copy of the content is stored.
+const MAX_DELTA_CHAIN: usize = 32;
+
+/// Shortest commit id prefix [`Storage::resolve_commit_prefix`] accepts.
+pub const MIN_COMMIT_PREFIX: usize = 4;
 
 pub struct Storage {
     conn: Connection,
+    /// Where writes are journaled, if anywhere
+    event_log: Option<EventLog>,
+    /// Checked by long operations running under [`Storage::cancellable`]
+    cancel: RefCell<Option<CancelToken>>,
+    /// Events held back until the [`Storage::batch`] writing them commits
+    pending_events: RefCell<Option<Vec<LogEvent>>>,
+    /// Where content kept outside the database is, if anywhere
+    objects: Option<ObjectStore>,
+    /// Content larger than this goes to `objects` rather than the database
+    external_threshold: Option<u64>,
+    /// Where the times storage stamps on records itself come from
+    clock: Arc<dyn Clock>,
 }
 
 impl Storage {
     pub fn new<P: AsRef<Path>>(db_path: P) -> Result<Self> {
-        let conn = Connection::open(db_path)?;
-        let mut storage = Self { conn };
+        let conn = Connection::open(db_path.as_ref())?;
+        let mut storage = Self {
+            conn,
+            event_log: None,
+            cancel: RefCell::new(None),
+            pending_events: RefCell::new(None),
+            objects: Some(ObjectStore::for_database(db_path.as_ref())),
+            external_threshold: None,
+            clock: Arc::new(SystemClock),
+        };
         storage.initialize()?;
         Ok(storage)
     }
 
+    /// Open the database at `settings.db_path` to write to it, storing
+    /// content the way the settings ask and appending to the event log if
+    /// one is kept.
+    pub fn open(settings: &Settings) -> Result<Self> {
+        let mut storage = Self::new(&settings.db_path)?;
+        if let Some(threshold) = settings.external_threshold {
+            storage = storage.with_external_store(threshold);
+        }
+        if settings.event_log {
+            if let Some(parent) = settings.event_log_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            storage = storage.with_event_log(EventLog::open(&settings.event_log_path)?);
+        }
+        Ok(storage)
+    }
+
     pub fn in_memory() -> Result<Self> {
         let conn = Connection::open_in_memory()?;
-        let mut storage = Self { conn };
+        let mut storage = Self {
+            conn,
+            event_log: None,
+            cancel: RefCell::new(None),
+            pending_events: RefCell::new(None),
+            objects: None,
+            external_threshold: None,
+            clock: Arc::new(SystemClock),
+        };
         storage.initialize()?;
         Ok(storage)
     }
 
+    /// Append every session, change and commit written from now on to `log`,
+    /// so the database can be rebuilt with [`crate::event_log::replay`].
+    pub fn with_event_log(mut self, log: EventLog) -> Self {
+        self.event_log = Some(log);
+        self
+    }
+
+    /// Read the time from `clock` wherever storage stamps a record itself,
+    /// such as snapshots, checkpoints and the retention cutoff.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The current time, as storage's clock has it.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Keep content larger than `threshold` bytes in the object store next
+    /// to the database, storing only its name in the database. Content
+    /// already stored that way is read back from there either way.
+    pub fn with_external_store(mut self, threshold: u64) -> Self {
+        self.external_threshold = Some(threshold);
+        self
+    }
+
+    /// Append an event to the event log, if there is one.
+    fn log_event(&self, event: impl FnOnce() -> LogEvent) -> Result<()> {
+        let Some(log) = &self.event_log else {
+            return Ok(());
+        };
+        match &mut *self.pending_events.borrow_mut() {
+            Some(pending) => {
+                pending.push(event());
+                Ok(())
+            }
+            None => log.append(&event()),
+        }
+    }
+
+    /// Run `f` as a single transaction, so many small writes such as a
+    /// burst of changes share one commit instead of syncing each to disk.
+    ///
+    /// If `f` fails nothing it wrote is kept. A write that fails inside `f`
+    /// without failing `f` leaves the others in place, since each statement
+    /// is atomic on its own. `f` mustn't call methods that start their own
+    /// transaction, such as [`Storage::merge_sessions`]. Batches don't nest:
+    /// an inner one runs as part of the outer, so `f` can call methods that
+    /// batch their own writes, such as [`Storage::create_commit`].
+    pub fn batch<T>(&self, f: impl FnOnce(&Self) -> Result<T>) -> Result<T> {
+        if self.pending_events.borrow().is_some() {
+            return f(self);
+        }
+
+        /// Drops held-back events if `f` fails or panics
+        struct Discard<'a>(&'a RefCell<Option<Vec<LogEvent>>>);
+        impl Drop for Discard<'_> {
+            fn drop(&mut self) {
+                self.0.replace(None);
+            }
+        }
+
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+        self.pending_events.replace(Some(Vec::new()));
+        let discard = Discard(&self.pending_events);
+        let value = f(self)?;
+        tx.commit()?;
+
+        let events = self.pending_events.take().unwrap_or_default();
+        drop(discard);
+        if let Some(log) = &self.event_log {
+            for event in &events {
+                log.append(event)?;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Run `f`, letting the expensive operations it calls stop early with
+    /// [`Error::Cancelled`] or [`Error::Timeout`] once `token` fires.
+    ///
+    /// Tree reconstruction, delta expansion and deep integrity checks check
+    /// the token as they go; an operation that stops early has written
+    /// nothing.
+    pub fn cancellable<T>(
+        &self,
+        token: &CancelToken,
+        f: impl FnOnce(&Self) -> Result<T>,
+    ) -> Result<T> {
+        /// Puts back the outer token even if `f` panics, since the storage
+        /// may go on being used
+        struct Restore<'a>(&'a RefCell<Option<CancelToken>>, Option<CancelToken>);
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                self.0.replace(self.1.take());
+            }
+        }
+
+        token.check()?;
+        let _restore = Restore(&self.cancel, self.cancel.replace(Some(token.clone())));
+        f(self)
+    }
+
+    /// Fail if the operation running under [`Storage::cancellable`] has been
+    /// cancelled or run past its deadline.
+    pub fn check_cancelled(&self) -> Result<()> {
+        match &*self.cancel.borrow() {
+            Some(token) => token.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Cap the database at roughly `bytes`. Writes that would grow it past
+    /// the cap fail with [`Error::StorageFull`], as they would on a full disk.
+    pub fn set_max_size(&self, bytes: u64) -> Result<()> {
+        let page_size: u64 = self
+            .conn
+            .query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        // A count of zero would leave the limit unchanged
+        let pages = (bytes / page_size).max(1);
+        self.conn.pragma_update(None, "max_page_count", pages)?;
+        Ok(())
+    }
+
     fn initialize(&mut self) -> Result<()> {
         self.conn.execute_batch(
             r#"
@@ -80,6 +449,8 @@ impl Storage {
 
             CREATE INDEX IF NOT EXISTS idx_changes_session ON changes(session_id);
             CREATE INDEX IF NOT EXISTS idx_changes_timestamp ON changes(timestamp);
+            CREATE INDEX IF NOT EXISTS idx_changes_path ON changes(path);
+            CREATE INDEX IF NOT EXISTS idx_changes_old_path ON changes(old_path);
             CREATE INDEX IF NOT EXISTS idx_commits_session ON commits(session_id);
             CREATE INDEX IF NOT EXISTS idx_commits_timestamp ON commits(timestamp);
             CREATE INDEX IF NOT EXISTS idx_commits_parent ON commits(parent);
@@ -91,11 +462,28 @@ impl Storage {
             .query_row("SELECT version FROM schema_version", [], |row| row.get(0))
             .optional()?;
 
-        if version.is_none() {
-            self.conn.execute(
-                "INSERT INTO schema_version (version) VALUES (?1)",
-                params![SCHEMA_VERSION],
+        // The tables above are the version 1 schema; migrations take it from there
+        let version = match version {
+            Some(version) => version,
+            None => {
+                self.conn
+                    .execute("INSERT INTO schema_version (version) VALUES (1)", [])?;
+                1
+            }
+        };
+
+        self.migrate(version)
+    }
+
+    fn migrate(&self, from_version: i32) -> Result<()> {
+        for version in from_version..SCHEMA_VERSION {
+            let tx = self.conn.unchecked_transaction()?;
+            tx.execute_batch(MIGRATIONS[(version - 1) as usize])?;
+            tx.execute(
+                "UPDATE schema_version SET version = ?1",
+                params![version + 1],
             )?;
+            tx.commit()?;
         }
 
         Ok(())
@@ -104,10 +492,19 @@ impl Storage {
     // Session operations
     pub fn create_session(&self, session: &Session) -> Result<()> {
         let ignore_patterns = serde_json::to_string(&session.ignore_patterns)?;
+        let redact_patterns = serde_json::to_string(&session.redact_patterns)?;
+        let include_patterns = serde_json::to_string(&session.include_patterns)?;
+        let metadata_schema = session
+            .metadata_schema
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
 
         self.conn.execute(
-            "INSERT INTO sessions (id, root_path, started, ended, active, ignore_patterns)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            "INSERT INTO sessions (id, root_path, started, ended, active, ignore_patterns,
+                                   store_deltas, content_root, redact_patterns, include_patterns,
+                                   metadata_schema, hash_algorithm)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
             params![
                 session.id.to_string(),
                 session.root_path.to_string_lossy().as_ref(),
@@ -115,16 +512,28 @@ impl Storage {
                 session.ended.map(|dt| dt.to_rfc3339()),
                 session.active as i32,
                 ignore_patterns,
+                session.store_deltas as i32,
+                session
+                    .content_root
+                    .as_ref()
+                    .map(|root| root.to_string_lossy().to_string()),
+                redact_patterns,
+                include_patterns,
+                metadata_schema,
+                session.hash_algorithm.as_str(),
             ],
         )?;
 
-        Ok(())
+        self.log_event(|| LogEvent::SessionCreated {
+            session: session.clone(),
+        })
     }
 
     pub fn get_session(&self, id: &Uuid) -> Result<Session> {
         self.conn
             .query_row(
-                "SELECT id, root_path, started, ended, active, ignore_patterns FROM sessions WHERE id = ?1",
+                "SELECT id, root_path, started, ended, active, ignore_patterns, store_deltas, content_root,
+                        redact_patterns, include_patterns, metadata_schema, hash_algorithm FROM sessions WHERE id = ?1",
                 params![id.to_string()],
                 |row| self.session_from_row(row),
             )
@@ -134,14 +543,194 @@ impl Storage {
     pub fn get_active_session(&self) -> Result<Session> {
         self.conn
             .query_row(
-                "SELECT id, root_path, started, ended, active, ignore_patterns FROM sessions WHERE active = 1 LIMIT 1",
+                "SELECT id, root_path, started, ended, active, ignore_patterns, store_deltas, content_root,
+                        redact_patterns, include_patterns, metadata_schema, hash_algorithm FROM sessions WHERE active = 1
+                 ORDER BY started DESC, rowid DESC LIMIT 1",
                 [],
                 |row| self.session_from_row(row),
             )
             .map_err(|_| Error::NoActiveSession)
     }
 
+    /// End every active session but the most recently started one, so
+    /// [`Storage::get_active_session`] has only one to choose from. Returns
+    /// the sessions that were ended.
+    pub fn repair_active_sessions(&self) -> Result<Vec<Session>> {
+        self.batch(|storage| {
+            let mut stmt = storage.conn.prepare(
+                "SELECT id, root_path, started, ended, active, ignore_patterns, store_deltas, content_root,
+                        redact_patterns, include_patterns, metadata_schema, hash_algorithm FROM sessions
+                 WHERE active = 1 ORDER BY started DESC, rowid DESC",
+            )?;
+            let stale = stmt
+                .query_map([], |row| storage.session_from_row(row))?
+                .skip(1)
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            let mut ended = Vec::new();
+            for mut session in stale {
+                session.end();
+                storage.write_session(&session)?;
+                ended.push(session);
+            }
+            storage.log_event(|| LogEvent::ActiveSessionsRepaired {
+                ended: ended.clone(),
+            })?;
+            Ok(ended)
+        })
+    }
+
+    /// Every session, oldest first.
+    pub fn get_sessions(&self) -> Result<Vec<Session>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, root_path, started, ended, active, ignore_patterns, store_deltas, content_root,
+                    redact_patterns, include_patterns, metadata_schema, hash_algorithm FROM sessions ORDER BY started, rowid",
+        )?;
+        let sessions = stmt
+            .query_map([], |row| self.session_from_row(row))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(sessions)
+    }
+
+    /// Fold the history of session `from` into session `into`, for work that
+    /// was split across two sessions, and remove `from`.
+    ///
+    /// Changes keep the order they were recorded in. All commits of both
+    /// sessions are chained into one line by timestamp, each the parent of
+    /// the next, so forks within either session are flattened. Both sessions
+    /// must store paths relative to the same directory.
+    pub fn merge_sessions(&self, into: &Uuid, from: &Uuid) -> Result<MergeReport> {
+        if into == from {
+            return Err(Error::InvalidOperation(
+                "Can't merge a session into itself".to_string(),
+            ));
+        }
+        let target = self.get_session(into)?;
+        let source = self.get_session(from)?;
+        if target.content_root() != source.content_root() {
+            return Err(Error::InvalidOperation(format!(
+                "Sessions track different directories: {} and {}",
+                target.content_root().display(),
+                source.content_root().display()
+            )));
+        }
+
+        let labels: Vec<String> = self
+            .conn
+            .prepare(
+                "SELECT label FROM checkpoints WHERE session_id = ?1
+                 INTERSECT SELECT label FROM checkpoints WHERE session_id = ?2",
+            )?
+            .query_map(params![into.to_string(), from.to_string()], |row| {
+                row.get(0)
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        if !labels.is_empty() {
+            return Err(Error::InvalidOperation(format!(
+                "Both sessions have checkpoint(s) named {}",
+                labels.join(", ")
+            )));
+        }
+
+        let overlapping_paths: Vec<PathBuf> = self
+            .conn
+            .prepare(
+                "SELECT path FROM changes WHERE session_id = ?1
+                 INTERSECT SELECT path FROM changes WHERE session_id = ?2
+                 ORDER BY path",
+            )?
+            .query_map(params![into.to_string(), from.to_string()], |row| {
+                row.get::<_, String>(0)
+            })?
+            .map(|path| path.map(PathBuf::from))
+            .collect::<rusqlite::Result<_>>()?;
+
+        let tx = self.conn.unchecked_transaction()?;
+        let changes_moved = tx.execute(
+            "UPDATE changes SET session_id = ?1 WHERE session_id = ?2",
+            params![into.to_string(), from.to_string()],
+        )?;
+        let commits_moved = tx.execute(
+            "UPDATE commits SET session_id = ?1 WHERE session_id = ?2",
+            params![into.to_string(), from.to_string()],
+        )?;
+        for table in ["snapshots", "checkpoints"] {
+            tx.execute(
+                &format!("UPDATE {} SET session_id = ?1 WHERE session_id = ?2", table),
+                params![into.to_string(), from.to_string()],
+            )?;
+        }
+
+        let commits: Vec<String> = tx
+            .prepare(
+                "SELECT id FROM commits WHERE session_id = ?1
+                 ORDER BY julianday(timestamp), rowid",
+            )?
+            .query_map(params![into.to_string()], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut parent: Option<&String> = None;
+        for id in &commits {
+            tx.execute(
+                "UPDATE commits SET parent = ?1 WHERE id = ?2",
+                params![parent, id],
+            )?;
+            parent = Some(id);
+        }
+
+        // The merged session covers both, and carries on if either was active
+        let started = target.started.min(source.started);
+        let (active, ended) = if target.active || source.active {
+            (true, None)
+        } else {
+            (false, target.ended.max(source.ended))
+        };
+        tx.execute(
+            "UPDATE sessions SET started = ?1, ended = ?2, active = ?3 WHERE id = ?4",
+            params![
+                started.to_rfc3339(),
+                ended.map(|dt| dt.to_rfc3339()),
+                active as i32,
+                into.to_string(),
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM sessions WHERE id = ?1",
+            params![from.to_string()],
+        )?;
+        tx.commit()?;
+
+        self.log_event(|| LogEvent::SessionsMerged {
+            into: *into,
+            from: *from,
+        })?;
+        Ok(MergeReport {
+            changes_moved,
+            commits_moved,
+            overlapping_paths,
+        })
+    }
+
+    /// End every active session and create `session` in their place.
+    pub fn start_session(&self, session: &Session) -> Result<()> {
+        self.batch(|storage| {
+            for mut active in storage.get_sessions()?.into_iter().filter(|s| s.active) {
+                active.end();
+                storage.update_session(&active)?;
+            }
+            storage.create_session(session)
+        })
+    }
+
     pub fn update_session(&self, session: &Session) -> Result<()> {
+        self.write_session(session)?;
+        self.log_event(|| LogEvent::SessionUpdated {
+            session: session.clone(),
+        })
+    }
+
+    /// [`Storage::update_session`] without logging it, for writes logged as
+    /// part of a larger event.
+    fn write_session(&self, session: &Session) -> Result<()> {
         let ignore_patterns = serde_json::to_string(&session.ignore_patterns)?;
 
         self.conn.execute(
@@ -153,19 +742,97 @@ impl Storage {
                 session.id.to_string(),
             ],
         )?;
-
         Ok(())
     }
 
     // Change operations
-    pub fn create_change(&self, change: &Change) -> Result<()> {
+
+    /// Store a change, returning the sequence number it was given.
+    ///
+    /// Files matching the session's redact patterns are stored with their
+    /// content replaced by a placeholder.
+    pub fn create_change(&self, change: &Change) -> Result<i64> {
+        self.check_change_metadata(change)?;
+        self.insert_change(change)
+    }
+
+    /// [`Storage::create_change`] for a change captured from the filesystem,
+    /// which has no metadata of its own to hold to the session's schema.
+    pub fn create_captured_change(&self, change: &Change) -> Result<i64> {
+        self.insert_change(change)
+    }
+
+    fn check_change_metadata(&self, change: &Change) -> Result<()> {
+        let Some(schema) = self.metadata_schema(&change.session_id)? else {
+            return Ok(());
+        };
+        let problems = schema.change_problems(&change.metadata);
+        check_metadata(
+            &schema,
+            &format!("change to {}", change.path.display()),
+            problems,
+        )
+    }
+
+    /// [`Storage::create_change`] without the metadata check, for changes
+    /// already accepted once, such as those replayed from the event log.
+    pub(crate) fn insert_change(&self, change: &Change) -> Result<i64> {
+        change.validate()?;
+        // Content is hashed the session's way before any of it is redacted
+        let algorithm = self.hash_algorithm(&change.session_id)?;
+        let prepared;
+        let change = match (self.should_redact(change)?, change.hashed_with(algorithm)) {
+            (false, true) => change,
+            (redact, _) => {
+                let rehashed = change.clone().with_hash_algorithm(algorithm);
+                prepared = if redact { rehashed.redact() } else { rehashed };
+                &prepared
+            }
+        };
         let metadata = serde_json::to_string(&change.metadata)?;
 
-        self.conn.execute(
+        // Content over the external threshold goes to the object store whole,
+        // with only the object names kept here
+        let external = self.store_externally(change)?;
+
+        // Sessions storing deltas keep text modifies as deltas and binary
+        // content deflated
+        let stores_deltas = external.is_none() && self.stores_deltas(&change.session_id)?;
+        let delta = if stores_deltas {
+            self.delta_for(change)?
+        } else {
+            None
+        };
+        let compressed = (stores_deltas && delta.is_none() && change.is_binary()).then(|| {
+            (
+                change.content_before.as_deref().map(compress),
+                change.content_after.as_deref().map(compress),
+            )
+        });
+        let (content_before, content_after, delta_base) = match (&external, &delta, &compressed) {
+            (Some(names), _, _) => {
+                let (before, after) = full_content(change, Some(names));
+                (before, after, None)
+            }
+            (None, Some((base_id, delta)), _) => {
+                (None, Some(delta.as_slice()), Some(base_id.to_string()))
+            }
+            (None, None, Some((before, after))) => (before.as_deref(), after.as_deref(), None),
+            (None, None, None) => (
+                change.content_before.as_deref(),
+                change.content_after.as_deref(),
+                None,
+            ),
+        };
+
+        let seq = self.conn.query_row(
             "INSERT INTO changes (id, session_id, timestamp, change_type, path, old_path,
                                   content_before, content_after, content_hash_before, content_hash_after,
-                                  agent_id, metadata)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                                  agent_id, metadata, delta_base, tool, language, mtime_before,
+                                  mtime_after, compressed, external, seq)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                     ?18, ?19, (SELECT IFNULL(MAX(seq), 0) + 1 FROM changes))
+             RETURNING seq",
             params![
                 change.id.to_string(),
                 change.session_id.to_string(),
@@ -173,281 +840,4906 @@ impl Storage {
                 change.change_type.as_str(),
                 change.path.to_string_lossy().as_ref(),
                 change.old_path.as_ref().map(|p| p.to_string_lossy().to_string()),
-                change.content_before.as_ref(),
-                change.content_after.as_ref(),
+                content_before,
+                content_after,
                 change.content_hash_before.as_ref(),
                 change.content_hash_after.as_ref(),
                 change.agent_id.as_ref(),
                 metadata,
+                delta_base,
+                change.tool.as_ref(),
+                change.language.as_ref(),
+                change.mtime_before.as_ref().map(mtime_to_sql),
+                change.mtime_after.as_ref().map(mtime_to_sql),
+                compressed.is_some(),
+                external.is_some(),
             ],
+            |row| row.get(0),
         )?;
 
-        Ok(())
+        self.log_event(|| match &external {
+            Some(names) => LogEvent::ChangeCreated {
+                change: change.without_content(),
+                objects: Some(names.clone()),
+            },
+            None => LogEvent::ChangeCreated {
+                change: change.clone(),
+                objects: None,
+            },
+        })?;
+        Ok(seq)
     }
 
-    pub fn get_change(&self, id: &Uuid) -> Result<Change> {
-        self.conn
-            .query_row(
-                "SELECT id, session_id, timestamp, change_type, path, old_path,
-                        content_before, content_after, content_hash_before, content_hash_after,
-                        agent_id, metadata FROM changes WHERE id = ?1",
-                params![id.to_string()],
-                |row| self.change_from_row(row),
-            )
-            .map_err(|_| Error::ChangeNotFound(id.to_string()))
+    /// The change as [`Storage::create_change`] would store it, without
+    /// storing it: validated, its metadata checked against the session's
+    /// schema, and redacted if its session asks for that.
+    pub fn preview_change(&self, change: Change) -> Result<Change> {
+        change.validate()?;
+        self.check_change_metadata(&change)?;
+        let algorithm = self.hash_algorithm(&change.session_id)?;
+        let change = change.with_hash_algorithm(algorithm);
+        if self.should_redact(&change)? {
+            Ok(change.redact())
+        } else {
+            Ok(change)
+        }
     }
 
-    pub fn get_uncommitted_changes(&self, session_id: &Uuid) -> Result<Vec<Change>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT c.id, c.session_id, c.timestamp, c.change_type, c.path, c.old_path,
-                    c.content_before, c.content_after, c.content_hash_before, c.content_hash_after,
-                    c.agent_id, c.metadata
-             FROM changes c
-             WHERE c.session_id = ?1 AND c.id NOT IN (
-                 SELECT change_id FROM commit_changes
-             )
-             ORDER BY c.timestamp DESC",
-        )?;
+    /// Split an uncommitted modify in two at `partial`, an intermediate
+    /// version of the file.
+    ///
+    /// The original change keeps its id and now ends at `partial`; a new
+    /// change takes the file from `partial` to the original content. Returns
+    /// both, in that order.
+    pub fn split_change(&self, change: &Change, partial: Vec<u8>) -> Result<(Change, Change)> {
+        if change.change_type != ChangeType::Modify {
+            return Err(Error::InvalidOperation(format!(
+                "Only modifications can be split, {} is a {}",
+                change.id,
+                change.change_type.as_str()
+            )));
+        }
 
-        let changes = stmt
-            .query_map(params![session_id.to_string()], |row| {
-                self.change_from_row(row)
-            })?
-            .collect::<rusqlite::Result<Vec<Change>>>()?;
+        self.ensure_editable(&change.id)?;
 
-        Ok(changes)
+        let algorithm = self.hash_algorithm(&change.session_id)?;
+        let change = change.clone().with_hash_algorithm(algorithm);
+        let first = change
+            .clone()
+            .with_content_after_hashed(partial.clone(), algorithm);
+        let mut rest = change.with_content_before_hashed(partial, algorithm);
+        rest.id = Uuid::new_v4();
+
+        let external = self.store_externally(&first)?;
+        let (content_before, content_after) = full_content(&first, external.as_ref());
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE changes SET content_before = ?1, content_after = ?2,
+                                content_hash_after = ?3, delta_base = NULL, compressed = 0,
+                                external = ?4
+             WHERE id = ?5",
+            params![
+                content_before,
+                content_after,
+                first.content_hash_after,
+                external.is_some(),
+                first.id.to_string(),
+            ],
+        )?;
+        self.create_change(&rest)?;
+        tx.commit()?;
+
+        self.log_event(|| content_replaced(&first, external.as_ref()))?;
+        Ok((first, rest))
     }
 
-    // Commit operations
-    pub fn create_commit(&self, commit: &Commit) -> Result<()> {
-        let metadata = serde_json::to_string(&commit.metadata)?;
+    /// Replace the after-content of an uncommitted change, for content
+    /// uploaded separately from the change itself. Returns the updated change.
+    pub fn set_content_after(&self, id: &Uuid, content: Vec<u8>) -> Result<Change> {
+        self.ensure_editable(id)?;
+        let change = self.get_change(id)?;
+        let algorithm = self.hash_algorithm(&change.session_id)?;
+        let mut change = change
+            .with_hash_algorithm(algorithm)
+            .with_content_after_hashed(content, algorithm);
+        change.validate()?;
+        if self.should_redact(&change)? {
+            change = change.redact();
+        }
 
+        // Stored in full, as the before-content may have come from a delta base
+        let external = self.store_externally(&change)?;
+        let (content_before, content_after) = full_content(&change, external.as_ref());
         self.conn.execute(
-            "INSERT INTO commits (id, session_id, parent, timestamp, message, agent_id, metadata)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            "UPDATE changes SET content_before = ?1, content_after = ?2,
+                                content_hash_after = ?3, language = ?4, delta_base = NULL,
+                                compressed = 0, external = ?5
+             WHERE id = ?6",
             params![
-                commit.id.to_string(),
-                commit.session_id.to_string(),
-                commit.parent.as_ref().map(|p| p.to_string()),
-                commit.timestamp.to_rfc3339(),
-                commit.message,
-                commit.agent_id,
-                metadata,
+                content_before,
+                content_after,
+                change.content_hash_after,
+                change.language,
+                external.is_some(),
+                change.id.to_string(),
             ],
         )?;
 
-        for change_id in &commit.changes {
-            self.conn.execute(
-                "INSERT INTO commit_changes (commit_id, change_id) VALUES (?1, ?2)",
-                params![commit.id.to_string(), change_id.to_string()],
-            )?;
+        self.log_event(|| content_replaced(&change, external.as_ref()))?;
+        Ok(change)
+    }
+
+    /// The metadata schema of a session, if it has one.
+    fn metadata_schema(&self, session_id: &Uuid) -> Result<Option<MetadataSchema>> {
+        let schema: Option<Option<String>> = self
+            .conn
+            .query_row(
+                "SELECT metadata_schema FROM sessions WHERE id = ?1",
+                params![session_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(match schema.flatten() {
+            Some(schema) => Some(serde_json::from_str(&schema)?),
+            None => None,
+        })
+    }
+
+    /// Whether a change is to a file matching its session's redact patterns.
+    fn should_redact(&self, change: &Change) -> Result<bool> {
+        let patterns: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT redact_patterns FROM sessions WHERE id = ?1",
+                params![change.session_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let patterns: Vec<String> = match patterns {
+            Some(patterns) => serde_json::from_str(&patterns)?,
+            None => return Ok(false),
+        };
+        if patterns.is_empty() {
+            return Ok(false);
         }
 
+        let matcher = IgnoreMatcher::new(&patterns)?;
+        Ok(std::iter::once(&change.path)
+            .chain(&change.old_path)
+            .any(|path| matcher.is_ignored(path)))
+    }
+
+    /// Fail unless the change is uncommitted and no other change is stored as
+    /// a delta against it, so rewriting its content can't break history.
+    fn ensure_editable(&self, id: &Uuid) -> Result<()> {
+        let (links, dependents): (i64, i64) = self.conn.query_row(
+            "SELECT (SELECT COUNT(*) FROM commit_changes WHERE change_id = ?1),
+                    (SELECT COUNT(*) FROM changes WHERE delta_base = ?1)",
+            params![id.to_string()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        if links > 0 || dependents > 0 {
+            return Err(Error::InvalidOperation(format!(
+                "Change {} is committed or has later changes built on it",
+                id
+            )));
+        }
         Ok(())
     }
 
-    pub fn get_commit(&self, id: &Uuid) -> Result<Commit> {
-        let commit = self
+    /// How a session hashes content; the default for unknown sessions.
+    fn hash_algorithm(&self, session_id: &Uuid) -> Result<HashAlgorithm> {
+        let algorithm: Option<String> = self
             .conn
             .query_row(
-                "SELECT id, session_id, parent, timestamp, message, agent_id, metadata
-                 FROM commits WHERE id = ?1",
-                params![id.to_string()],
-                |row| self.commit_from_row(row),
+                "SELECT hash_algorithm FROM sessions WHERE id = ?1",
+                params![session_id.to_string()],
+                |row| row.get(0),
             )
-            .map_err(|_| Error::CommitNotFound(id.to_string()))?;
+            .optional()?;
+        Ok(algorithm
+            .and_then(|algorithm| HashAlgorithm::parse(&algorithm))
+            .unwrap_or_default())
+    }
 
-        Ok(commit)
+    /// Whether the session stores changes as deltas where it can.
+    fn stores_deltas(&self, session_id: &Uuid) -> Result<bool> {
+        let store_deltas: Option<i32> = self
+            .conn
+            .query_row(
+                "SELECT store_deltas FROM sessions WHERE id = ?1",
+                params![session_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(store_deltas.unwrap_or(0) != 0)
     }
 
-    pub fn get_commits_for_session(&self, session_id: &Uuid) -> Result<Vec<CommitInfo>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, session_id, parent, timestamp, message, agent_id, metadata
-             FROM commits WHERE session_id = ?1 ORDER BY timestamp DESC",
-        )?;
+    /// Encode a text modify against the previous version of the same path.
+    /// Returns the base change id and the delta, or `None` when the full
+    /// content should be stored instead.
+    fn delta_for(&self, change: &Change) -> Result<Option<(Uuid, Vec<u8>)>> {
+        let content_after = match (&change.change_type, &change.content_after) {
+            (ChangeType::Modify, Some(content)) if !change.is_binary() => content,
+            _ => return Ok(None),
+        };
 
-        let mut commits = Vec::new();
-        let rows = stmt.query_map(params![session_id.to_string()], |row| {
-            self.commit_from_row(row)
-        })?;
+        let base_id: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT id FROM changes
+                 WHERE session_id = ?1 AND path = ?2 AND content_after IS NOT NULL
+                 ORDER BY timestamp DESC, rowid DESC LIMIT 1",
+                params![
+                    change.session_id.to_string(),
+                    change.path.to_string_lossy().as_ref()
+                ],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(base_id) = base_id else {
+            return Ok(None);
+        };
 
-        for commit_result in rows {
-            let commit = commit_result?;
-            let info = self.get_commit_info(&commit)?;
-            commits.push(info);
+        let (base, depth) = self.resolve_content_after(&base_id)?;
+        let Some(base) = base else {
+            return Ok(None);
+        };
+
+        // The before-content is reconstructed from the base on read, so it
+        // has to actually be the base
+        let before_matches = change
+            .content_before
+            .as_ref()
+            .is_none_or(|before| *before == base);
+        if !before_matches || depth >= MAX_DELTA_CHAIN {
+            return Ok(None);
         }
 
-        Ok(commits)
+        let delta = delta::encode(&base, content_after);
+        if delta.len() >= content_after.len() {
+            return Ok(None);
+        }
+
+        Ok(Some((Uuid::parse_str(&base_id).unwrap(), delta)))
     }
 
-    fn get_commit_info(&self, commit: &Commit) -> Result<CommitInfo> {
-        let changes: Vec<Change> = commit
-            .changes
-            .iter()
-            .filter_map(|id| self.get_change(id).ok())
-            .collect();
+    /// Reconstruct the stored after-content of a change, following any delta
+    /// chain. Also returns the chain length.
+    fn resolve_content_after(&self, change_id: &str) -> Result<(Option<Vec<u8>>, usize)> {
+        self.check_cancelled()?;
+        let (content_after, delta_base, compressed, external): (
+            Option<Vec<u8>>,
+            Option<String>,
+            bool,
+            bool,
+        ) = self.conn.query_row(
+            "SELECT content_after, delta_base, compressed, external FROM changes WHERE id = ?1",
+            params![change_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
 
-        let files_affected: Vec<PathBuf> = changes.iter().map(|c| c.path.clone()).collect();
+        match (delta_base, content_after) {
+            (Some(base_id), Some(delta)) => {
+                let (base, depth) = self.resolve_content_after(&base_id)?;
+                let base = base.ok_or_else(|| Error::ChangeNotFound(base_id.clone()))?;
+                Ok((Some(delta::apply(&base, &delta)?), depth + 1))
+            }
+            (_, Some(content)) if compressed => Ok((Some(decompress(&content)?), 0)),
+            (_, Some(name)) if external => Ok((Some(self.load_object(&name)?), 0)),
+            (_, content_after) => Ok((content_after, 0)),
+        }
+    }
 
-        Ok(CommitInfo {
-            commit: commit.clone(),
-            change_count: changes.len(),
-            files_affected,
-        })
+    /// The object store to keep `change`'s content in, if it's over the
+    /// external threshold.
+    fn external_store_for(&self, change: &Change) -> Option<&ObjectStore> {
+        let threshold = self.external_threshold?;
+        let oversized = [&change.content_before, &change.content_after]
+            .into_iter()
+            .flatten()
+            .any(|content| content.len() as u64 > threshold);
+        oversized.then_some(self.objects.as_ref()).flatten()
     }
 
-    // Helper methods
-    fn session_from_row(&self, row: &Row) -> rusqlite::Result<Session> {
-        let id: String = row.get(0)?;
-        let root_path: String = row.get(1)?;
-        let started: String = row.get(2)?;
-        let ended: Option<String> = row.get(3)?;
-        let active: i32 = row.get(4)?;
-        let ignore_patterns: String = row.get(5)?;
+    /// Put `change`'s content in the object store if it's over the external
+    /// threshold, returning the names it's stored under.
+    fn store_externally(&self, change: &Change) -> Result<Option<ObjectNames>> {
+        let Some(objects) = self.external_store_for(change) else {
+            return Ok(None);
+        };
+        let put = |content: &Option<Vec<u8>>| {
+            content
+                .as_deref()
+                .map(|content| objects.put(content))
+                .transpose()
+        };
+        Ok(Some(ObjectNames {
+            before: put(&change.content_before)?,
+            after: put(&change.content_after)?,
+        }))
+    }
 
-        Ok(Session {
-            id: Uuid::parse_str(&id).unwrap(),
-            root_path: PathBuf::from(root_path),
-            started: DateTime::parse_from_rfc3339(&started).unwrap().into(),
-            ended: ended.and_then(|s| DateTime::parse_from_rfc3339(&s).ok().map(|dt| dt.into())),
-            active: active != 0,
-            ignore_patterns: serde_json::from_str(&ignore_patterns).unwrap_or_default(),
-        })
+    /// Content kept in the object store under the name stored in a row.
+    pub(crate) fn load_object(&self, name: &[u8]) -> Result<Vec<u8>> {
+        let objects = self.objects.as_ref().ok_or_else(|| {
+            Error::InvalidOperation("This database has no object store".to_string())
+        })?;
+        objects.get(&String::from_utf8_lossy(name))
     }
 
-    fn change_from_row(&self, row: &Row) -> rusqlite::Result<Change> {
-        let id: String = row.get(0)?;
-        let session_id: String = row.get(1)?;
-        let timestamp: String = row.get(2)?;
-        let change_type: String = row.get(3)?;
-        let path: String = row.get(4)?;
-        let old_path: Option<String> = row.get(5)?;
-        let content_before: Option<Vec<u8>> = row.get(6)?;
-        let content_after: Option<Vec<u8>> = row.get(7)?;
-        let content_hash_before: Option<String> = row.get(8)?;
-        let content_hash_after: Option<String> = row.get(9)?;
-        let agent_id: Option<String> = row.get(10)?;
-        let metadata: String = row.get(11)?;
+    /// [`Storage::load_object`] for a name that may be missing.
+    pub(crate) fn load_object_named(&self, name: Option<String>) -> Result<Option<Vec<u8>>> {
+        name.map(|name| self.load_object(name.as_bytes()))
+            .transpose()
+    }
 
-        Ok(Change {
-            id: Uuid::parse_str(&id).unwrap(),
-            timestamp: DateTime::parse_from_rfc3339(&timestamp).unwrap().into(),
-            change_type: ChangeType::parse(&change_type).unwrap(),
-            path: PathBuf::from(path),
-            old_path: old_path.map(PathBuf::from),
-            content_before,
-            content_after,
-            content_hash_before,
-            content_hash_after,
-            agent_id,
-            metadata: serde_json::from_str(&metadata).unwrap_or_default(),
-            session_id: Uuid::parse_str(&session_id).unwrap(),
+    /// One side of a change's content, for sending on without holding all of
+    /// it in memory where that can be helped: content in the object store is
+    /// opened there, and anything else is loaded as [`Storage::get_change`]
+    /// would.
+    pub fn open_content(&self, id: &Uuid, before: bool) -> Result<Option<StoredContent>> {
+        let column = if before {
+            "content_before"
+        } else {
+            "content_after"
+        };
+        let stored: Option<(Option<Vec<u8>>, bool, bool)> = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT {}, external, delta_base IS NOT NULL FROM changes WHERE id = ?1",
+                    column
+                ),
+                params![id.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let Some((content, external, delta)) = stored else {
+            return Err(Error::ChangeNotFound(id.to_string()));
+        };
+
+        match (content, &self.objects) {
+            (Some(name), Some(objects)) if external && !delta => {
+                let file = objects.open(&String::from_utf8_lossy(&name))?;
+                Ok(Some(StoredContent::Object(file)))
+            }
+            _ => {
+                let change = self.get_change(id)?;
+                let content = if before {
+                    change.content_before
+                } else {
+                    change.content_after
+                };
+                Ok(content.map(StoredContent::Loaded))
+            }
+        }
+    }
+
+    /// Delete objects from the object store that no change refers to
+    /// anymore. Objects are shared by every change with the same content, so
+    /// one is only deleted once the last of them is gone.
+    pub fn sweep_objects(&self) -> Result<Vec<StoredObject>> {
+        let Some(objects) = &self.objects else {
+            return Ok(Vec::new());
+        };
+        let mut stmt = self
+            .conn
+            .prepare("SELECT content_before, content_after FROM changes WHERE external = 1")?;
+        let mut in_use = HashSet::new();
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Option<Vec<u8>>>(0)?,
+                row.get::<_, Option<Vec<u8>>>(1)?,
+            ))
+        })?;
+        for row in rows {
+            let (before, after) = row?;
+            in_use.extend(
+                [before, after]
+                    .into_iter()
+                    .flatten()
+                    .map(|name| String::from_utf8_lossy(&name).into_owned()),
+            );
+        }
+        objects.sweep(SystemTime::now(), |name| in_use.contains(name))
+    }
+
+    pub fn get_change(&self, id: &Uuid) -> Result<Change> {
+        self.conn
+            .query_row(
+                "SELECT id, session_id, timestamp, change_type, path, old_path,
+                        content_before, content_after, content_hash_before, content_hash_after,
+                        agent_id, metadata, delta_base, tool, seq, language, mtime_before, mtime_after,
+                        compressed, external
+                 FROM changes WHERE id = ?1",
+                params![id.to_string()],
+                |row| self.change_from_row(row),
+            )
+            .map_err(|e| match Error::from(e) {
+                e if e.is_cancellation() => e,
+                _ => Error::ChangeNotFound(id.to_string()),
+            })
+    }
+
+    /// The paths of the changes with these ids, read without their content.
+    /// Ids with no change are left out.
+    pub fn get_change_paths(&self, ids: &[Uuid]) -> Result<HashMap<Uuid, PathBuf>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, path FROM changes WHERE id IN ({})",
+            placeholders(ids.len())
+        ))?;
+        let paths = stmt
+            .query_map(
+                params_from_iter(ids.iter().map(|id| id.to_string())),
+                |row| {
+                    let id: String = row.get(0)?;
+                    let path: String = row.get(1)?;
+                    Ok((Uuid::parse_str(&id).unwrap(), PathBuf::from(path)))
+                },
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    /// Get a change with its before-content filled in, when it wasn't
+    /// captured, from the after-content of the most recent earlier change to
+    /// the same file in the session, committed or not. This makes the change
+    /// diffable on its own.
+    ///
+    /// Creates, and changes with no earlier change to draw on, are returned
+    /// as stored.
+    pub fn get_change_with_context(&self, id: &Uuid) -> Result<Change> {
+        let change = self.get_change(id)?;
+        if change.content_before.is_some() || change.change_type == ChangeType::Create {
+            return Ok(change);
+        }
+        let Some(seq) = change.seq else {
+            return Ok(change);
+        };
+
+        // A rename's content came from its old path
+        let path = change.old_path.as_ref().unwrap_or(&change.path);
+        let previous: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT id FROM changes
+                 WHERE session_id = ?1 AND path = ?2 AND seq < ?3
+                 ORDER BY seq DESC LIMIT 1",
+                params![
+                    change.session_id.to_string(),
+                    path.to_string_lossy().as_ref(),
+                    seq
+                ],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(previous) = previous else {
+            return Ok(change);
+        };
+
+        match self
+            .get_change(&Uuid::parse_str(&previous).unwrap())?
+            .content_after
+        {
+            Some(content) => {
+                let algorithm = self.hash_algorithm(&change.session_id)?;
+                Ok(change.with_content_before_hashed(content, algorithm))
+            }
+            None => Ok(change),
+        }
+    }
+
+    /// Every version of the file now at `path`, oldest first, following it
+    /// back through renames.
+    pub fn get_file_versions(&self, session_id: &Uuid, path: &Path) -> Result<Vec<FileVersion>> {
+        let mut versions = Vec::new();
+        self.for_each_file_version(session_id, path, true, |version| {
+            versions.push(version);
+            Ok(())
+        })?;
+        Ok(versions)
+    }
+
+    /// Like [`Storage::get_file_versions`], but handing over one version at a
+    /// time, so only one version's content is in memory at once. Content is
+    /// only loaded `with_content`.
+    pub fn for_each_file_version(
+        &self,
+        session_id: &Uuid,
+        path: &Path,
+        with_content: bool,
+        mut f: impl FnMut(FileVersion) -> Result<()>,
+    ) -> Result<()> {
+        // Walked newest first, moving to the old path at each rename
+        let mut stmt = self.conn.prepare(
+            "SELECT c.id, c.timestamp, c.change_type, c.path, c.old_path, c.seq, cc.commit_id
+             FROM changes c
+             LEFT JOIN commit_changes cc ON cc.change_id = c.id
+             WHERE c.session_id = ?1 AND c.path = ?2 AND c.seq < ?3
+             ORDER BY c.seq DESC",
+        )?;
+        let mut versions = Vec::new();
+        let mut path = path.to_string_lossy().to_string();
+        let mut before = i64::MAX;
+        loop {
+            let rows = stmt
+                .query_map(params![session_id.to_string(), path, before], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get::<_, Option<String>>(4)?,
+                        row.get::<_, i64>(5)?,
+                        row.get::<_, Option<String>>(6)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            let mut renamed_from = None;
+            for (id, timestamp, change_type, change_path, old_path, seq, commit_id) in rows {
+                let change_type = ChangeType::parse(&change_type).unwrap_or(ChangeType::Modify);
+                versions.push(FileVersion {
+                    change_id: Uuid::parse_str(&id).unwrap(),
+                    commit_id: commit_id.map(|id| Uuid::parse_str(&id).unwrap()),
+                    timestamp: DateTime::parse_from_rfc3339(&timestamp).unwrap().into(),
+                    change_type,
+                    path: PathBuf::from(change_path),
+                    content: None,
+                });
+                if change_type == ChangeType::Rename {
+                    renamed_from = old_path.map(|old_path| (old_path, seq));
+                    break;
+                }
+            }
+            match renamed_from {
+                Some((old_path, seq)) => {
+                    path = old_path;
+                    before = seq;
+                }
+                None => break,
+            }
+        }
+
+        for mut version in versions.into_iter().rev() {
+            if with_content && version.change_type != ChangeType::Delete {
+                // Skips loading the before-content a whole change would carry
+                version.content = self
+                    .resolve_content_after(&version.change_id.to_string())?
+                    .0;
+            }
+            f(version)?;
+        }
+        Ok(())
+    }
+
+    /// The content `path` was last recorded with in a session, or `None` if
+    /// it was deleted or moved away, or its content wasn't stored.
+    pub fn get_latest_content(&self, session_id: &Uuid, path: &Path) -> Result<Option<Vec<u8>>> {
+        let path = path.to_string_lossy();
+        let latest: Option<(String, String)> = self
+            .conn
+            .query_row(
+                "SELECT id, path FROM changes
+                 WHERE session_id = ?1 AND (path = ?2 OR old_path = ?2)
+                 ORDER BY seq DESC LIMIT 1",
+                params![session_id.to_string(), path.as_ref()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        match latest {
+            // Anything else is a rename away from the path
+            Some((id, latest_path)) if latest_path == path => Ok(self
+                .get_change(&Uuid::parse_str(&id).unwrap())?
+                .content_after),
+            _ => Ok(None),
+        }
+    }
+
+    /// When `path` was last modified as of its latest change in a session, or
+    /// `None` if that wasn't recorded or the file was deleted or moved away.
+    pub fn get_latest_mtime(
+        &self,
+        session_id: &Uuid,
+        path: &Path,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let path = path.to_string_lossy();
+        let latest: Option<(String, Option<String>)> = self
+            .conn
+            .query_row(
+                "SELECT path, mtime_after FROM changes
+                 WHERE session_id = ?1 AND (path = ?2 OR old_path = ?2)
+                 ORDER BY seq DESC LIMIT 1",
+                params![session_id.to_string(), path.as_ref()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        Ok(match latest {
+            Some((latest_path, Some(mtime))) if latest_path == path => {
+                DateTime::parse_from_rfc3339(&mtime).ok().map(Into::into)
+            }
+            _ => None,
+        })
+    }
+
+    /// Start a query for changes in a session, newest first until told otherwise.
+    pub fn query_changes(&self, session_id: &Uuid) -> ChangeQuery<'_> {
+        ChangeQuery {
+            storage: self,
+            session_id: *session_id,
+            agent: None,
+            path_prefix: None,
+            change_type: None,
+            since: None,
+            until: None,
+            committed: None,
+            after_seq: None,
+            limit: None,
+            order: SortOrder::default(),
+        }
+    }
+
+    pub fn get_uncommitted_changes(&self, session_id: &Uuid) -> Result<Vec<Change>> {
+        self.query_changes(session_id).committed(false).fetch()
+    }
+
+    /// The latest `n` changes in a session, committed or not, newest first.
+    pub fn get_recent_changes(&self, session_id: &Uuid, n: usize) -> Result<Vec<Change>> {
+        self.query_changes(session_id).limit(n).fetch()
+    }
+
+    /// How many uncommitted changes a session has, without loading them.
+    pub fn get_uncommitted_count(&self, session_id: &Uuid) -> Result<usize> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM changes
+             WHERE session_id = ?1 AND id NOT IN (SELECT change_id FROM commit_changes)",
+            params![session_id.to_string()],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    /// The newest `limit` uncommitted changes, and how many there are in all.
+    pub fn get_uncommitted_changes_limited(
+        &self,
+        session_id: &Uuid,
+        limit: usize,
+    ) -> Result<(Vec<Change>, usize)> {
+        let changes = self
+            .query_changes(session_id)
+            .committed(false)
+            .limit(limit)
+            .fetch()?;
+        Ok((changes, self.get_uncommitted_count(session_id)?))
+    }
+
+    /// Get uncommitted changes recorded after the change numbered `after_seq`,
+    /// oldest first. Pass the last `seq` seen to fetch only newer changes.
+    pub fn get_uncommitted_changes_after(
+        &self,
+        session_id: &Uuid,
+        after_seq: i64,
+        limit: Option<usize>,
+    ) -> Result<Vec<Change>> {
+        let query = self
+            .query_changes(session_id)
+            .committed(false)
+            .after_seq(after_seq)
+            .order(SortOrder::Ascending);
+        match limit {
+            Some(limit) => query.limit(limit).fetch(),
+            None => query.fetch(),
+        }
+    }
+
+    /// Mark the current point in a session's history, so later changes can be
+    /// listed with [`Storage::diff_since_snapshot`].
+    pub fn snapshot_uncommitted(&self, session_id: &Uuid) -> Result<Uuid> {
+        let id = Uuid::new_v4();
+        let created_at = self.now();
+        let seq: i64 = self.conn.query_row(
+            "INSERT INTO snapshots (id, session_id, seq, created_at)
+             VALUES (?1, ?2, (SELECT IFNULL(MAX(seq), 0) FROM changes), ?3)
+             RETURNING seq",
+            params![
+                id.to_string(),
+                session_id.to_string(),
+                created_at.to_rfc3339()
+            ],
+            |row| row.get(0),
+        )?;
+
+        self.log_event(|| LogEvent::SnapshotTaken {
+            snapshot_id: id,
+            session_id: *session_id,
+            seq,
+            created_at,
+        })?;
+        Ok(id)
+    }
+
+    /// Put back a snapshot exactly as it was taken, for [`crate::event_log::replay`].
+    pub(crate) fn restore_snapshot(
+        &self,
+        snapshot_id: &Uuid,
+        session_id: &Uuid,
+        seq: i64,
+        created_at: &DateTime<Utc>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO snapshots (id, session_id, seq, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                snapshot_id.to_string(),
+                session_id.to_string(),
+                seq,
+                created_at.to_rfc3339()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Changes recorded in the snapshot's session since it was taken, oldest
+    /// first. Changes committed since then are included.
+    pub fn diff_since_snapshot(&self, snapshot_id: &Uuid) -> Result<Vec<Change>> {
+        let (session_id, seq): (String, i64) = self
+            .conn
+            .query_row(
+                "SELECT session_id, seq FROM snapshots WHERE id = ?1",
+                params![snapshot_id.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+            .ok_or_else(|| Error::SnapshotNotFound(snapshot_id.to_string()))?;
+
+        self.changes_after_seq(&session_id, seq)
+    }
+
+    /// Mark the current point in a session's history under `label`, so later
+    /// changes can be listed with [`Storage::changes_since_checkpoint`].
+    /// Labels are unique within a session.
+    pub fn create_checkpoint(&self, session_id: &Uuid, label: &str) -> Result<Checkpoint> {
+        if label.trim().is_empty() {
+            return Err(Error::InvalidOperation(
+                "Checkpoint label can't be empty".to_string(),
+            ));
+        }
+
+        let id = Uuid::new_v4();
+        let created_at = self.now();
+        let seq = self
+            .conn
+            .query_row(
+                "INSERT INTO checkpoints (id, session_id, label, seq, created_at)
+                 VALUES (?1, ?2, ?3, (SELECT IFNULL(MAX(seq), 0) FROM changes), ?4)
+                 ON CONFLICT (session_id, label) DO NOTHING
+                 RETURNING seq",
+                params![
+                    id.to_string(),
+                    session_id.to_string(),
+                    label,
+                    created_at.to_rfc3339()
+                ],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| {
+                Error::InvalidOperation(format!("Checkpoint {} already exists", label))
+            })?;
+
+        let checkpoint = Checkpoint {
+            id,
+            session_id: *session_id,
+            label: label.to_string(),
+            seq,
+            created_at,
+        };
+        self.log_event(|| LogEvent::CheckpointCreated {
+            checkpoint: checkpoint.clone(),
+        })?;
+        Ok(checkpoint)
+    }
+
+    /// Put back a checkpoint exactly as it was made, for [`crate::event_log::replay`].
+    pub(crate) fn restore_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO checkpoints (id, session_id, label, seq, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                checkpoint.id.to_string(),
+                checkpoint.session_id.to_string(),
+                checkpoint.label,
+                checkpoint.seq,
+                checkpoint.created_at.to_rfc3339()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_checkpoint(&self, session_id: &Uuid, label: &str) -> Result<Checkpoint> {
+        self.conn
+            .query_row(
+                "SELECT id, session_id, label, seq, created_at FROM checkpoints
+                 WHERE session_id = ?1 AND label = ?2",
+                params![session_id.to_string(), label],
+                |row| self.checkpoint_from_row(row),
+            )
+            .optional()?
+            .ok_or_else(|| Error::CheckpointNotFound(label.to_string()))
+    }
+
+    /// A session's checkpoints, oldest first.
+    pub fn list_checkpoints(&self, session_id: &Uuid) -> Result<Vec<Checkpoint>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, session_id, label, seq, created_at FROM checkpoints
+             WHERE session_id = ?1
+             ORDER BY seq ASC, created_at ASC",
+        )?;
+
+        let checkpoints = stmt
+            .query_map(params![session_id.to_string()], |row| {
+                self.checkpoint_from_row(row)
+            })?
+            .collect::<rusqlite::Result<Vec<Checkpoint>>>()?;
+
+        Ok(checkpoints)
+    }
+
+    /// Changes recorded in the session since the checkpoint named `label`,
+    /// oldest first. Changes committed since then are included.
+    pub fn changes_since_checkpoint(&self, session_id: &Uuid, label: &str) -> Result<Vec<Change>> {
+        let checkpoint = self.get_checkpoint(session_id, label)?;
+        self.changes_after_seq(&session_id.to_string(), checkpoint.seq)
+    }
+
+    /// A session's changes numbered after `seq`, oldest first.
+    fn changes_after_seq(&self, session_id: &str, seq: i64) -> Result<Vec<Change>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT c.id, c.session_id, c.timestamp, c.change_type, c.path, c.old_path,
+                    c.content_before, c.content_after, c.content_hash_before, c.content_hash_after,
+                    c.agent_id, c.metadata, c.delta_base, c.tool, c.seq, c.language,
+                    c.mtime_before, c.mtime_after, c.compressed, c.external
+             FROM changes c
+             WHERE c.session_id = ?1 AND c.seq > ?2
+             ORDER BY c.seq ASC",
+        )?;
+
+        let changes = stmt
+            .query_map(params![session_id, seq], |row| self.change_from_row(row))?
+            .collect::<rusqlite::Result<Vec<Change>>>()?;
+
+        Ok(changes)
+    }
+
+    /// Count the changes in a session per language, most changed first.
+    /// Files of unknown language are counted under `None`.
+    pub fn count_changes_by_language(
+        &self,
+        session_id: &Uuid,
+    ) -> Result<Vec<(Option<String>, usize)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT language, COUNT(*) FROM changes WHERE session_id = ?1
+             GROUP BY language ORDER BY COUNT(*) DESC, language",
+        )?;
+
+        let counts = stmt
+            .query_map(params![session_id.to_string()], |row| {
+                Ok((row.get(0)?, row.get::<_, i64>(1)? as usize))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(counts)
+    }
+
+    // Audit log operations
+
+    /// Record a write made through the API.
+    pub fn record_audit(&self, entry: &AuditEntry) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO audit (timestamp, method, path, status, agent_id, target, source_ip,
+                                request_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                entry.timestamp.to_rfc3339(),
+                entry.method,
+                entry.path,
+                entry.status,
+                entry.claimed_agent_id,
+                entry.target,
+                entry.source_ip,
+                entry.request_id,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The audit log, newest first, up to `limit` entries.
+    pub fn get_audit_log(&self, limit: Option<usize>) -> Result<Vec<AuditEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT timestamp, method, path, status, agent_id, target, source_ip, request_id
+             FROM audit ORDER BY id DESC LIMIT ?1",
+        )?;
+        let limit = limit.map_or(-1, |limit| limit as i64);
+
+        let entries = stmt
+            .query_map(params![limit], |row| {
+                let timestamp: String = row.get(0)?;
+                Ok(AuditEntry {
+                    timestamp: DateTime::parse_from_rfc3339(&timestamp).unwrap().into(),
+                    method: row.get(1)?,
+                    path: row.get(2)?,
+                    status: row.get(3)?,
+                    claimed_agent_id: row.get(4)?,
+                    target: row.get(5)?,
+                    source_ip: row.get(6)?,
+                    request_id: row.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(entries)
+    }
+
+    /// Remove audit entries older than `keep_days`, returning how many went.
+    pub fn prune_audit_log(&self, keep_days: u32) -> Result<usize> {
+        let cutoff = self.now() - chrono::Duration::days(keep_days.into());
+        let removed = self.conn.execute(
+            "DELETE FROM audit WHERE julianday(timestamp) < julianday(?1)",
+            params![cutoff.to_rfc3339()],
+        )?;
+        Ok(removed)
+    }
+
+    /// Get the most recent change for every path in a session, omitting paths
+    /// whose latest change deleted them or renamed them away.
+    pub fn latest_change_per_file(&self, session_id: &Uuid) -> Result<HashMap<PathBuf, Change>> {
+        self.latest_changes(session_id, TreeScope::All)
+    }
+
+    /// Every path a change in the session touched, including the old paths
+    /// of renames, sorted. Nothing is decoded, so this stays cheap.
+    pub fn list_tracked_paths(&self, session_id: &Uuid) -> Result<Vec<PathBuf>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT path FROM changes WHERE session_id = ?1
+             UNION SELECT old_path FROM changes WHERE session_id = ?1 AND old_path IS NOT NULL
+             ORDER BY 1",
+        )?;
+        let paths = stmt
+            .query_map(params![session_id.to_string()], |row| {
+                row.get::<_, String>(0).map(PathBuf::from)
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(paths)
+    }
+
+    /// [`Storage::list_tracked_paths`] with whether each path still exists
+    /// as of the latest change touching it.
+    pub fn list_tracked_path_statuses(&self, session_id: &Uuid) -> Result<Vec<TrackedPath>> {
+        // A rename touches its old path too, leaving nothing there
+        let mut stmt = self.conn.prepare(
+            "WITH touched AS (
+                 SELECT path, seq, change_type = 'delete' AS gone
+                 FROM changes WHERE session_id = ?1
+                 UNION ALL
+                 SELECT old_path, seq, 1
+                 FROM changes WHERE session_id = ?1 AND old_path IS NOT NULL
+             )
+             SELECT path, gone FROM (
+                 SELECT path, gone,
+                        ROW_NUMBER() OVER (PARTITION BY path ORDER BY seq DESC) AS rn
+                 FROM touched
+             )
+             WHERE rn = 1
+             ORDER BY path",
+        )?;
+        let paths = stmt
+            .query_map(params![session_id.to_string()], |row| {
+                let path: String = row.get(0)?;
+                let gone: bool = row.get(1)?;
+                Ok(TrackedPath {
+                    path: PathBuf::from(path),
+                    status: Some(if gone {
+                        PathStatus::Deleted
+                    } else {
+                        PathStatus::Live
+                    }),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(paths)
+    }
+
+    /// The latest `limit` changes and commits in a session, mixed together
+    /// newest first. A commit made at the same moment as a change is taken
+    /// to have come after it.
+    pub fn recent_activity(&self, session_id: &Uuid, limit: usize) -> Result<Vec<ActivityEvent>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT kind, id FROM (
+                 SELECT 'change' AS kind, id, timestamp AS time, seq AS tiebreak
+                 FROM changes WHERE session_id = ?1
+                 UNION ALL
+                 SELECT 'commit', id, timestamp, rowid
+                 FROM commits WHERE session_id = ?1
+             )
+             ORDER BY time DESC, kind DESC, tiebreak DESC
+             LIMIT ?2",
+        )?;
+        let entries = stmt
+            .query_map(params![session_id.to_string(), limit as i64], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        entries
+            .into_iter()
+            .map(|(kind, id)| {
+                let id = Uuid::parse_str(&id).unwrap();
+                Ok(match kind.as_str() {
+                    "commit" => ActivityEvent::Commit(self.get_commit(&id)?),
+                    _ => ActivityEvent::Change(self.get_change(&id)?),
+                })
+            })
+            .collect()
+    }
+
+    /// Like [`Storage::latest_change_per_file`], but only counting committed
+    /// changes: the tree as of the head commit.
+    pub fn committed_tree(&self, session_id: &Uuid) -> Result<HashMap<PathBuf, Change>> {
+        self.latest_changes(session_id, TreeScope::Committed)
+    }
+
+    fn latest_changes(
+        &self,
+        session_id: &Uuid,
+        scope: TreeScope,
+    ) -> Result<HashMap<PathBuf, Change>> {
+        let (committed, up_to) = match scope {
+            TreeScope::All => ("", None),
+            TreeScope::Committed => ("AND c.id IN (SELECT change_id FROM commit_changes)", None),
+            TreeScope::UpTo(commit_id) => (
+                // Commits on other branches aren't part of this one's history,
+                // however recently they were made
+                "AND c.id IN (
+                     WITH RECURSIVE ancestors(id) AS (
+                         SELECT ?2
+                         UNION
+                         SELECT co.parent FROM commits co
+                         JOIN ancestors a ON co.id = a.id
+                         WHERE co.parent IS NOT NULL
+                     )
+                     SELECT change_id FROM commit_changes
+                     WHERE commit_id IN (SELECT id FROM ancestors)
+                 )",
+                Some(commit_id.to_string()),
+            ),
+        };
+        // As in `list_tracked_path_statuses`, a rename touches its old path
+        // too, leaving nothing there; whichever change touched a path last
+        // decides whether it's live
+        let mut stmt = self.conn.prepare(&format!(
+            "WITH scoped AS (
+                 SELECT c.id, c.path, c.old_path, c.seq, c.change_type
+                 FROM changes c
+                 WHERE c.session_id = ?1 {}
+             ),
+             touched AS (
+                 SELECT id, path, seq, change_type = 'delete' AS gone FROM scoped
+                 UNION ALL
+                 SELECT id, old_path, seq, 1 FROM scoped WHERE old_path IS NOT NULL
+             ),
+             latest AS (
+                 SELECT id, gone,
+                        ROW_NUMBER() OVER (PARTITION BY path ORDER BY seq DESC) AS rn
+                 FROM touched
+             )
+             SELECT id, session_id, timestamp, change_type, path, old_path,
+                    content_before, content_after, content_hash_before, content_hash_after,
+                    agent_id, metadata, delta_base, tool, seq, language, mtime_before, mtime_after,
+                    compressed, external
+             FROM changes
+             WHERE id IN (SELECT id FROM latest WHERE rn = 1 AND NOT gone)",
+            committed
+        ))?;
+
+        let mut latest = HashMap::new();
+        let mut rows = match &up_to {
+            Some(commit_id) => stmt.query(params![session_id.to_string(), commit_id])?,
+            None => stmt.query(params![session_id.to_string()])?,
+        };
+        while let Some(row) = rows.next()? {
+            self.check_cancelled()?;
+            let change = self.change_from_row(row)?;
+            latest.insert(change.path.clone(), change);
+        }
+
+        Ok(latest)
+    }
+
+    /// The files as of a commit: [`Storage::committed_tree`] counting only
+    /// the commit and its ancestors, found by following parents.
+    pub fn tree_at_commit(&self, commit_id: &Uuid) -> Result<BTreeMap<PathBuf, Change>> {
+        let commit = self.get_commit(commit_id)?;
+        let tree = self.latest_changes(&commit.session_id, TreeScope::UpTo(&commit.id))?;
+        Ok(tree.into_iter().collect())
+    }
+
+    // File index operations
+
+    /// Load the file index for the tree at `root`, keyed by path. Returns
+    /// `None` if the tree has never been indexed.
+    pub fn load_file_index(&self, root: &Path) -> Result<Option<HashMap<PathBuf, FileIndexEntry>>> {
+        let root = root.to_string_lossy();
+        let indexed: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM file_index WHERE root_path = ?1)",
+            params![root.as_ref()],
+            |row| row.get(0),
+        )?;
+        if !indexed {
+            return Ok(None);
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT path, mtime_ns, hash FROM file_index WHERE root_path = ?1")?;
+        let index = stmt
+            .query_map(params![root.as_ref()], |row| {
+                let path = PathBuf::from(row.get::<_, String>(0)?);
+                let entry = FileIndexEntry {
+                    path: path.clone(),
+                    mtime_ns: row.get(1)?,
+                    hash: row.get(2)?,
+                };
+                Ok((path, entry))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(Some(index))
+    }
+
+    /// Add or update entries in the file index for the tree at `root`.
+    pub fn save_file_index(&self, root: &Path, entries: &[FileIndexEntry]) -> Result<()> {
+        let root = root.to_string_lossy();
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO file_index (root_path, path, mtime_ns, hash) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (root_path, path) DO UPDATE SET mtime_ns = ?3, hash = ?4",
+            )?;
+            for entry in entries {
+                stmt.execute(params![
+                    root.as_ref(),
+                    entry.path.to_string_lossy().as_ref(),
+                    entry.mtime_ns,
+                    entry.hash,
+                ])?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Drop entries for files that no longer exist from the file index.
+    pub fn remove_from_file_index(&self, root: &Path, paths: &[PathBuf]) -> Result<()> {
+        let root = root.to_string_lossy();
+        let tx = self.conn.unchecked_transaction()?;
+        for path in paths {
+            tx.execute(
+                "DELETE FROM file_index WHERE root_path = ?1 AND path = ?2",
+                params![root.as_ref(), path.to_string_lossy().as_ref()],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    // Commit operations
+    /// Store a commit and link its changes. A parent, if set, must be a
+    /// commit in the same session.
+    pub fn create_commit(&self, commit: &Commit) -> Result<()> {
+        if let Some(parent) = &commit.parent {
+            let parent = self.get_commit(parent)?;
+            if parent.session_id != commit.session_id {
+                return Err(Error::InvalidOperation(format!(
+                    "Parent commit {} belongs to another session",
+                    parent.id
+                )));
+            }
+        }
+
+        if let Some(schema) = self.metadata_schema(&commit.session_id)? {
+            let problems = schema.commit_problems(&commit.metadata);
+            check_metadata(&schema, &format!("commit {}", commit.id), problems)?;
+        }
+        let metadata = serde_json::to_string(&commit.metadata)?;
+
+        // The commit row and its change links must land together, otherwise a
+        // crash in between leaves a commit with an incomplete change list.
+        // Taking the write lock up front means no other connection can commit
+        // the same changes between the check below and the inserts.
+        self.batch(|storage| {
+            // Another agent may have committed some of these changes since they
+            // were read as uncommitted; the caller should re-read and retry
+            let taken: Vec<String> = if commit.changes.is_empty() {
+                Vec::new()
+            } else {
+                let mut stmt = storage.conn.prepare(&format!(
+                    "SELECT DISTINCT change_id FROM commit_changes WHERE change_id IN ({})",
+                    placeholders(commit.changes.len())
+                ))?;
+                let taken = stmt
+                    .query_map(
+                        params_from_iter(commit.changes.iter().map(|id| id.to_string())),
+                        |row| row.get(0),
+                    )?
+                    .collect::<rusqlite::Result<_>>()?;
+                taken
+            };
+            if !taken.is_empty() {
+                return Err(Error::ConcurrentModification(format!(
+                    "change(s) already committed: {}",
+                    taken.join(", ")
+                )));
+            }
+
+            storage.conn.execute(
+                "INSERT INTO commits
+                     (id, session_id, parent, timestamp, message, agent_id, metadata, digest)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    commit.id.to_string(),
+                    commit.session_id.to_string(),
+                    commit.parent.as_ref().map(|p| p.to_string()),
+                    commit.timestamp.to_rfc3339(),
+                    commit.message,
+                    commit.agent_id,
+                    metadata,
+                    storage.commit_digest(commit)?,
+                ],
+            )?;
+
+            for change_id in &commit.changes {
+                storage.conn.execute(
+                    "INSERT INTO commit_changes (commit_id, change_id) VALUES (?1, ?2)",
+                    params![commit.id.to_string(), change_id.to_string()],
+                )?;
+            }
+
+            storage.log_event(|| LogEvent::CommitCreated {
+                commit: commit.clone(),
+            })
         })
     }
 
-    fn commit_from_row(&self, row: &Row) -> rusqlite::Result<Commit> {
-        let id: String = row.get(0)?;
-        let session_id: String = row.get(1)?;
-        let parent: Option<String> = row.get(2)?;
-        let timestamp: String = row.get(3)?;
-        let message: String = row.get(4)?;
-        let agent_id: String = row.get(5)?;
-        let metadata: String = row.get(6)?;
+    pub fn get_commit(&self, id: &Uuid) -> Result<Commit> {
+        let commit = self
+            .conn
+            .query_row(
+                "SELECT id, session_id, parent, timestamp, message, agent_id, metadata
+                 FROM commits WHERE id = ?1",
+                params![id.to_string()],
+                |row| self.commit_from_row(row),
+            )
+            .map_err(|_| Error::CommitNotFound(id.to_string()))?;
+
+        Ok(commit)
+    }
+
+    /// Whether a commit with this id exists.
+    pub fn commit_exists(&self, id: &Uuid) -> Result<bool> {
+        Ok(self.conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM commits WHERE id = ?1)",
+            params![id.to_string()],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// The commit in `session_id` whose id starts with `prefix`, as with git's
+    /// short hashes. A full id names a commit in any session.
+    pub fn resolve_commit_prefix(&self, session_id: &Uuid, prefix: &str) -> Result<Commit> {
+        if let Ok(id) = Uuid::parse_str(prefix) {
+            if self.commit_exists(&id)? {
+                return self.get_commit(&id);
+            }
+        }
+
+        let prefix = prefix.to_ascii_lowercase();
+        if prefix.len() < MIN_COMMIT_PREFIX {
+            return Err(Error::InvalidOperation(format!(
+                "Commit id {:?} is too short, give at least {} characters",
+                prefix, MIN_COMMIT_PREFIX
+            )));
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT id FROM commits
+             WHERE session_id = ?1 AND substr(id, 1, length(?2)) = ?2
+             LIMIT 2",
+        )?;
+        let ids = stmt
+            .query_map(params![session_id.to_string(), prefix], |row| {
+                row.get::<_, String>(0)
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        match &ids[..] {
+            [id] => self.get_commit(&Uuid::parse_str(id).unwrap()),
+            [] => Err(Error::CommitNotFound(prefix)),
+            _ => Err(Error::AmbiguousRef(prefix)),
+        }
+    }
+
+    pub fn get_commits_for_session(&self, session_id: &Uuid) -> Result<Vec<CommitInfo>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, session_id, parent, timestamp, message, agent_id, metadata
+             FROM commits WHERE session_id = ?1 ORDER BY timestamp DESC, rowid DESC",
+        )?;
+
+        let mut commits = Vec::new();
+        let rows = stmt.query_map(params![session_id.to_string()], |row| {
+            self.commit_from_row(row)
+        })?;
+
+        for commit_result in rows {
+            let commit = commit_result?;
+            let info = self.get_commit_info(&commit)?;
+            commits.push(info);
+        }
+
+        Ok(commits)
+    }
+
+    /// The commit `change_id` was committed in; the earliest, if it's in
+    /// several. Fails with [`Error::InvalidOperation`] for a change that isn't
+    /// committed yet.
+    pub fn get_commit_for_change(&self, change_id: &Uuid) -> Result<Commit> {
+        self.conn
+            .query_row(
+                "SELECT cm.id, cm.session_id, cm.parent, cm.timestamp, cm.message,
+                        cm.agent_id, cm.metadata
+                 FROM commits cm
+                 JOIN commit_changes cc ON cc.commit_id = cm.id
+                 WHERE cc.change_id = ?1
+                 ORDER BY cm.timestamp, cm.rowid LIMIT 1",
+                params![change_id.to_string()],
+                |row| self.commit_from_row(row),
+            )
+            .optional()?
+            .ok_or_else(|| {
+                Error::InvalidOperation(format!("Change {} isn't in any commit", change_id))
+            })
+    }
+
+    /// Get all commits in a session containing a change to `path`, newest first.
+    ///
+    /// Renames are matched on both their new and old path.
+    pub fn get_commits_touching_path(&self, session_id: &Uuid, path: &Path) -> Result<Vec<Commit>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT cm.id, cm.session_id, cm.parent, cm.timestamp, cm.message,
+                    cm.agent_id, cm.metadata
+             FROM commits cm
+             JOIN commit_changes cc ON cc.commit_id = cm.id
+             JOIN changes c ON c.id = cc.change_id
+             WHERE cm.session_id = ?1 AND (c.path = ?2 OR c.old_path = ?2)
+             ORDER BY cm.timestamp DESC",
+        )?;
+
+        let commits = stmt
+            .query_map(
+                params![session_id.to_string(), path.to_string_lossy().as_ref()],
+                |row| self.commit_from_row(row),
+            )?
+            .collect::<rusqlite::Result<Vec<Commit>>>()?;
+
+        Ok(commits)
+    }
+
+    /// Get all commits in a session containing a change made with `tool`, newest first.
+    pub fn get_commits_by_tool(&self, session_id: &Uuid, tool: &str) -> Result<Vec<Commit>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT cm.id, cm.session_id, cm.parent, cm.timestamp, cm.message,
+                    cm.agent_id, cm.metadata
+             FROM commits cm
+             JOIN commit_changes cc ON cc.commit_id = cm.id
+             JOIN changes c ON c.id = cc.change_id
+             WHERE cm.session_id = ?1 AND c.tool = ?2
+             ORDER BY cm.timestamp DESC",
+        )?;
+
+        let commits = stmt
+            .query_map(params![session_id.to_string(), tool], |row| {
+                self.commit_from_row(row)
+            })?
+            .collect::<rusqlite::Result<Vec<Commit>>>()?;
+
+        Ok(commits)
+    }
+
+    /// Get all commits linked to `issue`, across sessions, newest first.
+    pub fn get_commits_for_issue(&self, issue: &str) -> Result<Vec<Commit>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, session_id, parent, timestamp, message, agent_id, metadata
+             FROM commits
+             WHERE json_extract(metadata, '$.issue') = ?1
+             ORDER BY timestamp DESC",
+        )?;
+
+        let commits = stmt
+            .query_map(params![issue], |row| self.commit_from_row(row))?
+            .collect::<rusqlite::Result<Vec<Commit>>>()?;
+
+        Ok(commits)
+    }
+
+    /// Mark a commit as a milestone, or unmark it.
+    pub fn set_milestone(&self, commit_id: &Uuid, milestone: bool) -> Result<Commit> {
+        let mut commit = self.get_commit(commit_id)?;
+        if milestone {
+            commit = commit.with_milestone();
+        } else {
+            commit.metadata.remove(MILESTONE_KEY);
+        }
+        self.conn.execute(
+            "UPDATE commits SET metadata = ?2 WHERE id = ?1",
+            params![
+                commit_id.to_string(),
+                serde_json::to_string(&commit.metadata)?
+            ],
+        )?;
+
+        self.log_event(|| LogEvent::MilestoneSet {
+            commit_id: *commit_id,
+            milestone,
+        })?;
+        Ok(commit)
+    }
+
+    /// Ids of the commits in a session that carry metadata `key`, by its value.
+    pub fn commits_by_metadata(
+        &self,
+        session_id: &Uuid,
+        key: &str,
+    ) -> Result<HashMap<String, Uuid>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT json_extract(metadata, ?2), id FROM commits
+             WHERE session_id = ?1 AND json_extract(metadata, ?2) IS NOT NULL",
+        )?;
+        let commits = stmt
+            .query_map(
+                params![session_id.to_string(), format!("$.{}", key)],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )?
+            .map(|row| {
+                let (value, id) = row?;
+                Ok((value, Uuid::parse_str(&id).unwrap()))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        Ok(commits)
+    }
+
+    /// Milestone commits in a session, newest first.
+    pub fn get_milestones(&self, session_id: &Uuid) -> Result<Vec<Commit>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, session_id, parent, timestamp, message, agent_id, metadata
+             FROM commits
+             WHERE session_id = ?1 AND json_extract(metadata, '$.milestone') = 'true'
+             ORDER BY timestamp DESC",
+        )?;
+
+        let commits = stmt
+            .query_map(params![session_id.to_string()], |row| {
+                self.commit_from_row(row)
+            })?
+            .collect::<rusqlite::Result<Vec<Commit>>>()?;
+
+        Ok(commits)
+    }
+
+    /// Commits in a session from `from` to `to` inclusive, in the order they
+    /// were made. Fails if the two are in different sessions or `from` was
+    /// made after `to`.
+    ///
+    /// This includes commits on other branches made in between; for only
+    /// those `to` descends through, see [`Storage::get_ancestry_path`].
+    pub fn commit_range(&self, from: &Uuid, to: &Uuid) -> Result<Vec<Commit>> {
+        let from = self.get_commit(from)?;
+        let to = self.get_commit(to)?;
+        if from.session_id != to.session_id {
+            return Err(Error::InvalidOperation(format!(
+                "Commits {} and {} are in different sessions",
+                from.id, to.id
+            )));
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT id, session_id, parent, timestamp, message, agent_id, metadata
+             FROM commits
+             WHERE session_id = ?1
+               AND rowid BETWEEN (SELECT rowid FROM commits WHERE id = ?2)
+                             AND (SELECT rowid FROM commits WHERE id = ?3)
+             ORDER BY rowid ASC",
+        )?;
+        let commits = stmt
+            .query_map(
+                params![
+                    from.session_id.to_string(),
+                    from.id.to_string(),
+                    to.id.to_string()
+                ],
+                |row| self.commit_from_row(row),
+            )?
+            .collect::<rusqlite::Result<Vec<Commit>>>()?;
+
+        if commits.is_empty() {
+            return Err(Error::InvalidOperation(format!(
+                "Commit {} was made after {}",
+                from.id, to.id
+            )));
+        }
+
+        Ok(commits)
+    }
+
+    /// The commits on the ancestry path from `from` to `to`, following
+    /// parent pointers back from `to`: `from` itself is left out and `to` is
+    /// included, oldest first. Fails if `from` isn't an ancestor of `to`.
+    ///
+    /// Unlike [`Storage::commit_range`], commits made in between on other
+    /// branches are left out.
+    pub fn get_ancestry_path(&self, from: &Uuid, to: &Uuid) -> Result<Vec<Commit>> {
+        let from = self.get_commit(from)?;
+        let mut commit = self.get_commit(to)?;
+
+        let mut range = Vec::new();
+        let mut seen = HashSet::new();
+        while commit.id != from.id {
+            self.check_cancelled()?;
+            let parent = match commit.parent {
+                Some(parent) if seen.insert(commit.id) => parent,
+                _ => {
+                    return Err(Error::InvalidOperation(format!(
+                        "Commit {} is not an ancestor of {}",
+                        from.id, to
+                    )))
+                }
+            };
+            range.push(commit);
+            commit = self.get_commit(&parent)?;
+        }
+
+        range.reverse();
+        Ok(range)
+    }
+
+    /// Get the most recent commit in a session, if any.
+    pub fn get_head_commit(&self, session_id: &Uuid) -> Result<Option<Commit>> {
+        let commit = self
+            .conn
+            .query_row(
+                "SELECT id, session_id, parent, timestamp, message, agent_id, metadata
+                 FROM commits WHERE session_id = ?1
+                 ORDER BY timestamp DESC, rowid DESC LIMIT 1",
+                params![session_id.to_string()],
+                |row| self.commit_from_row(row),
+            )
+            .optional()?;
+
+        Ok(commit)
+    }
+
+    /// Undo the latest commit in a session, returning its changes to the
+    /// uncommitted state. The change rows themselves are kept.
+    ///
+    /// Returns the removed commit. The previous commit becomes the new head.
+    pub fn reset_head(&self, session_id: &Uuid) -> Result<Commit> {
+        let head = self
+            .get_head_commit(session_id)?
+            .ok_or_else(|| Error::InvalidOperation("No commits to reset".to_string()))?;
+
+        let descendants: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM commits WHERE parent = ?1",
+            params![head.id.to_string()],
+            |row| row.get(0),
+        )?;
+        if descendants > 0 {
+            return Err(Error::InvalidOperation(format!(
+                "Commit {} has descendants and cannot be reset",
+                head.id
+            )));
+        }
+
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM commit_changes WHERE commit_id = ?1",
+            params![head.id.to_string()],
+        )?;
+        tx.execute(
+            "DELETE FROM commits WHERE id = ?1",
+            params![head.id.to_string()],
+        )?;
+        tx.commit()?;
+
+        self.log_event(|| LogEvent::HeadReset {
+            session_id: *session_id,
+        })?;
+        Ok(head)
+    }
+
+    /// Remove commits in a session that fall outside `policy`, along with any
+    /// changes no surviving commit refers to, then reclaim the space.
+    ///
+    /// The latest commit, milestones and branch heads are always kept, as are
+    /// uncommitted changes. Changes stored as deltas against a removed change
+    /// are rewritten with their full content first.
+    pub fn prune_history(
+        &self,
+        session_id: &Uuid,
+        policy: &RetentionPolicy,
+    ) -> Result<PruneReport> {
+        if policy.is_empty() {
+            return Ok(PruneReport::default());
+        }
+
+        // Milestones, and branch heads (commits no other commit builds on),
+        // are kept whatever the policy says
+        let mut stmt = self.conn.prepare(
+            "SELECT id, timestamp,
+                    COALESCE(json_extract(metadata, '$.milestone') = 'true', 0)
+                    OR NOT EXISTS (SELECT 1 FROM commits child WHERE child.parent = commits.id)
+             FROM commits WHERE session_id = ?1
+             ORDER BY timestamp DESC, rowid DESC",
+        )?;
+        let commits = stmt
+            .query_map(params![session_id.to_string()], |row| {
+                let id: String = row.get(0)?;
+                let id = Uuid::parse_str(&id).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e))
+                })?;
+                Ok((id, row.get::<_, String>(1)?, row.get::<_, bool>(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let cutoff = policy
+            .keep_days
+            .map(|days| self.now() - chrono::Duration::days(days.into()));
+        let pruned: Vec<Uuid> = commits
+            .into_iter()
+            .enumerate()
+            .filter(|(index, (_, timestamp, protected))| {
+                let keep_by_count = policy.keep_commits.is_some_and(|n| *index < n);
+                let keep_by_age = cutoff.is_some_and(|cutoff| {
+                    DateTime::parse_from_rfc3339(timestamp).is_ok_and(|t| t >= cutoff)
+                });
+                *index > 0 && !protected && !keep_by_count && !keep_by_age
+            })
+            .map(|(_, (id, _, _))| id)
+            .collect();
+        if pruned.is_empty() {
+            return Ok(PruneReport::default());
+        }
+        let changes_removed = self.remove_commits(&pruned)?;
+
+        self.vacuum()?;
+        let swept = self.sweep_objects()?;
+
+        Ok(PruneReport {
+            commits_removed: pruned.len(),
+            changes_removed,
+            objects_removed: swept.len(),
+        })
+    }
+
+    /// Remove `commit_ids`, along with any changes no other commit refers
+    /// to, returning how many changes went.
+    pub(crate) fn remove_commits(&self, commit_ids: &[Uuid]) -> Result<usize> {
+        let pruned: Vec<String> = commit_ids.iter().map(Uuid::to_string).collect();
+
+        let tx = self.conn.unchecked_transaction()?;
+
+        let mut candidates = BTreeSet::new();
+        for commit_id in &pruned {
+            for change_id in self.get_changes_for_commit(commit_id)? {
+                candidates.insert(change_id.to_string());
+            }
+            tx.execute(
+                "DELETE FROM commit_changes WHERE commit_id = ?1",
+                params![commit_id],
+            )?;
+        }
+
+        let mut removed_changes = Vec::new();
+        for change_id in candidates {
+            let links: i64 = tx.query_row(
+                "SELECT COUNT(*) FROM commit_changes WHERE change_id = ?1",
+                params![change_id],
+                |row| row.get(0),
+            )?;
+            if links == 0 {
+                removed_changes.push(change_id);
+            }
+        }
+
+        self.expand_dependents(&tx, &removed_changes)?;
+
+        for commit_id in &pruned {
+            tx.execute(
+                "UPDATE commits SET parent = NULL WHERE parent = ?1",
+                params![commit_id],
+            )?;
+        }
+        tx.execute(
+            &format!(
+                "DELETE FROM commits WHERE id IN ({})",
+                placeholders(pruned.len())
+            ),
+            params_from_iter(&pruned),
+        )?;
+        if !removed_changes.is_empty() {
+            tx.execute(
+                &format!(
+                    "DELETE FROM changes WHERE id IN ({})",
+                    placeholders(removed_changes.len())
+                ),
+                params_from_iter(&removed_changes),
+            )?;
+        }
+        tx.commit()?;
+
+        self.log_event(|| LogEvent::HistoryPruned {
+            commit_ids: commit_ids.to_vec(),
+        })?;
+        Ok(removed_changes.len())
+    }
+
+    /// Find content nothing needs anymore: changes no commit refers to that
+    /// can't become part of one either. These are changes a later committed
+    /// change to the same file superseded, such as those a reset returned to
+    /// the uncommitted state or a merge brought in from an older session, and
+    /// changes whose session no longer exists.
+    ///
+    /// An uncommitted change superseded only by other uncommitted ones is
+    /// pending work, and is never found.
+    ///
+    /// Content kept in the object store is counted at the size of its objects.
+    pub fn find_orphan_blobs(&self) -> Result<Vec<OrphanBlob>> {
+        Ok(self.orphans()?.into_iter().map(|(blob, _)| blob).collect())
+    }
+
+    /// [`Storage::find_orphan_blobs`], along with whether each change's
+    /// content is in the object store.
+    fn orphans(&self) -> Result<Vec<(OrphanBlob, bool)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, external, content_before, content_after,
+                    COALESCE(length(content_before), 0) + COALESCE(length(content_after), 0)
+             FROM changes c
+             WHERE c.id NOT IN (SELECT change_id FROM commit_changes)
+               AND (
+                   c.session_id NOT IN (SELECT id FROM sessions)
+                   OR EXISTS (
+                       SELECT 1 FROM changes later
+                       JOIN commit_changes cc ON cc.change_id = later.id
+                       WHERE later.session_id = c.session_id
+                         AND later.seq > c.seq
+                         AND (later.path = c.path OR later.old_path = c.path)
+                   )
+               )
+             ORDER BY c.rowid",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, bool>(1)?,
+                    row.get::<_, Option<Vec<u8>>>(2)?,
+                    row.get::<_, Option<Vec<u8>>>(3)?,
+                    row.get::<_, u64>(4)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut blobs = Vec::with_capacity(rows.len());
+        for (id, external, before, after, stored) in rows {
+            let bytes = match (&self.objects, external) {
+                (Some(objects), true) => {
+                    let mut bytes = 0;
+                    for name in [before, after].into_iter().flatten() {
+                        bytes += objects.size(&String::from_utf8_lossy(&name))?;
+                    }
+                    bytes
+                }
+                _ => stored,
+            };
+            let blob = OrphanBlob {
+                change_id: Uuid::parse_str(&id).unwrap(),
+                bytes,
+            };
+            blobs.push((blob, external));
+        }
+        Ok(blobs)
+    }
+
+    /// Delete the content found by [`Storage::find_orphan_blobs`], then
+    /// reclaim the space. Changes stored as deltas against a removed change
+    /// are rewritten with their full content first, and objects no change
+    /// refers to anymore are swept from the object store.
+    pub fn collect_orphan_blobs(&self) -> Result<GcReport> {
+        let orphans = self.orphans()?;
+        if !orphans.is_empty() {
+            let ids: Vec<Uuid> = orphans.iter().map(|(o, _)| o.change_id).collect();
+            self.remove_orphans(&ids)?;
+            self.vacuum()?;
+        }
+        let swept = self.sweep_objects()?;
+
+        // Objects may be shared with changes that are still around, so only
+        // those actually swept count towards what an external change freed
+        let in_database: u64 = orphans
+            .iter()
+            .filter(|(_, external)| !external)
+            .map(|(o, _)| o.bytes)
+            .sum();
+        Ok(GcReport {
+            changes_removed: orphans.len(),
+            objects_removed: swept.len(),
+            bytes_reclaimed: in_database + swept.iter().map(|o| o.bytes).sum::<u64>(),
+        })
+    }
+
+    /// Delete the changes `change_ids`, which no commit refers to.
+    pub(crate) fn remove_orphans(&self, change_ids: &[Uuid]) -> Result<()> {
+        let ids: Vec<String> = change_ids.iter().map(Uuid::to_string).collect();
+        let tx = self.conn.unchecked_transaction()?;
+        self.expand_dependents(&tx, &ids)?;
+        tx.execute(
+            &format!(
+                "DELETE FROM changes WHERE id IN ({})",
+                placeholders(ids.len())
+            ),
+            params_from_iter(&ids),
+        )?;
+        tx.commit()?;
+
+        self.log_event(|| LogEvent::OrphansCollected {
+            change_ids: change_ids.to_vec(),
+        })
+    }
+
+    /// Rebuild the database file, returning free pages to the filesystem.
+    pub fn vacuum(&self) -> Result<()> {
+        self.conn.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    pub fn get_commit_info(&self, commit: &Commit) -> Result<CommitInfo> {
+        let changes: Vec<Change> = commit
+            .changes
+            .iter()
+            .filter_map(|id| self.get_change(id).ok())
+            .collect();
+
+        let files_affected: Vec<PathBuf> = changes.iter().map(|c| c.path.clone()).collect();
+
+        let co_authors: BTreeSet<String> = changes
+            .iter()
+            .filter_map(|c| c.agent_id.clone())
+            .filter(|agent| *agent != commit.agent_id)
+            .collect();
+
+        Ok(CommitInfo {
+            commit: commit.clone(),
+            change_count: changes.len(),
+            files_affected,
+            co_authors: co_authors.into_iter().collect(),
+        })
+    }
+
+    // Integrity operations
+    pub fn check_integrity(&self) -> Result<IntegrityReport> {
+        let missing_changes = self.query_links(
+            "SELECT commit_id, change_id FROM commit_changes
+             WHERE commit_id IN (SELECT id FROM commits)
+               AND change_id NOT IN (SELECT id FROM changes)",
+        )?;
+
+        let dangling_links = self.query_links(
+            "SELECT commit_id, change_id FROM commit_changes
+             WHERE commit_id NOT IN (SELECT id FROM commits)",
+        )?;
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id FROM changes WHERE session_id NOT IN (SELECT id FROM sessions)")?;
+        let orphaned_changes = stmt
+            .query_map([], |row| {
+                let id: String = row.get(0)?;
+                Ok(Uuid::parse_str(&id).unwrap())
+            })?
+            .collect::<rusqlite::Result<Vec<Uuid>>>()?;
+
+        Ok(IntegrityReport {
+            missing_changes,
+            dangling_links,
+            orphaned_changes,
+            corrupted_changes: Vec::new(),
+            altered_commits: Vec::new(),
+        })
+    }
+
+    /// Like [`Storage::check_integrity`], but also reads every change and
+    /// checks its content against the stored hashes, and checks every commit
+    /// against the digest taken when it was made.
+    pub fn check_integrity_deep(&self) -> Result<IntegrityReport> {
+        let mut report = self.check_integrity()?;
+
+        let mut stmt = self.conn.prepare("SELECT id FROM changes ORDER BY rowid")?;
+        let ids = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for id in ids {
+            self.check_cancelled()?;
+            let id = Uuid::parse_str(&id).unwrap();
+            // A delta that can't be expanded is as corrupt as a hash mismatch
+            let intact = match self.get_change(&id) {
+                Ok(change) => change.verify_content_integrity(),
+                Err(e) if e.is_cancellation() => return Err(e),
+                Err(_) => false,
+            };
+            if !intact {
+                report.corrupted_changes.push(id);
+            }
+        }
+
+        // Commits made before digests were kept have none to check against
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, digest FROM commits WHERE digest IS NOT NULL ORDER BY rowid")?;
+        let digests = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for (id, digest) in digests {
+            self.check_cancelled()?;
+            let commit = self.get_commit(&Uuid::parse_str(&id).unwrap())?;
+            if self.commit_digest(&commit)? != digest {
+                report.altered_commits.push(commit.id);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Remove commit/change links reported as missing or dangling.
+    ///
+    /// Orphaned changes are left in place since they may still hold content
+    /// worth recovering. Returns the number of links removed.
+    pub fn repair_integrity(&self, report: &IntegrityReport) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        let mut removed = 0;
+
+        for link in report.missing_changes.iter().chain(&report.dangling_links) {
+            removed += tx.execute(
+                "DELETE FROM commit_changes WHERE commit_id = ?1 AND change_id = ?2",
+                params![link.commit_id.to_string(), link.change_id.to_string()],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    /// Give anything stored as a delta against one of `removed` its content
+    /// back in full, so the bases can be deleted.
+    fn expand_dependents(&self, conn: &Connection, removed: &[String]) -> Result<()> {
+        for change_id in removed {
+            let mut stmt = conn.prepare("SELECT id FROM changes WHERE delta_base = ?1")?;
+            let dependents = stmt
+                .query_map(params![change_id], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            for dependent in dependents {
+                if removed.contains(&dependent) {
+                    continue;
+                }
+                let change = self.get_change(&Uuid::parse_str(&dependent).unwrap())?;
+                let external = self.store_externally(&change)?;
+                let (content_before, content_after) = full_content(&change, external.as_ref());
+                conn.execute(
+                    "UPDATE changes SET content_before = ?1, content_after = ?2, delta_base = NULL,
+                                        compressed = 0, external = ?3
+                     WHERE id = ?4",
+                    params![content_before, content_after, external.is_some(), dependent],
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn query_links(&self, sql: &str) -> Result<Vec<CommitChangeLink>> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let links = stmt
+            .query_map([], |row| {
+                let commit_id: String = row.get(0)?;
+                let change_id: String = row.get(1)?;
+                Ok(CommitChangeLink {
+                    commit_id: Uuid::parse_str(&commit_id).unwrap(),
+                    change_id: Uuid::parse_str(&change_id).unwrap(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<CommitChangeLink>>>()?;
+
+        Ok(links)
+    }
+
+    // Helper methods
+    fn checkpoint_from_row(&self, row: &Row) -> rusqlite::Result<Checkpoint> {
+        let id: String = row.get(0)?;
+        let session_id: String = row.get(1)?;
+        let created_at: String = row.get(4)?;
+        Ok(Checkpoint {
+            id: Uuid::parse_str(&id).unwrap(),
+            session_id: Uuid::parse_str(&session_id).unwrap(),
+            label: row.get(2)?,
+            seq: row.get(3)?,
+            created_at: DateTime::parse_from_rfc3339(&created_at).unwrap().into(),
+        })
+    }
+
+    fn session_from_row(&self, row: &Row) -> rusqlite::Result<Session> {
+        let id: String = row.get(0)?;
+        let root_path: String = row.get(1)?;
+        let started: String = row.get(2)?;
+        let ended: Option<String> = row.get(3)?;
+        let active: i32 = row.get(4)?;
+        let ignore_patterns: String = row.get(5)?;
+        let store_deltas: i32 = row.get(6)?;
+        let content_root: Option<String> = row.get(7)?;
+        let redact_patterns: String = row.get(8)?;
+        let include_patterns: String = row.get(9)?;
+        let metadata_schema: Option<String> = row.get(10)?;
+        let hash_algorithm: String = row.get(11)?;
+
+        Ok(Session {
+            id: Uuid::parse_str(&id).unwrap(),
+            root_path: PathBuf::from(root_path),
+            started: DateTime::parse_from_rfc3339(&started).unwrap().into(),
+            ended: ended.and_then(|s| DateTime::parse_from_rfc3339(&s).ok().map(|dt| dt.into())),
+            active: active != 0,
+            ignore_patterns: serde_json::from_str(&ignore_patterns).unwrap_or_default(),
+            store_deltas: store_deltas != 0,
+            content_root: content_root.map(PathBuf::from),
+            redact_patterns: serde_json::from_str(&redact_patterns).unwrap_or_default(),
+            include_patterns: serde_json::from_str(&include_patterns).unwrap_or_default(),
+            metadata_schema: metadata_schema.and_then(|schema| serde_json::from_str(&schema).ok()),
+            hash_algorithm: HashAlgorithm::parse(&hash_algorithm).unwrap_or_default(),
+        })
+    }
+
+    fn change_from_row(&self, row: &Row) -> rusqlite::Result<Change> {
+        let id: String = row.get(0)?;
+        let session_id: String = row.get(1)?;
+        let timestamp: String = row.get(2)?;
+        let change_type: String = row.get(3)?;
+        let path: String = row.get(4)?;
+        let old_path: Option<String> = row.get(5)?;
+        let content_before: Option<Vec<u8>> = row.get(6)?;
+        let content_after: Option<Vec<u8>> = row.get(7)?;
+        let content_hash_before: Option<String> = row.get(8)?;
+        let content_hash_after: Option<String> = row.get(9)?;
+        let agent_id: Option<String> = row.get(10)?;
+        let metadata: String = row.get(11)?;
+        let delta_base: Option<String> = row.get(12)?;
+        let tool: Option<String> = row.get(13)?;
+        let seq: Option<i64> = row.get(14)?;
+        let language: Option<String> = row.get(15)?;
+        let mtime_before: Option<String> = row.get(16)?;
+        let mtime_after: Option<String> = row.get(17)?;
+        let compressed: bool = row.get(18)?;
+        let external: bool = row.get(19)?;
+        let parse_mtime =
+            |mtime: Option<String>| mtime.and_then(|m| DateTime::parse_from_rfc3339(&m).ok());
+
+        let (content_before, content_after) = match delta_base {
+            Some(base_id) => self
+                .expand_delta(&base_id, content_after, content_hash_before.is_some())
+                .map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(7, Type::Blob, Box::new(e))
+                })?,
+            None if compressed => {
+                let inflate = |content: Option<Vec<u8>>| {
+                    content.as_deref().map(decompress).transpose().map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(7, Type::Blob, Box::new(e))
+                    })
+                };
+                (inflate(content_before)?, inflate(content_after)?)
+            }
+            None if external => {
+                let load = |name: Option<Vec<u8>>| {
+                    name.map(|name| self.load_object(&name))
+                        .transpose()
+                        .map_err(|e| {
+                            rusqlite::Error::FromSqlConversionFailure(7, Type::Blob, Box::new(e))
+                        })
+                };
+                (load(content_before)?, load(content_after)?)
+            }
+            None => (content_before, content_after),
+        };
+
+        Ok(Change {
+            id: Uuid::parse_str(&id).unwrap(),
+            timestamp: DateTime::parse_from_rfc3339(&timestamp).unwrap().into(),
+            change_type: ChangeType::parse(&change_type).unwrap(),
+            path: PathBuf::from(path),
+            old_path: old_path.map(PathBuf::from),
+            content_before,
+            content_after,
+            content_hash_before,
+            content_hash_after,
+            agent_id,
+            tool,
+            language,
+            mtime_before: parse_mtime(mtime_before).map(Into::into),
+            mtime_after: parse_mtime(mtime_after).map(Into::into),
+            metadata: serde_json::from_str(&metadata).unwrap_or_default(),
+            session_id: Uuid::parse_str(&session_id).unwrap(),
+            seq,
+        })
+    }
+
+    /// Turn a delta-encoded row's content back into full before/after content.
+    fn expand_delta(
+        &self,
+        base_id: &str,
+        delta: Option<Vec<u8>>,
+        has_before: bool,
+    ) -> Result<ContentPair> {
+        let (base, _) = self.resolve_content_after(base_id)?;
+        let base = base.ok_or_else(|| Error::ChangeNotFound(base_id.to_string()))?;
+        let delta = delta.ok_or_else(|| Error::DiffFailed("missing delta".to_string()))?;
+        let content_after = delta::apply(&base, &delta)?;
+
+        Ok((has_before.then_some(base), Some(content_after)))
+    }
+
+    fn commit_from_row(&self, row: &Row) -> rusqlite::Result<Commit> {
+        let id: String = row.get(0)?;
+        let session_id: String = row.get(1)?;
+        let parent: Option<String> = row.get(2)?;
+        let timestamp: String = row.get(3)?;
+        let message: String = row.get(4)?;
+        let agent_id: String = row.get(5)?;
+        let metadata: String = row.get(6)?;
+
+        let changes = self.get_changes_for_commit(&id)?;
+
+        Ok(Commit {
+            id: Uuid::parse_str(&id).unwrap(),
+            parent: parent.and_then(|p| Uuid::parse_str(&p).ok()),
+            timestamp: DateTime::parse_from_rfc3339(&timestamp).unwrap().into(),
+            message,
+            agent_id,
+            changes,
+            session_id: Uuid::parse_str(&session_id).unwrap(),
+            metadata: serde_json::from_str(&metadata).unwrap_or_default(),
+        })
+    }
+
+    /// A commit's changes in canonical order, as [`Change::canonical_cmp`]
+    /// sorts them. Links to missing changes come last.
+    fn get_changes_for_commit(&self, commit_id: &str) -> rusqlite::Result<Vec<Uuid>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT cc.change_id FROM commit_changes cc
+             LEFT JOIN changes c ON c.id = cc.change_id
+             WHERE cc.commit_id = ?1
+             ORDER BY c.id IS NULL, c.path, c.seq, cc.change_id",
+        )?;
+
+        let changes = stmt
+            .query_map(params![commit_id], |row| {
+                let id: String = row.get(0)?;
+                Ok(Uuid::parse_str(&id).unwrap())
+            })?
+            .collect::<rusqlite::Result<Vec<Uuid>>>()?;
+
+        Ok(changes)
+    }
+
+    /// [`Commit::digest`] of `commit`, with its changes read without their
+    /// content, which the digest only knows by hash.
+    fn commit_digest(&self, commit: &Commit) -> Result<String> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, session_id, change_type, path, old_path, seq,
+                    content_hash_before, content_hash_after
+             FROM changes WHERE id IN ({})",
+            placeholders(commit.changes.len())
+        ))?;
+        let changes = stmt
+            .query_map(
+                params_from_iter(commit.changes.iter().map(|id| id.to_string())),
+                |row| {
+                    let session_id: String = row.get(1)?;
+                    let change_type: String = row.get(2)?;
+                    let path: String = row.get(3)?;
+                    let mut change = Change::new(
+                        ChangeType::parse(&change_type).unwrap(),
+                        PathBuf::from(path),
+                        Uuid::parse_str(&session_id).unwrap(),
+                    );
+                    change.id = Uuid::parse_str(&row.get::<_, String>(0)?).unwrap();
+                    change.old_path = row.get::<_, Option<String>>(4)?.map(PathBuf::from);
+                    change.seq = row.get(5)?;
+                    change.content_hash_before = row.get(6)?;
+                    change.content_hash_after = row.get(7)?;
+                    Ok(change)
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(commit.digest(&changes))
+    }
+}
+
+/// Content as [`Storage::open_content`] found it.
+#[derive(Debug)]
+pub enum StoredContent {
+    /// An object in the object store, to be read from as it's sent
+    Object(std::fs::File),
+    /// Content kept in the database, loaded in full
+    Loaded(Vec<u8>),
+}
+
+/// Which end of a session's history query results start from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    /// Oldest first, in the order changes were recorded
+    Ascending,
+    /// Newest first
+    #[default]
+    Descending,
+}
+
+/// Filters for the changes in a session, combined into a single query.
+///
+/// Built with [`Storage::query_changes`]; every filter is optional and they
+/// all have to match.
+#[derive(Clone)]
+pub struct ChangeQuery<'a> {
+    storage: &'a Storage,
+    session_id: Uuid,
+    agent: Option<String>,
+    path_prefix: Option<PathBuf>,
+    change_type: Option<ChangeType>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    committed: Option<bool>,
+    after_seq: Option<i64>,
+    limit: Option<usize>,
+    order: SortOrder,
+}
+
+impl<'a> ChangeQuery<'a> {
+    /// Only changes made by this agent.
+    pub fn agent(mut self, agent: impl Into<String>) -> Self {
+        self.agent = Some(agent.into());
+        self
+    }
+
+    /// Only changes to `prefix` itself or to files under it.
+    pub fn path_prefix(mut self, prefix: impl Into<PathBuf>) -> Self {
+        self.path_prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn change_type(mut self, change_type: ChangeType) -> Self {
+        self.change_type = Some(change_type);
+        self
+    }
+
+    /// Only changes made at or after `since`.
+    pub fn since(mut self, since: DateTime<Utc>) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// Only changes made before `until`.
+    pub fn until(mut self, until: DateTime<Utc>) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    /// Only changes that are (or aren't) part of a commit.
+    pub fn committed(mut self, committed: bool) -> Self {
+        self.committed = Some(committed);
+        self
+    }
+
+    /// Only changes recorded after the change numbered `seq`.
+    pub fn after_seq(mut self, seq: i64) -> Self {
+        self.after_seq = Some(seq);
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn order(mut self, order: SortOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Run the query.
+    pub fn fetch(self) -> Result<Vec<Change>> {
+        let mut conditions = vec!["c.session_id = ?".to_string()];
+        let mut values = vec![Value::Text(self.session_id.to_string())];
+
+        if let Some(agent) = self.agent {
+            conditions.push("c.agent_id = ?".to_string());
+            values.push(Value::Text(agent));
+        }
+        if let Some(prefix) = self.path_prefix {
+            let prefix = prefix.to_string_lossy().trim_end_matches('/').to_string();
+            // Compared by substring so `_` and `%` in paths aren't wildcards
+            if !prefix.is_empty() {
+                conditions.push(
+                    "(c.path = ? OR substr(c.path, 1, length(?) + 1) = ? || '/')".to_string(),
+                );
+                values.extend(std::iter::repeat_n(Value::Text(prefix), 3));
+            }
+        }
+        if let Some(change_type) = self.change_type {
+            conditions.push("c.change_type = ?".to_string());
+            values.push(Value::Text(change_type.as_str().to_string()));
+        }
+        if let Some(since) = self.since {
+            conditions.push("julianday(c.timestamp) >= julianday(?)".to_string());
+            values.push(Value::Text(since.to_rfc3339()));
+        }
+        if let Some(until) = self.until {
+            conditions.push("julianday(c.timestamp) < julianday(?)".to_string());
+            values.push(Value::Text(until.to_rfc3339()));
+        }
+        if let Some(committed) = self.committed {
+            let membership = if committed { "IN" } else { "NOT IN" };
+            conditions.push(format!(
+                "c.id {} (SELECT change_id FROM commit_changes)",
+                membership
+            ));
+        }
+        if let Some(seq) = self.after_seq {
+            conditions.push("c.seq > ?".to_string());
+            values.push(Value::Integer(seq));
+        }
+
+        let order = match self.order {
+            SortOrder::Ascending => "ASC",
+            SortOrder::Descending => "DESC",
+        };
+        // SQLite treats a negative limit as no limit
+        values.push(Value::Integer(self.limit.map_or(-1, |limit| limit as i64)));
+
+        let sql = format!(
+            "SELECT c.id, c.session_id, c.timestamp, c.change_type, c.path, c.old_path,
+                    c.content_before, c.content_after, c.content_hash_before, c.content_hash_after,
+                    c.agent_id, c.metadata, c.delta_base, c.tool, c.seq, c.language,
+                    c.mtime_before, c.mtime_after, c.compressed, c.external
+             FROM changes c
+             WHERE {}
+             ORDER BY c.seq {}
+             LIMIT ?",
+            conditions.join(" AND "),
+            order
+        );
+        let mut stmt = self.storage.conn.prepare(&sql)?;
+        let changes = stmt
+            .query_map(params_from_iter(values), |row| {
+                self.storage.change_from_row(row)
+            })?
+            .collect::<rusqlite::Result<Vec<Change>>>()?;
+
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::MetadataField;
+
+    #[test]
+    fn test_storage_initialization() {
+        let storage = Storage::in_memory().unwrap();
+        assert!(storage.conn.is_autocommit());
+    }
+
+    #[test]
+    fn test_session_crud() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+
+        storage.create_session(&session).unwrap();
+        let retrieved = storage.get_session(&session.id).unwrap();
+
+        assert_eq!(session.id, retrieved.id);
+        assert_eq!(session.root_path, retrieved.root_path);
+        assert!(retrieved.active);
+    }
+
+    #[test]
+    fn test_change_creation() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let change = Change::new(ChangeType::Create, PathBuf::from("test.txt"), session.id)
+            .with_content_after(b"Hello".to_vec());
+
+        storage.create_change(&change).unwrap();
+        let retrieved = storage.get_change(&change.id).unwrap();
+
+        assert_eq!(change.id, retrieved.id);
+        assert_eq!(change.path, retrieved.path);
+        assert_eq!(change.change_type, retrieved.change_type);
+    }
+
+    #[test]
+    fn test_commit_with_changes() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let change1 = Change::new(ChangeType::Create, PathBuf::from("file1.txt"), session.id);
+        let change2 = Change::new(ChangeType::Create, PathBuf::from("file2.txt"), session.id);
+
+        storage.create_change(&change1).unwrap();
+        storage.create_change(&change2).unwrap();
+
+        let commit = Commit::new(
+            "Test commit".to_string(),
+            "test-agent".to_string(),
+            vec![change1.id, change2.id],
+            session.id,
+        );
+
+        storage.create_commit(&commit).unwrap();
+        let retrieved = storage.get_commit(&commit.id).unwrap();
+
+        assert_eq!(commit.id, retrieved.id);
+        assert_eq!(commit.message, retrieved.message);
+        assert_eq!(2, retrieved.changes.len());
+    }
+
+    #[test]
+    fn test_racing_commits_do_not_share_changes() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("gitent.db");
+        // Two agents, each with its own connection
+        let first = Storage::new(&db_path).unwrap();
+        let second = Storage::new(&db_path).unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        first.create_session(&session).unwrap();
+
+        let changes: Vec<Change> = ["a.txt", "b.txt", "c.txt"]
+            .into_iter()
+            .map(|path| {
+                let change = Change::new(ChangeType::Create, PathBuf::from(path), session.id);
+                first.create_change(&change).unwrap();
+                change
+            })
+            .collect();
+
+        // Both read the same uncommitted changes, then commit overlapping sets
+        let seen_first = first.get_uncommitted_changes(&session.id).unwrap();
+        let seen_second = second.get_uncommitted_changes(&session.id).unwrap();
+        assert_eq!(seen_first.len(), 3);
+        assert_eq!(seen_second.len(), 3);
+
+        let winner = Commit::new(
+            "First".into(),
+            "agent-1".into(),
+            vec![changes[0].id, changes[1].id],
+            session.id,
+        );
+        first.create_commit(&winner).unwrap();
+        let loser = Commit::new(
+            "Second".into(),
+            "agent-2".into(),
+            vec![changes[1].id, changes[2].id],
+            session.id,
+        );
+        let err = second.create_commit(&loser).unwrap_err();
+        assert!(matches!(err, Error::ConcurrentModification(_)));
+        assert!(err.to_string().contains(&changes[1].id.to_string()));
+
+        // The losing commit left nothing behind
+        assert!(matches!(
+            second.get_commit(&loser.id),
+            Err(Error::CommitNotFound(_))
+        ));
+        assert_eq!(
+            second.get_commits_for_session(&session.id).unwrap().len(),
+            1
+        );
+
+        // Retrying with a fresh read commits what's left
+        let remaining: Vec<Uuid> = second
+            .get_uncommitted_changes(&session.id)
+            .unwrap()
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(remaining, vec![changes[2].id]);
+        let retry = Commit::new("Second".into(), "agent-2".into(), remaining, session.id);
+        second.create_commit(&retry).unwrap();
+        assert!(first
+            .get_uncommitted_changes(&session.id)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_commit_info_co_authors() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let change1 = Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id)
+            .with_agent_id("agent-b".to_string());
+        let change2 = Change::new(ChangeType::Create, PathBuf::from("b.txt"), session.id)
+            .with_agent_id("agent-a".to_string());
+        let change3 = Change::new(ChangeType::Create, PathBuf::from("c.txt"), session.id)
+            .with_agent_id("agent-b".to_string());
+        for change in [&change1, &change2, &change3] {
+            storage.create_change(change).unwrap();
+        }
+
+        let commit = Commit::new(
+            "Joint work".to_string(),
+            "cli-user".to_string(),
+            vec![change1.id, change2.id, change3.id],
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+
+        let info = storage.get_commit_info(&commit).unwrap();
+        assert_eq!(info.co_authors, vec!["agent-a", "agent-b"]);
+        assert_eq!(
+            info.co_author_trailers(),
+            vec!["Co-authored-by: agent-a", "Co-authored-by: agent-b"]
+        );
+    }
+
+    #[test]
+    fn test_commit_changes_in_canonical_order() {
+        let timestamp = Utc::now();
+        // The same commit, with its changes recorded and listed in different orders
+        let history = |paths: &[&str], listed: &[usize]| {
+            let storage = Storage::in_memory().unwrap();
+            let session = Session::new(PathBuf::from("/test"));
+            storage.create_session(&session).unwrap();
+            let ids: Vec<Uuid> = paths
+                .iter()
+                .map(|path| {
+                    let change = Change::new(ChangeType::Create, PathBuf::from(path), session.id)
+                        .with_content_after(path.as_bytes().to_vec());
+                    storage.create_change(&change).unwrap();
+                    change.id
+                })
+                .collect();
+            let mut commit = Commit::new(
+                "Add files".to_string(),
+                "agent".to_string(),
+                listed.iter().map(|&i| ids[i]).collect(),
+                session.id,
+            );
+            commit.timestamp = timestamp;
+            storage.create_commit(&commit).unwrap();
+
+            let commit = storage.get_commit(&commit.id).unwrap();
+            let changes: Vec<Change> = commit
+                .changes
+                .iter()
+                .map(|id| storage.get_change(id).unwrap())
+                .collect();
+            let mut sorted = changes.clone();
+            sorted.sort_by(Change::canonical_cmp);
+            assert!(sorted
+                .iter()
+                .map(|c| c.id)
+                .eq(commit.changes.iter().copied()));
+            let paths: Vec<PathBuf> = changes.iter().map(|c| c.path.clone()).collect();
+            let files_affected = storage.get_commit_info(&commit).unwrap().files_affected;
+            assert_eq!(files_affected, paths);
+            let stored: String = storage
+                .conn
+                .query_row(
+                    "SELECT digest FROM commits WHERE id = ?1",
+                    params![commit.id.to_string()],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(stored, commit.digest(&changes));
+            (paths, stored)
+        };
+
+        let (paths, digest) = history(
+            &["src/b.rs", "a.txt", "src-old.txt", "src/a.rs"],
+            &[0, 1, 2, 3],
+        );
+        assert_eq!(
+            paths,
+            ["a.txt", "src-old.txt", "src/a.rs", "src/b.rs"].map(PathBuf::from)
+        );
+        let (other_paths, other_digest) = history(
+            &["src/a.rs", "src-old.txt", "src/b.rs", "a.txt"],
+            &[3, 1, 0, 2],
+        );
+        assert_eq!(other_paths, paths);
+        assert_eq!(other_digest, digest);
+
+        let (_, different) = history(&["src/b.rs", "a.txt", "src-old.txt"], &[0, 1, 2]);
+        assert_ne!(different, digest);
+    }
+
+    #[test]
+    fn test_metadata_schema() {
+        let schema = |strict| MetadataSchema {
+            strict,
+            commits: [(
+                "ticket".to_string(),
+                MetadataField {
+                    required: true,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let commit = |session: &Session| {
+            Commit::new(
+                "Untracked work".to_string(),
+                "agent".to_string(),
+                vec![],
+                session.id,
+            )
+        };
+
+        let storage = Storage::in_memory().unwrap();
+        let strict = Session::new(PathBuf::from("/strict")).with_metadata_schema(schema(true));
+        storage.create_session(&strict).unwrap();
+        assert_eq!(
+            storage.get_session(&strict.id).unwrap().metadata_schema,
+            Some(schema(true))
+        );
+        assert!(matches!(
+            storage.create_commit(&commit(&strict)),
+            Err(Error::InvalidMetadata(_))
+        ));
+        storage
+            .create_commit(&commit(&strict).with_metadata("ticket".to_string(), "GE-7".to_string()))
+            .unwrap();
+        // Changes have no required keys
+        storage
+            .create_change(&Change::new(
+                ChangeType::Create,
+                PathBuf::from("a.txt"),
+                strict.id,
+            ))
+            .unwrap();
+
+        let lenient = Session::new(PathBuf::from("/lenient")).with_metadata_schema(schema(false));
+        storage.create_session(&lenient).unwrap();
+        let accepted = commit(&lenient);
+        storage.create_commit(&accepted).unwrap();
+        assert!(storage.get_commit(&accepted.id).is_ok());
+    }
+
+    #[test]
+    fn test_change_metadata_schema() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test")).with_metadata_schema(MetadataSchema {
+            strict: true,
+            changes: [(
+                "task".to_string(),
+                MetadataField {
+                    required: true,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Default::default()
+        });
+        storage.create_session(&session).unwrap();
+        let change = || Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id);
+
+        assert!(matches!(
+            storage.create_change(&change()),
+            Err(Error::InvalidMetadata(_))
+        ));
+        assert!(matches!(
+            storage.preview_change(change()),
+            Err(Error::InvalidMetadata(_))
+        ));
+        storage
+            .create_change(&change().with_metadata("task".to_string(), "T-1".to_string()))
+            .unwrap();
+        // The watcher has no way to add the keys
+        storage.create_captured_change(&change()).unwrap();
+    }
+
+    #[test]
+    fn test_repair_active_sessions() {
+        let storage = Storage::in_memory().unwrap();
+        let start = Utc::now() - chrono::Duration::hours(3);
+        let sessions: Vec<Session> = [2, 0, 1]
+            .into_iter()
+            .map(|hours| {
+                let mut session = Session::new(PathBuf::from("/test"));
+                session.started = start + chrono::Duration::hours(hours);
+                storage.create_session(&session).unwrap();
+                session
+            })
+            .collect();
+        let newest = sessions[0].id;
+        assert_eq!(storage.get_active_session().unwrap().id, newest);
+
+        let ended = storage.repair_active_sessions().unwrap();
+        assert_eq!(ended.len(), 2);
+        let active: Vec<Uuid> = storage
+            .get_sessions()
+            .unwrap()
+            .into_iter()
+            .filter(|s| s.active)
+            .map(|s| s.id)
+            .collect();
+        assert_eq!(active, vec![newest]);
+        assert!(storage.repair_active_sessions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_session_hash_algorithm() {
+        let storage = Storage::in_memory().unwrap();
+        let session =
+            Session::new(PathBuf::from("/test")).with_hash_algorithm(HashAlgorithm::Blake3);
+        storage.create_session(&session).unwrap();
+        assert_eq!(
+            storage.get_session(&session.id).unwrap().hash_algorithm,
+            HashAlgorithm::Blake3
+        );
+
+        let change = Change::new(ChangeType::Modify, PathBuf::from("a.txt"), session.id)
+            .with_content_before(b"old".to_vec())
+            .with_content_after(b"new".to_vec());
+        storage.create_change(&change).unwrap();
+        let stored = storage.get_change(&change.id).unwrap();
+        assert_eq!(
+            stored.content_hash_after.as_deref(),
+            Some(HashAlgorithm::Blake3.hash(b"new").as_str())
+        );
+        assert_ne!(stored.content_hash_after, change.content_hash_after);
+        assert!(stored.verify_content_integrity());
+
+        // Previews are hashed the way the change would be stored
+        let preview = storage.preview_change(change.clone()).unwrap();
+        assert_eq!(preview.content_hash_after, stored.content_hash_after);
+        let hashed = Change::new(ChangeType::Create, PathBuf::from("b.txt"), session.id)
+            .with_content_after_hashed(b"new".to_vec(), HashAlgorithm::Blake3);
+        assert!(hashed.hashed_with(HashAlgorithm::Blake3));
+
+        let (first, rest) = storage.split_change(&stored, b"mid".to_vec()).unwrap();
+        for change in [first, rest] {
+            let stored = storage.get_change(&change.id).unwrap();
+            assert!(stored.hashed_with(HashAlgorithm::Blake3));
+            assert!(stored.verify_content_integrity());
+        }
+    }
+
+    #[test]
+    fn test_redacted_content_is_not_stored() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"))
+            .with_redact_patterns(vec![".env".to_string(), "*.pem".to_string()]);
+        storage.create_session(&session).unwrap();
+        assert_eq!(
+            storage.get_session(&session.id).unwrap().redact_patterns,
+            vec![".env", "*.pem"]
+        );
+
+        let secret = Change::new(ChangeType::Modify, PathBuf::from(".env"), session.id)
+            .with_content_before(b"TOKEN=old".to_vec())
+            .with_content_after(b"TOKEN=new".to_vec());
+        let key = Change::new(
+            ChangeType::Create,
+            PathBuf::from("certs/key.pem"),
+            session.id,
+        )
+        .with_content_after(b"-----BEGIN KEY-----".to_vec());
+        let plain = Change::new(ChangeType::Create, PathBuf::from("main.rs"), session.id)
+            .with_content_after(b"fn main() {}".to_vec());
+        for change in [&secret, &key, &plain] {
+            storage.create_change(change).unwrap();
+        }
+
+        let stored = storage.get_change(&secret.id).unwrap();
+        assert!(stored.is_redacted());
+        assert_eq!(
+            stored.content_before.as_deref(),
+            Some(crate::models::REDACTED_CONTENT)
+        );
+        assert_eq!(
+            stored.content_after.as_deref(),
+            Some(crate::models::REDACTED_CONTENT)
+        );
+        // Hashes of the real content, so edits are still noticed
+        assert_eq!(stored.content_hash_after, secret.content_hash_after);
+        assert!(stored.verify_content_integrity());
+
+        let stored = storage.get_change(&key.id).unwrap();
+        assert_eq!(
+            stored.content_after.as_deref(),
+            Some(crate::models::REDACTED_CONTENT)
+        );
+
+        let stored = storage.get_change(&plain.id).unwrap();
+        assert!(!stored.is_redacted());
+        assert_eq!(stored.content_after.as_deref(), Some(&b"fn main() {}"[..]));
+    }
+
+    #[test]
+    fn test_query_changes() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        let start = Utc::now();
+
+        let change = |change_type, path: &str, agent: &str, minutes: i64| {
+            let mut change = Change::new(change_type, PathBuf::from(path), session.id)
+                .with_agent_id(agent.to_string());
+            change.timestamp = start + chrono::Duration::minutes(minutes);
+            storage.create_change(&change).unwrap();
+            change.id
+        };
+        let a = change(ChangeType::Create, "src/a.rs", "alice", 0);
+        let b = change(ChangeType::Create, "src/b.rs", "bob", 1);
+        let c = change(ChangeType::Delete, "src_old/c.rs", "alice", 2);
+        let d = change(ChangeType::Create, "src/nested/d.rs", "alice", 3);
+        let e = change(ChangeType::Create, "README.md", "alice", 4);
+        let commit = Commit::new(
+            "First".to_string(),
+            "alice".to_string(),
+            vec![a],
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+
+        let ids = |query: ChangeQuery| -> Vec<Uuid> {
+            query.fetch().unwrap().iter().map(|c| c.id).collect()
+        };
+        let query = || storage.query_changes(&session.id);
+
+        assert_eq!(ids(query()), vec![e, d, c, b, a]);
+        // A prefix matches whole path components only
+        assert_eq!(ids(query().path_prefix("src")), vec![d, b, a]);
+        assert_eq!(ids(query().path_prefix("src/").agent("alice")), vec![d, a]);
+        assert_eq!(
+            ids(query()
+                .agent("alice")
+                .change_type(ChangeType::Create)
+                .committed(false)),
+            vec![e, d]
+        );
+        assert_eq!(ids(query().committed(true)), vec![a]);
+        assert_eq!(
+            ids(query()
+                .since(start + chrono::Duration::minutes(1))
+                .until(start + chrono::Duration::minutes(3))
+                .order(SortOrder::Ascending)),
+            vec![b, c]
+        );
+        assert_eq!(
+            ids(query().agent("alice").order(SortOrder::Ascending).limit(2)),
+            vec![a, c]
+        );
+        assert!(ids(query().agent("carol")).is_empty());
+    }
+
+    #[test]
+    fn test_get_latest_content() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        let create = Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id)
+            .with_content_after(b"one".to_vec());
+        let modify = Change::new(ChangeType::Modify, PathBuf::from("a.txt"), session.id)
+            .with_content_after(b"two".to_vec());
+        storage.create_change(&create).unwrap();
+        storage.create_change(&modify).unwrap();
+
+        let latest = |path: &str| {
+            storage
+                .get_latest_content(&session.id, Path::new(path))
+                .unwrap()
+        };
+        assert_eq!(latest("a.txt"), Some(b"two".to_vec()));
+        assert_eq!(latest("b.txt"), None);
+
+        let rename = Change::new(ChangeType::Rename, PathBuf::from("b.txt"), session.id)
+            .with_old_path(PathBuf::from("a.txt"))
+            .with_content_after(b"two".to_vec());
+        storage.create_change(&rename).unwrap();
+        assert_eq!(latest("a.txt"), None);
+        assert_eq!(latest("b.txt"), Some(b"two".to_vec()));
+    }
+
+    #[test]
+    fn test_get_file_versions() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        let create = Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id)
+            .with_content_after(b"one".to_vec());
+        let first = Change::new(ChangeType::Modify, PathBuf::from("a.txt"), session.id)
+            .with_content_before(b"one".to_vec())
+            .with_content_after(b"two".to_vec());
+        let second = Change::new(ChangeType::Modify, PathBuf::from("a.txt"), session.id)
+            .with_content_before(b"two".to_vec())
+            .with_content_after(b"three".to_vec());
+        let other = Change::new(ChangeType::Create, PathBuf::from("c.txt"), session.id)
+            .with_content_after(b"other".to_vec());
+        for change in [&create, &first, &other, &second] {
+            storage.create_change(change).unwrap();
+        }
+        let commit = Commit::new(
+            "Write a".to_string(),
+            "test-agent".to_string(),
+            vec![create.id, first.id],
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+
+        let versions = storage
+            .get_file_versions(&session.id, Path::new("a.txt"))
+            .unwrap();
+        assert_eq!(
+            versions
+                .iter()
+                .map(|v| (v.change_id, v.commit_id, v.change_type, v.content.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    create.id,
+                    Some(commit.id),
+                    ChangeType::Create,
+                    Some(b"one".to_vec())
+                ),
+                (
+                    first.id,
+                    Some(commit.id),
+                    ChangeType::Modify,
+                    Some(b"two".to_vec())
+                ),
+                (second.id, None, ChangeType::Modify, Some(b"three".to_vec())),
+            ]
+        );
+
+        // Followed back through a rename, with later changes at the old path left out
+        let rename = Change::new(ChangeType::Rename, PathBuf::from("b.txt"), session.id)
+            .with_old_path(PathBuf::from("a.txt"))
+            .with_content_after(b"three".to_vec());
+        let recreate = Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id)
+            .with_content_after(b"new".to_vec());
+        storage.create_change(&rename).unwrap();
+        storage.create_change(&recreate).unwrap();
+        let versions = storage
+            .get_file_versions(&session.id, Path::new("b.txt"))
+            .unwrap();
+        assert_eq!(
+            versions.iter().map(|v| v.change_id).collect::<Vec<_>>(),
+            vec![create.id, first.id, second.id, rename.id]
+        );
+        assert_eq!(versions[2].path, PathBuf::from("a.txt"));
+        assert_eq!(versions[3].path, PathBuf::from("b.txt"));
+
+        let mut without_content = Vec::new();
+        storage
+            .for_each_file_version(&session.id, Path::new("b.txt"), false, |v| {
+                without_content.push(v.content);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(without_content, vec![None; 4]);
+        assert!(storage
+            .get_file_versions(&session.id, Path::new("missing.txt"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_get_change_with_context() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let create = Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id)
+            .with_content_after(b"v1".to_vec());
+        let modify = Change::new(ChangeType::Modify, PathBuf::from("a.txt"), session.id)
+            .with_content_before(b"v1".to_vec())
+            .with_content_after(b"v2".to_vec());
+        let other = Change::new(ChangeType::Modify, PathBuf::from("b.txt"), session.id)
+            .with_content_after(b"other".to_vec());
+        for change in [&create, &modify, &other] {
+            storage.create_change(change).unwrap();
+        }
+        storage
+            .create_commit(&Commit::new(
+                "Write a".to_string(),
+                "test-agent".to_string(),
+                vec![create.id, modify.id],
+                session.id,
+            ))
+            .unwrap();
+
+        // As the watcher records it, with only the new content
+        let standalone = Change::new(ChangeType::Modify, PathBuf::from("a.txt"), session.id)
+            .with_content_after(b"v3".to_vec());
+        storage.create_change(&standalone).unwrap();
+        assert_eq!(
+            storage.get_change(&standalone.id).unwrap().content_before,
+            None
+        );
+
+        let change = storage.get_change_with_context(&standalone.id).unwrap();
+        assert_eq!(change.content_before.as_deref(), Some(&b"v2"[..]));
+        assert_eq!(change.content_after.as_deref(), Some(&b"v3"[..]));
+        assert!(change.verify_content_integrity());
+
+        // Nothing earlier to draw on
+        let change = storage.get_change_with_context(&other.id).unwrap();
+        assert_eq!(change.content_before, None);
+    }
+
+    #[test]
+    fn test_get_commits_for_issue() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let linked = Commit::new(
+            "Fix crash".to_string(),
+            "cli-user".to_string(),
+            vec![],
+            session.id,
+        )
+        .with_issue("GH-123".to_string())
+        .with_pr_url("https://github.com/org/repo/pull/7".to_string());
+        let other = Commit::new(
+            "Unrelated".to_string(),
+            "cli-user".to_string(),
+            vec![],
+            session.id,
+        )
+        .with_issue("GH-124".to_string());
+        storage.create_commit(&linked).unwrap();
+        storage.create_commit(&other).unwrap();
+
+        let commits = storage.get_commits_for_issue("GH-123").unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].id, linked.id);
+        assert_eq!(commits[0].issue(), Some("GH-123"));
+        assert_eq!(
+            commits[0].pr_url(),
+            Some("https://github.com/org/repo/pull/7")
+        );
+        assert!(storage.get_commits_for_issue("GH-1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_milestones() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let commits: Vec<Commit> = ["Scaffold", "Tweak", "Feature done"]
+            .into_iter()
+            .map(|message| {
+                let commit = Commit::new(message.to_string(), "agent".into(), vec![], session.id);
+                storage.create_commit(&commit).unwrap();
+                commit
+            })
+            .collect();
+        let released =
+            Commit::new("Release".into(), "agent".into(), vec![], session.id).with_milestone();
+        storage.create_commit(&released).unwrap();
+        assert_eq!(storage.get_milestones(&session.id).unwrap().len(), 1);
+
+        let marked = storage.set_milestone(&commits[2].id, true).unwrap();
+        assert!(marked.is_milestone());
+        let milestones = storage.get_milestones(&session.id).unwrap();
+        let ids: Vec<_> = milestones.iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![released.id, commits[2].id]);
+        assert!(milestones.iter().all(Commit::is_milestone));
+
+        storage.set_milestone(&released.id, false).unwrap();
+        assert!(!storage.get_commit(&released.id).unwrap().is_milestone());
+        let ids: Vec<_> = storage
+            .get_milestones(&session.id)
+            .unwrap()
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(ids, vec![commits[2].id]);
+        assert!(matches!(
+            storage.set_milestone(&Uuid::new_v4(), true),
+            Err(Error::CommitNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_get_commits_touching_path() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let change1 = Change::new(ChangeType::Create, PathBuf::from("target.txt"), session.id);
+        let change2 = Change::new(ChangeType::Create, PathBuf::from("other.txt"), session.id);
+        let change3 = Change::new(ChangeType::Rename, PathBuf::from("renamed.txt"), session.id)
+            .with_old_path(PathBuf::from("target.txt"));
+
+        for change in [&change1, &change2, &change3] {
+            storage.create_change(change).unwrap();
+        }
+
+        let commit1 = Commit::new(
+            "Add target".to_string(),
+            "test-agent".to_string(),
+            vec![change1.id],
+            session.id,
+        );
+        let commit2 = Commit::new(
+            "Add other".to_string(),
+            "test-agent".to_string(),
+            vec![change2.id],
+            session.id,
+        );
+        let commit3 = Commit::new(
+            "Rename target".to_string(),
+            "test-agent".to_string(),
+            vec![change3.id],
+            session.id,
+        );
+
+        for commit in [&commit1, &commit2, &commit3] {
+            storage.create_commit(commit).unwrap();
+        }
+
+        let commits = storage
+            .get_commits_touching_path(&session.id, Path::new("target.txt"))
+            .unwrap();
+        let ids: Vec<Uuid> = commits.iter().map(|c| c.id).collect();
+
+        assert_eq!(2, ids.len());
+        assert!(ids.contains(&commit1.id));
+        assert!(ids.contains(&commit3.id));
+        assert!(!ids.contains(&commit2.id));
+    }
+
+    #[test]
+    fn test_changes_with_identical_timestamps() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let timestamp = Utc::now();
+        let mut ids = Vec::new();
+        for i in 0..6 {
+            let mut change = Change::new(
+                ChangeType::Create,
+                PathBuf::from(format!("{}.txt", i)),
+                session.id,
+            );
+            change.timestamp = timestamp;
+            let seq = storage.create_change(&change).unwrap();
+            assert_eq!(storage.get_change(&change.id).unwrap().seq, Some(seq));
+            ids.push(change.id);
+        }
+
+        // Newest first, in exactly the reverse of insertion order
+        let newest_first: Vec<Uuid> = storage
+            .get_uncommitted_changes(&session.id)
+            .unwrap()
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(newest_first, ids.iter().rev().copied().collect::<Vec<_>>());
+
+        let mut paged = Vec::new();
+        let mut after_seq = 0;
+        loop {
+            let page = storage
+                .get_uncommitted_changes_after(&session.id, after_seq, Some(4))
+                .unwrap();
+            let Some(last) = page.last() else {
+                break;
+            };
+            after_seq = last.seq.unwrap();
+            paged.extend(page.iter().map(|c| c.id));
+        }
+        assert_eq!(paged, ids);
+    }
+
+    #[test]
+    fn test_count_changes_by_language() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        for path in ["main.rs", "lib.rs", "app.py", "notes.xyz"] {
+            let change = Change::new(ChangeType::Create, PathBuf::from(path), session.id);
+            storage.create_change(&change).unwrap();
+        }
+
+        let change = storage
+            .get_uncommitted_changes(&session.id)
+            .unwrap()
+            .into_iter()
+            .find(|c| c.path == Path::new("main.rs"))
+            .unwrap();
+        assert_eq!(change.language.as_deref(), Some("Rust"));
+
+        let counts = storage.count_changes_by_language(&session.id).unwrap();
+        assert_eq!(
+            counts,
+            vec![
+                (Some("Rust".to_string()), 2),
+                (None, 1),
+                (Some("Python".to_string()), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_migration_makes_paths_relative() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("gitent.db");
+        let session = Session::new(PathBuf::from("/work/project"));
+        let inside = Change::new(
+            ChangeType::Rename,
+            PathBuf::from("/work/project/src/new.rs"),
+            session.id,
+        )
+        .with_old_path(PathBuf::from("/work/project/src/old.rs"));
+        let outside = Change::new(
+            ChangeType::Create,
+            PathBuf::from("/work/project-other/a.rs"),
+            session.id,
+        );
+
+        {
+            let storage = Storage::new(&db_path).unwrap();
+            storage.create_session(&session).unwrap();
+            storage.create_change(&inside).unwrap();
+            storage.create_change(&outside).unwrap();
+            // Back to a version 7 database
+            storage
+                .conn
+                .execute_batch(
+                    "ALTER TABLE commits DROP COLUMN digest;
+                     ALTER TABLE changes DROP COLUMN external;
+                     ALTER TABLE sessions DROP COLUMN hash_algorithm;
+                     ALTER TABLE sessions DROP COLUMN metadata_schema;
+                     ALTER TABLE sessions DROP COLUMN include_patterns;
+                     ALTER TABLE changes DROP COLUMN compressed; ALTER TABLE changes DROP COLUMN mtime_after;
+                     ALTER TABLE changes DROP COLUMN mtime_before;
+                     DROP TABLE audit;
+                     ALTER TABLE sessions DROP COLUMN redact_patterns;
+                     DROP INDEX idx_commits_issue;
+                     DROP TABLE checkpoints;
+                     ALTER TABLE sessions DROP COLUMN content_root;
+                     UPDATE schema_version SET version = 7;",
+                )
+                .unwrap();
+        }
+
+        let storage = Storage::new(&db_path).unwrap();
+        let inside = storage.get_change(&inside.id).unwrap();
+        assert_eq!(inside.path, PathBuf::from("src/new.rs"));
+        assert_eq!(inside.old_path, Some(PathBuf::from("src/old.rs")));
+        assert_eq!(
+            storage.get_change(&outside.id).unwrap().path,
+            PathBuf::from("/work/project-other/a.rs")
+        );
+    }
+
+    #[test]
+    fn test_commit_range() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let mut ids = Vec::new();
+        for i in 0..3 {
+            let change = Change::new(ChangeType::Create, format!("{}.txt", i).into(), session.id);
+            storage.create_change(&change).unwrap();
+            let commit = Commit::new(i.to_string(), "agent".into(), vec![change.id], session.id);
+            storage.create_commit(&commit).unwrap();
+            ids.push(commit.id);
+        }
+
+        let range: Vec<Uuid> = storage
+            .commit_range(&ids[1], &ids[2])
+            .unwrap()
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(range, [ids[1], ids[2]]);
+        assert_eq!(storage.commit_range(&ids[0], &ids[0]).unwrap().len(), 1);
+
+        // Backwards
+        assert!(matches!(
+            storage.commit_range(&ids[2], &ids[0]),
+            Err(Error::InvalidOperation(_))
+        ));
+    }
+
+    #[test]
+    fn test_tree_at_commit() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        let commit = |changes: Vec<Change>| {
+            for change in &changes {
+                storage.create_change(change).unwrap();
+            }
+            let mut commit = Commit::new(
+                "Work".to_string(),
+                "agent".to_string(),
+                changes.iter().map(|c| c.id).collect(),
+                session.id,
+            );
+            if let Some(head) = storage.get_head_commit(&session.id).unwrap() {
+                commit = commit.with_parent(head.id);
+            }
+            storage.create_commit(&commit).unwrap();
+            commit.id
+        };
+        let create = |path: &str, content: &str| {
+            Change::new(ChangeType::Create, path.into(), session.id)
+                .with_content_after(content.as_bytes().to_vec())
+        };
+
+        let first = commit(vec![create("a.txt", "a1"), create("b.txt", "b")]);
+        let second = commit(vec![
+            Change::new(ChangeType::Modify, "a.txt".into(), session.id)
+                .with_content_before(b"a1".to_vec())
+                .with_content_after(b"a2".to_vec()),
+            Change::new(ChangeType::Rename, "c.txt".into(), session.id)
+                .with_old_path("b.txt".into())
+                .with_content_after(b"b".to_vec()),
+        ]);
+        let third = commit(vec![Change::new(
+            ChangeType::Delete,
+            "a.txt".into(),
+            session.id,
+        )]);
+        // Uncommitted changes aren't part of any commit's tree
+        storage.create_change(&create("d.txt", "d")).unwrap();
+
+        let contents = |commit_id| -> Vec<(PathBuf, Vec<u8>)> {
+            storage
+                .tree_at_commit(&commit_id)
+                .unwrap()
+                .into_iter()
+                .map(|(path, change)| (path, change.content_after.unwrap()))
+                .collect()
+        };
+        assert_eq!(
+            contents(first),
+            [
+                ("a.txt".into(), b"a1".to_vec()),
+                ("b.txt".into(), b"b".to_vec())
+            ]
+        );
+        assert_eq!(
+            contents(second),
+            [
+                ("a.txt".into(), b"a2".to_vec()),
+                ("c.txt".into(), b"b".to_vec())
+            ]
+        );
+        assert_eq!(contents(third), [("c.txt".into(), b"b".to_vec())]);
+    }
+
+    #[test]
+    fn test_parent_must_be_in_same_session() {
+        let storage = Storage::in_memory().unwrap();
+        let first = Session::new(PathBuf::from("/first"));
+        let second = Session::new(PathBuf::from("/second"));
+        storage.create_session(&first).unwrap();
+        storage.create_session(&second).unwrap();
+
+        let base = Commit::new("base".into(), "agent".into(), vec![], first.id);
+        storage.create_commit(&base).unwrap();
+
+        let other =
+            Commit::new("other".into(), "agent".into(), vec![], second.id).with_parent(base.id);
+        assert!(matches!(
+            storage.create_commit(&other),
+            Err(Error::InvalidOperation(_))
+        ));
+
+        let orphan = Commit::new("orphan".into(), "agent".into(), vec![], first.id)
+            .with_parent(Uuid::new_v4());
+        assert!(matches!(
+            storage.create_commit(&orphan),
+            Err(Error::CommitNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_ancestry_path_follows_parents() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let first = Commit::new("first".into(), "agent".into(), vec![], session.id);
+        let second =
+            Commit::new("second".into(), "agent".into(), vec![], session.id).with_parent(first.id);
+        let third =
+            Commit::new("third".into(), "agent".into(), vec![], session.id).with_parent(second.id);
+        // A fork off the first commit, not on the path to the third
+        let side =
+            Commit::new("side".into(), "agent".into(), vec![], session.id).with_parent(first.id);
+        for commit in [&first, &second, &side, &third] {
+            storage.create_commit(commit).unwrap();
+        }
+
+        let ids = |from: &Commit, to: &Commit| -> Vec<Uuid> {
+            storage
+                .get_ancestry_path(&from.id, &to.id)
+                .unwrap()
+                .iter()
+                .map(|c| c.id)
+                .collect()
+        };
+        assert_eq!(ids(&first, &third), vec![second.id, third.id]);
+        assert_eq!(ids(&first, &side), vec![side.id]);
+        assert!(ids(&third, &third).is_empty());
+
+        assert!(matches!(
+            storage.get_ancestry_path(&third.id, &first.id),
+            Err(Error::InvalidOperation(_))
+        ));
+        assert!(matches!(
+            storage.get_ancestry_path(&side.id, &third.id),
+            Err(Error::InvalidOperation(_))
+        ));
+    }
+
+    #[test]
+    fn test_diff_since_snapshot() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let change = |path: &str| Change::new(ChangeType::Modify, PathBuf::from(path), session.id);
+        storage.create_change(&change("/test/before.txt")).unwrap();
+        let snapshot = storage.snapshot_uncommitted(&session.id).unwrap();
+        assert!(storage.diff_since_snapshot(&snapshot).unwrap().is_empty());
+
+        let first = change("/test/a.txt");
+        let second = change("/test/b.txt");
+        storage.create_change(&first).unwrap();
+        storage.create_change(&second).unwrap();
+
+        let since: Vec<Uuid> = storage
+            .diff_since_snapshot(&snapshot)
+            .unwrap()
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(since, vec![first.id, second.id]);
+
+        assert!(matches!(
+            storage.diff_since_snapshot(&Uuid::new_v4()),
+            Err(Error::SnapshotNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_changes_since_checkpoint() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let change = |path: &str| Change::new(ChangeType::Modify, PathBuf::from(path), session.id);
+        storage.create_change(&change("setup.txt")).unwrap();
+        storage
+            .create_checkpoint(&session.id, "tests pass")
+            .unwrap();
+
+        let refactor = change("a.txt");
+        storage.create_change(&refactor).unwrap();
+        storage
+            .create_checkpoint(&session.id, "refactored")
+            .unwrap();
+
+        let cleanup = change("b.txt");
+        storage.create_change(&cleanup).unwrap();
+        let commit = Commit::new(
+            "Cleanup".into(),
+            "agent".into(),
+            vec![cleanup.id],
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+
+        let since = |label: &str| -> Vec<Uuid> {
+            storage
+                .changes_since_checkpoint(&session.id, label)
+                .unwrap()
+                .iter()
+                .map(|c| c.id)
+                .collect()
+        };
+        assert_eq!(since("tests pass"), vec![refactor.id, cleanup.id]);
+        assert_eq!(since("refactored"), vec![cleanup.id]);
+
+        let labels: Vec<String> = storage
+            .list_checkpoints(&session.id)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.label)
+            .collect();
+        assert_eq!(labels, ["tests pass", "refactored"]);
+
+        assert!(matches!(
+            storage.create_checkpoint(&session.id, "refactored"),
+            Err(Error::InvalidOperation(_))
+        ));
+        assert!(matches!(
+            storage.changes_since_checkpoint(&session.id, "missing"),
+            Err(Error::CheckpointNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_insert_past_max_size_is_storage_full() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        storage.set_max_size(0).unwrap();
+
+        let change = Change::new(
+            ChangeType::Create,
+            PathBuf::from("/test/big.bin"),
+            session.id,
+        )
+        .with_content_after(vec![7; 1 << 20]);
+        assert!(matches!(
+            storage.create_change(&change),
+            Err(Error::StorageFull(_))
+        ));
+    }
+
+    #[test]
+    fn test_file_index_round_trip() {
+        let storage = Storage::in_memory().unwrap();
+        let root = Path::new("/project");
+        assert!(storage.load_file_index(root).unwrap().is_none());
+
+        let entry = |path: &str, mtime_ns, hash: Option<&str>| FileIndexEntry {
+            path: root.join(path),
+            mtime_ns,
+            hash: hash.map(str::to_string),
+        };
+        storage
+            .save_file_index(
+                root,
+                &[entry("a.txt", 1, Some("aaa")), entry("b.txt", 2, None)],
+            )
+            .unwrap();
+        storage
+            .save_file_index(root, &[entry("a.txt", 3, Some("bbb"))])
+            .unwrap();
+        storage
+            .remove_from_file_index(root, &[root.join("b.txt")])
+            .unwrap();
+
+        let index = storage.load_file_index(root).unwrap().unwrap();
+        assert_eq!(index.len(), 1);
+        assert_eq!(index[&root.join("a.txt")], entry("a.txt", 3, Some("bbb")));
+        assert!(storage
+            .load_file_index(Path::new("/other"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_change_tool_round_trip() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let formatted = Change::new(ChangeType::Modify, PathBuf::from("main.rs"), session.id)
+            .with_content_after(b"fn main() {}\n".to_vec())
+            .with_tool("rustfmt".to_string());
+        let untagged = Change::new(ChangeType::Create, PathBuf::from("notes.txt"), session.id);
+        storage.create_change(&formatted).unwrap();
+        storage.create_change(&untagged).unwrap();
+
+        assert_eq!(
+            storage.get_change(&formatted.id).unwrap().tool.as_deref(),
+            Some("rustfmt")
+        );
+        assert_eq!(storage.get_change(&untagged.id).unwrap().tool, None);
+
+        let format_commit = Commit::new(
+            "Format".to_string(),
+            "test-agent".to_string(),
+            vec![formatted.id],
+            session.id,
+        );
+        let notes_commit = Commit::new(
+            "Notes".to_string(),
+            "test-agent".to_string(),
+            vec![untagged.id],
+            session.id,
+        );
+        storage.create_commit(&format_commit).unwrap();
+        storage.create_commit(&notes_commit).unwrap();
+
+        let commits = storage.get_commits_by_tool(&session.id, "rustfmt").unwrap();
+        assert_eq!(1, commits.len());
+        assert_eq!(format_commit.id, commits[0].id);
+        assert!(storage
+            .get_commits_by_tool(&session.id, "editor")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_check_integrity_reports_dangling_rows() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let change = Change::new(ChangeType::Create, PathBuf::from("file.txt"), session.id);
+        storage.create_change(&change).unwrap();
+
+        let commit = Commit::new(
+            "Test commit".to_string(),
+            "test-agent".to_string(),
+            vec![change.id],
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+
+        assert!(storage.check_integrity().unwrap().is_clean());
+
+        // Simulate a database written without foreign key enforcement
+        storage
+            .conn
+            .execute_batch("PRAGMA foreign_keys = OFF")
+            .unwrap();
+
+        let missing_change = Uuid::new_v4();
+        let missing_commit = Uuid::new_v4();
+        storage
+            .conn
+            .execute(
+                "INSERT INTO commit_changes (commit_id, change_id) VALUES (?1, ?2)",
+                params![commit.id.to_string(), missing_change.to_string()],
+            )
+            .unwrap();
+        storage
+            .conn
+            .execute(
+                "INSERT INTO commit_changes (commit_id, change_id) VALUES (?1, ?2)",
+                params![missing_commit.to_string(), change.id.to_string()],
+            )
+            .unwrap();
+
+        let orphan = Change::new(
+            ChangeType::Create,
+            PathBuf::from("orphan.txt"),
+            Uuid::new_v4(),
+        );
+        storage.create_change(&orphan).unwrap();
+
+        let report = storage.check_integrity().unwrap();
+        assert_eq!(
+            report.missing_changes,
+            vec![CommitChangeLink {
+                commit_id: commit.id,
+                change_id: missing_change,
+            }]
+        );
+        assert_eq!(
+            report.dangling_links,
+            vec![CommitChangeLink {
+                commit_id: missing_commit,
+                change_id: change.id,
+            }]
+        );
+        assert_eq!(report.orphaned_changes, vec![orphan.id]);
+
+        assert_eq!(2, storage.repair_integrity(&report).unwrap());
+        let report = storage.check_integrity().unwrap();
+        assert!(report.missing_changes.is_empty());
+        assert!(report.dangling_links.is_empty());
+        assert_eq!(1, storage.get_commit(&commit.id).unwrap().changes.len());
+    }
+
+    #[test]
+    fn test_collect_orphan_blobs() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        let modify = |before: &str, after: &str| {
+            let change = Change::new(ChangeType::Modify, PathBuf::from("a.txt"), session.id)
+                .with_content_before(before.as_bytes().to_vec())
+                .with_content_after(after.as_bytes().to_vec());
+            storage.create_change(&change).unwrap();
+            change
+        };
+        let commit = |changes: &[&Change]| {
+            let mut commit = Commit::new(
+                "Edit".to_string(),
+                "test-agent".to_string(),
+                changes.iter().map(|c| c.id).collect(),
+                session.id,
+            );
+            if let Some(head) = storage.get_head_commit(&session.id).unwrap() {
+                commit = commit.with_parent(head.id);
+            }
+            storage.create_commit(&commit).unwrap();
+        };
+
+        let committed = modify("one", "two");
+        commit(&[&committed]);
+        let orphan = modify("two", "three!");
+        commit(&[&orphan]);
+        // Undone, and then redone differently
+        storage.reset_head(&session.id).unwrap();
+        assert!(storage.find_orphan_blobs().unwrap().is_empty());
+        let redone = modify("two", "3");
+        commit(&[&redone]);
+
+        // Pending edits are kept, even when a later one supersedes them
+        let pending = modify("3", "4");
+        let latest = modify("4", "5");
+
+        assert_eq!(
+            storage.find_orphan_blobs().unwrap(),
+            vec![OrphanBlob {
+                change_id: orphan.id,
+                bytes: 9,
+            }]
+        );
+
+        let report = storage.collect_orphan_blobs().unwrap();
+        assert_eq!(
+            report,
+            GcReport {
+                changes_removed: 1,
+                objects_removed: 0,
+                bytes_reclaimed: 9,
+            }
+        );
+        assert!(storage.get_change(&orphan.id).is_err());
+        for kept in [&committed, &redone, &pending, &latest] {
+            assert!(storage.get_change(&kept.id).is_ok());
+        }
+        assert!(storage.find_orphan_blobs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deep_check_finds_corrupted_content() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let good = Change::new(ChangeType::Create, PathBuf::from("good.txt"), session.id)
+            .with_content_after(b"good".to_vec());
+        let bad = Change::new(ChangeType::Create, PathBuf::from("bad.txt"), session.id)
+            .with_content_after(b"bad".to_vec());
+        storage.create_change(&good).unwrap();
+        storage.create_change(&bad).unwrap();
+        storage
+            .conn
+            .execute(
+                "UPDATE changes SET content_after = ?1 WHERE id = ?2",
+                params![b"flipped".to_vec(), bad.id.to_string()],
+            )
+            .unwrap();
+
+        assert!(storage.check_integrity().unwrap().is_clean());
+        let report = storage.check_integrity_deep().unwrap();
+        assert_eq!(report.corrupted_changes, vec![bad.id]);
+    }
+
+    #[test]
+    fn test_deep_check_finds_altered_commits() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let change = Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id)
+            .with_content_after(b"a".to_vec());
+        storage.create_change(&change).unwrap();
+        let commit = Commit::new("Add a".into(), "agent".into(), vec![change.id], session.id);
+        storage.create_commit(&commit).unwrap();
+        let empty = Commit::new("Nothing".into(), "agent".into(), vec![], session.id);
+        storage.create_commit(&empty).unwrap();
+        assert!(storage.check_integrity_deep().unwrap().is_clean());
+
+        storage
+            .conn
+            .execute(
+                "UPDATE changes SET path = 'b.txt' WHERE id = ?1",
+                params![change.id.to_string()],
+            )
+            .unwrap();
+        assert!(storage.check_integrity().unwrap().is_clean());
+        let report = storage.check_integrity_deep().unwrap();
+        assert_eq!(report.altered_commits, vec![commit.id]);
+        assert!(report.corrupted_changes.is_empty());
+    }
+
+    #[test]
+    fn test_delta_storage_for_small_edit() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test")).with_delta_storage(true);
+        storage.create_session(&session).unwrap();
+
+        let original: Vec<u8> = (0..5000)
+            .map(|i| format!("line number {}\n", i))
+            .collect::<String>()
+            .into_bytes();
+        let mut edited = original.clone();
+        edited.extend_from_slice(b"one more line\n");
+
+        let create = Change::new(ChangeType::Create, PathBuf::from("big.txt"), session.id)
+            .with_content_after(original.clone());
+        storage.create_change(&create).unwrap();
+
+        let modify = Change::new(ChangeType::Modify, PathBuf::from("big.txt"), session.id)
+            .with_content_before(original.clone())
+            .with_content_after(edited.clone());
+        storage.create_change(&modify).unwrap();
+
+        let stored_bytes: i64 = storage
+            .conn
+            .query_row(
+                "SELECT COALESCE(length(content_before), 0) + length(content_after)
+                 FROM changes WHERE id = ?1",
+                params![modify.id.to_string()],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!((stored_bytes as usize) < edited.len() / 100);
+
+        let retrieved = storage.get_change(&modify.id).unwrap();
+        assert_eq!(retrieved.content_before, Some(original));
+        assert_eq!(retrieved.content_after, Some(edited));
+        assert_eq!(retrieved.content_hash_after, modify.content_hash_after);
+    }
+
+    #[test]
+    fn test_batch_is_one_transaction() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("gitent.db");
+        let log_path = temp_dir.path().join("events.log");
+        let storage = Storage::new(&db_path)
+            .unwrap()
+            .with_event_log(EventLog::open(&log_path).unwrap());
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        let log_lines = || std::fs::read_to_string(&log_path).unwrap().lines().count();
+        let logged = log_lines();
+
+        let changes: Vec<_> = (0..3)
+            .map(|i| {
+                Change::new(
+                    ChangeType::Create,
+                    PathBuf::from(format!("{}.txt", i)),
+                    session.id,
+                )
+                .with_content_after(vec![i])
+            })
+            .collect();
+        let other = Connection::open(&db_path).unwrap();
+        let visible = || -> i64 {
+            other
+                .query_row("SELECT COUNT(*) FROM changes", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        // A failed write is skipped, and nothing is visible until the end
+        let recorded = storage
+            .batch(|storage| {
+                let mut recorded = 0;
+                for change in changes.iter().chain(&changes[..1]) {
+                    if storage.create_change(change).is_ok() {
+                        recorded += 1;
+                    }
+                }
+                assert_eq!(visible(), 0);
+                assert_eq!(log_lines(), logged);
+                Ok(recorded)
+            })
+            .unwrap();
+        assert_eq!(recorded, 3);
+        assert_eq!(visible(), 3);
+        assert_eq!(log_lines(), logged + 3);
+
+        // A failed batch keeps nothing
+        let extra = Change::new(ChangeType::Create, PathBuf::from("x.txt"), session.id);
+        let result: Result<()> = storage.batch(|storage| {
+            storage.create_change(&extra)?;
+            Err(Error::StorageFull("test".to_string()))
+        });
+        assert!(result.is_err());
+        assert!(storage.get_change(&extra.id).is_err());
+        assert_eq!(visible(), 3);
+        assert_eq!(log_lines(), logged + 3);
+
+        // Writes outside a batch are logged straight away again
+        storage.create_change(&extra).unwrap();
+        assert_eq!(log_lines(), logged + 4);
+    }
+
+    #[test]
+    fn test_cancel_long_reconstruction() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test")).with_delta_storage(true);
+        storage.create_session(&session).unwrap();
 
-        let changes = self.get_changes_for_commit(&id)?;
+        let mut content: Vec<u8> = (0..2000)
+            .map(|i| format!("line number {}\n", i))
+            .collect::<String>()
+            .into_bytes();
+        let create = Change::new(ChangeType::Create, PathBuf::from("big.txt"), session.id)
+            .with_content_after(content.clone());
+        storage.create_change(&create).unwrap();
+        let mut last = create.id;
+        for i in 0..MAX_DELTA_CHAIN - 1 {
+            let mut edited = content.clone();
+            edited.extend_from_slice(format!("edit {}\n", i).as_bytes());
+            let modify = Change::new(ChangeType::Modify, PathBuf::from("big.txt"), session.id)
+                .with_content_before(content)
+                .with_content_after(edited.clone());
+            storage.create_change(&modify).unwrap();
+            content = edited;
+            last = modify.id;
+        }
 
-        Ok(Commit {
-            id: Uuid::parse_str(&id).unwrap(),
-            parent: parent.and_then(|p| Uuid::parse_str(&p).ok()),
-            timestamp: DateTime::parse_from_rfc3339(&timestamp).unwrap().into(),
-            message,
-            agent_id,
-            changes,
-            session_id: Uuid::parse_str(&session_id).unwrap(),
-            metadata: serde_json::from_str(&metadata).unwrap_or_default(),
-        })
+        // Keep reconstructing the end of the chain until cancelled from
+        // another thread
+        let token = CancelToken::new();
+        let canceller = {
+            let token = token.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                token.cancel();
+                std::time::Instant::now()
+            })
+        };
+        let result: Result<()> = storage.cancellable(&token, |storage| loop {
+            storage.latest_change_per_file(&session.id)?;
+        });
+        let stopped = std::time::Instant::now();
+        let cancelled = canceller.join().unwrap();
+
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(stopped.duration_since(cancelled) < std::time::Duration::from_secs(1));
+
+        // The token only applied inside `cancellable`, and nothing was harmed
+        let change = storage.get_change(&last).unwrap();
+        assert_eq!(change.content_after, Some(content));
+        assert!(storage
+            .check_integrity_deep()
+            .unwrap()
+            .corrupted_changes
+            .is_empty());
+        let expired = CancelToken::new().with_deadline(std::time::Instant::now());
+        assert!(matches!(
+            storage.cancellable(&expired, |storage| storage.get_change(&last)),
+            Err(Error::Timeout)
+        ));
     }
 
-    fn get_changes_for_commit(&self, commit_id: &str) -> rusqlite::Result<Vec<Uuid>> {
-        let mut stmt = self
+    #[test]
+    fn test_full_content_without_delta_storage() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let create = Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id)
+            .with_content_after(b"a\nb\n".to_vec());
+        let modify = Change::new(ChangeType::Modify, PathBuf::from("a.txt"), session.id)
+            .with_content_after(b"a\nb\nc\n".to_vec());
+        storage.create_change(&create).unwrap();
+        storage.create_change(&modify).unwrap();
+
+        let delta_base: Option<String> = storage
             .conn
-            .prepare("SELECT change_id FROM commit_changes WHERE commit_id = ?1")?;
+            .query_row(
+                "SELECT delta_base FROM changes WHERE id = ?1",
+                params![modify.id.to_string()],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(delta_base.is_none());
+    }
 
-        let changes = stmt
-            .query_map(params![commit_id], |row| {
-                let id: String = row.get(0)?;
-                Ok(Uuid::parse_str(&id).unwrap())
-            })?
-            .collect::<rusqlite::Result<Vec<Uuid>>>()?;
+    #[test]
+    fn test_deltas_for_text_compression_for_binaries() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test")).with_delta_storage(true);
+        storage.create_session(&session).unwrap();
+        let stored = |id: &Uuid| -> (Option<String>, bool, Vec<u8>) {
+            storage
+                .conn
+                .query_row(
+                    "SELECT delta_base, compressed, content_after FROM changes WHERE id = ?1",
+                    params![id.to_string()],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .unwrap()
+        };
 
-        Ok(changes)
+        let text: Vec<u8> = (0..500)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>()
+            .into_bytes();
+        let mut edited = text.clone();
+        edited.extend_from_slice(b"one more\n");
+        let create = Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id)
+            .with_content_after(text.clone());
+        let modify = Change::new(ChangeType::Modify, PathBuf::from("a.txt"), session.id)
+            .with_content_before(text.clone())
+            .with_content_after(edited.clone());
+        storage.create_change(&create).unwrap();
+        storage.create_change(&modify).unwrap();
+
+        let (delta_base, compressed, content) = stored(&modify.id);
+        assert_eq!(delta_base, Some(create.id.to_string()));
+        assert!(!compressed);
+        assert!(content.len() < edited.len() / 10);
+        let retrieved = storage.get_change(&modify.id).unwrap();
+        assert_eq!(retrieved.content_before, Some(text));
+        assert_eq!(retrieved.content_after, Some(edited));
+
+        // Binary edits are never deltas, but their content is deflated
+        let image: Vec<u8> = [b"\x89PNG\r\n\x1a\n\0".as_slice(), &[0; 4096]].concat();
+        let mut recolored = image.clone();
+        recolored[100] = 1;
+        let create = Change::new(ChangeType::Create, PathBuf::from("a.png"), session.id)
+            .with_content_after(image.clone());
+        let modify = Change::new(ChangeType::Modify, PathBuf::from("a.png"), session.id)
+            .with_content_before(image.clone())
+            .with_content_after(recolored.clone());
+        storage.create_change(&create).unwrap();
+        storage.create_change(&modify).unwrap();
+
+        let (delta_base, compressed, content) = stored(&modify.id);
+        assert!(delta_base.is_none());
+        assert!(compressed);
+        assert!(content.len() < recolored.len() / 10);
+        let retrieved = storage.get_change(&modify.id).unwrap();
+        assert_eq!(retrieved.content_before, Some(image));
+        assert_eq!(retrieved.content_after, Some(recolored.clone()));
+        assert_eq!(retrieved.content_hash_after, modify.content_hash_after);
+
+        // A text version after a binary one can still be based on it
+        let text_again = Change::new(ChangeType::Modify, PathBuf::from("a.png"), session.id)
+            .with_content_after(b"now text\n".to_vec());
+        storage.create_change(&text_again).unwrap();
+        assert_eq!(
+            storage.get_change(&text_again.id).unwrap().content_after,
+            Some(b"now text\n".to_vec())
+        );
+        assert!(storage
+            .check_integrity_deep()
+            .unwrap()
+            .corrupted_changes
+            .is_empty());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_oversized_content_kept_in_object_store() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let gitent_dir = temp_dir.path().join(".gitent");
+        std::fs::create_dir_all(&gitent_dir).unwrap();
+        let storage = Storage::new(gitent_dir.join("gitent.db"))
+            .unwrap()
+            .with_external_store(1024);
+        let root = temp_dir.path().to_path_buf();
+        let session = Session::new(root.clone()).with_delta_storage(true);
+        storage.create_session(&session).unwrap();
+
+        let asset: Vec<u8> = [b"\x89PNG\r\n\x1a\n\0".as_slice(), &[7; 4096]].concat();
+        let mut edited = asset.clone();
+        edited[100] = 1;
+        let create = Change::new(ChangeType::Create, PathBuf::from("asset.png"), session.id)
+            .with_content_after(asset.clone());
+        let modify = Change::new(ChangeType::Modify, PathBuf::from("asset.png"), session.id)
+            .with_content_before(asset.clone())
+            .with_content_after(edited.clone());
+        let small = Change::new(ChangeType::Create, PathBuf::from("small.txt"), session.id)
+            .with_content_after(b"small\n".to_vec());
+        storage.create_change(&create).unwrap();
+        storage.create_change(&modify).unwrap();
+        storage.create_change(&small).unwrap();
+
+        // The database holds only the object's name
+        let stored = |id: &Uuid| -> (bool, Vec<u8>) {
+            storage
+                .conn
+                .query_row(
+                    "SELECT external, content_after FROM changes WHERE id = ?1",
+                    params![id.to_string()],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap()
+        };
+        let (external, name) = stored(&modify.id);
+        assert!(external);
+        let object = gitent_dir
+            .join("objects")
+            .join(String::from_utf8(name).unwrap());
+        assert_eq!(std::fs::read(object).unwrap(), edited);
+        assert_eq!(stored(&small.id), (false, b"small\n".to_vec()));
+
+        let retrieved = storage.get_change(&modify.id).unwrap();
+        assert_eq!(retrieved.content_before, Some(asset.clone()));
+        assert_eq!(retrieved.content_after, Some(edited.clone()));
+        assert!(storage
+            .check_integrity_deep()
+            .unwrap()
+            .corrupted_changes
+            .is_empty());
+
+        // Rolling back restores the earlier version from the store
+        std::fs::write(root.join("asset.png"), &edited).unwrap();
+        crate::rollback::rollback_change(&retrieved, &root).unwrap();
+        assert_eq!(std::fs::read(root.join("asset.png")).unwrap(), asset);
+    }
 
     #[test]
-    fn test_storage_initialization() {
+    fn test_rewritten_content_kept_in_object_store_and_swept() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let gitent_dir = temp_dir.path().join(".gitent");
+        std::fs::create_dir_all(&gitent_dir).unwrap();
+        let storage = Storage::new(gitent_dir.join("gitent.db"))
+            .unwrap()
+            .with_external_store(1024);
+        let session = Session::new(temp_dir.path().to_path_buf());
+        storage.create_session(&session).unwrap();
+
+        let big = vec![b'x'; 4096];
+        let first = Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id)
+            .with_content_after(b"small\n".to_vec());
+        let second = Change::new(ChangeType::Create, PathBuf::from("b.txt"), session.id)
+            .with_content_after(big.clone());
+        storage.create_change(&first).unwrap();
+        storage.create_change(&second).unwrap();
+
+        // Content uploaded later goes to the store like any other
+        storage.set_content_after(&first.id, big.clone()).unwrap();
+        let external: bool = storage
+            .conn
+            .query_row(
+                "SELECT external FROM changes WHERE id = ?1",
+                params![first.id.to_string()],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(external);
+        assert_eq!(
+            storage.get_change(&first.id).unwrap().content_after,
+            Some(big.clone())
+        );
+
+        let objects = ObjectStore::for_database(&gitent_dir.join("gitent.db"));
+        let backdate = |objects: &ObjectStore| {
+            let old = filetime::FileTime::from_system_time(
+                SystemTime::now() - crate::objects::SWEEP_GRACE * 2,
+            );
+            for object in objects.list().unwrap() {
+                let path = gitent_dir.join("objects").join(&object.name);
+                filetime::set_file_mtime(path, old).unwrap();
+            }
+        };
+        let unused = objects.put(b"nothing refers to this").unwrap();
+        backdate(&objects);
+
+        // The object both changes share survives either of them going
+        storage
+            .conn
+            .execute(
+                "DELETE FROM changes WHERE id = ?1",
+                params![second.id.to_string()],
+            )
+            .unwrap();
+        let swept = storage.sweep_objects().unwrap();
+        assert_eq!(swept.len(), 1);
+        assert_eq!(swept[0].name, unused);
+        assert_eq!(objects.list().unwrap().len(), 1);
+
+        storage
+            .conn
+            .execute(
+                "DELETE FROM changes WHERE id = ?1",
+                params![first.id.to_string()],
+            )
+            .unwrap();
+        let swept = storage.sweep_objects().unwrap();
+        assert_eq!(swept.len(), 1);
+        assert_eq!(swept[0].bytes, 4096);
+        assert!(objects.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_split_change() {
         let storage = Storage::in_memory().unwrap();
-        assert!(storage.conn.is_autocommit());
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let change = Change::new(ChangeType::Modify, PathBuf::from("file.txt"), session.id)
+            .with_content_before(b"a\nb\n".to_vec())
+            .with_content_after(b"A\nB\n".to_vec());
+        storage.create_change(&change).unwrap();
+
+        let (first, rest) = storage.split_change(&change, b"A\nb\n".to_vec()).unwrap();
+        assert_eq!(first.id, change.id);
+
+        let changes = storage.get_uncommitted_changes(&session.id).unwrap();
+        assert_eq!(changes.len(), 2);
+        let stored_first = storage.get_change(&first.id).unwrap();
+        assert_eq!(stored_first.content_before.as_deref(), Some(&b"a\nb\n"[..]));
+        assert_eq!(stored_first.content_after.as_deref(), Some(&b"A\nb\n"[..]));
+        let stored_rest = storage.get_change(&rest.id).unwrap();
+        assert_eq!(stored_rest.content_before.as_deref(), Some(&b"A\nb\n"[..]));
+        assert_eq!(stored_rest.content_after.as_deref(), Some(&b"A\nB\n"[..]));
     }
 
     #[test]
-    fn test_session_crud() {
+    fn test_set_content_after() {
         let storage = Storage::in_memory().unwrap();
         let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let change = Change::new(ChangeType::Create, PathBuf::from("run"), session.id);
+        storage.create_change(&change).unwrap();
+        let content = b"#!/usr/bin/env python3\nprint('hi')\n".to_vec();
+        storage
+            .set_content_after(&change.id, content.clone())
+            .unwrap();
+
+        let stored = storage.get_change(&change.id).unwrap();
+        assert_eq!(stored.content_after, Some(content));
+        assert!(stored.verify_content_integrity());
+        assert_eq!(stored.language.as_deref(), Some("Python"));
 
+        let commit = Commit::new("Add".into(), "agent".into(), vec![change.id], session.id);
+        storage.create_commit(&commit).unwrap();
+        assert!(matches!(
+            storage.set_content_after(&change.id, b"later".to_vec()),
+            Err(Error::InvalidOperation(_))
+        ));
+    }
+
+    #[test]
+    fn test_prune_history_keeps_newest_commits() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test")).with_delta_storage(true);
         storage.create_session(&session).unwrap();
-        let retrieved = storage.get_session(&session.id).unwrap();
 
-        assert_eq!(session.id, retrieved.id);
-        assert_eq!(session.root_path, retrieved.root_path);
-        assert!(retrieved.active);
+        let original: Vec<u8> = (0..1000)
+            .map(|i| format!("line number {}\n", i))
+            .collect::<String>()
+            .into_bytes();
+        let create = Change::new(ChangeType::Create, PathBuf::from("big.txt"), session.id)
+            .with_content_after(original.clone());
+        storage.create_change(&create).unwrap();
+
+        let mut commits: Vec<Commit> = Vec::new();
+        let mut content = original;
+        let mut previous = create.id;
+        for i in 0..5 {
+            let mut edited = content.clone();
+            edited.extend_from_slice(format!("edit {}\n", i).as_bytes());
+            let modify = Change::new(ChangeType::Modify, PathBuf::from("big.txt"), session.id)
+                .with_content_before(content)
+                .with_content_after(edited.clone());
+            storage.create_change(&modify).unwrap();
+            content = edited;
+
+            let changes = if i == 0 {
+                vec![previous, modify.id]
+            } else {
+                vec![modify.id]
+            };
+            previous = modify.id;
+
+            let mut commit = Commit::new(
+                format!("Edit {}", i),
+                "agent".to_string(),
+                changes,
+                session.id,
+            );
+            if let Some(parent) = commits.last() {
+                commit = commit.with_parent(parent.id);
+            }
+            // A milestone is protected
+            if i == 1 {
+                commit = commit.with_milestone();
+            }
+            commit.timestamp = Utc::now() - chrono::Duration::minutes(10 - i);
+            storage.create_commit(&commit).unwrap();
+            commits.push(commit);
+        }
+
+        // So is the head of a branch, however old
+        let side_change = Change::new(ChangeType::Create, PathBuf::from("side.txt"), session.id)
+            .with_content_after(b"side".to_vec());
+        storage.create_change(&side_change).unwrap();
+        let mut side = Commit::new(
+            "Side".to_string(),
+            "agent".to_string(),
+            vec![side_change.id],
+            session.id,
+        )
+        .with_parent(commits[0].id);
+        side.timestamp = Utc::now() - chrono::Duration::minutes(20);
+        storage.create_commit(&side).unwrap();
+
+        let uncommitted = Change::new(ChangeType::Create, PathBuf::from("new.txt"), session.id);
+        storage.create_change(&uncommitted).unwrap();
+
+        let policy = RetentionPolicy {
+            keep_commits: Some(2),
+            keep_days: None,
+        };
+        let report = storage.prune_history(&session.id, &policy).unwrap();
+        assert_eq!(report.commits_removed, 2);
+        assert_eq!(report.changes_removed, 3);
+
+        let remaining: Vec<Uuid> = storage
+            .get_commits_for_session(&session.id)
+            .unwrap()
+            .into_iter()
+            .map(|info| info.commit.id)
+            .collect();
+        assert_eq!(
+            remaining,
+            vec![commits[4].id, commits[3].id, commits[1].id, side.id]
+        );
+
+        // The surviving modify was a delta against a pruned one
+        let latest = storage.get_change(&commits[4].changes[0]).unwrap();
+        assert_eq!(latest.content_after, Some(content));
+        assert!(storage.get_change(&uncommitted.id).is_ok());
+        assert!(storage.check_integrity().unwrap().is_clean());
+
+        // The latest commit is kept even when the policy would drop it
+        let policy = RetentionPolicy {
+            keep_commits: Some(0),
+            keep_days: None,
+        };
+        let report = storage.prune_history(&session.id, &policy).unwrap();
+        assert_eq!(report.commits_removed, 1);
+        assert_eq!(
+            storage.get_head_commit(&session.id).unwrap().unwrap().id,
+            commits[4].id
+        );
     }
 
     #[test]
-    fn test_change_creation() {
+    fn test_storage_clock_stamps_records() {
+        let now = DateTime::parse_from_rfc3339("2020-01-10T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let storage = Storage::in_memory()
+            .unwrap()
+            .with_clock(Arc::new(crate::clock::FixedClock::new(now)));
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let checkpoint = storage.create_checkpoint(&session.id, "pinned").unwrap();
+        assert_eq!(checkpoint.created_at, now);
+
+        let mut parent = None;
+        for days_ago in [9, 2, 1] {
+            let change = Change::new(ChangeType::Modify, PathBuf::from("a.txt"), session.id);
+            storage.create_change(&change).unwrap();
+            let mut commit = Commit::new(
+                format!("{} days ago", days_ago),
+                "agent".to_string(),
+                vec![change.id],
+                session.id,
+            );
+            if let Some(parent) = parent {
+                commit = commit.with_parent(parent);
+            }
+            parent = Some(commit.id);
+            commit.timestamp = now - chrono::Duration::days(days_ago);
+            storage.create_commit(&commit).unwrap();
+        }
+
+        let policy = RetentionPolicy {
+            keep_commits: None,
+            keep_days: Some(3),
+        };
+        let report = storage.prune_history(&session.id, &policy).unwrap();
+        assert_eq!(report.commits_removed, 1);
+        assert_eq!(
+            storage.get_commits_for_session(&session.id).unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_prune_audit_log() {
+        let now = DateTime::parse_from_rfc3339("2020-01-10T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let storage = Storage::in_memory()
+            .unwrap()
+            .with_clock(Arc::new(crate::clock::FixedClock::new(now)));
+        for days_ago in [40, 31, 2] {
+            storage
+                .record_audit(&AuditEntry {
+                    timestamp: now - chrono::Duration::days(days_ago),
+                    method: "POST".to_string(),
+                    path: format!("/{}-days-ago", days_ago),
+                    status: 200,
+                    claimed_agent_id: None,
+                    target: None,
+                    source_ip: None,
+                    request_id: None,
+                })
+                .unwrap();
+        }
+
+        assert_eq!(storage.prune_audit_log(30).unwrap(), 2);
+        let paths: Vec<String> = storage
+            .get_audit_log(None)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(paths, ["/2-days-ago"]);
+    }
+
+    #[test]
+    fn test_recent_changes() {
         let storage = Storage::in_memory().unwrap();
         let session = Session::new(PathBuf::from("/test"));
         storage.create_session(&session).unwrap();
+        let changes: Vec<_> = (0..5)
+            .map(|i| {
+                let change =
+                    Change::new(ChangeType::Create, format!("{}.txt", i).into(), session.id);
+                storage.create_change(&change).unwrap();
+                change.id
+            })
+            .collect();
+        // The newest change and an older one are committed
+        let commit = Commit::new(
+            "Some".to_string(),
+            "agent".to_string(),
+            vec![changes[2], changes[4]],
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
 
-        let change = Change::new(ChangeType::Create, PathBuf::from("test.txt"), session.id)
-            .with_content_after(b"Hello".to_vec());
+        let recent: Vec<Uuid> = storage
+            .get_recent_changes(&session.id, 3)
+            .unwrap()
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(recent, [changes[4], changes[3], changes[2]]);
+        assert_eq!(
+            storage.get_recent_changes(&session.id, 10).unwrap().len(),
+            5
+        );
+    }
 
-        storage.create_change(&change).unwrap();
-        let retrieved = storage.get_change(&change.id).unwrap();
+    #[test]
+    fn test_uncommitted_count() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        assert_eq!(storage.get_uncommitted_count(&session.id).unwrap(), 0);
 
-        assert_eq!(change.id, retrieved.id);
-        assert_eq!(change.path, retrieved.path);
-        assert_eq!(change.change_type, retrieved.change_type);
+        let changes: Vec<_> = (0..10)
+            .map(|i| {
+                let change = Change::new(
+                    ChangeType::Create,
+                    PathBuf::from(format!("file{}.txt", i)),
+                    session.id,
+                )
+                .with_content_after(vec![b'x'; 64 * 1024]);
+                storage.create_change(&change).unwrap();
+                change
+            })
+            .collect();
+        let commit = Commit::new(
+            "First three".to_string(),
+            "test-agent".to_string(),
+            changes[..3].iter().map(|c| c.id).collect(),
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+
+        assert_eq!(storage.get_uncommitted_count(&session.id).unwrap(), 7);
+        let (first, total) = storage
+            .get_uncommitted_changes_limited(&session.id, 2)
+            .unwrap();
+        assert_eq!(total, 7);
+        assert_eq!(
+            first.iter().map(|c| c.id).collect::<Vec<_>>(),
+            vec![changes[9].id, changes[8].id]
+        );
+
+        // Other sessions don't count
+        let other = Session::new(PathBuf::from("/other"));
+        storage.create_session(&other).unwrap();
+        assert_eq!(storage.get_uncommitted_count(&other.id).unwrap(), 0);
     }
 
     #[test]
-    fn test_commit_with_changes() {
+    fn test_merge_sessions() {
+        let storage = Storage::in_memory().unwrap();
+        let mut first = Session::new(PathBuf::from("/test"));
+        first.active = false;
+        let second = Session::new(PathBuf::from("/test"));
+        storage.create_session(&first).unwrap();
+        storage.create_session(&second).unwrap();
+
+        // Alternate commits between the sessions, each chained within its own
+        let start = Utc::now() - chrono::Duration::hours(1);
+        let mut parents: HashMap<Uuid, Uuid> = HashMap::new();
+        let mut commits = Vec::new();
+        for (minutes, session, path) in [
+            (0, &first, "a.txt"),
+            (10, &second, "shared.txt"),
+            (20, &first, "shared.txt"),
+            (30, &second, "b.txt"),
+        ] {
+            let change = Change::new(ChangeType::Create, PathBuf::from(path), session.id);
+            storage.create_change(&change).unwrap();
+            let mut commit = Commit::new(
+                path.to_string(),
+                "agent".to_string(),
+                vec![change.id],
+                session.id,
+            );
+            commit.timestamp = start + chrono::Duration::minutes(minutes);
+            if let Some(parent) = parents.get(&session.id) {
+                commit = commit.with_parent(*parent);
+            }
+            storage.create_commit(&commit).unwrap();
+            parents.insert(session.id, commit.id);
+            commits.push(commit.id);
+        }
+        let uncommitted = Change::new(ChangeType::Create, PathBuf::from("c.txt"), second.id);
+        storage.create_change(&uncommitted).unwrap();
+
+        let report = storage.merge_sessions(&first.id, &second.id).unwrap();
+        assert_eq!(report.changes_moved, 3);
+        assert_eq!(report.commits_moved, 2);
+        assert_eq!(report.overlapping_paths, vec![PathBuf::from("shared.txt")]);
+
+        // One chain, oldest first, alternating between the old sessions
+        let mut history: Vec<_> = storage
+            .get_commits_for_session(&first.id)
+            .unwrap()
+            .into_iter()
+            .map(|info| (info.commit.id, info.commit.parent))
+            .collect();
+        history.reverse();
+        assert_eq!(
+            history,
+            vec![
+                (commits[0], None),
+                (commits[1], Some(commits[0])),
+                (commits[2], Some(commits[1])),
+                (commits[3], Some(commits[2])),
+            ]
+        );
+        assert_eq!(
+            storage.get_uncommitted_changes(&first.id).unwrap()[0].id,
+            uncommitted.id
+        );
+
+        // The second session is gone, and its activity carries over
+        assert!(storage.get_session(&second.id).is_err());
+        assert_eq!(storage.get_active_session().unwrap().id, first.id);
+        assert_eq!(storage.get_sessions().unwrap().len(), 1);
+
+        let elsewhere = Session::new(PathBuf::from("/other"));
+        storage.create_session(&elsewhere).unwrap();
+        assert!(matches!(
+            storage.merge_sessions(&first.id, &elsewhere.id),
+            Err(Error::InvalidOperation(_))
+        ));
+    }
+
+    #[test]
+    fn test_resolve_commit_prefix() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        let other = Session::new(PathBuf::from("/other"));
+        storage.create_session(&other).unwrap();
+
+        let commit = |id: &str, session_id| {
+            let mut commit = Commit::new(
+                "Commit".to_string(),
+                "agent".to_string(),
+                vec![],
+                session_id,
+            );
+            commit.id = Uuid::parse_str(id).unwrap();
+            storage.create_commit(&commit).unwrap();
+            commit.id
+        };
+        let first = commit("a1b2c3d4-0000-4000-8000-000000000001", session.id);
+        let second = commit("a1b2ffff-0000-4000-8000-000000000002", session.id);
+        // Only commits in the given session count, unless named in full
+        let elsewhere = commit("a1b2c3ee-0000-4000-8000-000000000003", other.id);
+
+        let resolve = |prefix: &str| storage.resolve_commit_prefix(&session.id, prefix);
+        assert!(matches!(resolve("a1b2"), Err(Error::AmbiguousRef(_))));
+        assert_eq!(resolve("a1b2c").unwrap().id, first);
+        assert_eq!(resolve("A1B2F").unwrap().id, second);
+        assert_eq!(resolve(&first.to_string()).unwrap().id, first);
+        assert_eq!(resolve(&elsewhere.to_string()).unwrap().id, elsewhere);
+        assert!(matches!(resolve("a1b2c3e"), Err(Error::CommitNotFound(_))));
+        assert!(matches!(resolve("ffff"), Err(Error::CommitNotFound(_))));
+        assert!(matches!(resolve("a1b"), Err(Error::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn test_recent_activity() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        let start = Utc::now() - chrono::Duration::hours(1);
+        let at = |minutes: i64| start + chrono::Duration::minutes(minutes);
+
+        let change = |minutes, path: &str| {
+            let mut change = Change::new(ChangeType::Create, PathBuf::from(path), session.id);
+            change.timestamp = at(minutes);
+            storage.create_change(&change).unwrap();
+            change.id
+        };
+        let commit = |minutes, changes, parent: Option<Uuid>| {
+            let mut commit = Commit::new(
+                "Commit".to_string(),
+                "agent".to_string(),
+                changes,
+                session.id,
+            );
+            commit.parent = parent;
+            commit.timestamp = at(minutes);
+            storage.create_commit(&commit).unwrap();
+            commit.id
+        };
+
+        let a = change(0, "a.txt");
+        let b = change(1, "b.txt");
+        let first = commit(2, vec![a, b], None);
+        let c = change(3, "c.txt");
+        // Committed in the same instant as the change it takes in
+        let second = commit(3, vec![c], Some(first));
+        let d = change(5, "d.txt");
+        // Recorded late, with an earlier timestamp than the last commit
+        let e = change(4, "e.txt");
+
+        let ids = |limit| -> Vec<Uuid> {
+            storage
+                .recent_activity(&session.id, limit)
+                .unwrap()
+                .into_iter()
+                .map(|event| match event {
+                    ActivityEvent::Change(change) => change.id,
+                    ActivityEvent::Commit(commit) => commit.id,
+                })
+                .collect()
+        };
+        assert_eq!(ids(100), vec![d, e, second, c, first, b, a]);
+        assert_eq!(ids(3), vec![d, e, second]);
+
+        let feed = storage.recent_activity(&session.id, 100).unwrap();
+        assert!(feed
+            .windows(2)
+            .all(|pair| pair[0].timestamp() >= pair[1].timestamp()));
+        assert!(matches!(&feed[2], ActivityEvent::Commit(commit) if commit.changes == vec![c]));
+
+        let other = Session::new(PathBuf::from("/other"));
+        storage.create_session(&other).unwrap();
+        assert!(storage.recent_activity(&other.id, 100).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_list_tracked_paths() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        for change in [
+            Change::new(ChangeType::Create, PathBuf::from("src/main.rs"), session.id),
+            Change::new(ChangeType::Create, PathBuf::from("old.txt"), session.id),
+            Change::new(ChangeType::Modify, PathBuf::from("src/main.rs"), session.id),
+            Change::new(ChangeType::Create, PathBuf::from("gone.txt"), session.id),
+            Change::new(ChangeType::Delete, PathBuf::from("gone.txt"), session.id),
+            Change::new(ChangeType::Rename, PathBuf::from("new.txt"), session.id)
+                .with_old_path(PathBuf::from("old.txt")),
+            Change::new(ChangeType::Modify, PathBuf::from("src/main.rs"), session.id),
+        ] {
+            storage.create_change(&change).unwrap();
+        }
+        let other = Session::new(PathBuf::from("/other"));
+        storage.create_session(&other).unwrap();
+        storage
+            .create_change(&Change::new(
+                ChangeType::Create,
+                PathBuf::from("elsewhere.txt"),
+                other.id,
+            ))
+            .unwrap();
+
+        let paths = storage.list_tracked_paths(&session.id).unwrap();
+        assert_eq!(
+            paths,
+            ["gone.txt", "new.txt", "old.txt", "src/main.rs"]
+                .map(PathBuf::from)
+                .to_vec()
+        );
+
+        let statuses: Vec<_> = storage
+            .list_tracked_path_statuses(&session.id)
+            .unwrap()
+            .into_iter()
+            .map(|tracked| (tracked.path, tracked.status.unwrap()))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (PathBuf::from("gone.txt"), PathStatus::Deleted),
+                (PathBuf::from("new.txt"), PathStatus::Live),
+                (PathBuf::from("old.txt"), PathStatus::Deleted),
+                (PathBuf::from("src/main.rs"), PathStatus::Live),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reset_head() {
         let storage = Storage::in_memory().unwrap();
         let session = Session::new(PathBuf::from("/test"));
         storage.create_session(&session).unwrap();
 
         let change1 = Change::new(ChangeType::Create, PathBuf::from("file1.txt"), session.id);
         let change2 = Change::new(ChangeType::Create, PathBuf::from("file2.txt"), session.id);
-
         storage.create_change(&change1).unwrap();
         storage.create_change(&change2).unwrap();
 
-        let commit = Commit::new(
-            "Test commit".to_string(),
+        let first = Commit::new(
+            "First".to_string(),
             "test-agent".to_string(),
-            vec![change1.id, change2.id],
+            vec![change1.id],
             session.id,
         );
+        storage.create_commit(&first).unwrap();
 
-        storage.create_commit(&commit).unwrap();
-        let retrieved = storage.get_commit(&commit.id).unwrap();
+        let second = Commit::new(
+            "Second".to_string(),
+            "test-agent".to_string(),
+            vec![change2.id],
+            session.id,
+        )
+        .with_parent(first.id);
+        storage.create_commit(&second).unwrap();
 
-        assert_eq!(commit.id, retrieved.id);
-        assert_eq!(commit.message, retrieved.message);
-        assert_eq!(2, retrieved.changes.len());
+        assert!(storage
+            .get_uncommitted_changes(&session.id)
+            .unwrap()
+            .is_empty());
+
+        // The first commit has a descendant, but the head doesn't
+        let reset = storage.reset_head(&session.id).unwrap();
+        assert_eq!(reset.id, second.id);
+
+        let uncommitted = storage.get_uncommitted_changes(&session.id).unwrap();
+        assert_eq!(1, uncommitted.len());
+        assert_eq!(change2.id, uncommitted[0].id);
+        assert!(storage.get_commit(&second.id).is_err());
+
+        let head = storage.get_head_commit(&session.id).unwrap().unwrap();
+        assert_eq!(head.id, first.id);
+
+        storage.reset_head(&session.id).unwrap();
+        assert!(storage.get_head_commit(&session.id).unwrap().is_none());
+        assert!(matches!(
+            storage.reset_head(&session.id),
+            Err(Error::InvalidOperation(_))
+        ));
+    }
+
+    #[test]
+    fn test_latest_change_per_file() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+
+        let changes = [
+            Change::new(ChangeType::Create, PathBuf::from("kept.txt"), session.id),
+            Change::new(ChangeType::Create, PathBuf::from("deleted.txt"), session.id),
+            Change::new(ChangeType::Create, PathBuf::from("old.txt"), session.id),
+            Change::new(ChangeType::Modify, PathBuf::from("kept.txt"), session.id),
+            Change::new(ChangeType::Delete, PathBuf::from("deleted.txt"), session.id),
+            Change::new(ChangeType::Rename, PathBuf::from("new.txt"), session.id)
+                .with_old_path(PathBuf::from("old.txt")),
+        ];
+        for change in &changes {
+            storage.create_change(change).unwrap();
+        }
+
+        let snapshot = storage.latest_change_per_file(&session.id).unwrap();
+
+        assert_eq!(2, snapshot.len());
+        let kept = &snapshot[&PathBuf::from("kept.txt")];
+        assert_eq!(kept.id, changes[3].id);
+        assert_eq!(kept.change_type, ChangeType::Modify);
+        assert_eq!(
+            snapshot[&PathBuf::from("new.txt")].change_type,
+            ChangeType::Rename
+        );
+        assert!(!snapshot.contains_key(&PathBuf::from("deleted.txt")));
+        assert!(!snapshot.contains_key(&PathBuf::from("old.txt")));
+    }
+
+    #[test]
+    fn test_renamed_away_path_stays_gone() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(PathBuf::from("/test"));
+        storage.create_session(&session).unwrap();
+        let paths = |storage: &Storage| {
+            let mut paths: Vec<PathBuf> = storage
+                .latest_change_per_file(&session.id)
+                .unwrap()
+                .into_keys()
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        for change in [
+            Change::new(ChangeType::Create, PathBuf::from("a.txt"), session.id),
+            Change::new(ChangeType::Rename, PathBuf::from("b.txt"), session.id)
+                .with_old_path(PathBuf::from("a.txt")),
+            Change::new(ChangeType::Modify, PathBuf::from("b.txt"), session.id),
+        ] {
+            storage.create_change(&change).unwrap();
+        }
+        assert_eq!(paths(&storage), [PathBuf::from("b.txt")]);
+
+        let delete = Change::new(ChangeType::Delete, PathBuf::from("b.txt"), session.id);
+        storage.create_change(&delete).unwrap();
+        assert!(paths(&storage).is_empty());
     }
 }
@@ -0,0 +1,94 @@
+//! Comparing the tree at the head commit with the files on disk.
+
+use crate::error::Result;
+use crate::hash;
+use crate::models::{Change, ChangeType, Session};
+use crate::storage::Storage;
+use std::io::ErrorKind;
+
+/// Changes that would take each committed file to its current on-disk
+/// content, read fresh and independent of what the watcher recorded.
+///
+/// Only files in the committed tree are compared, so files that were never
+/// committed don't show up. Files whose committed content hash is unknown are
+/// skipped. Results are sorted by path.
+pub fn changes(storage: &Storage, session: &Session) -> Result<Vec<Change>> {
+    let mut tree: Vec<Change> = storage.committed_tree(&session.id)?.into_values().collect();
+    tree.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut changes = Vec::new();
+    for committed in tree {
+        let Some(committed_hash) = &committed.content_hash_after else {
+            continue;
+        };
+        let full_path = session.absolute_path(&committed.path);
+
+        let mut change = match std::fs::read(&full_path) {
+            Ok(content) => {
+                if hash::matches(committed_hash, &content) {
+                    continue;
+                }
+                Change::new(ChangeType::Modify, committed.path.clone(), session.id)
+                    .with_content_after_hashed(content, session.hash_algorithm)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Change::new(ChangeType::Delete, committed.path.clone(), session.id)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        change.content_hash_before = committed.content_hash_after;
+        change.content_before = committed.content_after;
+        changes.push(change);
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Commit;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_edit_behind_watchers_back() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        storage.create_session(&session).unwrap();
+
+        let notes = temp_dir.path().join("notes.txt");
+        let gone = temp_dir.path().join("gone.txt");
+        let mut ids = Vec::new();
+        for (path, content) in [(&notes, "one\n"), (&gone, "bye\n")] {
+            std::fs::write(path, content).unwrap();
+            let change = Change::new(ChangeType::Create, path.clone(), session.id)
+                .with_content_after(content.as_bytes().to_vec());
+            storage.create_change(&change).unwrap();
+            ids.push(change.id);
+        }
+        let commit = Commit::new("Add files".into(), "agent".into(), ids, session.id);
+        storage.create_commit(&commit).unwrap();
+
+        // Nothing changed yet
+        assert!(changes(&storage, &session).unwrap().is_empty());
+
+        // Edit and delete without recording anything
+        std::fs::write(&notes, "one\ntwo\n").unwrap();
+        std::fs::remove_file(&gone).unwrap();
+        assert!(storage
+            .get_uncommitted_changes(&session.id)
+            .unwrap()
+            .is_empty());
+
+        let drift = changes(&storage, &session).unwrap();
+        assert_eq!(drift.len(), 2);
+        assert_eq!(drift[0].change_type, ChangeType::Delete);
+        assert_eq!(drift[0].path, gone);
+        assert_eq!(drift[0].content_before.as_deref(), Some(&b"bye\n"[..]));
+        assert_eq!(drift[1].change_type, ChangeType::Modify);
+        assert_eq!(drift[1].path, notes);
+        assert_eq!(drift[1].content_before.as_deref(), Some(&b"one\n"[..]));
+        assert_eq!(drift[1].content_after.as_deref(), Some(&b"one\ntwo\n"[..]));
+    }
+}
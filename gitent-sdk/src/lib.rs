@@ -17,14 +17,48 @@
 //! ```
 
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// First delay between readiness checks in [`GitentClient::wait_for_ready`].
+const READY_MIN_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Longest delay between readiness checks.
+const READY_MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Media type for MessagePack bodies.
+const MSGPACK: &str = "application/msgpack";
+
+/// Header naming the agent behind a request, for the server's audit log.
+const AGENT_ID_HEADER: &str = "x-agent-id";
+
+/// How request and response bodies are encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    /// MessagePack, cheaper to encode and decode for large content
+    MsgPack,
+}
 
 #[derive(Clone)]
 pub struct GitentClient {
     base_url: String,
     agent_id: String,
     client: reqwest::blocking::Client,
+    /// Per-request timeout, if one was set with [`GitentClient::with_timeout`]
+    timeout: Option<Duration>,
+    format: WireFormat,
+    /// Sent to admin endpoints such as `POST /session`
+    admin_token: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -34,6 +68,10 @@ struct CreateChangeRequest {
     content_before: Option<String>,
     content_after: Option<String>,
     agent_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, String>,
 }
 
 #[derive(Serialize)]
@@ -41,6 +79,35 @@ struct CreateCommitRequest {
     message: String,
     agent_id: String,
     change_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct RollbackRequest {
+    commit_id: String,
+    execute: bool,
+    force: bool,
+}
+
+#[derive(Serialize)]
+struct RevertChangeRequest {
+    execute: bool,
+    force: bool,
+    verify: bool,
+}
+
+#[derive(Serialize)]
+struct PreviewDiffRequest<'a> {
+    path: &'a str,
+    content: &'a str,
+}
+
+#[derive(Serialize)]
+struct StartSessionRequest {
+    root_path: PathBuf,
 }
 
 #[derive(Deserialize)]
@@ -48,6 +115,122 @@ struct Change {
     id: String,
 }
 
+/// The session the server is currently recording changes into.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionDto {
+    pub id: Uuid,
+    /// Directory being watched; change paths are relative to it unless a
+    /// content root is set
+    pub root_path: PathBuf,
+    /// Directory change paths are relative to, if not `root_path`
+    #[serde(default)]
+    pub content_root: Option<PathBuf>,
+    pub started: DateTime<Utc>,
+    pub ignore_patterns: Vec<String>,
+    /// Patterns for files whose content is replaced with a placeholder
+    #[serde(default)]
+    pub redact_patterns: Vec<String>,
+}
+
+/// How the server would record a change, from [`GitentClient::preview_change`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChangePreview {
+    /// The change as it would be stored, with its path normalized and
+    /// hashes computed
+    pub change: HashMap<String, serde_json::Value>,
+    /// Why the watcher wouldn't track writes to this file, if it wouldn't
+    pub skipped: Option<String>,
+    /// The content isn't text, so it has no line diff
+    pub binary: bool,
+    /// The content is over the server's size limit, so the watcher would
+    /// record the change without it
+    pub too_large: bool,
+}
+
+impl ChangePreview {
+    /// Whether writes to the file are tracked at all.
+    pub fn is_tracked(&self) -> bool {
+        self.skipped.is_none()
+    }
+}
+
+/// A diff against the latest tracked version of a file, from
+/// [`GitentClient::preview_diff`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiffPreview {
+    pub path: String,
+    /// The latest tracked content, `None` if the file isn't tracked or isn't
+    /// text
+    pub old_content: Option<String>,
+    pub new_content: Option<String>,
+    pub diff_lines: Vec<DiffLineDto>,
+}
+
+impl DiffPreview {
+    /// Number of lines the new content adds.
+    pub fn additions(&self) -> usize {
+        self.count("addition")
+    }
+
+    /// Number of lines the new content removes.
+    pub fn deletions(&self) -> usize {
+        self.count("deletion")
+    }
+
+    fn count(&self, line_type: &str) -> usize {
+        self.diff_lines
+            .iter()
+            .filter(|line| line.line_type == line_type)
+            .count()
+    }
+}
+
+/// One line of a [`DiffPreview`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiffLineDto {
+    /// `context`, `addition` or `deletion`
+    pub line_type: String,
+    pub content: String,
+    pub old_line_number: Option<usize>,
+    pub new_line_number: Option<usize>,
+}
+
+/// Returned when the server has no active session.
+///
+/// Check for it with `error.downcast_ref::<NoActiveSession>()`.
+#[derive(Debug)]
+pub struct NoActiveSession;
+
+impl fmt::Display for NoActiveSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No active session")
+    }
+}
+
+impl std::error::Error for NoActiveSession {}
+
+/// Returned by [`GitentClient::wait_for_ready`] when the server didn't become
+/// healthy in time.
+///
+/// Check for it with `error.downcast_ref::<NotReady>()`.
+#[derive(Debug)]
+pub struct NotReady {
+    pub base_url: String,
+    pub waited: Duration,
+}
+
+impl fmt::Display for NotReady {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Server at {} was not ready after {:?}",
+            self.base_url, self.waited
+        )
+    }
+}
+
+impl std::error::Error for NotReady {}
+
 impl GitentClient {
     /// Create a new gitent client
     ///
@@ -60,12 +243,39 @@ impl GitentClient {
             base_url: base_url.into(),
             agent_id: agent_id.into(),
             client: reqwest::blocking::Client::new(),
+            timeout: None,
+            format: WireFormat::default(),
+            admin_token: None,
         }
     }
 
+    /// Give up on any request that takes longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client = reqwest::blocking::Client::builder()
+            .timeout(timeout)
+            .build()
+            .expect("failed to build HTTP client");
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Encode requests and ask for responses in `format`. Servers that don't
+    /// support it answer in JSON, which is still understood.
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Authenticate admin requests, such as [`GitentClient::start_session`],
+    /// with the server's `admin_token`
+    pub fn with_admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
     /// Announce that a file was created
     pub fn file_created(&self, path: &str, content: &str) -> Result<()> {
-        self.create_change("create", path, None, Some(content))
+        self.create_change("create", path, None, Some(content), None, HashMap::new())
     }
 
     /// Announce that a file was modified
@@ -75,7 +285,14 @@ impl GitentClient {
         content_before: &str,
         content_after: &str,
     ) -> Result<()> {
-        self.create_change("modify", path, Some(content_before), Some(content_after))
+        self.create_change(
+            "modify",
+            path,
+            Some(content_before),
+            Some(content_after),
+            None,
+            HashMap::new(),
+        )
     }
 
     /// Announce that a file was written (create or modify)
@@ -92,9 +309,82 @@ impl GitentClient {
         }
     }
 
+    /// Announce that a file was written (create or modify) by a specific tool,
+    /// such as an editor, formatter or code generator
+    pub fn file_written_with_tool(
+        &self,
+        path: &str,
+        content: &str,
+        previous_content: Option<&str>,
+        tool: &str,
+    ) -> Result<()> {
+        let change_type = if previous_content.is_some() {
+            "modify"
+        } else {
+            "create"
+        };
+        self.create_change(
+            change_type,
+            path,
+            previous_content,
+            Some(content),
+            Some(tool),
+            HashMap::new(),
+        )
+    }
+
+    /// Announce that a file was written (create or modify), with metadata
+    /// for the change, such as the keys the session's metadata schema asks
+    /// for
+    pub fn file_written_with_metadata(
+        &self,
+        path: &str,
+        content: &str,
+        previous_content: Option<&str>,
+        metadata: HashMap<String, String>,
+    ) -> Result<()> {
+        let change_type = if previous_content.is_some() {
+            "modify"
+        } else {
+            "create"
+        };
+        self.create_change(
+            change_type,
+            path,
+            previous_content,
+            Some(content),
+            None,
+            metadata,
+        )
+    }
+
+    /// Announce that a file was written, streaming its new content from
+    /// `reader` rather than building it into a JSON string. Suited to large
+    /// files.
+    pub fn file_written_from_reader<R: Read + Send + 'static>(
+        &self,
+        path: &str,
+        reader: R,
+    ) -> Result<()> {
+        self.client
+            .post(format!("{}/changes/raw", self.base_url))
+            .query(&[
+                ("change_type", "modify"),
+                ("path", path),
+                ("agent_id", &self.agent_id),
+            ])
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .header(AGENT_ID_HEADER, &self.agent_id)
+            .body(reqwest::blocking::Body::new(reader))
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
     /// Announce that a file was deleted
     pub fn file_deleted(&self, path: &str, content_before: Option<&str>) -> Result<()> {
-        self.create_change("delete", path, content_before, None)
+        self.create_change("delete", path, content_before, None, None, HashMap::new())
     }
 
     fn create_change(
@@ -103,6 +393,8 @@ impl GitentClient {
         path: &str,
         content_before: Option<&str>,
         content_after: Option<&str>,
+        tool: Option<&str>,
+        metadata: HashMap<String, String>,
     ) -> Result<()> {
         let request = CreateChangeRequest {
             change_type: change_type.to_string(),
@@ -110,37 +402,118 @@ impl GitentClient {
             content_before: content_before.map(|s| s.to_string()),
             content_after: content_after.map(|s| s.to_string()),
             agent_id: Some(self.agent_id.clone()),
+            tool: tool.map(|s| s.to_string()),
+            metadata,
         };
 
-        self.client
-            .post(format!("{}/changes", self.base_url))
-            .json(&request)
+        self.post("/changes", &request)?
             .send()?
             .error_for_status()?;
 
         Ok(())
     }
 
+    /// Check how a change would be recorded without recording it.
+    ///
+    /// `change_type` is one of `create`, `modify`, `delete` or `rename`.
+    pub fn preview_change(
+        &self,
+        change_type: &str,
+        path: &str,
+        content_before: Option<&str>,
+        content_after: Option<&str>,
+    ) -> Result<ChangePreview> {
+        let request = CreateChangeRequest {
+            change_type: change_type.to_string(),
+            path: path.to_string(),
+            content_before: content_before.map(|s| s.to_string()),
+            content_after: content_after.map(|s| s.to_string()),
+            agent_id: Some(self.agent_id.clone()),
+            tool: None,
+            metadata: HashMap::new(),
+        };
+        let response = self
+            .post("/changes", &request)?
+            .query(&[("dry_run", "true")])
+            .send()?
+            .error_for_status()?;
+
+        read(response)
+    }
+
+    /// Diff `content` against the latest tracked version of `path` without
+    /// recording anything
+    pub fn preview_diff(&self, path: &str, content: &str) -> Result<DiffPreview> {
+        let request = PreviewDiffRequest { path, content };
+        let response = self
+            .post("/changes/preview-diff", &request)?
+            .send()?
+            .error_for_status()?;
+
+        read(response)
+    }
+
     /// Get all uncommitted changes
     pub fn get_uncommitted_changes(&self) -> Result<Vec<HashMap<String, serde_json::Value>>> {
+        let response = self.get("/changes").send()?.error_for_status()?;
+
+        read(response)
+    }
+
+    /// Get the newest `n` changes, committed or not, newest first
+    pub fn tail_changes(&self, n: usize) -> Result<Vec<HashMap<String, serde_json::Value>>> {
+        let response = self
+            .get(&format!("/changes/tail?n={}", n))
+            .send()?
+            .error_for_status()?;
+
+        read(response)
+    }
+
+    /// Get all uncommitted changes as a single unified diff
+    pub fn uncommitted_diff(&self, context: usize) -> Result<String> {
         let response = self
             .client
-            .get(format!("{}/changes", self.base_url))
+            .get(format!("{}/changes/diff", self.base_url))
+            .query(&[
+                ("format", "unified".to_string()),
+                ("context", context.to_string()),
+            ])
             .send()?
             .error_for_status()?;
 
-        Ok(response.json()?)
+        Ok(response.text()?)
     }
 
     /// Commit all uncommitted changes
     pub fn commit(&self, message: &str) -> Result<String> {
+        self.create_commit(message, None, HashMap::new())
+    }
+
+    /// Commit all uncommitted changes on top of `parent` rather than the
+    /// latest commit, forking history at that point
+    pub fn commit_with_parent(&self, message: &str, parent: &str) -> Result<String> {
+        self.create_commit(message, Some(parent), HashMap::new())
+    }
+
+    /// Commit all uncommitted changes with metadata for the commit, such as
+    /// the keys the session's metadata schema asks for
+    pub fn commit_with_metadata(
+        &self,
+        message: &str,
+        metadata: HashMap<String, String>,
+    ) -> Result<String> {
+        self.create_commit(message, None, metadata)
+    }
+
+    fn create_commit(
+        &self,
+        message: &str,
+        parent: Option<&str>,
+        metadata: HashMap<String, String>,
+    ) -> Result<String> {
         // Get uncommitted changes
-        let changes: Vec<Change> = self
-            .client
-            .get(format!("{}/changes", self.base_url))
-            .send()?
-            .error_for_status()?
-            .json()?;
+        let changes: Vec<Change> = read(self.get("/changes").send()?.error_for_status()?)?;
 
         let change_ids: Vec<String> = changes.iter().map(|c| c.id.clone()).collect();
 
@@ -148,28 +521,119 @@ impl GitentClient {
             message: message.to_string(),
             agent_id: self.agent_id.clone(),
             change_ids,
+            parent: parent.map(|s| s.to_string()),
+            metadata,
         };
 
-        let response: serde_json::Value = self
-            .client
-            .post(format!("{}/commits", self.base_url))
-            .json(&request)
-            .send()?
-            .error_for_status()?
-            .json()?;
+        let response: serde_json::Value = read(
+            self.post("/commits", &request)?
+                .send()?
+                .error_for_status()?,
+        )?;
 
         Ok(response["id"].as_str().unwrap_or("unknown").to_string())
     }
 
     /// Get commit history
     pub fn get_commits(&self) -> Result<Vec<HashMap<String, serde_json::Value>>> {
-        let response = self
+        let response = self.get("/commits").send()?.error_for_status()?;
+
+        read(response)
+    }
+
+    /// Download the files as of a commit as a `.tar.gz` into `writer`,
+    /// returning how many bytes were written
+    pub fn download_archive<W: Write + ?Sized>(
+        &self,
+        commit_id: &str,
+        writer: &mut W,
+    ) -> Result<u64> {
+        let mut response = self
             .client
-            .get(format!("{}/commits", self.base_url))
+            .get(format!("{}/commits/{}/archive", self.base_url, commit_id))
+            .header(AGENT_ID_HEADER, &self.agent_id)
             .send()?
             .error_for_status()?;
 
-        Ok(response.json()?)
+        Ok(response.copy_to(writer)?)
+    }
+
+    /// Roll back the changes made in a commit
+    ///
+    /// Without `execute`, this only returns the plan of what would change.
+    /// Files modified since the commit are reported as conflicts and skipped.
+    pub fn rollback(
+        &self,
+        commit_id: &str,
+        execute: bool,
+    ) -> Result<HashMap<String, serde_json::Value>> {
+        let request = RollbackRequest {
+            commit_id: commit_id.to_string(),
+            execute,
+            force: false,
+        };
+
+        let response = self
+            .post("/rollback", &request)?
+            .send()?
+            .error_for_status()?;
+
+        read(response)
+    }
+
+    /// Undo a single committed change on disk, leaving the rest of its commit
+    /// alone
+    ///
+    /// Without `execute`, this only returns the plan of what would change. A
+    /// file modified since the commit is reported as a conflict and skipped
+    /// unless `force` is set. With `verify`, stored content that doesn't match
+    /// its hash is refused.
+    pub fn revert_change(
+        &self,
+        change_id: &str,
+        execute: bool,
+        force: bool,
+        verify: bool,
+    ) -> Result<HashMap<String, serde_json::Value>> {
+        let request = RevertChangeRequest {
+            execute,
+            force,
+            verify,
+        };
+
+        let response = self
+            .post(&format!("/changes/{}/revert", change_id), &request)?
+            .send()?
+            .error_for_status()?;
+
+        read(response)
+    }
+
+    /// Get the session the server is currently recording into
+    pub fn active_session(&self) -> Result<SessionDto> {
+        let response = self.get("/session").send()?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(NoActiveSession.into());
+        }
+
+        read(response.error_for_status()?)
+    }
+
+    /// End the server's active session and start tracking `path`, a
+    /// directory on the server's machine, in a new one. Needs the admin token.
+    pub fn start_session(&self, path: impl Into<PathBuf>) -> Result<SessionDto> {
+        let request = StartSessionRequest {
+            root_path: path.into(),
+        };
+
+        let mut request = self.post("/session", &request)?;
+        if let Some(token) = &self.admin_token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send()?.error_for_status()?;
+
+        read(response)
     }
 
     /// Check server health
@@ -181,11 +645,130 @@ impl GitentClient {
 
         Ok(response.status().is_success())
     }
+
+    /// Wait for the server to answer health checks, for use right after
+    /// starting it
+    ///
+    /// Polls `/health` with increasing delays until it succeeds, returning
+    /// [`NotReady`] once `timeout` has passed. Each attempt is also limited
+    /// by the client's own request timeout.
+    pub fn wait_for_ready(&self, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        let mut backoff = READY_MIN_BACKOFF;
+
+        loop {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return Err(NotReady {
+                    base_url: self.base_url.clone(),
+                    waited: start.elapsed(),
+                }
+                .into());
+            }
+
+            let attempt_timeout = self.timeout.map_or(remaining, |t| t.min(remaining));
+            let healthy = self
+                .client
+                .get(format!("{}/health", self.base_url))
+                .timeout(attempt_timeout)
+                .send()
+                .is_ok_and(|response| response.status().is_success());
+            if healthy {
+                return Ok(());
+            }
+
+            std::thread::sleep(backoff.min(timeout.saturating_sub(start.elapsed())));
+            backoff = (backoff * 2).min(READY_MAX_BACKOFF);
+        }
+    }
+}
+
+impl GitentClient {
+    /// A GET request that asks for a response in the client's format.
+    fn get(&self, path: &str) -> RequestBuilder {
+        self.accept(self.client.get(format!("{}{}", self.base_url, path)))
+    }
+
+    /// A POST request with `body` encoded in the client's format.
+    fn post<T: Serialize>(&self, path: &str, body: &T) -> Result<RequestBuilder> {
+        let request = self.accept(self.client.post(format!("{}{}", self.base_url, path)));
+        Ok(match self.format {
+            WireFormat::Json => request.json(body),
+            WireFormat::MsgPack => request
+                .header(CONTENT_TYPE, MSGPACK)
+                .body(to_msgpack(body)?),
+        })
+    }
+
+    /// Name the agent and ask for responses in the client's format.
+    fn accept(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = request.header(AGENT_ID_HEADER, &self.agent_id);
+        match self.format {
+            WireFormat::Json => request,
+            WireFormat::MsgPack => request.header(ACCEPT, MSGPACK),
+        }
+    }
+}
+
+/// Decode a response body in whichever format the server answered with.
+fn read<T: DeserializeOwned>(response: Response) -> Result<T> {
+    let is_msgpack = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(MSGPACK));
+    if is_msgpack {
+        let body = response.bytes()?;
+        let mut deserializer = rmp_serde::Deserializer::new(body.as_ref()).with_human_readable();
+        Ok(T::deserialize(&mut deserializer)?)
+    } else {
+        Ok(response.json()?)
+    }
+}
+
+/// Encode `value` as MessagePack in the shape the server expects: named
+/// fields, with ids and timestamps as strings.
+fn to_msgpack<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut serializer = rmp_serde::Serializer::new(&mut body)
+        .with_struct_map()
+        .with_human_readable();
+    value.serialize(&mut serializer)?;
+    Ok(body)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Serve a single canned response and return the server's base URL.
+    fn mock_server(status: &'static str, body: &'static str) -> String {
+        mock_server_with(status, "application/json", body.as_bytes().to_vec())
+    }
+
+    fn mock_server_with(status: &'static str, content_type: &'static str, body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                content_type,
+                body.len(),
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+        });
+
+        format!("http://{}", addr)
+    }
 
     #[test]
     fn test_client_creation() {
@@ -193,4 +776,127 @@ mod tests {
         assert_eq!(client.base_url, "http://localhost:3030");
         assert_eq!(client.agent_id, "test-agent");
     }
+
+    #[test]
+    fn test_active_session() {
+        let url = mock_server(
+            "200 OK",
+            r#"{"id":"6f1c3c1e-2a4b-4c5d-8e9f-0a1b2c3d4e5f","root_path":"/work/project","started":"2024-01-01T12:00:00Z","ended":null,"active":true,"ignore_patterns":[".git","target"],"store_deltas":false}"#,
+        );
+        let client = GitentClient::new(url, "test-agent");
+
+        let session = client.active_session().unwrap();
+        assert_eq!(
+            session.id,
+            Uuid::parse_str("6f1c3c1e-2a4b-4c5d-8e9f-0a1b2c3d4e5f").unwrap()
+        );
+        assert_eq!(session.root_path, PathBuf::from("/work/project"));
+        assert_eq!(session.ignore_patterns, vec![".git", "target"]);
+    }
+
+    #[test]
+    fn test_download_archive() {
+        let archive = vec![0x1f, 0x8b, 8, 0, 1, 2, 3];
+        let url = mock_server_with("200 OK", "application/gzip", archive.clone());
+        let client = GitentClient::new(url, "test-agent");
+
+        let mut downloaded = Vec::new();
+        let written = client
+            .download_archive("6f1c3c1e-2a4b-4c5d-8e9f-0a1b2c3d4e5f", &mut downloaded)
+            .unwrap();
+        assert_eq!(written, archive.len() as u64);
+        assert_eq!(downloaded, archive);
+    }
+
+    #[test]
+    fn test_preview_diff() {
+        let url = mock_server(
+            "200 OK",
+            r#"{"path":"notes.txt","old_path":null,"old_content":"a\nb\n","new_content":"a\nc\nd\n","diff_lines":[
+                {"line_type":"context","content":"a\n","old_line_number":1,"new_line_number":1},
+                {"line_type":"deletion","content":"b\n","old_line_number":2,"new_line_number":null},
+                {"line_type":"addition","content":"c\n","old_line_number":null,"new_line_number":2},
+                {"line_type":"addition","content":"d\n","old_line_number":null,"new_line_number":3}]}"#,
+        );
+        let client = GitentClient::new(url, "test-agent");
+
+        let preview = client.preview_diff("notes.txt", "a\nc\nd\n").unwrap();
+        assert_eq!(preview.old_content.as_deref(), Some("a\nb\n"));
+        assert_eq!(preview.additions(), 2);
+        assert_eq!(preview.deletions(), 1);
+    }
+
+    #[test]
+    fn test_active_session_msgpack() {
+        let session = serde_json::json!({
+            "id": "6f1c3c1e-2a4b-4c5d-8e9f-0a1b2c3d4e5f",
+            "root_path": "/work/project",
+            "started": "2024-01-01T12:00:00Z",
+            "ignore_patterns": [".git"],
+        });
+        let url = mock_server_with("200 OK", MSGPACK, to_msgpack(&session).unwrap());
+        let client = GitentClient::new(url, "test-agent").with_format(WireFormat::MsgPack);
+
+        let session = client.active_session().unwrap();
+        assert_eq!(session.root_path, PathBuf::from("/work/project"));
+        assert_eq!(session.ignore_patterns, vec![".git"]);
+    }
+
+    #[test]
+    fn test_no_active_session() {
+        let url = mock_server(
+            "404 Not Found",
+            r#"{"error":"No active session","request_id":"abc"}"#,
+        );
+        let client = GitentClient::new(url, "test-agent");
+
+        let error = client.active_session().unwrap_err();
+        assert!(error.downcast_ref::<NoActiveSession>().is_some());
+    }
+
+    #[test]
+    fn test_wait_for_ready() {
+        // Reserve a free port, then leave it closed until the server "starts"
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let start = Instant::now();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            let listener = TcpListener::bind(addr).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).unwrap();
+            let body = r#"{"status":"ok"}"#;
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+
+        let client = GitentClient::new(format!("http://{}", addr), "test-agent")
+            .with_timeout(Duration::from_secs(1));
+        client.wait_for_ready(Duration::from_secs(5)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_wait_for_ready_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let client = GitentClient::new(format!("http://{}", addr), "test-agent");
+        let start = Instant::now();
+        let error = client
+            .wait_for_ready(Duration::from_millis(200))
+            .unwrap_err();
+
+        assert!(error.downcast_ref::<NotReady>().is_some());
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
 }
@@ -1,157 +1,3382 @@
+use crate::archive::{self, ArchiveFormat};
+use crate::audit::{audit_writes, require_admin, AuditTarget};
+use crate::diff_cache::DiffCache;
+use crate::health::CaptureStatus;
+use crate::metrics::{track_requests, Metrics};
+use crate::middleware::{request_context, REQUEST_ID_HEADER};
+use crate::store::StorageHandle;
+use crate::watcher::{ActiveWatcher, WatcherOptions};
+use crate::webhook::Webhooks;
+use crate::wire::{negotiate, Wire};
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::{IntoResponse, Json},
+    body::{Body, HttpBody},
+    error_handling::HandleErrorLayer,
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{from_fn, from_fn_with_state},
+    response::{IntoResponse, Json, Response},
     routing::{get, post},
-    Router,
+    Extension, Router,
 };
-use gitent_core::{Change, ChangeType, Commit, CommitInfo, Session, Storage};
-use serde::Deserialize;
-use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Utc};
+use gitent_core::diff;
+use gitent_core::ignore::IgnoreMatcher;
+use gitent_core::rollback::{self, RollbackPlan};
+use gitent_core::{
+    ActivityEvent, AuditEntry, Change, ChangeType, Commit, CommitInfo, Error, FileVersion, Session,
+    SortOrder, StoredContent, TrackedPath,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::io::ReaderStream;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::{BoxError, ServiceBuilder};
+use tower_http::compression::predicate::{DefaultPredicate, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::warn;
 use uuid::Uuid;
 
+/// Which browser origins may call the API.
+#[derive(Clone, Debug, Default)]
+pub enum CorsPolicy {
+    /// No CORS headers are sent, so cross-origin browser requests fail
+    #[default]
+    Disabled,
+    /// Any origin is allowed; intended for local development
+    Any,
+    /// Only the listed origins are allowed
+    Origins(Vec<HeaderValue>),
+}
+
+impl CorsPolicy {
+    pub fn from_origins(origins: &[String]) -> anyhow::Result<Self> {
+        let origins = origins
+            .iter()
+            .map(|origin| HeaderValue::from_str(origin))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(CorsPolicy::Origins(origins))
+    }
+
+    fn layer(&self) -> Option<CorsLayer> {
+        let origins = match self {
+            CorsPolicy::Disabled => return None,
+            CorsPolicy::Any => AllowOrigin::any(),
+            CorsPolicy::Origins(origins) => AllowOrigin::list(origins.clone()),
+        };
+
+        let request_id = HeaderName::from_static(REQUEST_ID_HEADER);
+        Some(
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods([Method::GET, Method::POST, Method::DELETE])
+                .allow_headers([header::CONTENT_TYPE, request_id.clone()])
+                .expose_headers([request_id]),
+        )
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
-    pub storage: Arc<Mutex<Storage>>,
+    pub storage: StorageHandle,
+    pub cors: CorsPolicy,
+    /// Set when `GET /metrics` is enabled
+    pub metrics: Option<Arc<Metrics>>,
+    /// Reported at `GET /health`
+    pub capture: Arc<CaptureStatus>,
+    /// Requests handled at once; more are turned away with a 503
+    pub max_concurrency: Option<usize>,
+    /// Largest request body read; larger ones are turned away with a 413
+    pub max_body_bytes: Option<usize>,
+    /// Recently rendered commit diffs
+    pub diff_cache: Arc<DiffCache>,
+    /// Files the watcher records without content, reported by dry runs
+    pub max_file_size: Option<u64>,
+    /// How long tree and diff requests may spend in storage
+    pub storage_timeout: Option<Duration>,
+    /// Bearer token for admin endpoints such as `GET /audit`
+    pub admin_token: Option<String>,
+    /// Notified after each commit
+    pub webhooks: Option<Webhooks>,
+    /// Moved over to each session started through `POST /session`
+    pub watcher: Arc<ActiveWatcher>,
+    /// Held while `POST /session` switches both the session and the watcher
+    pub session_switch: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl AppState {
+    pub fn new(storage: StorageHandle) -> Self {
+        Self {
+            storage,
+            cors: CorsPolicy::default(),
+            metrics: None,
+            capture: Arc::new(CaptureStatus::new()),
+            max_concurrency: None,
+            max_body_bytes: None,
+            diff_cache: Arc::new(DiffCache::default()),
+            max_file_size: None,
+            storage_timeout: None,
+            admin_token: None,
+            webhooks: None,
+            watcher: Arc::new(ActiveWatcher::new(WatcherOptions::default())),
+            session_switch: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    pub fn with_cors(mut self, cors: CorsPolicy) -> Self {
+        self.cors = cors;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn with_capture(mut self, capture: Arc<CaptureStatus>) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    pub fn with_max_concurrency(mut self, limit: usize) -> Self {
+        self.max_concurrency = Some(limit);
+        self
+    }
+
+    pub fn with_max_file_size(mut self, limit: u64) -> Self {
+        self.max_file_size = Some(limit);
+        self
+    }
+
+    pub fn with_webhooks(mut self, webhooks: Webhooks) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    pub fn with_watcher(mut self, watcher: Arc<ActiveWatcher>) -> Self {
+        self.watcher = watcher;
+        self
+    }
+
+    pub fn with_admin_token(mut self, token: String) -> Self {
+        self.admin_token = Some(token);
+        self
+    }
+
+    pub fn with_max_body_bytes(mut self, limit: usize) -> Self {
+        self.max_body_bytes = Some(limit);
+        self
+    }
+
+    pub fn with_storage_timeout(mut self, timeout: Duration) -> Self {
+        self.storage_timeout = Some(timeout);
+        self
+    }
+
+    /// Keep up to `capacity` commit diffs; 0 disables caching.
+    pub fn with_diff_cache(mut self, capacity: usize) -> Self {
+        self.diff_cache = Arc::new(DiffCache::new(capacity));
+        self
+    }
 }
 
+/// Largest body accepted by the endpoints taking raw file content, unless
+/// `max_body_bytes` is set.
+const MAX_CONTENT_SIZE: usize = 512 * 1024 * 1024;
+
+/// Responses smaller than this many bytes are sent uncompressed.
+const MIN_COMPRESSED_SIZE: u16 = 1024;
+
 pub fn create_router(state: AppState) -> Router {
-    Router::new()
-        .route("/health", get(health_check))
-        .route("/session", get(get_active_session))
-        .route("/changes", get(get_uncommitted_changes))
+    let cors = state.cors.layer();
+
+    let router = Router::new()
+        .route("/session", get(get_active_session).post(start_session))
+        .route("/changes", get(get_changes))
         .route("/changes", post(create_change))
+        .route("/changes/diff", get(get_uncommitted_diff))
+        .route("/changes/tail", get(get_changes_tail))
+        .route("/changes/preview-diff", post(preview_diff))
+        .route("/changes/raw", post(create_change_raw))
+        .route(
+            "/changes/:id/content",
+            get(get_change_content).post(set_change_content),
+        )
+        .route("/changes/:id/revert", post(revert_change))
         .route("/commits", get(get_commits))
         .route("/commits", post(create_commit))
+        .route("/commits/range", get(get_commit_range))
         .route("/commits/:id", get(get_commit))
-        .with_state(state)
+        .route("/commits/:id/diff", get(get_commit_diff))
+        .route("/commits/:id/archive", get(get_commit_archive))
+        .route("/rollback", post(rollback_commit))
+        .route("/tree", get(get_tree))
+        .route("/paths", get(get_paths))
+        // Only `/files/*path/versions` for now; a wildcard has to come last
+        .route("/files/*path", get(get_file_versions))
+        .route("/activity", get(get_activity))
+        .route("/audit", get(get_audit))
+        // Bodies are JSON unless the client asks for MessagePack
+        .layer(from_fn(negotiate));
+    // Every other endpoint keeps axum's default limit unless one is configured
+    let router = match state.max_body_bytes {
+        Some(limit) => router.layer(DefaultBodyLimit::max(limit)),
+        None => router,
+    };
+
+    // Shed load rather than queueing every request behind the single storage
+    // thread. `Router::layer` wraps each route separately, so the limit has to
+    // share one semaphore to apply to the server as a whole.
+    let router = match state.max_concurrency {
+        Some(limit) => router.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(overloaded))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::new(limit)),
+        ),
+        None => router,
+    };
+    // Added after the limit so a busy server still answers health checks
+    let router = router.route("/health", get(health_check));
+
+    let router = match &state.metrics {
+        Some(metrics) => router
+            .route("/metrics", get(get_metrics))
+            .layer(from_fn_with_state(Arc::clone(metrics), track_requests)),
+        None => router,
+    };
+    // Inside the request context so entries carry the request id, and outside
+    // the concurrency limit so shed requests are recorded too
+    let router = router.layer(from_fn_with_state(state.storage.clone(), audit_writes));
+    let router = router.layer(from_fn(request_context));
+
+    // Compress when the client asks for it, skipping bodies too small to benefit
+    let router = router.layer(
+        CompressionLayer::new()
+            .compress_when(DefaultPredicate::new().and(SizeAbove::new(MIN_COMPRESSED_SIZE))),
+    );
+
+    // CORS goes outermost so preflight requests are answered before anything else
+    let router = match cors {
+        Some(cors) => router.layer(cors),
+        None => router,
+    };
+
+    router.with_state(state)
 }
 
-async fn health_check() -> impl IntoResponse {
-    Json(serde_json::json!({"status": "ok"}))
+async fn overloaded(_: BoxError) -> (StatusCode, String) {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Server is busy, try again shortly".to_string(),
+    )
+}
+
+async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
+    // The server still answers while degraded, so this stays a 200
+    let dropped_events = state.capture.dropped_events();
+    let mut body = match state.capture.degraded_reason() {
+        Some(reason) => serde_json::json!({
+            "status": "degraded",
+            "reason": reason,
+            "dropped_events": dropped_events,
+        }),
+        None => serde_json::json!({"status": "ok", "dropped_events": dropped_events}),
+    };
+    if let Some(progress) = state.capture.catch_up_progress() {
+        body["catch_up"] = serde_json::json!({"done": progress.done, "total": progress.total});
+    }
+    Json(body)
+}
+
+async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let uncommitted = state
+        .storage
+        .call(|storage| {
+            storage
+                .get_active_session()
+                .and_then(|session| storage.get_uncommitted_count(&session.id))
+                .unwrap_or(0)
+        })
+        .await;
+    let body = state
+        .metrics
+        .as_ref()
+        .map(|metrics| metrics.render(uncommitted))
+        .unwrap_or_default();
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
 }
 
 async fn get_active_session(
     State(state): State<AppState>,
-) -> Result<Json<Session>, (StatusCode, String)> {
-    let storage = state.storage.lock().unwrap();
-    storage
-        .get_active_session()
-        .map(Json)
+) -> Result<Wire<Session>, (StatusCode, String)> {
+    state
+        .storage
+        .call(|storage| storage.get_active_session())
+        .await
+        .map(Wire)
         .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))
 }
 
-async fn get_uncommitted_changes(
+#[derive(Deserialize)]
+struct StartSessionRequest {
+    root_path: PathBuf,
+    /// Defaults to the patterns of the session being ended
+    ignore_patterns: Option<Vec<String>>,
+}
+
+/// End the active session and start tracking `root_path` in a new one.
+///
+/// Settings other than the ignore patterns carry over from the session being
+/// ended, except for its content root, which belonged to the old directory.
+/// Needs the admin token, since it points the watcher anywhere on the
+/// server's machine.
+async fn start_session(
     State(state): State<AppState>,
-) -> Result<Json<Vec<Change>>, (StatusCode, String)> {
-    let storage = state.storage.lock().unwrap();
-    let session = storage
-        .get_active_session()
-        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    headers: HeaderMap,
+    Wire(req): Wire<StartSessionRequest>,
+) -> Result<(Extension<AuditTarget>, Wire<Session>), (StatusCode, String)> {
+    require_admin(&headers, state.admin_token.as_deref())?;
+    let root_path = std::fs::canonicalize(&req.root_path).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Cannot watch {}: {}", req.root_path.display(), e),
+        )
+    })?;
+    if !root_path.is_dir() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("{} is not a directory", root_path.display()),
+        ));
+    }
 
-    storage
-        .get_uncommitted_changes(&session.id)
-        .map(Json)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    // Switching the session and then the watcher is one step as far as
+    // other requests are concerned
+    let _switching = state.session_switch.lock().await;
+    let (session, previous) = state
+        .storage
+        .call(move |storage| {
+            let previous: Vec<Session> = storage
+                .get_sessions()
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+                .into_iter()
+                .filter(|s| s.active)
+                .collect();
+            let mut session = Session::new(root_path);
+            if let Ok(previous) = storage.get_active_session() {
+                session = session
+                    .with_ignore_patterns(previous.ignore_patterns)
+                    .with_redact_patterns(previous.redact_patterns)
+                    .with_include_patterns(previous.include_patterns)
+                    .with_delta_storage(previous.store_deltas)
+                    .with_hash_algorithm(previous.hash_algorithm);
+                session.metadata_schema = previous.metadata_schema;
+            }
+            if let Some(patterns) = req.ignore_patterns {
+                session = session.with_ignore_patterns(patterns);
+            }
+            IgnoreMatcher::for_session(&session)
+                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+            storage
+                .start_session(&session)
+                .map(|_| (session, previous))
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        })
+        .await?;
+
+    if let Err(e) = state.watcher.start(&session, &state.storage).await {
+        restore_sessions(&state, &session, previous).await;
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Couldn't watch {}: {}", session.root_path.display(), e),
+        ));
+    }
+
+    Ok((Extension(AuditTarget::new(session.id)), Wire(session)))
+}
+
+/// Undo a session switch whose watcher didn't start: end `started` and make
+/// the sessions active before it active again, watching the first of them.
+async fn restore_sessions(state: &AppState, started: &Session, previous: Vec<Session>) {
+    let mut started = started.clone();
+    let restored = state
+        .storage
+        .call({
+            let previous = previous.clone();
+            move |storage| {
+                storage.batch(|storage| {
+                    started.end();
+                    storage.update_session(&started)?;
+                    for mut session in previous {
+                        session.active = true;
+                        session.ended = None;
+                        storage.update_session(&session)?;
+                    }
+                    Ok(())
+                })
+            }
+        })
+        .await;
+    if let Err(e) = restored {
+        warn!("Failed to restore the previous session: {}", e);
+        return;
+    }
+    if let Some(session) = previous.first() {
+        if let Err(e) = state.watcher.start(session, &state.storage).await {
+            warn!(
+                "Failed to watch {} again: {}",
+                session.root_path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    /// Only return changes recorded after this `seq`, oldest first
+    after_seq: Option<i64>,
+    limit: Option<usize>,
+    agent: Option<String>,
+    /// Only changes to this file or to files under this directory
+    path: Option<String>,
+    change_type: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    /// Return committed changes instead of uncommitted ones
+    #[serde(default)]
+    committed: bool,
+    /// `asc` or `desc` by `seq`
+    order: Option<String>,
+}
+
+/// Changes `/changes/tail` returns when `n` isn't given.
+const DEFAULT_TAIL: usize = 20;
+
+/// The most changes `/changes/tail` returns, whatever `n` asks for, as each
+/// comes with its content.
+const MAX_TAIL: usize = 1000;
+
+#[derive(Deserialize)]
+struct TailQuery {
+    n: Option<usize>,
+}
+
+/// The newest changes in the active session, committed or not, newest first.
+async fn get_changes_tail(
+    State(state): State<AppState>,
+    Query(query): Query<TailQuery>,
+) -> Result<Wire<Vec<Change>>, (StatusCode, String)> {
+    let n = query.n.unwrap_or(DEFAULT_TAIL).min(MAX_TAIL);
+    state
+        .storage
+        .call(move |storage| {
+            let session = storage.get_active_session()?;
+            storage.get_recent_changes(&session.id, n)
+        })
+        .await
+        .map(Wire)
+        .map_err(storage_error)
+}
+
+async fn get_changes(
+    State(state): State<AppState>,
+    Query(query): Query<ChangesQuery>,
+) -> Result<Wire<Vec<Change>>, (StatusCode, String)> {
+    let change_type = query
+        .change_type
+        .as_deref()
+        .map(|change_type| {
+            ChangeType::parse(change_type).ok_or_else(|| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Unknown change type: {}", change_type),
+                )
+            })
+        })
+        .transpose()?;
+    let order = match query.order.as_deref() {
+        Some("asc") => SortOrder::Ascending,
+        Some("desc") => SortOrder::Descending,
+        // Polling with `after_seq` reads forward from the last change seen
+        None if query.after_seq.is_some() => SortOrder::Ascending,
+        None => SortOrder::Descending,
+        Some(other) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Unknown order: {}, expected asc or desc", other),
+            ))
+        }
+    };
+
+    state
+        .storage
+        .call(move |storage| {
+            let session = storage
+                .get_active_session()
+                .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+
+            let mut changes = storage
+                .query_changes(&session.id)
+                .committed(query.committed)
+                .order(order);
+            if let Some(path) = query.path {
+                let path = session
+                    .relative_path(std::path::Path::new(&path))
+                    .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+                changes = changes.path_prefix(path);
+            }
+            if let Some(agent) = query.agent {
+                changes = changes.agent(agent);
+            }
+            if let Some(change_type) = change_type {
+                changes = changes.change_type(change_type);
+            }
+            if let Some(since) = query.since {
+                changes = changes.since(since);
+            }
+            if let Some(until) = query.until {
+                changes = changes.until(until);
+            }
+            if let Some(after_seq) = query.after_seq {
+                changes = changes.after_seq(after_seq);
+            }
+            if let Some(limit) = query.limit {
+                changes = changes.limit(limit);
+            }
+
+            changes
+                .fetch()
+                .map(Wire)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        })
+        .await
 }
 
 #[derive(Deserialize)]
 struct CreateChangeRequest {
     change_type: String,
     path: String,
+    old_path: Option<String>,
     content_before: Option<String>,
     content_after: Option<String>,
     agent_id: Option<String>,
+    tool: Option<String>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct CreateChangeQuery {
+    /// Check and describe the change without recording it
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// What a dry run found out about a change.
+#[derive(Serialize, Deserialize)]
+struct ChangePreview {
+    /// The change as it would be stored, with its path normalized, hashes
+    /// computed and content redacted if need be
+    change: Change,
+    /// Why the watcher wouldn't track writes to this file, if it wouldn't
+    skipped: Option<String>,
+    /// The content isn't text, so it has no line diff
+    binary: bool,
+    /// The content is over the size limit, so the watcher would record the
+    /// change without it
+    too_large: bool,
 }
 
 async fn create_change(
     State(state): State<AppState>,
-    Json(req): Json<CreateChangeRequest>,
-) -> Result<Json<Change>, (StatusCode, String)> {
-    let storage = state.storage.lock().unwrap();
-    let session = storage
-        .get_active_session()
-        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    Query(query): Query<CreateChangeQuery>,
+    Wire(mut req): Wire<CreateChangeRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    let content_after = req.content_after.take().map(String::into_bytes);
+    if query.dry_run {
+        return preview_change(state, req, content_after)
+            .await
+            .map(IntoResponse::into_response);
+    }
+    record_change(state, req, content_after)
+        .await
+        .map(IntoResponse::into_response)
+}
+
+#[derive(Deserialize)]
+struct RawChangeQuery {
+    change_type: String,
+    path: String,
+    old_path: Option<String>,
+    agent_id: Option<String>,
+    tool: Option<String>,
+}
+
+/// Read a raw content body as it arrives, into one buffer sized from its
+/// `Content-Length`. It's refused with a 413 as soon as it passes the limit,
+/// rather than once all of it has been received.
+async fn read_content(
+    state: &AppState,
+    headers: &HeaderMap,
+    mut body: Body,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    let limit = state.max_body_bytes.unwrap_or(MAX_CONTENT_SIZE);
+    let too_large = || {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Content is over the {} byte limit", limit),
+        )
+    };
+    let expected = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if expected.is_some_and(|expected| expected > limit) {
+        return Err(too_large());
+    }
 
+    let mut content = Vec::with_capacity(expected.unwrap_or(0));
+    while let Some(frame) =
+        std::future::poll_fn(|cx| std::pin::Pin::new(&mut body).poll_frame(cx)).await
+    {
+        let frame = frame.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        let Ok(chunk) = frame.into_data() else {
+            continue;
+        };
+        if content.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        content.extend_from_slice(&chunk);
+    }
+    Ok(content)
+}
+
+/// Create a change whose after-content is the raw request body, so large files
+/// don't have to be encoded into a JSON string.
+async fn create_change_raw(
+    State(state): State<AppState>,
+    Query(query): Query<RawChangeQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(Extension<AuditTarget>, Wire<Change>), (StatusCode, String)> {
+    let content = read_content(&state, &headers, body).await?;
+    let req = CreateChangeRequest {
+        change_type: query.change_type,
+        path: query.path,
+        old_path: query.old_path,
+        content_before: None,
+        content_after: None,
+        agent_id: query.agent_id,
+        tool: query.tool,
+        metadata: HashMap::new(),
+    };
+    record_change(state, req, Some(content)).await
+}
+
+/// Build the change a create request describes, with its paths relative to
+/// the content root.
+async fn build_change(
+    state: &AppState,
+    req: CreateChangeRequest,
+    content_after: Option<Vec<u8>>,
+) -> Result<(Session, Change), (StatusCode, String)> {
     let change_type = ChangeType::parse(&req.change_type)
         .ok_or_else(|| (StatusCode::BAD_REQUEST, "Invalid change type".to_string()))?;
 
-    let mut change = Change::new(change_type, std::path::PathBuf::from(req.path), session.id);
+    let session = state
+        .storage
+        .call(|storage| storage.get_active_session())
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+
+    // Store the path the way the watcher would, so both name the file the same
+    let path = session
+        .relative_path(std::path::Path::new(&req.path))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let mut change = Change::new(change_type, path, session.id);
 
+    if let Some(old_path) = req.old_path {
+        let old_path = session
+            .relative_path(std::path::Path::new(&old_path))
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        change = change.with_old_path(old_path);
+    }
+
+    // Hashed the session's way up front, so storing it needn't hash again
     if let Some(content) = req.content_before {
-        change = change.with_content_before(content.into_bytes());
+        change = change.with_content_before_hashed(content.into_bytes(), session.hash_algorithm);
     }
 
-    if let Some(content) = req.content_after {
-        change = change.with_content_after(content.into_bytes());
+    if let Some(content) = content_after {
+        change = change.with_content_after_hashed(content, session.hash_algorithm);
     }
 
     if let Some(agent_id) = req.agent_id {
         change = change.with_agent_id(agent_id);
     }
 
-    storage
-        .create_change(&change)
+    if let Some(tool) = req.tool {
+        change = change.with_tool(tool);
+    }
+    change.metadata.extend(req.metadata);
+
+    Ok((session, change))
+}
+
+async fn record_change(
+    state: AppState,
+    req: CreateChangeRequest,
+    content_after: Option<Vec<u8>>,
+) -> Result<(Extension<AuditTarget>, Wire<Change>), (StatusCode, String)> {
+    let (_, change) = build_change(&state, req, content_after).await?;
+    change
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let change = state
+        .storage
+        .call(move |storage| {
+            storage.create_change(&change).map(|seq| Change {
+                seq: Some(seq),
+                ..change
+            })
+        })
+        .await
+        .map_err(|e| match e {
+            Error::InvalidMetadata(_) => (StatusCode::BAD_REQUEST, e.to_string()),
+            e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })?;
+    if let Some(metrics) = &state.metrics {
+        metrics.record_change();
+    }
+
+    let target = AuditTarget::new(change.id).with_agent_id(change.agent_id.clone());
+    Ok((Extension(target), Wire(change)))
+}
+
+async fn preview_change(
+    state: AppState,
+    req: CreateChangeRequest,
+    content_after: Option<Vec<u8>>,
+) -> Result<Wire<ChangePreview>, (StatusCode, String)> {
+    let (session, change) = build_change(&state, req, content_after).await?;
+    // Checked before redaction replaces the content
+    let binary = change.is_binary();
+    let too_large = state.max_file_size.is_some_and(|limit| {
+        change
+            .content_after
+            .as_ref()
+            .is_some_and(|content| content.len() as u64 > limit)
+    });
+
+    let ignore = IgnoreMatcher::for_session(&session)
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let skipped = match session
+        .absolute_path(&change.path)
+        .strip_prefix(&session.root_path)
+    {
+        Ok(watched) if !ignore.is_included(watched) => {
+            Some("matches no include pattern".to_string())
+        }
+        Ok(watched) if ignore.is_ignored(watched) => Some("matches an ignore pattern".to_string()),
+        Ok(_) => None,
+        Err(_) => Some("outside the watched directory".to_string()),
+    };
+
+    let change = state
+        .storage
+        .call(move |storage| storage.preview_change(change))
+        .await
+        .map_err(|e| match e {
+            Error::InvalidOperation(_) => (StatusCode::BAD_REQUEST, e.to_string()),
+            e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })?;
 
-    Ok(Json(change))
+    Ok(Wire(ChangePreview {
+        change,
+        skipped,
+        binary,
+        too_large,
+    }))
 }
 
-async fn get_commits(
+#[derive(Deserialize)]
+struct DiffQuery {
+    format: Option<String>,
+    context: Option<usize>,
+}
+
+/// Lines of context used when the client doesn't ask for a specific amount.
+const DEFAULT_DIFF_CONTEXT: usize = 3;
+
+/// How the diff endpoints render changes.
+#[derive(Clone, Copy)]
+enum DiffFormat {
+    Unified {
+        context: usize,
+    },
+    /// An RFC 6902 JSON Patch per change, for JSON files
+    JsonPatch,
+}
+
+impl DiffQuery {
+    /// The format asked for, unified unless told otherwise.
+    fn diff_format(&self) -> Result<DiffFormat, (StatusCode, String)> {
+        match self.format.as_deref() {
+            None | Some("unified") => Ok(DiffFormat::Unified {
+                context: self.context.unwrap_or(DEFAULT_DIFF_CONTEXT),
+            }),
+            Some("json-patch") => Ok(DiffFormat::JsonPatch),
+            Some(_) => Err((
+                StatusCode::BAD_REQUEST,
+                "Only the unified and json-patch formats are supported".to_string(),
+            )),
+        }
+    }
+}
+
+fn patch_response(patch: impl IntoResponse) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/x-diff; charset=utf-8")],
+        patch,
+    )
+}
+
+/// `changes` rendered in `format`. Changes a JSON Patch can't be made for
+/// are listed with the reason instead.
+fn diff_response(changes: &[Change], format: DiffFormat) -> Result<Response, (StatusCode, String)> {
+    match format {
+        DiffFormat::Unified { context } => diff::unified_patch(changes, context)
+            .map(|patch| patch_response(patch).into_response())
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        DiffFormat::JsonPatch => {
+            // Oldest first, so patches to the same file apply in turn
+            let mut ordered: Vec<&Change> = changes.iter().collect();
+            ordered.sort_by_key(|change| change.seq);
+            Ok(Wire(diff::semantic::json_patches(ordered)).into_response())
+        }
+    }
+}
+
+/// The response for an error from a cancellable storage call.
+fn storage_error(e: Error) -> (StatusCode, String) {
+    let status = match e {
+        Error::NoActiveSession | Error::CommitNotFound(_) => StatusCode::NOT_FOUND,
+        Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
+}
+
+async fn get_uncommitted_diff(
     State(state): State<AppState>,
-) -> Result<Json<Vec<CommitInfo>>, (StatusCode, String)> {
-    let storage = state.storage.lock().unwrap();
-    let session = storage
-        .get_active_session()
-        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    Query(query): Query<DiffQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let format = query.diff_format()?;
+
+    let changes = state
+        .storage
+        .call_cancellable(state.storage_timeout, |storage| {
+            let session = storage.get_active_session()?;
+            storage.get_uncommitted_changes(&session.id)
+        })
+        .await
+        .map_err(storage_error)?;
+
+    diff_response(&changes, format)
+}
+
+#[derive(Deserialize)]
+struct PreviewDiffRequest {
+    path: String,
+    content: String,
+}
+
+/// Diff `content` against the latest tracked version of `path`, recording
+/// nothing. A file with no tracked content is diffed as a create.
+async fn preview_diff(
+    State(state): State<AppState>,
+    Wire(req): Wire<PreviewDiffRequest>,
+) -> Result<Wire<diff::FileDiff>, (StatusCode, String)> {
+    let session = state
+        .storage
+        .call(|storage| storage.get_active_session())
+        .await
+        .map_err(storage_error)?;
+    let path = session
+        .relative_path(std::path::Path::new(&req.path))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let tracked = {
+        let path = path.clone();
+        state
+            .storage
+            .call_cancellable(state.storage_timeout, move |storage| {
+                storage.get_latest_content(&session.id, &path)
+            })
+            .await
+            .map_err(storage_error)?
+    };
+
+    let change = match tracked {
+        Some(before) => {
+            Change::new(ChangeType::Modify, path, session.id).with_content_before(before)
+        }
+        None => Change::new(ChangeType::Create, path, session.id),
+    }
+    .with_content_after(req.content.into_bytes());
 
-    storage
-        .get_commits_for_session(&session.id)
-        .map(Json)
+    diff::FileDiff::from_change(&change)
+        .map(Wire)
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
 }
 
+/// A commit's changes as a unified patch, served from the diff cache when
+/// the same commit was asked for recently.
+async fn get_commit_diff(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<DiffQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let commit_id =
+        Uuid::parse_str(&id).map_err(|_| (StatusCode::BAD_REQUEST, "Invalid UUID".to_string()))?;
+    let format = query.diff_format()?;
+
+    if let DiffFormat::Unified { context } = format {
+        if let Some(patch) = state.diff_cache.get(commit_id, context) {
+            // Reset, prune and gc from the CLI may have removed the commit
+            // since, without this process knowing
+            let exists = state
+                .storage
+                .call(move |storage| storage.commit_exists(&commit_id))
+                .await
+                .map_err(storage_error)?;
+            if exists {
+                return Ok(patch_response(patch.to_string()).into_response());
+            }
+            state.diff_cache.evict(commit_id);
+        }
+    }
+
+    let changes = state
+        .storage
+        .call_cancellable(state.storage_timeout, move |storage| {
+            let commit = storage.get_commit(&commit_id)?;
+            commit
+                .changes
+                .iter()
+                .map(|id| storage.get_change(id))
+                .collect::<gitent_core::Result<Vec<_>>>()
+        })
+        .await
+        .map_err(storage_error)?;
+
+    let DiffFormat::Unified { context } = format else {
+        return diff_response(&changes, format);
+    };
+    let patch = diff::unified_patch(&changes, context)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    state
+        .diff_cache
+        .insert(commit_id, context, Arc::from(patch.as_str()));
+
+    Ok(patch_response(patch).into_response())
+}
+
+#[derive(Deserialize)]
+struct ArchiveQuery {
+    #[serde(default)]
+    format: ArchiveFormat,
+}
+
+/// The files as of a commit, packed into a `.tar.gz` or, with
+/// `?format=zip`, a zip archive.
+async fn get_commit_archive(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<ArchiveQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let commit_id =
+        Uuid::parse_str(&id).map_err(|_| (StatusCode::BAD_REQUEST, "Invalid UUID".to_string()))?;
+
+    let tree = state
+        .storage
+        .call_cancellable(state.storage_timeout, move |storage| {
+            storage.tree_at_commit(&commit_id)
+        })
+        .await
+        .map_err(storage_error)?;
+
+    let format = query.format;
+
+    let disposition = format!(
+        "attachment; filename=\"{}.{}\"",
+        commit_id,
+        format.extension()
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        archive::body(tree, format),
+    ))
+}
+
+#[derive(Deserialize)]
+struct CommitsQuery {
+    path: Option<String>,
+}
+
+async fn get_commits(
+    State(state): State<AppState>,
+    Query(query): Query<CommitsQuery>,
+) -> Result<Wire<Vec<CommitInfo>>, (StatusCode, String)> {
+    state
+        .storage
+        .call(move |storage| {
+            let session = storage
+                .get_active_session()
+                .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+
+            let commits = match query.path {
+                Some(path) => {
+                    let path = session
+                        .relative_path(std::path::Path::new(&path))
+                        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+                    storage
+                        .get_commits_touching_path(&session.id, &path)
+                        .and_then(|commits| {
+                            commits
+                                .iter()
+                                .map(|commit| storage.get_commit_info(commit))
+                                .collect()
+                        })
+                }
+                None => storage.get_commits_for_session(&session.id),
+            };
+
+            commits
+                .map(Wire)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        })
+        .await
+}
+
 #[derive(Deserialize)]
 struct CreateCommitRequest {
     message: String,
     agent_id: String,
     change_ids: Vec<String>,
+    /// Commit to build on; defaults to the session's latest commit
+    parent: Option<Uuid>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
 }
 
 async fn create_commit(
     State(state): State<AppState>,
-    Json(req): Json<CreateCommitRequest>,
-) -> Result<Json<Commit>, (StatusCode, String)> {
-    let storage = state.storage.lock().unwrap();
-    let session = storage
-        .get_active_session()
-        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
-
+    Wire(req): Wire<CreateCommitRequest>,
+) -> Result<(Extension<AuditTarget>, Wire<Commit>), (StatusCode, String)> {
     let change_ids: Vec<Uuid> = req
         .change_ids
         .iter()
         .filter_map(|id| Uuid::parse_str(id).ok())
         .collect();
 
-    let commit = Commit::new(req.message, req.agent_id, change_ids, session.id);
+    let commit = state
+        .storage
+        .call(move |storage| {
+            let session = storage
+                .get_active_session()
+                .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
 
-    storage
-        .create_commit(&commit)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            let parent = match req.parent {
+                Some(parent) => Some(parent),
+                None => storage
+                    .get_head_commit(&session.id)
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+                    .map(|head| head.id),
+            };
 
-    Ok(Json(commit))
+            let mut commit = Commit::new(req.message, req.agent_id, change_ids, session.id);
+            commit.metadata.extend(req.metadata);
+            if let Some(parent) = parent {
+                commit = commit.with_parent(parent);
+            }
+            // Read back so the changes are listed in canonical order
+            storage
+                .create_commit(&commit)
+                .and_then(|_| storage.get_commit(&commit.id))
+                .map_err(|e| match e {
+                    Error::CommitNotFound(_)
+                    | Error::InvalidOperation(_)
+                    | Error::InvalidMetadata(_) => (StatusCode::BAD_REQUEST, e.to_string()),
+                    Error::ConcurrentModification(_) => (StatusCode::CONFLICT, e.to_string()),
+                    e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+                })
+        })
+        .await?;
+    if let Some(metrics) = &state.metrics {
+        metrics.record_commit();
+    }
+    if let Some(webhooks) = &state.webhooks {
+        webhooks.notify_commit(state.storage.clone(), commit.clone());
+    }
+
+    let target = AuditTarget::new(commit.id).with_agent_id(Some(commit.agent_id.clone()));
+    Ok((Extension(target), Wire(commit)))
+}
+
+#[derive(Deserialize)]
+struct CommitRangeQuery {
+    from: String,
+    to: String,
+}
+
+/// The commits on the ancestry path from `from`, exclusive, to `to`,
+/// inclusive, oldest first. Either end can be given as a short id.
+async fn get_commit_range(
+    State(state): State<AppState>,
+    Query(query): Query<CommitRangeQuery>,
+) -> Result<Wire<Vec<Commit>>, (StatusCode, String)> {
+    state
+        .storage
+        .call_cancellable(state.storage_timeout, move |storage| {
+            let session = storage.get_active_session()?;
+            let from = storage.resolve_commit_prefix(&session.id, &query.from)?;
+            let to = storage.resolve_commit_prefix(&session.id, &query.to)?;
+            storage.get_ancestry_path(&from.id, &to.id)
+        })
+        .await
+        .map(Wire)
+        .map_err(|e| match e {
+            Error::InvalidOperation(_) | Error::AmbiguousRef(_) => {
+                (StatusCode::BAD_REQUEST, e.to_string())
+            }
+            e => storage_error(e),
+        })
 }
 
 async fn get_commit(
     State(state): State<AppState>,
     Path(id): Path<String>,
-) -> Result<Json<Commit>, (StatusCode, String)> {
+) -> Result<Wire<Commit>, (StatusCode, String)> {
     let commit_id =
         Uuid::parse_str(&id).map_err(|_| (StatusCode::BAD_REQUEST, "Invalid UUID".to_string()))?;
 
-    let storage = state.storage.lock().unwrap();
-    storage
-        .get_commit(&commit_id)
-        .map(Json)
+    state
+        .storage
+        .call(move |storage| storage.get_commit(&commit_id))
+        .await
+        .map(Wire)
         .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))
 }
+
+#[derive(Deserialize)]
+struct ContentQuery {
+    /// `before` or `after`, defaulting to `after`
+    side: Option<String>,
+}
+
+/// The raw content of one side of a change.
+async fn get_change_content(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<ContentQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let change_id =
+        Uuid::parse_str(&id).map_err(|_| (StatusCode::BAD_REQUEST, "Invalid UUID".to_string()))?;
+    let before = match query.side.as_deref() {
+        None | Some("after") => false,
+        Some("before") => true,
+        Some(other) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Unknown side: {}, expected before or after", other),
+            ))
+        }
+    };
+
+    let content = state
+        .storage
+        .call(move |storage| storage.open_content(&change_id, before))
+        .await
+        .map_err(|e| match e {
+            Error::ChangeNotFound(_) => (StatusCode::NOT_FOUND, e.to_string()),
+            e => storage_error(e),
+        })?
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("Change {} has no stored content", change_id),
+            )
+        })?;
+
+    // Content in the object store is sent as it's read, so a large file
+    // never sits in memory whole
+    let body = match content {
+        StoredContent::Object(file) => {
+            Body::from_stream(ReaderStream::new(tokio::fs::File::from_std(file)))
+        }
+        StoredContent::Loaded(content) => Body::from(content),
+    };
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], body))
+}
+
+/// Replace the after-content of an uncommitted change with the raw request body.
+async fn set_change_content(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(Extension<AuditTarget>, Wire<Change>), (StatusCode, String)> {
+    let change_id =
+        Uuid::parse_str(&id).map_err(|_| (StatusCode::BAD_REQUEST, "Invalid UUID".to_string()))?;
+    let content = read_content(&state, &headers, body).await?;
+
+    state
+        .storage
+        .call(move |storage| storage.set_content_after(&change_id, content))
+        .await
+        .map(|change| {
+            let target = AuditTarget::new(change.id).with_agent_id(change.agent_id.clone());
+            (Extension(target), Wire(change))
+        })
+        .map_err(|e| match e {
+            Error::ChangeNotFound(_) => (StatusCode::NOT_FOUND, e.to_string()),
+            Error::InvalidOperation(_) => (StatusCode::BAD_REQUEST, e.to_string()),
+            e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })
+}
+
+#[derive(Serialize, Deserialize)]
+struct TreeEntry {
+    path: String,
+    change_type: ChangeType,
+    change_id: Uuid,
+    timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+async fn get_tree(
+    State(state): State<AppState>,
+) -> Result<Wire<Vec<TreeEntry>>, (StatusCode, String)> {
+    let latest = state
+        .storage
+        .call_cancellable(state.storage_timeout, |storage| {
+            let session = storage.get_active_session()?;
+            storage.latest_change_per_file(&session.id)
+        })
+        .await
+        .map_err(storage_error)?;
+
+    let mut entries: Vec<TreeEntry> = latest
+        .into_iter()
+        .map(|(path, change)| TreeEntry {
+            path: path.to_string_lossy().to_string(),
+            change_type: change.change_type,
+            change_id: change.id,
+            timestamp: change.timestamp,
+        })
+        .collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(Wire(entries))
+}
+
+#[derive(Deserialize)]
+struct PathsQuery {
+    /// Also say whether each path still exists
+    #[serde(default)]
+    status: bool,
+}
+
+/// Every path touched in the active session, without loading any changes.
+async fn get_paths(
+    State(state): State<AppState>,
+    Query(query): Query<PathsQuery>,
+) -> Result<Wire<Vec<TrackedPath>>, (StatusCode, String)> {
+    state
+        .storage
+        .call(move |storage| {
+            let session = storage.get_active_session()?;
+            if query.status {
+                return storage.list_tracked_path_statuses(&session.id);
+            }
+            let paths = storage.list_tracked_paths(&session.id)?;
+            Ok(paths
+                .into_iter()
+                .map(|path| TrackedPath { path, status: None })
+                .collect())
+        })
+        .await
+        .map(Wire)
+        .map_err(storage_error)
+}
+
+#[derive(Deserialize)]
+struct FileVersionsQuery {
+    /// Include each version's content; without it, fetch versions one at a
+    /// time from `/changes/:id/content`
+    #[serde(default)]
+    content: bool,
+}
+
+/// Every version of a file in the active session, oldest first, following
+/// renames.
+async fn get_file_versions(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    Query(query): Query<FileVersionsQuery>,
+) -> Result<Wire<Vec<FileVersion>>, (StatusCode, String)> {
+    let Some(path) = path.strip_suffix("/versions") else {
+        return Err((StatusCode::NOT_FOUND, "Not found".to_string()));
+    };
+    let path = PathBuf::from(path);
+    state
+        .storage
+        .call_cancellable(state.storage_timeout, move |storage| {
+            let session = storage.get_active_session()?;
+            let mut versions = Vec::new();
+            storage.for_each_file_version(&session.id, &path, query.content, |version| {
+                versions.push(version);
+                Ok(())
+            })?;
+            Ok(versions)
+        })
+        .await
+        .map(Wire)
+        .map_err(storage_error)
+}
+
+/// Entries in the activity feed when no limit is given.
+const DEFAULT_ACTIVITY_LIMIT: usize = 50;
+
+#[derive(Deserialize)]
+struct ActivityQuery {
+    limit: Option<usize>,
+}
+
+/// The latest changes and commits in the active session, newest first.
+async fn get_activity(
+    State(state): State<AppState>,
+    Query(query): Query<ActivityQuery>,
+) -> Result<Wire<Vec<ActivityEvent>>, (StatusCode, String)> {
+    let limit = query.limit.unwrap_or(DEFAULT_ACTIVITY_LIMIT);
+    state
+        .storage
+        .call(move |storage| {
+            let session = storage.get_active_session()?;
+            storage.recent_activity(&session.id, limit)
+        })
+        .await
+        .map(Wire)
+        .map_err(storage_error)
+}
+
+#[derive(Deserialize)]
+struct RollbackRequest {
+    commit_id: String,
+    #[serde(default)]
+    execute: bool,
+    #[serde(default)]
+    force: bool,
+    /// Refuse changes whose stored content doesn't match its hash
+    #[serde(default)]
+    verify: bool,
+}
+
+async fn rollback_commit(
+    State(state): State<AppState>,
+    Wire(req): Wire<RollbackRequest>,
+) -> Result<(Extension<AuditTarget>, Wire<RollbackPlan>), (StatusCode, String)> {
+    let commit_id = Uuid::parse_str(&req.commit_id)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid UUID".to_string()))?;
+
+    let (session, commit, changes) = state
+        .storage
+        .call(move |storage| {
+            let active = storage
+                .get_active_session()
+                .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+            let commit = storage
+                .get_commit(&commit_id)
+                .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+            // Another session's files may live under a different root, and
+            // aren't what's being watched
+            if commit.session_id != active.id {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Commit {} is not in the active session", commit.id),
+                ));
+            }
+            let session = storage
+                .get_session(&commit.session_id)
+                .map_err(storage_error)?;
+
+            let changes: Vec<Change> = commit
+                .changes
+                .iter()
+                .filter_map(|id| storage.get_change(id).ok())
+                .collect();
+
+            Ok((session, commit, changes))
+        })
+        .await?;
+
+    let target = Extension(AuditTarget::new(commit.id));
+    let plan = rollback::plan(&commit, &changes, session.content_root());
+    if !req.execute {
+        return Ok((target, Wire(plan)));
+    }
+
+    let plan = rollback::execute(
+        plan,
+        &changes,
+        session.content_root(),
+        req.force,
+        req.verify,
+    );
+    Ok((target, Wire(plan)))
+}
+
+#[derive(Deserialize)]
+struct RevertChangeRequest {
+    #[serde(default)]
+    execute: bool,
+    #[serde(default)]
+    force: bool,
+    /// Refuse a change whose stored content doesn't match its hash
+    #[serde(default)]
+    verify: bool,
+}
+
+/// Undo one committed change on disk, leaving the rest of its commit alone.
+/// Only plans the revert unless `execute` is set. The watcher records the
+/// revert as it would any other edit.
+async fn revert_change(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Wire(req): Wire<RevertChangeRequest>,
+) -> Result<(Extension<AuditTarget>, Wire<RollbackPlan>), (StatusCode, String)> {
+    let change_id =
+        Uuid::parse_str(&id).map_err(|_| (StatusCode::BAD_REQUEST, "Invalid UUID".to_string()))?;
+
+    let (session, commit, change) = state
+        .storage
+        .call(move |storage| {
+            let session = storage
+                .get_active_session()
+                .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+            let change = storage
+                .get_change(&change_id)
+                .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+            // As with rollback, another session's files aren't what's being
+            // watched, and may live under a different root
+            if change.session_id != session.id {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Change {} is not in the active session", change.id),
+                ));
+            }
+            let commit = storage
+                .get_commit_for_change(&change_id)
+                .map_err(|e| match e {
+                    Error::InvalidOperation(_) => (StatusCode::BAD_REQUEST, e.to_string()),
+                    e => storage_error(e),
+                })?;
+            Ok((session, commit, change))
+        })
+        .await?;
+
+    let target = Extension(AuditTarget::new(change.id).with_agent_id(change.agent_id.clone()));
+    let root = session.content_root();
+    let plan = rollback::plan_change(&commit, &change, root)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if !req.execute {
+        return Ok((target, Wire(plan)));
+    }
+
+    let plan = rollback::execute(
+        plan,
+        std::slice::from_ref(&change),
+        root,
+        req.force,
+        req.verify,
+    );
+    Ok((target, Wire(plan)))
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    limit: Option<usize>,
+}
+
+/// The audit log of API writes, newest first. Needs the admin token.
+async fn get_audit(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> Result<Wire<Vec<AuditEntry>>, (StatusCode, String)> {
+    require_admin(&headers, state.admin_token.as_deref())?;
+
+    state
+        .storage
+        .call(move |storage| storage.get_audit_log(query.limit))
+        .await
+        .map(Wire)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{from_msgpack, to_msgpack, MSGPACK};
+    use axum::body::{to_bytes, Body};
+    use axum::http::{header, Request};
+    use gitent_core::rollback::RollbackOutcome;
+    use gitent_core::{Progress, Storage};
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_rollback_through_api() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("file.txt");
+        std::fs::write(&file_path, "after").unwrap();
+
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        storage.create_session(&session).unwrap();
+
+        let change = Change::new(ChangeType::Modify, "file.txt".into(), session.id)
+            .with_content_before(b"before".to_vec())
+            .with_content_after(b"after".to_vec());
+        storage.create_change(&change).unwrap();
+        let commit = Commit::new(
+            "Edit".to_string(),
+            "test-agent".to_string(),
+            vec![change.id],
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        let body = serde_json::json!({
+            "commit_id": commit.id.to_string(),
+            "execute": true,
+        });
+        let response = router
+            .oneshot(
+                Request::post("/rollback")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let plan: RollbackPlan = serde_json::from_slice(&body).unwrap();
+
+        assert!(plan.executed);
+        assert_eq!(plan.count(RollbackOutcome::Applied), 1);
+        assert_eq!(std::fs::read_to_string(&file_path).unwrap(), "before");
+    }
+
+    #[tokio::test]
+    async fn test_rollback_refuses_other_sessions() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("file.txt");
+        std::fs::write(&file_path, "after").unwrap();
+
+        let storage = Storage::in_memory().unwrap();
+        let old = Session::new(temp_dir.path().to_path_buf());
+        storage.create_session(&old).unwrap();
+        let change = Change::new(ChangeType::Modify, "file.txt".into(), old.id)
+            .with_content_before(b"before".to_vec())
+            .with_content_after(b"after".to_vec());
+        storage.create_change(&change).unwrap();
+        let commit = Commit::new(
+            "Edit".to_string(),
+            "test-agent".to_string(),
+            vec![change.id],
+            old.id,
+        );
+        storage.create_commit(&commit).unwrap();
+        storage
+            .start_session(&Session::new(PathBuf::from("/elsewhere")))
+            .unwrap();
+
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+        let body = serde_json::json!({
+            "commit_id": commit.id.to_string(),
+            "execute": true,
+        });
+        let response = router
+            .oneshot(
+                Request::post("/rollback")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(std::fs::read_to_string(&file_path).unwrap(), "after");
+    }
+
+    #[tokio::test]
+    async fn test_revert_change_refuses_other_sessions() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("file.txt");
+        std::fs::write(&file_path, "after").unwrap();
+
+        let storage = Storage::in_memory().unwrap();
+        let old = Session::new(temp_dir.path().to_path_buf());
+        storage.create_session(&old).unwrap();
+        let change = Change::new(ChangeType::Modify, "file.txt".into(), old.id)
+            .with_content_before(b"before".to_vec())
+            .with_content_after(b"after".to_vec());
+        storage.create_change(&change).unwrap();
+        let commit = Commit::new(
+            "Edit".to_string(),
+            "test-agent".to_string(),
+            vec![change.id],
+            old.id,
+        );
+        storage.create_commit(&commit).unwrap();
+        storage
+            .start_session(&Session::new(PathBuf::from("/elsewhere")))
+            .unwrap();
+
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+        let body = serde_json::json!({"execute": true});
+        let response = router
+            .oneshot(
+                Request::post(format!("/changes/{}/revert", change.id))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(std::fs::read_to_string(&file_path).unwrap(), "after");
+    }
+
+    #[tokio::test]
+    async fn test_revert_change() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        storage.create_session(&session).unwrap();
+
+        let mut changes = Vec::new();
+        for path in ["kept.txt", "reverted.txt"] {
+            std::fs::write(temp_dir.path().join(path), "after").unwrap();
+            let change = Change::new(ChangeType::Modify, path.into(), session.id)
+                .with_content_before(b"before".to_vec())
+                .with_content_after(b"after".to_vec());
+            storage.create_change(&change).unwrap();
+            changes.push(change);
+        }
+        let commit = Commit::new(
+            "Edit".to_string(),
+            "test-agent".to_string(),
+            changes.iter().map(|c| c.id).collect(),
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+        let uncommitted = Change::new(ChangeType::Create, "new.txt".into(), session.id)
+            .with_content_after(b"new".to_vec());
+        storage.create_change(&uncommitted).unwrap();
+
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+        let revert = |id: Uuid, body: &'static str| {
+            router.clone().oneshot(
+                Request::post(format!("/changes/{}/revert", id))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+        let read = |path: &str| std::fs::read_to_string(temp_dir.path().join(path)).unwrap();
+
+        // Only planned unless asked to execute
+        let response = revert(changes[1].id, "{}").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["commit_id"], commit.id.to_string());
+        assert_eq!(body["executed"], false);
+        assert_eq!(body["steps"][0]["outcome"], "planned");
+        assert_eq!(read("reverted.txt"), "after");
+
+        let response = revert(changes[1].id, r#"{"execute": true}"#).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["steps"][0]["outcome"], "applied");
+        assert_eq!(read("reverted.txt"), "before");
+        assert_eq!(read("kept.txt"), "after");
+
+        let response = revert(uncommitted.id, "{}").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = revert(Uuid::new_v4(), "{}").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_commit_archive() {
+        use std::io::Read;
+
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into()).with_redact_patterns(vec![".env".to_string()]);
+        storage.create_session(&session).unwrap();
+        let changes = [
+            Change::new(ChangeType::Create, "README.md".into(), session.id)
+                .with_content_after(b"# Demo".to_vec()),
+            Change::new(ChangeType::Create, "src/main.rs".into(), session.id)
+                .with_content_after(b"fn main() {}".to_vec()),
+            // Redacted content isn't the file's, so it's left out
+            Change::new(ChangeType::Create, ".env".into(), session.id)
+                .with_content_after(b"TOKEN=secret".to_vec()),
+        ];
+        for change in &changes {
+            storage.create_change(change).unwrap();
+        }
+        let commit = Commit::new(
+            "Add files".to_string(),
+            "agent".to_string(),
+            changes.iter().map(|c| c.id).collect(),
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+        let download = |query: &str| {
+            router.clone().oneshot(
+                Request::get(format!("/commits/{}/archive{}", commit.id, query))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+        let expected = vec![
+            ("README.md".to_string(), b"# Demo".to_vec()),
+            ("src/main.rs".to_string(), b"fn main() {}".to_vec()),
+        ];
+
+        let response = download("").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/gzip");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(&body[..]));
+        let files: Vec<(String, Vec<u8>)> = tar
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().to_string();
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content).unwrap();
+                (path, content)
+            })
+            .collect();
+        assert_eq!(files, expected);
+
+        let response = download("?format=zip").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/zip");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(body)).unwrap();
+        let files: Vec<(String, Vec<u8>)> = (0..zip.len())
+            .map(|i| {
+                let mut file = zip.by_index(i).unwrap();
+                let mut content = Vec::new();
+                file.read_to_end(&mut content).unwrap();
+                (file.name().to_string(), content)
+            })
+            .collect();
+        assert_eq!(files, expected);
+
+        let response = download("?format=rar").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = router
+            .oneshot(
+                Request::get(format!("/commits/{}/archive", Uuid::new_v4()))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_branch_archive_leaves_out_other_branches() {
+        use std::io::Read;
+
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let commit = |path: &str, parent: Option<Uuid>| {
+            let change = Change::new(ChangeType::Create, path.into(), session.id)
+                .with_content_after(path.as_bytes().to_vec());
+            storage.create_change(&change).unwrap();
+            let mut commit = Commit::new(
+                format!("Add {}", path),
+                "agent".to_string(),
+                vec![change.id],
+                session.id,
+            );
+            if let Some(parent) = parent {
+                commit = commit.with_parent(parent);
+            }
+            storage.create_commit(&commit).unwrap();
+            commit.id
+        };
+        let base = commit("base.txt", None);
+        let first = commit("first.txt", Some(base));
+        // Made after `first`, but on a branch of its own
+        let second = commit("second.txt", Some(base));
+
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+        let files = |commit_id: Uuid| {
+            let router = router.clone();
+            async move {
+                let response = router
+                    .oneshot(
+                        Request::get(format!("/commits/{}/archive", commit_id))
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(&body[..]));
+                tar.entries()
+                    .unwrap()
+                    .map(|entry| {
+                        let mut entry = entry.unwrap();
+                        let mut content = String::new();
+                        entry.read_to_string(&mut content).unwrap();
+                        content
+                    })
+                    .collect::<Vec<String>>()
+            }
+        };
+
+        assert_eq!(files(first).await, ["base.txt", "first.txt"]);
+        assert_eq!(files(second).await, ["base.txt", "second.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_start_session() {
+        let storage = Storage::in_memory().unwrap();
+        let old = Session::new("/test".into()).with_redact_patterns(vec![".env".to_string()]);
+        storage.create_session(&old).unwrap();
+        let storage = StorageHandle::spawn(storage);
+        let watcher = Arc::new(ActiveWatcher::new(WatcherOptions {
+            debounce: Duration::from_millis(50),
+            ..Default::default()
+        }));
+        let router = create_router(
+            AppState::new(storage.clone())
+                .with_watcher(Arc::clone(&watcher))
+                .with_admin_token("secret".to_string()),
+        );
+        let start = |root_path: serde_json::Value| {
+            router.clone().oneshot(
+                Request::post("/session")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::AUTHORIZATION, "Bearer secret")
+                    .body(Body::from(
+                        serde_json::json!({"root_path": root_path}).to_string(),
+                    ))
+                    .unwrap(),
+            )
+        };
+
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("file.txt");
+        std::fs::write(&file, "").unwrap();
+
+        // Only an admin can point the watcher somewhere else
+        let response = router
+            .clone()
+            .oneshot(
+                Request::post("/session")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        serde_json::json!({"root_path": temp_dir.path()}).to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        for bad in [file.clone(), temp_dir.path().join("missing")] {
+            let response = start(bad.to_string_lossy().into()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(watcher.session_id(), None);
+
+        let response = start(temp_dir.path().to_string_lossy().into())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let session: Session = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session.root_path, temp_dir.path().canonicalize().unwrap());
+        assert_eq!(session.redact_patterns, vec![".env"]);
+        assert_eq!(watcher.session_id(), Some(session.id));
+
+        let (active, old) = storage
+            .call(move |storage| {
+                (
+                    storage.get_active_session().unwrap(),
+                    storage.get_session(&old.id).unwrap(),
+                )
+            })
+            .await;
+        assert_eq!(active.id, session.id);
+        assert!(!old.active);
+
+        // The new directory is being watched
+        std::fs::write(temp_dir.path().join("new.txt"), "hello").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let changes = storage
+                .call(move |storage| storage.get_uncommitted_changes(&session.id))
+                .await
+                .unwrap();
+            if changes
+                .iter()
+                .any(|c| c.path == std::path::Path::new("new.txt"))
+            {
+                break;
+            }
+            assert!(Instant::now() < deadline, "new.txt was never recorded");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_activity_feed() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let change = Change::new(ChangeType::Create, "a.txt".into(), session.id);
+        storage.create_change(&change).unwrap();
+        let commit = Commit::new(
+            "Add a".to_string(),
+            "agent".to_string(),
+            vec![change.id],
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+        storage
+            .create_change(&Change::new(ChangeType::Create, "b.txt".into(), session.id))
+            .unwrap();
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        let response = router
+            .oneshot(
+                Request::get("/activity?limit=2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let feed: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        let kinds: Vec<&str> = feed.iter().map(|e| e["kind"].as_str().unwrap()).collect();
+        assert_eq!(kinds, ["change", "commit"]);
+        assert_eq!(feed[1]["id"], commit.id.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_file_versions() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let changes = [
+            Change::new(ChangeType::Create, "src/a.txt".into(), session.id)
+                .with_content_after(b"one".to_vec()),
+            Change::new(ChangeType::Modify, "src/a.txt".into(), session.id)
+                .with_content_after(b"two".to_vec()),
+            Change::new(ChangeType::Modify, "src/a.txt".into(), session.id)
+                .with_content_after(b"three".to_vec()),
+        ];
+        for change in &changes {
+            storage.create_change(change).unwrap();
+        }
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        let get = |uri: &'static str| {
+            let router = router.clone();
+            async move {
+                let response = router
+                    .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, body)
+            }
+        };
+
+        let (status, body) = get("/files/src/a.txt/versions?content=true").await;
+        assert_eq!(status, StatusCode::OK);
+        let versions: Vec<FileVersion> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            versions
+                .iter()
+                .map(|v| (v.change_id, v.commit_id, v.content.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (changes[0].id, None, Some(b"one".to_vec())),
+                (changes[1].id, None, Some(b"two".to_vec())),
+                (changes[2].id, None, Some(b"three".to_vec())),
+            ]
+        );
+
+        // Metadata only unless content is asked for
+        let (_, body) = get("/files/src/a.txt/versions").await;
+        let versions: Vec<FileVersion> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(versions.len(), 3);
+        assert!(versions.iter().all(|v| v.content.is_none()));
+
+        let (status, _) = get("/files/src/a.txt").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_changes_tail_is_capped() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        storage
+            .batch(|storage| {
+                for i in 0..=MAX_TAIL {
+                    let change =
+                        Change::new(ChangeType::Create, format!("{}.txt", i).into(), session.id);
+                    storage.create_change(&change)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        let response = router
+            .oneshot(
+                Request::get(format!("/changes/tail?n={}", MAX_TAIL + 1))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let changes: Vec<Change> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(changes.len(), MAX_TAIL);
+        assert_eq!(changes[0].path, PathBuf::from(format!("{}.txt", MAX_TAIL)));
+    }
+
+    #[tokio::test]
+    async fn test_preview_diff() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        for content in ["a\nb\n", "a\nb\nc\n"] {
+            let change = Change::new(ChangeType::Modify, "notes.txt".into(), session.id)
+                .with_content_after(content.as_bytes().to_vec());
+            storage.create_change(&change).unwrap();
+        }
+        let storage = StorageHandle::spawn(storage);
+        let router = create_router(AppState::new(storage.clone()));
+
+        let body = serde_json::json!({"path": "notes.txt", "content": "a\nC\nd\n"});
+        let response = router
+            .oneshot(
+                Request::post("/changes/preview-diff")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["diff_lines"][0]["line_type"], "context");
+        let preview: diff::FileDiff = serde_json::from_value(json).unwrap();
+
+        assert_eq!(preview.old_content.as_deref(), Some("a\nb\nc\n"));
+        let count = |line_type| {
+            preview
+                .diff_lines
+                .iter()
+                .filter(|l| l.line_type == line_type)
+                .count()
+        };
+        assert_eq!(count(diff::DiffLineType::Addition), 2);
+        assert_eq!(count(diff::DiffLineType::Deletion), 2);
+        assert_eq!(count(diff::DiffLineType::Context), 1);
+
+        // Nothing was recorded
+        let changes = storage
+            .call(move |storage| storage.get_uncommitted_changes(&session.id))
+            .await
+            .unwrap();
+        assert_eq!(changes.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_commit_range() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let mut commits: Vec<Commit> = Vec::new();
+        for message in ["v1", "middle", "v2"] {
+            let mut commit = Commit::new(message.into(), "agent".into(), vec![], session.id);
+            if let Some(parent) = commits.last() {
+                commit = commit.with_parent(parent.id);
+            }
+            storage.create_commit(&commit).unwrap();
+            commits.push(commit);
+        }
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        let get = |uri: String| {
+            let router = router.clone();
+            async move {
+                let response = router
+                    .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, body)
+            }
+        };
+
+        let short = |commit: &Commit| commit.id.to_string()[..8].to_string();
+        let (status, body) = get(format!(
+            "/commits/range?from={}&to={}",
+            short(&commits[0]),
+            commits[2].id
+        ))
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let range: Vec<Commit> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            range.iter().map(|c| c.message.as_str()).collect::<Vec<_>>(),
+            vec!["middle", "v2"]
+        );
+
+        // Backwards, `from` isn't an ancestor of `to`
+        let (status, _) = get(format!(
+            "/commits/range?from={}&to={}",
+            short(&commits[2]),
+            short(&commits[0])
+        ))
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_after_activity() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+
+        let router = create_router(
+            AppState::new(StorageHandle::spawn(storage)).with_metrics(Arc::new(Metrics::new())),
+        );
+
+        let body = serde_json::json!({
+            "change_type": "create",
+            "path": "file.txt",
+            "content_after": "hello",
+        });
+        let response = router
+            .clone()
+            .oneshot(
+                Request::post("/changes")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = router
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(body.contains("gitent_changes_recorded_total 1\n"));
+        assert!(body.contains("gitent_commits_created_total 0\n"));
+        assert!(body.contains("gitent_uncommitted_changes 1\n"));
+        assert!(
+            body.contains("gitent_http_requests_total{endpoint=\"/changes\",status=\"200\"} 1\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_uncommitted_diff() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let change = Change::new(ChangeType::Modify, "file.txt".into(), session.id)
+            .with_content_before(b"one\ntwo\nthree\n".to_vec())
+            .with_content_after(b"one\n2\nthree\n".to_vec());
+        storage.create_change(&change).unwrap();
+
+        let response = create_router(AppState::new(StorageHandle::spawn(storage)))
+            .oneshot(
+                Request::get("/changes/diff?format=unified&context=1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            "--- file.txt\n+++ file.txt\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_uncommitted_diff_as_json_patch() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let changes = [
+            Change::new(ChangeType::Modify, "config.json".into(), session.id)
+                .with_content_before(br#"{"port": 80, "debug": true}"#.to_vec())
+                .with_content_after(br#"{"port": 8080}"#.to_vec()),
+            Change::new(ChangeType::Modify, "notes.txt".into(), session.id)
+                .with_content_before(b"one\n".to_vec())
+                .with_content_after(b"two\n".to_vec()),
+        ];
+        for change in &changes {
+            storage.create_change(change).unwrap();
+        }
+
+        let response = create_router(AppState::new(StorageHandle::spawn(storage)))
+            .oneshot(
+                Request::get("/changes/diff?format=json-patch")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let patches: Vec<diff::semantic::FilePatch> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(
+            patches[0].patch,
+            Some(serde_json::json!([
+                {"op": "remove", "path": "/debug"},
+                {"op": "replace", "path": "/port", "value": 8080},
+            ]))
+        );
+        assert!(patches[1].patch.is_none());
+        assert!(patches[1]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("isn't a JSON file"));
+    }
+
+    #[tokio::test]
+    async fn test_commit_diff_as_json_patch() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let changes = [
+            Change::new(ChangeType::Modify, "config.json".into(), session.id)
+                .with_content_before(br#"{"port": 80}"#.to_vec())
+                .with_content_after(br#"{"port": 8080}"#.to_vec()),
+            Change::new(ChangeType::Create, "new.json".into(), session.id)
+                .with_content_after(br#"{"name": "app"}"#.to_vec()),
+            Change::new(ChangeType::Delete, "old.json".into(), session.id)
+                .with_content_before(b"[]".to_vec()),
+        ];
+        for change in &changes {
+            storage.create_change(change).unwrap();
+        }
+        let commit = Commit::new(
+            "Edit config".to_string(),
+            "test-agent".to_string(),
+            changes.iter().map(|change| change.id).collect(),
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+
+        let response = create_router(AppState::new(StorageHandle::spawn(storage)))
+            .oneshot(
+                Request::get(format!("/commits/{}/diff?format=json-patch", commit.id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let patches: Vec<diff::semantic::FilePatch> = serde_json::from_slice(&body).unwrap();
+        let patches: Vec<(&str, Option<&serde_json::Value>)> = patches
+            .iter()
+            .map(|patch| (patch.path.as_str(), patch.patch.as_ref()))
+            .collect();
+        assert_eq!(
+            patches,
+            [
+                (
+                    "config.json",
+                    Some(&serde_json::json!([
+                        {"op": "replace", "path": "/port", "value": 8080}
+                    ]))
+                ),
+                (
+                    "new.json",
+                    Some(&serde_json::json!([
+                        {"op": "add", "path": "", "value": {"name": "app"}}
+                    ]))
+                ),
+                (
+                    "old.json",
+                    Some(&serde_json::json!([{"op": "remove", "path": ""}]))
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commit_diff_is_cached() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let change = Change::new(ChangeType::Modify, "file.txt".into(), session.id)
+            .with_content_before(b"one\ntwo\nthree\n".to_vec())
+            .with_content_after(b"one\n2\nthree\n".to_vec());
+        storage.create_change(&change).unwrap();
+        let commit = Commit::new(
+            "Edit".to_string(),
+            "test-agent".to_string(),
+            vec![change.id],
+            session.id,
+        );
+        storage.create_commit(&commit).unwrap();
+
+        let storage = StorageHandle::spawn(storage);
+        let state = AppState::new(storage.clone());
+        let cache = Arc::clone(&state.diff_cache);
+        let router = create_router(state);
+        let uri = format!("/commits/{}/diff?context=1", commit.id);
+
+        let mut bodies = Vec::new();
+        for _ in 0..2 {
+            let response = router
+                .clone()
+                .oneshot(Request::get(&uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            bodies.push(to_bytes(response.into_body(), usize::MAX).await.unwrap());
+        }
+
+        assert_eq!(
+            String::from_utf8(bodies[0].to_vec()).unwrap(),
+            "--- file.txt\n+++ file.txt\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n"
+        );
+        assert_eq!(bodies[0], bodies[1]);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+        // Once the commit is reset away, its cached diff isn't served
+        storage
+            .call(move |storage| storage.reset_head(&session.id))
+            .await
+            .unwrap();
+        let response = router
+            .oneshot(Request::get(&uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(cache.get(commit.id, 1).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_storage_timeout() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let change = Change::new(ChangeType::Create, "file.txt".into(), session.id)
+            .with_content_after(b"hello\n".to_vec());
+        storage.create_change(&change).unwrap();
+
+        let handle = StorageHandle::spawn(storage);
+        for (timeout, status) in [
+            (Duration::ZERO, StatusCode::GATEWAY_TIMEOUT),
+            (Duration::from_secs(60), StatusCode::OK),
+        ] {
+            let router = create_router(AppState::new(handle.clone()).with_storage_timeout(timeout));
+            for uri in ["/tree", "/changes/diff"] {
+                let response = router
+                    .clone()
+                    .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), status, "{}", uri);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_audited() {
+        let storage = Storage::in_memory().unwrap();
+        storage
+            .create_session(&Session::new("/test".into()))
+            .unwrap();
+        let handle = StorageHandle::spawn(storage);
+        let router =
+            create_router(AppState::new(handle.clone()).with_admin_token("s3cret".to_string()));
+
+        let post = |uri: &str, body: serde_json::Value| {
+            let request = Request::post(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("x-agent-id", "agent-7")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let router = router.clone();
+            async move { router.oneshot(request).await.unwrap() }
+        };
+
+        let response = post(
+            "/changes",
+            serde_json::json!({
+                "change_type": "create",
+                "path": "file.txt",
+                "content_after": "hello",
+            }),
+        )
+        .await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let change: Change = serde_json::from_slice(&body).unwrap();
+
+        let rejected = post(
+            "/commits",
+            serde_json::json!({
+                "message": "work",
+                "agent_id": "agent-7",
+                "change_ids": [change.id.to_string()],
+                "parent": Uuid::new_v4().to_string(),
+            }),
+        )
+        .await;
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+
+        let response = post(
+            "/commits",
+            serde_json::json!({
+                "message": "work",
+                "agent_id": "agent-7",
+                "change_ids": [change.id.to_string()],
+            }),
+        )
+        .await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let commit: Commit = serde_json::from_slice(&body).unwrap();
+
+        // Reads aren't audited
+        router
+            .clone()
+            .oneshot(Request::get("/changes").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        let entries = handle.call(|s| s.get_audit_log(None)).await.unwrap();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| (e.path.as_str(), e.status, e.target.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("/commits", 200, Some(commit.id.to_string())),
+                ("/commits", 400, None),
+                ("/changes", 200, Some(change.id.to_string())),
+            ]
+        );
+        assert!(entries
+            .iter()
+            .all(|e| e.method == "POST" && e.claimed_agent_id.as_deref() == Some("agent-7")));
+        assert!(entries.iter().all(|e| e.request_id.is_some()));
+
+        let audit = |token: Option<&str>| {
+            let mut request = Request::get("/audit?limit=1");
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let router = router.clone();
+            async move {
+                router
+                    .oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+            }
+        };
+        assert_eq!(audit(None).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            audit(Some("wrong")).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let response = audit(Some("s3cret")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let listed: Vec<AuditEntry> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].status, 200);
+
+        // Admin endpoints are off without a token
+        let router = create_router(AppState::new(handle));
+        let response = router
+            .oneshot(Request::get("/audit").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests() {
+        let storage = Storage::in_memory().unwrap();
+        storage
+            .create_session(&Session::new("/test".into()))
+            .unwrap();
+        let storage = StorageHandle::spawn(storage);
+        let router = create_router(AppState::new(storage.clone()));
+
+        // Hold the storage thread up so the requests queue behind it
+        let slow = tokio::spawn({
+            let storage = storage.clone();
+            async move {
+                storage
+                    .call(|_| std::thread::sleep(Duration::from_millis(200)))
+                    .await
+            }
+        });
+
+        let requests: Vec<_> = (0..20)
+            .map(|i| {
+                let body = serde_json::json!({
+                    "change_type": "create",
+                    "path": format!("file{}.txt", i),
+                    "content_after": "hello",
+                });
+                let router = router.clone();
+                tokio::spawn(async move {
+                    router
+                        .oneshot(
+                            Request::post("/changes")
+                                .header(header::CONTENT_TYPE, "application/json")
+                                .body(Body::from(body.to_string()))
+                                .unwrap(),
+                        )
+                        .await
+                        .unwrap()
+                        .status()
+                })
+            })
+            .collect();
+
+        // The test runtime is single-threaded, so this only wakes on time if
+        // nothing above is blocking it
+        let start = Instant::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(start.elapsed() < Duration::from_millis(150));
+
+        slow.await.unwrap();
+        for request in requests {
+            assert_eq!(request.await.unwrap(), StatusCode::OK);
+        }
+
+        let response = router
+            .oneshot(Request::get("/changes").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let changes: Vec<Change> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(changes.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_commits_fork_from_explicit_parent() {
+        let storage = Storage::in_memory().unwrap();
+        storage
+            .create_session(&Session::new("/test".into()))
+            .unwrap();
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        let commit = |parent: Option<Uuid>| {
+            let mut body = serde_json::json!({
+                "message": "work",
+                "agent_id": "agent",
+                "change_ids": [],
+            });
+            if let Some(parent) = parent {
+                body["parent"] = parent.to_string().into();
+            }
+            let router = router.clone();
+            async move {
+                let response = router
+                    .oneshot(
+                        Request::post("/commits")
+                            .header(header::CONTENT_TYPE, "application/json")
+                            .body(Body::from(body.to_string()))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<Commit>(&body).ok())
+            }
+        };
+
+        let (_, base) = commit(None).await;
+        let base = base.unwrap();
+        assert_eq!(base.parent, None);
+
+        // Without a parent, commits build on the latest one
+        let (_, next) = commit(None).await;
+        assert_eq!(next.unwrap().parent, Some(base.id));
+
+        // Two commits forked from the same point
+        let (_, left) = commit(Some(base.id)).await;
+        let (_, right) = commit(Some(base.id)).await;
+        let (left, right) = (left.unwrap(), right.unwrap());
+        assert_ne!(left.id, right.id);
+        assert_eq!(left.parent, Some(base.id));
+        assert_eq!(right.parent, Some(base.id));
+
+        let (status, _) = commit(Some(Uuid::new_v4())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_is_rejected() {
+        let storage = Storage::in_memory().unwrap();
+        storage
+            .create_session(&Session::new("/test".into()))
+            .unwrap();
+        let router =
+            create_router(AppState::new(StorageHandle::spawn(storage)).with_max_body_bytes(1024));
+
+        let post = |uri: &'static str, content_type: &'static str, body: Vec<u8>| {
+            let router = router.clone();
+            async move {
+                router
+                    .oneshot(
+                        Request::post(uri)
+                            .header(header::CONTENT_TYPE, content_type)
+                            .body(Body::from(body))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+        let json = |content: String| {
+            serde_json::json!({
+                "change_type": "create",
+                "path": "a.txt",
+                "content_after": content,
+            })
+            .to_string()
+            .into_bytes()
+        };
+
+        let status = post("/changes", "application/json", json("x".repeat(4096))).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let status = post(
+            "/changes/raw?change_type=create&path=a.bin",
+            "application/octet-stream",
+            vec![0; 4096],
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let status = post("/changes", "application/json", json("small".to_string())).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit_is_shared_across_routes() {
+        let storage = Storage::in_memory().unwrap();
+        storage
+            .create_session(&Session::new("/test".into()))
+            .unwrap();
+        let storage = StorageHandle::spawn(storage);
+        let router = create_router(AppState::new(storage.clone()).with_max_concurrency(2));
+
+        // Hold the storage thread up so the first requests stay in flight
+        let slow = tokio::spawn({
+            let storage = storage.clone();
+            async move {
+                storage
+                    .call(|_| std::thread::sleep(Duration::from_millis(200)))
+                    .await
+            }
+        });
+
+        // Half to each of two routes, which would each admit two requests if
+        // they had a limit of their own
+        let requests: Vec<_> = (0..20)
+            .map(|i| {
+                let uri = if i % 2 == 0 { "/changes" } else { "/commits" };
+                let router = router.clone();
+                tokio::spawn(async move {
+                    router
+                        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                        .await
+                        .unwrap()
+                        .status()
+                })
+            })
+            .collect();
+
+        slow.await.unwrap();
+        let mut accepted = 0;
+        for request in requests {
+            match request.await.unwrap() {
+                StatusCode::OK => accepted += 1,
+                StatusCode::SERVICE_UNAVAILABLE => {}
+                status => panic!("unexpected status {}", status),
+            }
+        }
+        assert!((1..=2).contains(&accepted), "{} accepted", accepted);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit_sheds_load() {
+        let storage = Storage::in_memory().unwrap();
+        storage
+            .create_session(&Session::new("/test".into()))
+            .unwrap();
+        let storage = StorageHandle::spawn(storage);
+        let router = create_router(AppState::new(storage.clone()).with_max_concurrency(2));
+
+        // Hold the storage thread up so the first requests stay in flight
+        let slow = tokio::spawn({
+            let storage = storage.clone();
+            async move {
+                storage
+                    .call(|_| std::thread::sleep(Duration::from_millis(200)))
+                    .await
+            }
+        });
+
+        let requests: Vec<_> = (0..20)
+            .map(|i| {
+                let body = serde_json::json!({
+                    "change_type": "create",
+                    "path": format!("file{}.txt", i),
+                    "content_after": "hello",
+                });
+                let router = router.clone();
+                tokio::spawn(async move {
+                    router
+                        .oneshot(
+                            Request::post("/changes")
+                                .header(header::CONTENT_TYPE, "application/json")
+                                .body(Body::from(body.to_string()))
+                                .unwrap(),
+                        )
+                        .await
+                        .unwrap()
+                        .status()
+                })
+            })
+            .collect();
+
+        // Health checks aren't subject to the limit
+        let health = router
+            .clone()
+            .oneshot(Request::get("/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(health.status(), StatusCode::OK);
+
+        slow.await.unwrap();
+        let mut accepted = 0;
+        let mut shed = 0;
+        for request in requests {
+            match request.await.unwrap() {
+                StatusCode::OK => accepted += 1,
+                StatusCode::SERVICE_UNAVAILABLE => shed += 1,
+                status => panic!("unexpected status {}", status),
+            }
+        }
+        assert!((1..=2).contains(&accepted));
+        assert_eq!(accepted + shed, 20);
+
+        // Only the accepted requests were recorded
+        let response = router
+            .oneshot(Request::get("/changes").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let changes: Vec<Change> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(changes.len(), accepted);
+    }
+
+    #[tokio::test]
+    async fn test_changes_cursor() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        for i in 0..5 {
+            let change = Change::new(
+                ChangeType::Create,
+                format!("file{}.txt", i).into(),
+                session.id,
+            );
+            storage.create_change(&change).unwrap();
+        }
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        let mut after_seq = 0;
+        let mut pages = Vec::new();
+        loop {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::get(format!("/changes?after_seq={}&limit=2", after_seq))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let page: Vec<Change> = serde_json::from_slice(&body).unwrap();
+            let Some(last) = page.last() else {
+                break;
+            };
+            after_seq = last.seq.unwrap();
+            pages.push(page.iter().map(|c| c.path.clone()).collect::<Vec<_>>());
+        }
+
+        assert_eq!(pages.len(), 3);
+        assert_eq!(
+            pages.concat(),
+            (0..5)
+                .map(|i| std::path::PathBuf::from(format!("file{}.txt", i)))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_change_dry_run() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let state = AppState::new(StorageHandle::spawn(storage)).with_max_file_size(8);
+        let router = create_router(state.clone());
+
+        let preview = |body: serde_json::Value| {
+            let router = router.clone();
+            async move {
+                let response = router
+                    .oneshot(
+                        Request::post("/changes?dry_run=true")
+                            .header(header::CONTENT_TYPE, "application/json")
+                            .body(Body::from(body.to_string()))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<ChangePreview>(&body).unwrap()
+            }
+        };
+
+        let ignored = preview(serde_json::json!({
+            "change_type": "create",
+            "path": "target/debug/app",
+            "content_after": "binary",
+        }))
+        .await;
+        assert_eq!(
+            ignored.skipped.as_deref(),
+            Some("matches an ignore pattern")
+        );
+
+        let tracked = preview(serde_json::json!({
+            "change_type": "modify",
+            "path": "/test/src/./main.rs",
+            "content_before": "fn main() {}",
+            "content_after": "fn main() { run() }",
+        }))
+        .await;
+        assert_eq!(tracked.skipped, None);
+        assert_eq!(tracked.change.path, std::path::PathBuf::from("src/main.rs"));
+        let expected = Change::new(ChangeType::Modify, "src/main.rs".into(), session.id)
+            .with_content_after(b"fn main() { run() }".to_vec());
+        assert_eq!(
+            tracked.change.content_hash_after,
+            expected.content_hash_after
+        );
+        assert!(!tracked.binary);
+        assert!(tracked.too_large);
+
+        // Nothing was recorded
+        let changes = state
+            .storage
+            .call(move |storage| storage.get_uncommitted_changes(&session.id))
+            .await
+            .unwrap();
+        assert!(changes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_changes_filters() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        for (change_type, path, agent) in [
+            (ChangeType::Create, "src/a.rs", "alice"),
+            (ChangeType::Delete, "src/b.rs", "alice"),
+            (ChangeType::Create, "src/c.rs", "bob"),
+            (ChangeType::Create, "docs/d.md", "alice"),
+        ] {
+            let change =
+                Change::new(change_type, path.into(), session.id).with_agent_id(agent.to_string());
+            storage.create_change(&change).unwrap();
+        }
+        let router = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        let paths = |uri: &'static str| {
+            let router = router.clone();
+            async move {
+                let response = router
+                    .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let changes: Vec<Change> = serde_json::from_slice(&body).unwrap();
+                changes
+                    .into_iter()
+                    .map(|c| c.path.to_string_lossy().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(
+            paths("/changes?path=src&agent=alice&order=asc").await,
+            vec!["src/a.rs", "src/b.rs"]
+        );
+        assert_eq!(
+            paths("/changes?change_type=create&agent=alice").await,
+            vec!["docs/d.md", "src/a.rs"]
+        );
+        assert!(paths("/changes?committed=true").await.is_empty());
+
+        let response = router
+            .oneshot(
+                Request::get("/changes?change_type=copy")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_paused_capture() {
+        let storage = StorageHandle::spawn(Storage::in_memory().unwrap());
+        let capture = Arc::new(CaptureStatus::new());
+        let app = create_router(AppState::new(storage).with_capture(Arc::clone(&capture)));
+
+        let health = |app: Router| async move {
+            let response = app
+                .oneshot(Request::get("/health").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        assert_eq!(health(app.clone()).await["status"], "ok");
+
+        capture.pause("database or disk is full".to_string());
+        capture.record_dropped_events(3);
+        let body = health(app.clone()).await;
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["reason"], "database or disk is full");
+        assert_eq!(body["dropped_events"], 3);
+        assert!(body.get("catch_up").is_none());
+
+        capture.set_catch_up_progress(Some(Progress {
+            done: 1000,
+            total: 2500,
+        }));
+        let body = health(app).await;
+        assert_eq!(body["catch_up"]["done"], 1000);
+        assert_eq!(body["catch_up"]["total"], 2500);
+    }
+
+    #[tokio::test]
+    async fn test_large_responses_are_compressed() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        for i in 0..50 {
+            let change = Change::new(
+                ChangeType::Create,
+                format!("file{}.txt", i).into(),
+                session.id,
+            )
+            .with_content_after(b"content".to_vec());
+            storage.create_change(&change).unwrap();
+        }
+        let app = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        let get = |app: Router, uri: &'static str| async move {
+            app.oneshot(
+                Request::get(uri)
+                    .header(header::ACCEPT_ENCODING, "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+        };
+
+        let response = get(app.clone(), "/changes").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let response = get(app, "/health").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_inconsistent_changes_are_rejected() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let app = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        let post = |app: Router, body: serde_json::Value| async move {
+            app.oneshot(
+                Request::post("/changes")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+            .status()
+        };
+
+        let rename = serde_json::json!({ "change_type": "rename", "path": "new.txt" });
+        assert_eq!(post(app.clone(), rename).await, StatusCode::BAD_REQUEST);
+
+        let rename = serde_json::json!({
+            "change_type": "rename",
+            "path": "new.txt",
+            "old_path": "old.txt",
+        });
+        assert_eq!(post(app, rename).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_raw_content_round_trip() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let app = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        // Several MB, well over the default body limit for JSON
+        let content: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/changes/raw?change_type=create&path=data.bin")
+                    .header(header::CONTENT_TYPE, "application/octet-stream")
+                    .body(Body::from(content.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let change: Change = serde_json::from_slice(&body).unwrap();
+
+        let download = |app: Router| async move {
+            let response = app
+                .oneshot(
+                    Request::get(format!("/changes/{}/content", change.id))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            to_bytes(response.into_body(), usize::MAX).await.unwrap()
+        };
+        assert_eq!(download(app.clone()).await, content);
+
+        let replaced: Vec<u8> = content.iter().rev().copied().collect();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post(format!("/changes/{}/content", change.id))
+                    .body(Body::from(replaced.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(download(app.clone()).await, replaced);
+
+        let response = app
+            .oneshot(
+                Request::get(format!("/changes/{}/content?side=before", change.id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_raw_content_streamed_from_object_store() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path().join("gitent.db"))
+            .unwrap()
+            .with_external_store(1024);
+        storage
+            .create_session(&Session::new("/test".into()))
+            .unwrap();
+        let app = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        let content: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        // Sent in chunks, without a Content-Length
+        let chunks = ReaderStream::with_capacity(std::io::Cursor::new(content.clone()), 4096);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/changes/raw?change_type=create&path=data.bin")
+                    .body(Body::from_stream(chunks))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let change: Change = serde_json::from_slice(&body).unwrap();
+        let objects =
+            gitent_core::objects::ObjectStore::for_database(&temp_dir.path().join("gitent.db"));
+        assert_eq!(objects.list().unwrap().len(), 1);
+
+        let response = app
+            .oneshot(
+                Request::get(format!("/changes/{}/content", change.id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, content);
+    }
+
+    #[tokio::test]
+    async fn test_msgpack_round_trip() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let app = create_router(AppState::new(StorageHandle::spawn(storage)));
+
+        let post = |uri: &str, body: serde_json::Value| {
+            Request::post(uri)
+                .header(header::CONTENT_TYPE, MSGPACK)
+                .header(header::ACCEPT, MSGPACK)
+                .body(Body::from(to_msgpack(&body).unwrap()))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(post(
+                "/changes",
+                serde_json::json!({
+                    "change_type": "create",
+                    "path": "main.rs",
+                    "content_after": "fn main() {}",
+                    "agent_id": "test-agent",
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], MSGPACK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let change: Change = from_msgpack(&body).unwrap();
+        assert_eq!(change.content_after.as_deref(), Some(&b"fn main() {}"[..]));
+
+        let response = app
+            .clone()
+            .oneshot(post(
+                "/commits",
+                serde_json::json!({
+                    "message": "Add main",
+                    "agent_id": "test-agent",
+                    "change_ids": [change.id],
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/commits")
+                    .header(header::ACCEPT, MSGPACK)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], MSGPACK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let commits: Vec<CommitInfo> = from_msgpack(&body).unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].commit.message, "Add main");
+        assert_eq!(commits[0].commit.changes, vec![change.id]);
+
+        // Without an Accept header the same data comes back as JSON
+        let response = app
+            .oneshot(Request::get("/commits").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let commits: Vec<CommitInfo> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(commits[0].commit.message, "Add main");
+    }
+
+    #[tokio::test]
+    async fn test_metrics_disabled_by_default() {
+        let storage = StorageHandle::spawn(Storage::in_memory().unwrap());
+        let response = create_router(AppState::new(storage))
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    fn cors_router(cors: CorsPolicy) -> Router {
+        let storage = StorageHandle::spawn(Storage::in_memory().unwrap());
+        create_router(AppState::new(storage).with_cors(cors))
+    }
+
+    #[tokio::test]
+    async fn test_cors_for_configured_origin() {
+        let origin = "http://dashboard.example";
+        let cors = CorsPolicy::from_origins(&[origin.to_string()]).unwrap();
+
+        let response = cors_router(cors.clone())
+            .oneshot(
+                Request::get("/health")
+                    .header(header::ORIGIN, origin)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            response
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            origin
+        );
+
+        let preflight = cors_router(cors)
+            .oneshot(
+                Request::options("/changes")
+                    .header(header::ORIGIN, origin)
+                    .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                    .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(preflight.status().is_success());
+        assert_eq!(
+            preflight
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            origin
+        );
+        assert!(preflight
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+    }
+
+    #[tokio::test]
+    async fn test_cors_disabled_by_default() {
+        let response = cors_router(CorsPolicy::default())
+            .oneshot(
+                Request::get("/health")
+                    .header(header::ORIGIN, "http://dashboard.example")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
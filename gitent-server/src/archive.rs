@@ -0,0 +1,131 @@
+//! Packing the files at a commit into an archive for `GET /commits/:id/archive`.
+
+use axum::body::Body;
+use chrono::{Datelike, Timelike};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use gitent_core::Change;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::{Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use tokio_util::io::{ReaderStream, SyncIoBridge};
+use tracing::error;
+use zip::write::SimpleFileOptions;
+use zip::{DateTime, ZipWriter};
+
+/// Bytes of archive buffered between the task writing it and the response.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Archive formats a commit can be downloaded in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    /// A gzipped tarball
+    #[default]
+    #[serde(rename = "tar.gz", alias = "tgz")]
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+}
+
+/// Pack `tree`, as returned by `Storage::tree_at_commit`, into an archive,
+/// streamed out as a response body while it's written. Must be called from
+/// within the runtime, as the archive is written on a blocking thread.
+pub fn body(tree: BTreeMap<PathBuf, Change>, format: ArchiveFormat) -> Body {
+    let (reader, writer) = tokio::io::duplex(PIPE_CAPACITY);
+    tokio::task::spawn_blocking(move || {
+        let mut out = SyncIoBridge::new(writer);
+        let written = match format {
+            ArchiveFormat::TarGz => write_tar_gz(&tree, &mut out),
+            // Zip entries are patched once written, so it can't go straight
+            // to a stream; a temporary file keeps it out of memory at least
+            ArchiveFormat::Zip => tempfile::tempfile().and_then(|mut file| {
+                write_zip(&tree, &mut file)?;
+                file.rewind()?;
+                std::io::copy(&mut file, &mut out).map(drop)
+            }),
+        };
+        // The response has started by now, so all that's left is to cut it
+        // short, which leaves the client with an archive it can't open
+        if let Err(e) = written.and_then(|_| out.shutdown()) {
+            error!("Failed to write archive: {}", e);
+        }
+    });
+    Body::from_stream(ReaderStream::new(reader))
+}
+
+/// The files to put in an archive of `tree`.
+///
+/// Files are stored under their paths relative to the content root, with the
+/// time of the change that last wrote them. Files whose content wasn't
+/// recorded or was redacted, and paths outside the content root, are left out.
+fn files(tree: &BTreeMap<PathBuf, Change>) -> impl Iterator<Item = (&PathBuf, &Change, &[u8])> {
+    tree.iter().filter_map(|(path, change)| {
+        let content = change.content_after.as_deref()?;
+        (is_relative(path) && !change.is_redacted()).then_some((path, change, content))
+    })
+}
+
+fn write_tar_gz(tree: &BTreeMap<PathBuf, Change>, out: impl Write) -> std::io::Result<()> {
+    let mut tar = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+    for (path, change, content) in files(tree) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(change.timestamp.timestamp().max(0) as u64);
+        tar.append_data(&mut header, path, content)?;
+    }
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+fn write_zip(tree: &BTreeMap<PathBuf, Change>, out: impl Write + Seek) -> std::io::Result<()> {
+    let mut zip = ZipWriter::new(out);
+    for (path, change, content) in files(tree) {
+        let mut options = SimpleFileOptions::default().unix_permissions(0o644);
+        let time = change.timestamp;
+        // Zip times only cover 1980 to 2107
+        if let Ok(mtime) = DateTime::from_date_and_time(
+            u16::try_from(time.year()).unwrap_or(0),
+            time.month() as u8,
+            time.day() as u8,
+            time.hour() as u8,
+            time.minute() as u8,
+            time.second() as u8,
+        ) {
+            options = options.last_modified_time(mtime);
+        }
+        // Zip entries always use forward slashes
+        let name = path
+            .components()
+            .map(|part| part.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        zip.start_file(name, options)?;
+        zip.write_all(content)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Whether `path` stays inside the directory it's relative to.
+fn is_relative(path: &Path) -> bool {
+    path.components()
+        .all(|part| matches!(part, Component::Normal(_) | Component::CurDir))
+}
@@ -0,0 +1,132 @@
+//! An audit log of writes made through the API.
+//!
+//! Every request that isn't a plain read is recorded once it has been
+//! answered, whether it succeeded or not, so operators of a shared server can
+//! review what each agent did.
+
+use crate::middleware::RequestId;
+use crate::store::StorageHandle;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use gitent_core::AuditEntry;
+use std::net::SocketAddr;
+use tracing::warn;
+
+/// Header naming the agent making a request.
+pub const AGENT_ID_HEADER: &str = "x-agent-id";
+
+/// What a write acted on, attached to its response for the audit log.
+#[derive(Debug, Clone)]
+pub struct AuditTarget {
+    /// The change or commit written
+    pub id: String,
+    /// The agent the write was made for, when the request said so
+    pub agent_id: Option<String>,
+}
+
+impl AuditTarget {
+    pub fn new(id: impl ToString) -> Self {
+        Self {
+            id: id.to_string(),
+            agent_id: None,
+        }
+    }
+
+    pub fn with_agent_id(mut self, agent_id: Option<String>) -> Self {
+        self.agent_id = agent_id;
+        self
+    }
+}
+
+/// Record every request that isn't a read in the audit log.
+///
+/// The agent comes from the `x-agent-id` header, falling back to the one the
+/// handler wrote for. Either way it's only what the client claimed, as
+/// recorded in [`AuditEntry::claimed_agent_id`]. Failing to record is logged
+/// but doesn't fail the request.
+pub async fn audit_writes(
+    State(storage): State<StorageHandle>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let agent_id = req
+        .headers()
+        .get(AGENT_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string);
+    let source_ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|RequestId(id)| id.clone());
+
+    let response = next.run(req).await;
+
+    let target = response.extensions().get::<AuditTarget>().cloned();
+    let status = response.status().as_u16();
+    if let Err(e) = storage
+        .call(move |storage| {
+            storage.record_audit(&AuditEntry {
+                timestamp: storage.now(),
+                method,
+                path,
+                status,
+                claimed_agent_id: agent_id
+                    .or_else(|| target.as_ref().and_then(|t| t.agent_id.clone())),
+                target: target.map(|t| t.id),
+                source_ip,
+                request_id,
+            })
+        })
+        .await
+    {
+        warn!("Failed to record audit entry: {}", e);
+    }
+
+    response
+}
+
+/// Check the request carries `Authorization: Bearer <token>` for the admin
+/// token. Without an admin token configured, nobody is allowed.
+pub fn require_admin(
+    headers: &HeaderMap,
+    admin_token: Option<&str>,
+) -> Result<(), (StatusCode, String)> {
+    let Some(admin_token) = admin_token else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Set admin_token to enable admin endpoints".to_string(),
+        ));
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) if constant_time_eq(given.as_bytes(), admin_token.as_bytes()) => Ok(()),
+        _ => Err((
+            StatusCode::UNAUTHORIZED,
+            "Missing or wrong admin token".to_string(),
+        )),
+    }
+}
+
+/// Compare without bailing out at the first difference, so response times
+/// don't reveal how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
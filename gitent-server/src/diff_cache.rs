@@ -0,0 +1,143 @@
+//! Memoized commit diffs for `GET /commits/:id/diff`.
+
+use gitent_core::config::DEFAULT_DIFF_CACHE_SIZE;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// A commit and the lines of context its diff was rendered with.
+type Key = (Uuid, usize);
+
+/// The most recently used commit diffs, rendered as unified patches.
+///
+/// Committed changes can't be edited, but reset, prune and gc can remove a
+/// commit altogether, so its entries are evicted when that happens.
+#[derive(Debug)]
+pub struct DiffCache {
+    /// `None` when caching is disabled
+    entries: Option<Mutex<LruCache<Key, Arc<str>>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl DiffCache {
+    /// A cache holding up to `capacity` diffs; 0 disables caching.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: NonZeroUsize::new(capacity)
+                .map(|capacity| Mutex::new(LruCache::new(capacity))),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The cached diff of `commit_id` with `context` lines, if any.
+    pub fn get(&self, commit_id: Uuid, context: usize) -> Option<Arc<str>> {
+        let patch = self
+            .entries
+            .as_ref()
+            .and_then(|entries| entries.lock().unwrap().get(&(commit_id, context)).cloned());
+        let counter = if patch.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        patch
+    }
+
+    pub fn insert(&self, commit_id: Uuid, context: usize, patch: Arc<str>) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().put((commit_id, context), patch);
+        }
+    }
+
+    /// Drop every diff of `commit_id`, whatever its context.
+    pub fn evict(&self, commit_id: Uuid) {
+        if let Some(entries) = &self.entries {
+            let mut entries = entries.lock().unwrap();
+            let keys: Vec<Key> = entries
+                .iter()
+                .map(|(key, _)| *key)
+                .filter(|(id, _)| *id == commit_id)
+                .collect();
+            for key in keys {
+                entries.pop(&key);
+            }
+        }
+    }
+
+    /// Drop every cached diff.
+    pub fn clear(&self) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().clear();
+        }
+    }
+
+    /// Lookups answered from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Lookups that had to compute the diff.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for DiffCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_DIFF_CACHE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = DiffCache::new(2);
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        cache.insert(a, 3, Arc::from("a"));
+        cache.insert(b, 3, Arc::from("b"));
+        // Touch `a` so `b` is the one evicted
+        assert!(cache.get(a, 3).is_some());
+        cache.insert(c, 3, Arc::from("c"));
+
+        assert!(cache.get(b, 3).is_none());
+        assert_eq!(cache.get(a, 3).as_deref(), Some("a"));
+        assert_eq!(cache.get(c, 3).as_deref(), Some("c"));
+        // Different context is a different diff
+        assert!(cache.get(a, 1).is_none());
+        assert_eq!((cache.hits(), cache.misses()), (3, 2));
+    }
+
+    #[test]
+    fn test_evict_drops_every_context() {
+        let cache = DiffCache::new(4);
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        cache.insert(a, 3, Arc::from("a3"));
+        cache.insert(a, 1, Arc::from("a1"));
+        cache.insert(b, 3, Arc::from("b"));
+
+        cache.evict(a);
+        assert!(cache.get(a, 3).is_none());
+        assert!(cache.get(a, 1).is_none());
+        assert!(cache.get(b, 3).is_some());
+
+        cache.clear();
+        assert!(cache.get(b, 3).is_none());
+    }
+
+    #[test]
+    fn test_zero_capacity_disables_caching() {
+        let cache = DiffCache::new(0);
+        let id = Uuid::new_v4();
+        cache.insert(id, 3, Arc::from("patch"));
+
+        assert!(cache.get(id, 3).is_none());
+    }
+}
@@ -0,0 +1,134 @@
+//! Whether the watcher is capturing changes, reported at `GET /health`.
+
+use gitent_core::Progress;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long capture pauses after the first failed write.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest capture pauses between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Shared between the watcher, which pauses capture when storage is full and
+/// counts events it had to drop, and the API, which reports both.
+#[derive(Debug, Default)]
+pub struct CaptureStatus {
+    state: Mutex<State>,
+    /// Events dropped because the watcher's queue was full or capture was
+    /// paused
+    dropped_events: AtomicU64,
+    /// Events were dropped while capture was paused, so the tree should be
+    /// scanned for what they missed once it resumes
+    catch_up_due: AtomicBool,
+    /// How far the running catch-up scan has got
+    catch_up: Mutex<Option<Progress>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Why the last write failed, cleared once a write succeeds
+    reason: Option<String>,
+    paused_until: Option<Instant>,
+    failures: u32,
+}
+
+impl CaptureStatus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pause capture after a failed write, for twice as long as the last
+    /// pause up to [`MAX_BACKOFF`]. Returns how long capture is paused for.
+    pub fn pause(&self, reason: String) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let backoff = MIN_BACKOFF
+            .saturating_mul(1 << state.failures.min(16))
+            .min(MAX_BACKOFF);
+        state.failures += 1;
+        state.paused_until = Some(Instant::now() + backoff);
+        state.reason = Some(reason);
+        backoff
+    }
+
+    /// Record a successful write, ending any degraded state.
+    pub fn resume(&self) {
+        *self.state.lock().unwrap() = State::default();
+    }
+
+    /// Whether events should be dropped rather than written right now.
+    pub fn is_paused(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .paused_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Why capture is degraded, if the last write failed.
+    pub fn degraded_reason(&self) -> Option<String> {
+        self.state.lock().unwrap().reason.clone()
+    }
+
+    pub fn record_dropped_events(&self, count: u64) {
+        self.dropped_events.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Count events dropped while capture is paused, and ask for a catch-up
+    /// scan once it resumes.
+    pub fn record_paused_events(&self, count: u64) {
+        self.record_dropped_events(count);
+        self.catch_up_due.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether a catch-up scan is due, clearing the request.
+    pub fn take_catch_up_due(&self) -> bool {
+        self.catch_up_due.swap(false, Ordering::Relaxed)
+    }
+
+    /// Ask again for a catch-up scan, after one failed.
+    pub fn set_catch_up_due(&self) {
+        self.catch_up_due.store(true, Ordering::Relaxed);
+    }
+
+    /// Report how far a catch-up scan has got, or `None` once it's over.
+    pub fn set_catch_up_progress(&self, progress: Option<Progress>) {
+        *self.catch_up.lock().unwrap() = progress;
+    }
+
+    /// How far the running catch-up scan has got, if one is running.
+    pub fn catch_up_progress(&self) -> Option<Progress> {
+        *self.catch_up.lock().unwrap()
+    }
+
+    /// Events dropped since the server started.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_and_resets() {
+        let status = CaptureStatus::new();
+        assert!(!status.is_paused());
+
+        assert_eq!(status.pause("full".to_string()), Duration::from_secs(1));
+        assert_eq!(status.pause("full".to_string()), Duration::from_secs(2));
+        assert!(status.is_paused());
+        assert_eq!(status.degraded_reason().as_deref(), Some("full"));
+
+        for _ in 0..10 {
+            status.pause("full".to_string());
+        }
+        assert_eq!(status.pause("full".to_string()), MAX_BACKOFF);
+
+        status.resume();
+        assert!(!status.is_paused());
+        assert!(status.degraded_reason().is_none());
+        assert_eq!(status.pause("full".to_string()), MIN_BACKOFF);
+    }
+}
@@ -3,8 +3,23 @@
 //! Server component for gitent that watches files and provides an API for agents.
 
 pub mod api;
+pub mod archive;
+pub mod audit;
+pub mod diff_cache;
+pub mod health;
+pub mod metrics;
+pub mod middleware;
 pub mod server;
+pub mod store;
 pub mod watcher;
+pub mod webhook;
+pub mod wire;
 
+pub use api::CorsPolicy;
+pub use diff_cache::DiffCache;
+pub use health::CaptureStatus;
+pub use metrics::Metrics;
 pub use server::GitentServer;
-pub use watcher::FileWatcher;
+pub use store::StorageHandle;
+pub use watcher::{ActiveWatcher, FileWatcher, WatcherOptions};
+pub use webhook::Webhooks;
@@ -0,0 +1,114 @@
+//! Counters exposed in the Prometheus text format at `GET /metrics`.
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    changes_recorded: AtomicU64,
+    commits_created: AtomicU64,
+    watcher_events: AtomicU64,
+    /// Request counts keyed by route and status code
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_change(&self) {
+        self.changes_recorded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_commit(&self) {
+        self.commits_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_watcher_events(&self, count: u64) {
+        self.watcher_events.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_request(&self, endpoint: &str, status: u16) {
+        let mut requests = self.requests.lock().unwrap();
+        *requests.entry((endpoint.to_string(), status)).or_default() += 1;
+    }
+
+    /// Render every metric, with `uncommitted` as the current uncommitted change count.
+    pub fn render(&self, uncommitted: usize) -> String {
+        let mut out = String::new();
+
+        write_metric(
+            &mut out,
+            "gitent_changes_recorded_total",
+            "counter",
+            "Changes recorded since the server started",
+            self.changes_recorded.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "gitent_commits_created_total",
+            "counter",
+            "Commits created since the server started",
+            self.commits_created.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "gitent_watcher_events_total",
+            "counter",
+            "File system events processed by the watcher",
+            self.watcher_events.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "gitent_uncommitted_changes",
+            "gauge",
+            "Changes in the active session not yet committed",
+            uncommitted as u64,
+        );
+
+        out.push_str("# HELP gitent_http_requests_total HTTP requests by endpoint and status\n");
+        out.push_str("# TYPE gitent_http_requests_total counter\n");
+        for ((endpoint, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "gitent_http_requests_total{{endpoint=\"{}\",status=\"{}\"}} {}",
+                endpoint, status, count
+            );
+        }
+
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Count each request by its route and response status.
+pub async fn track_requests(
+    State(metrics): State<Arc<Metrics>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    // Label by route pattern rather than the raw path to keep label values bounded
+    let endpoint = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let response = next.run(req).await;
+    metrics.record_request(&endpoint, response.status().as_u16());
+
+    response
+}
@@ -0,0 +1,190 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::time::Instant;
+use tracing::{info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Largest error body that will be rewritten into the structured error format.
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+/// Identifier assigned to each request, available to handlers as an extension.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Assigns a request id, traces the request, and tags error responses with the id.
+///
+/// An incoming `x-request-id` header is reused so ids can be propagated from
+/// clients; otherwise a new UUID is generated. The id is echoed back in the
+/// `x-request-id` response header, and plain-text error bodies are rewritten
+/// to `{"error": ..., "request_id": ...}`.
+pub async fn request_context(mut req: Request, next: Next) -> Response {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.uri().path(),
+    );
+
+    async move {
+        let started = Instant::now();
+        let response = next.run(req).await;
+        let status = response.status();
+        let latency_ms = started.elapsed().as_millis();
+
+        if status.is_server_error() {
+            warn!(status = status.as_u16(), latency_ms, "request failed");
+        } else {
+            info!(status = status.as_u16(), latency_ms, "request completed");
+        }
+
+        let mut response = if status.is_client_error() || status.is_server_error() {
+            structured_error(response, &request_id).await
+        } else {
+            response
+        };
+
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+
+        response
+    }
+    .instrument(span)
+    .await
+}
+
+async fn structured_error(response: Response, request_id: &str) -> Response {
+    let is_text = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/plain"));
+    if !is_text {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    // The body is replaced either way, so the old length no longer holds
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let message = match to_bytes(body, MAX_ERROR_BODY).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => return Response::from_parts(parts, Body::empty()),
+    };
+
+    let body = serde_json::json!({
+        "error": message,
+        "request_id": request_id,
+    });
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Response::from_parts(parts, Body::from(body.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{create_router, AppState};
+    use crate::store::StorageHandle;
+    use axum::http::StatusCode;
+    use gitent_core::Storage;
+    use tower::ServiceExt;
+
+    fn router() -> axum::Router {
+        create_router(AppState::new(StorageHandle::spawn(
+            Storage::in_memory().unwrap(),
+        )))
+    }
+
+    #[tokio::test]
+    async fn test_response_has_request_id() {
+        let response = router()
+            .oneshot(Request::get("/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let request_id = response.headers().get(REQUEST_ID_HEADER).unwrap();
+        assert!(Uuid::parse_str(request_id.to_str().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_error_body_includes_request_id() {
+        let response = router()
+            .oneshot(
+                Request::get("/session")
+                    .header(REQUEST_ID_HEADER, "client-supplied-id")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers().get(REQUEST_ID_HEADER).unwrap(),
+            "client-supplied-id"
+        );
+
+        let body = to_bytes(response.into_body(), MAX_ERROR_BODY)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["request_id"], "client-supplied-id");
+        assert_eq!(body["error"], "No active session");
+    }
+
+    #[tokio::test]
+    async fn test_rewritten_error_length_matches_body() {
+        let app = axum::Router::new()
+            .route(
+                "/fail",
+                axum::routing::get(|| async {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        [(header::CONTENT_LENGTH, "4")],
+                        "nope",
+                    )
+                }),
+            )
+            .layer(axum::middleware::from_fn(request_context));
+
+        let response = app
+            .oneshot(Request::get("/fail").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        let length = response.headers().get(header::CONTENT_LENGTH).cloned();
+        let body = to_bytes(response.into_body(), MAX_ERROR_BODY)
+            .await
+            .unwrap();
+        if let Some(length) = length {
+            assert_eq!(length, body.len().to_string().as_str());
+        }
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "nope");
+    }
+}
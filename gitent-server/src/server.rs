@@ -1,40 +1,183 @@
-use crate::api::{create_router, AppState};
-use crate::watcher::FileWatcher;
-use gitent_core::{Session, Storage};
+use crate::api::{create_router, AppState, CorsPolicy};
+use crate::diff_cache::DiffCache;
+use crate::health::CaptureStatus;
+use crate::metrics::Metrics;
+use crate::store::StorageHandle;
+use crate::watcher::{ActiveWatcher, FileWatcher, WatcherOptions};
+use crate::webhook::Webhooks;
+#[cfg(feature = "git")]
+use gitent_core::git::{self, ImportReport};
+#[cfg(feature = "git")]
+use gitent_core::Progress;
+use gitent_core::{RetentionPolicy, Session, Settings, Storage};
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use tracing::info;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// How often history is pruned when a retention policy is set, and the
+/// audit log when it has a retention.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 pub struct GitentServer {
     session: Session,
-    storage: Arc<Mutex<Storage>>,
-    _watcher: FileWatcher,
+    storage: StorageHandle,
+    cors: CorsPolicy,
+    retention: RetentionPolicy,
+    audit_keep_days: Option<u32>,
+    metrics: Arc<Metrics>,
+    metrics_enabled: bool,
+    capture: Arc<CaptureStatus>,
+    max_concurrency: Option<usize>,
+    max_body_bytes: Option<usize>,
+    storage_timeout: Option<Duration>,
+    admin_token: Option<String>,
+    webhooks: Vec<String>,
+    diff_cache_size: usize,
+    max_file_size: Option<u64>,
+    watcher: Arc<ActiveWatcher>,
 }
 
 impl GitentServer {
     pub fn new(root_path: PathBuf, db_path: PathBuf) -> anyhow::Result<Self> {
-        let session = Session::new(root_path);
-        let storage = Arc::new(Mutex::new(Storage::new(db_path)?));
+        let mut settings = Settings::load(&root_path, Default::default())?;
+        settings.db_path = db_path;
+        Self::with_settings(root_path, &settings)
+    }
+
+    pub fn with_settings(root_path: PathBuf, settings: &Settings) -> anyhow::Result<Self> {
+        let mut session = Session::new(root_path)
+            .with_ignore_patterns(settings.ignore_patterns.clone())
+            .with_redact_patterns(settings.redact_patterns.clone())
+            .with_include_patterns(settings.include_patterns.clone())
+            .with_delta_storage(settings.store_deltas)
+            .with_hash_algorithm(settings.hash_algorithm);
+        if let Some(schema) = &settings.metadata_schema {
+            session = session.with_metadata_schema(schema.clone());
+        }
+        if let Some(content_root) = &settings.content_root {
+            let content_root = std::fs::canonicalize(content_root)?;
+            // Otherwise no watched file could be stored
+            anyhow::ensure!(
+                session.root_path.starts_with(&content_root)
+                    || content_root.starts_with(&session.root_path),
+                "Content root {} must contain or be inside {}",
+                content_root.display(),
+                session.root_path.display()
+            );
+            session = session.with_content_root(content_root);
+        }
+        let storage = Storage::open(settings)?;
+        // Sessions left active by a server that didn't stop cleanly are ended
+        storage.start_session(&session)?;
 
-        {
-            let storage_guard = storage.lock().unwrap();
-            storage_guard.create_session(&session)?;
+        // Counters are always kept so the watcher can be started before
+        // knowing whether they will be exposed
+        let metrics = Arc::new(Metrics::new());
+        let options = WatcherOptions {
+            metrics: Some(Arc::clone(&metrics)),
+            ..WatcherOptions::from(settings)
+        };
+
+        if options.file_index {
+            let caught_up = FileWatcher::catch_up_reported(&session, &storage, &options)?;
+            info!("Recorded {} change(s) made while stopped", caught_up);
         }
 
-        let watcher = FileWatcher::new(&session, Arc::clone(&storage))?;
+        let storage = StorageHandle::spawn(storage);
+        let capture = Arc::clone(&options.capture);
+        let watcher = FileWatcher::with_options(&session, storage.clone(), options.clone())?;
+        let watcher = Arc::new(ActiveWatcher::new(options).with_watcher(watcher));
 
         Ok(Self {
             session,
             storage,
-            _watcher: watcher,
+            cors: CorsPolicy::default(),
+            retention: settings.retention,
+            audit_keep_days: settings.audit_keep_days,
+            metrics,
+            metrics_enabled: false,
+            capture,
+            max_concurrency: settings.max_concurrency,
+            max_body_bytes: settings.max_body_bytes,
+            storage_timeout: settings.storage_timeout,
+            admin_token: settings.admin_token.clone(),
+            webhooks: settings.webhooks.clone(),
+            diff_cache_size: settings.diff_cache_size,
+            max_file_size: settings.max_file_size,
+            watcher,
         })
     }
 
+    pub fn with_cors(mut self, cors: CorsPolicy) -> Self {
+        self.cors = cors;
+        self
+    }
+
+    pub fn with_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Expose counters at `GET /metrics`.
+    pub fn with_metrics(mut self, enabled: bool) -> Self {
+        self.metrics_enabled = enabled;
+        self
+    }
+
+    /// Seed the session with the history of the git repository at its root,
+    /// importing at most `max_depth` of the newest commits. `progress` is
+    /// called after each commit.
+    #[cfg(feature = "git")]
+    pub async fn import_git(
+        &self,
+        max_depth: Option<usize>,
+        progress: impl FnMut(Progress) + Send + 'static,
+    ) -> anyhow::Result<ImportReport> {
+        let session = self.session.clone();
+        let report = self
+            .storage
+            .call(move |storage| {
+                git::import_history(storage, &session, &session.root_path, max_depth, progress)
+            })
+            .await?;
+        Ok(report)
+    }
+
     pub async fn serve(self, addr: SocketAddr) -> anyhow::Result<()> {
-        let state = AppState {
-            storage: self.storage,
-        };
+        let mut state = AppState::new(self.storage.clone())
+            .with_cors(self.cors)
+            .with_capture(self.capture)
+            .with_watcher(self.watcher)
+            .with_diff_cache(self.diff_cache_size);
+        if let Some(limit) = self.max_concurrency {
+            state = state.with_max_concurrency(limit);
+        }
+        if let Some(limit) = self.max_body_bytes {
+            state = state.with_max_body_bytes(limit);
+        }
+        if let Some(limit) = self.max_file_size {
+            state = state.with_max_file_size(limit);
+        }
+        if let Some(timeout) = self.storage_timeout {
+            state = state.with_storage_timeout(timeout);
+        }
+        if let Some(token) = self.admin_token {
+            state = state.with_admin_token(token);
+        }
+        if !self.webhooks.is_empty() {
+            state = state.with_webhooks(Webhooks::new(self.webhooks));
+        }
+        if self.metrics_enabled {
+            state = state.with_metrics(self.metrics);
+        }
+        if !self.retention.is_empty() {
+            Self::spawn_pruning(self.storage, self.retention, Arc::clone(&state.diff_cache));
+        }
+        if let Some(keep_days) = self.audit_keep_days {
+            Self::spawn_audit_pruning(state.storage.clone(), keep_days);
+        }
 
         let app = create_router(state);
 
@@ -43,11 +186,66 @@ impl GitentServer {
         info!("Watching: {:?}", self.session.root_path);
 
         let listener = tokio::net::TcpListener::bind(addr).await?;
-        axum::serve(listener, app).await?;
+        // Connection info gives the audit log each request's source address
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await?;
 
         Ok(())
     }
 
+    /// Prune the active session's history on startup and then every
+    /// [`PRUNE_INTERVAL`], dropping cached diffs once commits are removed.
+    ///
+    /// The session is looked up each time, as `POST /session` can switch to
+    /// another while the server runs.
+    fn spawn_pruning(storage: StorageHandle, policy: RetentionPolicy, diff_cache: Arc<DiffCache>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let result = storage
+                    .call(move |storage| {
+                        let session = storage.get_active_session()?;
+                        storage.prune_history(&session.id, &policy)
+                    })
+                    .await;
+                match result {
+                    Ok(report) if report.commits_removed > 0 => {
+                        diff_cache.clear();
+                        info!(
+                            "Pruned {} commit(s) and {} change(s)",
+                            report.commits_removed, report.changes_removed
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("Failed to prune history: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Drop audit entries older than `keep_days` on startup and then every
+    /// [`PRUNE_INTERVAL`].
+    fn spawn_audit_pruning(storage: StorageHandle, keep_days: u32) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                match storage
+                    .call(move |storage| storage.prune_audit_log(keep_days))
+                    .await
+                {
+                    Ok(0) => {}
+                    Ok(removed) => info!("Pruned {} audit entry(s)", removed),
+                    Err(e) => error!("Failed to prune the audit log: {}", e),
+                }
+            }
+        });
+    }
+
     pub fn session_id(&self) -> uuid::Uuid {
         self.session.id
     }
@@ -68,4 +266,20 @@ mod tests {
 
         assert!(server.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_restart_ends_previous_session() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_dir = TempDir::new().unwrap();
+        let db_path = db_dir.path().join("test.db");
+
+        let first = GitentServer::new(temp_dir.path().to_path_buf(), db_path.clone()).unwrap();
+        let second = GitentServer::new(temp_dir.path().to_path_buf(), db_path.clone()).unwrap();
+        drop(first);
+
+        let sessions = Storage::new(&db_path).unwrap().get_sessions().unwrap();
+        let active: Vec<_> = sessions.iter().filter(|s| s.active).map(|s| s.id).collect();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(active, vec![second.session.id]);
+    }
 }
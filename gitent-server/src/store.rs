@@ -0,0 +1,134 @@
+//! Access to [`Storage`] from async code without blocking the runtime.
+//!
+//! SQLite calls are synchronous and can be slow, so the connection lives on a
+//! dedicated thread. Callers send it closures and await the result.
+
+use gitent_core::{CancelToken, Storage};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::error;
+
+type Job = Box<dyn FnOnce(&Storage) + Send>;
+
+/// A cheaply cloneable handle to a [`Storage`] owned by a worker thread.
+///
+/// Jobs run one at a time in the order they were sent. The thread exits once
+/// every handle has been dropped.
+#[derive(Clone)]
+pub struct StorageHandle {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl StorageHandle {
+    /// Move `storage` onto a new worker thread.
+    pub fn spawn(storage: Storage) -> Self {
+        let (jobs, rx) = mpsc::channel::<Job>();
+
+        std::thread::Builder::new()
+            .name("gitent-storage".to_string())
+            .spawn(move || {
+                for job in rx {
+                    // A panicking job shouldn't take every later request down with it
+                    if catch_unwind(AssertUnwindSafe(|| job(&storage))).is_err() {
+                        error!("Storage job panicked");
+                    }
+                }
+            })
+            .expect("failed to spawn storage thread");
+
+        Self { jobs }
+    }
+
+    /// Run `f` on the storage thread and wait for its result.
+    ///
+    /// Panics if `f` panics, as it would if called directly.
+    pub async fn call<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&Storage) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.send(f).await.expect("storage job panicked")
+    }
+
+    /// Like [`StorageHandle::call`], for expensive work that should stop early
+    /// if the returned future is dropped, as when a client disconnects, or
+    /// once `timeout` has passed since the call.
+    ///
+    /// The timeout includes time spent waiting behind other jobs, so a
+    /// backlog doesn't start work nobody will wait for.
+    pub async fn call_cancellable<F, T>(
+        &self,
+        timeout: Option<Duration>,
+        f: F,
+    ) -> gitent_core::Result<T>
+    where
+        F: FnOnce(&Storage) -> gitent_core::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let token = match timeout {
+            Some(timeout) => CancelToken::new().with_timeout(timeout),
+            None => CancelToken::new(),
+        };
+        let _cancel = token.cancel_on_drop();
+        self.call(move |storage| storage.cancellable(&token, f))
+            .await
+    }
+
+    fn send<F, T>(&self, f: F) -> oneshot::Receiver<T>
+    where
+        F: FnOnce(&Storage) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move |storage| {
+            let _ = tx.send(f(storage));
+        });
+        // The worker only stops once every handle is gone, so this can't fail
+        // while `self` is alive
+        let _ = self.jobs.send(job);
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_slow_job_does_not_block_runtime() {
+        let storage = StorageHandle::spawn(Storage::in_memory().unwrap());
+
+        let slow = tokio::spawn({
+            let storage = storage.clone();
+            async move {
+                storage
+                    .call(|_| std::thread::sleep(Duration::from_millis(300)))
+                    .await
+            }
+        });
+
+        // On this single-threaded runtime a blocked executor would hold the timer up too
+        let start = Instant::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        slow.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_panicking_job_keeps_worker_alive() {
+        let storage = StorageHandle::spawn(Storage::in_memory().unwrap());
+
+        let result = tokio::spawn({
+            let storage = storage.clone();
+            async move { storage.call(|_| -> () { panic!("boom") }).await }
+        })
+        .await;
+        assert!(result.is_err());
+
+        assert!(storage.call(|s| s.get_active_session()).await.is_err());
+    }
+}
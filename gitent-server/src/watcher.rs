@@ -1,66 +1,178 @@
-use gitent_core::{Change, ChangeType, Session, Storage};
-use notify::{Event, EventKind, RecursiveMode, Watcher};
-use notify_debouncer_full::{new_debouncer, DebounceEventResult, Debouncer, FileIdMap};
-use std::path::Path;
+use crate::health::CaptureStatus;
+use crate::metrics::Metrics;
+use crate::store::StorageHandle;
+use chrono::{DateTime, Utc};
+use gitent_core::config::OverflowPolicy;
+use gitent_core::ignore::IgnoreMatcher;
+use gitent_core::{
+    Change, ChangeType, Error, FileIndexEntry, HashAlgorithm, Progress, Session, Settings, Storage,
+};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use notify_debouncer_full::{new_debouncer_opt, DebounceEventResult, Debouncer, FileIdMap};
+use std::collections::{HashMap, HashSet};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 use tokio::sync::mpsc;
-use tracing::{error, info};
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
+use walkdir::WalkDir;
+
+/// Tool recorded on changes picked up by the watcher.
+pub const FILESYSTEM_TOOL: &str = "filesystem";
+
+/// Files a catch-up scans between saving what it has found, so an
+/// interrupted one doesn't start over.
+const CATCH_UP_CHECKPOINT: usize = 1000;
+
+/// Tuning knobs for the file watcher.
+#[derive(Debug, Clone)]
+pub struct WatcherOptions {
+    /// How long to wait for events on a path to settle before recording them
+    pub debounce: Duration,
+    /// Poll for changes at this interval instead of relying on OS notifications,
+    /// for filesystems (NFS, SMB, some container mounts) that don't deliver them
+    pub poll_interval: Option<Duration>,
+    /// Debounced batches waiting to be recorded before `overflow` applies
+    pub queue_capacity: usize,
+    pub overflow: OverflowPolicy,
+    /// Files larger than this are recorded without their content
+    pub max_file_size: Option<u64>,
+    /// Counters to update as events are processed
+    pub metrics: Option<Arc<Metrics>>,
+    /// The database being written to, which is never tracked
+    pub db_path: Option<PathBuf>,
+    /// Keep the file index up to date so [`FileWatcher::catch_up`] can find
+    /// changes made while the server was stopped
+    pub file_index: bool,
+    /// Paused while storage is full, so a full disk isn't retried on every event
+    pub capture: Arc<CaptureStatus>,
+}
+
+impl Default for WatcherOptions {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_millis(gitent_core::config::DEFAULT_DEBOUNCE_MS),
+            poll_interval: None,
+            queue_capacity: gitent_core::config::DEFAULT_WATCH_QUEUE_CAPACITY,
+            overflow: OverflowPolicy::default(),
+            max_file_size: None,
+            metrics: None,
+            db_path: None,
+            file_index: false,
+            capture: Arc::new(CaptureStatus::new()),
+        }
+    }
+}
+
+impl From<&Settings> for WatcherOptions {
+    fn from(settings: &Settings) -> Self {
+        Self {
+            debounce: settings.debounce,
+            poll_interval: settings.poll_interval,
+            queue_capacity: settings.watch_queue_capacity,
+            overflow: settings.watch_overflow,
+            max_file_size: settings.max_file_size,
+            metrics: None,
+            db_path: Some(settings.db_path.clone()),
+            file_index: settings.file_index,
+            capture: Arc::new(CaptureStatus::new()),
+        }
+    }
+}
+
+/// The notify backend in use, kept alive for as long as the watcher is.
+enum Backend {
+    Native(Debouncer<RecommendedWatcher, FileIdMap>),
+    Poll(Debouncer<PollWatcher, FileIdMap>),
+}
+
+impl Backend {
+    fn watch(&mut self, path: &Path) -> notify::Result<()> {
+        match self {
+            Backend::Native(debouncer) => debouncer.watcher().watch(path, RecursiveMode::Recursive),
+            Backend::Poll(debouncer) => debouncer.watcher().watch(path, RecursiveMode::Recursive),
+        }
+    }
+}
 
 pub struct FileWatcher {
-    _session_id: Uuid,
-    _storage: Arc<Mutex<Storage>>,
-    _debouncer: Debouncer<notify::RecommendedWatcher, FileIdMap>,
+    session_id: Uuid,
+    backend: Backend,
 }
 
 impl FileWatcher {
-    pub fn new(session: &Session, storage: Arc<Mutex<Storage>>) -> anyhow::Result<Self> {
+    pub fn new(session: &Session, storage: StorageHandle) -> anyhow::Result<Self> {
+        Self::with_options(session, storage, WatcherOptions::default())
+    }
+
+    pub fn with_options(
+        session: &Session,
+        storage: StorageHandle,
+        options: WatcherOptions,
+    ) -> anyhow::Result<Self> {
         let session_id = session.id;
+        let session = session.clone();
         let root_path = session.root_path.clone();
-        let root_path_for_watch = root_path.clone();
-        let ignore_patterns = session.ignore_patterns.clone();
-        let storage_clone = Arc::clone(&storage);
+        let ignore = IgnoreMatcher::for_session(&session)?;
 
-        let (tx, mut rx) = mpsc::channel(100);
+        let (tx, mut rx) = mpsc::channel(options.queue_capacity.max(1));
 
-        let debouncer = new_debouncer(
-            Duration::from_millis(500),
-            None,
-            move |result: DebounceEventResult| {
-                if let Err(e) = tx.blocking_send(result) {
-                    error!("Failed to send event: {}", e);
-                }
-            },
-        )?;
+        let overflow = options.overflow;
+        let capture = Arc::clone(&options.capture);
+        let handler = move |result: DebounceEventResult| {
+            Self::enqueue(&tx, result, overflow, &capture);
+        };
+
+        let backend = match options.poll_interval {
+            Some(interval) => Backend::Poll(new_debouncer_opt(
+                options.debounce,
+                None,
+                handler,
+                FileIdMap::new(),
+                notify::Config::default().with_poll_interval(interval),
+            )?),
+            None => Backend::Native(new_debouncer_opt(
+                options.debounce,
+                None,
+                handler,
+                FileIdMap::new(),
+                notify::Config::default(),
+            )?),
+        };
 
         let mut watcher = Self {
-            _session_id: session_id,
-            _storage: storage,
-            _debouncer: debouncer,
+            session_id,
+            backend,
         };
 
-        watcher
-            ._debouncer
-            .watcher()
-            .watch(&root_path_for_watch, RecursiveMode::Recursive)?;
+        watcher.backend.watch(&root_path)?;
 
-        info!("File watcher started for {:?}", root_path);
+        match options.poll_interval {
+            Some(interval) => info!(
+                "File watcher started for {:?}, polling every {:?}",
+                root_path, interval
+            ),
+            None => info!("File watcher started for {:?}", root_path),
+        }
 
         tokio::spawn(async move {
             while let Some(result) = rx.recv().await {
                 match result {
                     Ok(events) => {
-                        for event in events {
-                            if let Err(e) = Self::handle_event(
-                                event.event,
-                                session_id,
-                                &root_path,
-                                &ignore_patterns,
-                                &storage_clone,
-                            ) {
-                                error!("Error handling event: {}", e);
-                            }
+                        if let Err(e) = Self::handle_events(
+                            events.into_iter().map(|event| event.event),
+                            &session,
+                            &ignore,
+                            &storage,
+                            &options,
+                        )
+                        .await
+                        {
+                            error!("Error handling events: {}", e);
                         }
                     }
                     Err(errors) => {
@@ -75,108 +187,1428 @@ impl FileWatcher {
         Ok(watcher)
     }
 
-    fn handle_event(
-        event: Event,
-        session_id: Uuid,
-        root_path: &Path,
-        ignore_patterns: &[String],
-        storage: &Arc<Mutex<Storage>>,
+    /// Queue a batch of events from the notify thread, applying `overflow` if
+    /// the queue is full.
+    fn enqueue(
+        tx: &mpsc::Sender<DebounceEventResult>,
+        result: DebounceEventResult,
+        overflow: OverflowPolicy,
+        capture: &CaptureStatus,
+    ) {
+        match overflow {
+            OverflowPolicy::Block => {
+                if let Err(e) = tx.blocking_send(result) {
+                    error!("Failed to send event: {}", e);
+                }
+            }
+            OverflowPolicy::Drop => match tx.try_send(result) {
+                Ok(()) => {}
+                Err(TrySendError::Full(result)) => {
+                    let count = match &result {
+                        Ok(events) => events.len(),
+                        Err(errors) => errors.len(),
+                    };
+                    capture.record_dropped_events(count as u64);
+                    warn!("Watcher queue is full, dropping {} event(s)", count);
+                }
+                Err(TrySendError::Closed(_)) => error!("Failed to send event: queue closed"),
+            },
+        }
+    }
+
+    /// The session changes are recorded in.
+    pub fn session_id(&self) -> Uuid {
+        self.session_id
+    }
+
+    /// Whether this watcher scans for changes rather than using OS notifications.
+    pub fn is_polling(&self) -> bool {
+        matches!(self.backend, Backend::Poll(_))
+    }
+
+    /// Record the changes from one debounced batch of events.
+    async fn handle_events(
+        events: impl IntoIterator<Item = Event>,
+        session: &Session,
+        ignore: &IgnoreMatcher,
+        storage: &StorageHandle,
+        options: &WatcherOptions,
     ) -> anyhow::Result<()> {
+        let metrics = options.metrics.as_deref();
+        let events: Vec<Event> = events.into_iter().collect();
+        if let Some(metrics) = metrics {
+            metrics.record_watcher_events(events.len() as u64);
+        }
+        if options.capture.is_paused() {
+            warn!(
+                "Capture paused while storage is full, dropping {} event(s)",
+                events.len()
+            );
+            options.capture.record_paused_events(events.len() as u64);
+            return Ok(());
+        }
+
+        let mut vanished = HashSet::new();
+        let changes = events
+            .into_iter()
+            .flat_map(|event| {
+                Self::changes_for_event(event, session, ignore, options, &mut vanished)
+            })
+            .collect();
+        let changes = Self::coalesce(changes, &vanished);
+        let file_index = options.file_index;
+        let batch_session = session.clone();
+
+        // The whole batch is written in one transaction. A change that can't
+        // be recorded is skipped, unless storage is full, which abandons the
+        // batch. Report how many were written, so the metrics stay right if
+        // the index update after fails
+        let (recorded, result) = storage
+            .call(move |storage| {
+                let recorded = storage.batch(|storage| {
+                    let mut recorded = Vec::with_capacity(changes.len());
+                    for change in changes {
+                        let path = change.path.clone();
+                        let result = Self::with_previous_state(storage, &batch_session, change)
+                            .and_then(|change| {
+                                storage.create_captured_change(&change)?;
+                                Ok(change)
+                            });
+                        match result {
+                            Ok(change) => recorded.push(change),
+                            Err(e @ Error::StorageFull(_)) => return Err(e),
+                            Err(e) => warn!("Failed to record change to {}: {}", path.display(), e),
+                        }
+                    }
+                    Ok(recorded)
+                });
+                let recorded = match recorded {
+                    Ok(recorded) => recorded,
+                    Err(e) => return (0, Err(e)),
+                };
+                // The index has transactions of its own, so is saved once the
+                // changes are in
+                let result = if file_index {
+                    let (root, updated, removed) = Self::index_updates(&recorded, &batch_session);
+                    storage
+                        .save_file_index(&root, &updated)
+                        .and_then(|_| storage.remove_from_file_index(&root, &removed))
+                } else {
+                    Ok(())
+                };
+                (recorded.len(), result)
+            })
+            .await;
+        if let Some(metrics) = metrics {
+            for _ in 0..recorded {
+                metrics.record_change();
+            }
+        }
+
+        match result {
+            Ok(()) => {
+                options.capture.resume();
+                if options.capture.take_catch_up_due() {
+                    Self::catch_up_dropped(session, storage, options).await?;
+                }
+                Ok(())
+            }
+            Err(Error::StorageFull(reason)) => {
+                let backoff = options.capture.pause(reason.clone());
+                warn!(
+                    "Storage is full, pausing capture for {:?}; free up space to resume",
+                    backoff
+                );
+                Err(Error::StorageFull(reason).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Record the changes whose events were dropped while capture was paused,
+    /// now that it has resumed. They're found by comparing the tree against
+    /// the file index, so without one they stay lost.
+    async fn catch_up_dropped(
+        session: &Session,
+        storage: &StorageHandle,
+        options: &WatcherOptions,
+    ) -> anyhow::Result<()> {
+        if !options.file_index {
+            warn!("Changes dropped while capture was paused can't be recovered without the file index");
+            return Ok(());
+        }
+        let result = storage
+            .call({
+                let session = session.clone();
+                let options = options.clone();
+                move |storage| Self::catch_up_reported(&session, storage, &options)
+            })
+            .await;
+        match result {
+            Ok(caught_up) => {
+                info!(
+                    "Recorded {} change(s) missed while capture was paused",
+                    caught_up
+                );
+                Ok(())
+            }
+            Err(e) => {
+                options.capture.set_catch_up_due();
+                if let Some(Error::StorageFull(reason)) = e.downcast_ref::<Error>() {
+                    options.capture.pause(reason.clone());
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Give a change the modification time its file was last recorded with,
+    /// and a rename the content its old path was last recorded with, so edits
+    /// made along with the move show up in its diff. A delete gets the
+    /// content its path was last recorded with, so it can be paired with a
+    /// create as a rename.
+    fn with_previous_state(
+        storage: &Storage,
+        session: &Session,
+        mut change: Change,
+    ) -> gitent_core::Result<Change> {
+        if change.change_type != ChangeType::Create && change.mtime_before.is_none() {
+            let previous_path = change.old_path.as_ref().unwrap_or(&change.path);
+            if let Some(mtime) = storage.get_latest_mtime(&change.session_id, previous_path)? {
+                change = change.with_mtime_before(mtime);
+            }
+        }
+
+        if change.content_before.is_some() {
+            return Ok(change);
+        }
+        let previous_path = match (change.change_type, change.old_path.as_ref()) {
+            (ChangeType::Rename, Some(old_path)) => old_path,
+            (ChangeType::Delete, _) => &change.path,
+            _ => return Ok(change),
+        };
+        match storage.get_latest_content(&change.session_id, previous_path)? {
+            Some(content) => Ok(change.with_content_before_hashed(content, session.hash_algorithm)),
+            None => Ok(change),
+        }
+    }
+
+    /// Index entries to store and paths to drop for a batch of changes.
+    fn index_updates(
+        changes: &[Change],
+        session: &Session,
+    ) -> (PathBuf, Vec<FileIndexEntry>, Vec<PathBuf>) {
+        let mut updated = Vec::new();
+        let mut removed = Vec::new();
+
+        for change in changes {
+            // The index is keyed by absolute path, as the tree is walked
+            let path = session.absolute_path(&change.path);
+            if let Some(old_path) = &change.old_path {
+                removed.push(session.absolute_path(old_path));
+            }
+            if change.change_type == ChangeType::Delete {
+                removed.push(path);
+                continue;
+            }
+            let mtime_ns = std::fs::metadata(&path)
+                .ok()
+                .as_ref()
+                .and_then(Self::mtime_ns);
+            if let Some(mtime_ns) = mtime_ns {
+                updated.push(FileIndexEntry {
+                    path,
+                    mtime_ns,
+                    hash: change.content_hash_after.clone(),
+                });
+            }
+        }
+
+        (session.root_path.clone(), updated, removed)
+    }
+
+    /// [`FileWatcher::catch_up`], logging its progress every
+    /// [`CATCH_UP_CHECKPOINT`] files and reporting it at `GET /health` while
+    /// it runs.
+    pub fn catch_up_reported(
+        session: &Session,
+        storage: &Storage,
+        options: &WatcherOptions,
+    ) -> anyhow::Result<usize> {
+        let capture = &options.capture;
+        let result = Self::catch_up(session, storage, options, |progress| {
+            if progress.done > 0 && progress.done % CATCH_UP_CHECKPOINT == 0 {
+                info!(
+                    "Catching up: scanned {} of {} files",
+                    progress.done, progress.total
+                );
+            }
+            capture.set_catch_up_progress(Some(progress));
+        });
+        capture.set_catch_up_progress(None);
+        result
+    }
+
+    /// Record what changed under the session's root since the file index was
+    /// last updated, returning the number of changes recorded.
+    ///
+    /// Files whose modification time matches the index are skipped without
+    /// being read. The first run only builds the index. Later runs save
+    /// their progress as they go, so one that is interrupted picks up where
+    /// it left off. `progress` is called as files are scanned.
+    pub fn catch_up(
+        session: &Session,
+        storage: &Storage,
+        options: &WatcherOptions,
+        mut progress: impl FnMut(Progress),
+    ) -> anyhow::Result<usize> {
+        let root_path = &session.root_path;
+        let ignore = IgnoreMatcher::for_session(session)?;
+        let db_path = options.db_path.as_deref();
+        let previous = storage.load_file_index(root_path)?;
+
+        let mut changes = Vec::new();
+        let mut recorded = 0;
+        let mut seen = Vec::new();
+        let mut saved = 0;
+        let entries: Vec<_> = WalkDir::new(root_path)
+            .into_iter()
+            .filter_entry(|entry| {
+                if entry.depth() == 0 {
+                    return true;
+                }
+                // Include patterns name files, so don't keep the walk out of
+                // the directories holding them
+                if entry.file_type().is_dir() {
+                    let relative = entry.path().strip_prefix(root_path).unwrap_or(entry.path());
+                    return !ignore.is_ignored_dir(relative);
+                }
+                !Self::should_ignore(entry.path(), root_path, &ignore, db_path)
+            })
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .collect();
+
+        let total = entries.len();
+        for (done, entry) in entries.into_iter().enumerate() {
+            progress(Progress { done, total });
+            // Without an index yet there's nothing to record, and a partial
+            // index would make the rest look new next time
+            if previous.is_some() && done > 0 && done % CATCH_UP_CHECKPOINT == 0 {
+                recorded += Self::record_caught_up(storage, session, options, &mut changes)?;
+                storage.save_file_index(root_path, &seen[saved..])?;
+                saved = seen.len();
+            }
+
+            let path = entry.path().to_path_buf();
+            // Files outside the content root aren't tracked
+            let Ok(relative) = session.relative_path(&path) else {
+                continue;
+            };
+            let Some(mtime_ns) = entry.metadata().ok().as_ref().and_then(Self::mtime_ns) else {
+                continue;
+            };
+            let indexed = previous.as_ref().and_then(|index| index.get(&path));
+            if let Some(indexed) = indexed.filter(|indexed| indexed.mtime_ns == mtime_ns) {
+                seen.push(indexed.clone());
+                continue;
+            }
+
+            let change_type = match indexed {
+                Some(_) => ChangeType::Modify,
+                None => ChangeType::Create,
+            };
+            let change = Change::new(change_type, relative, session.id)
+                .with_tool(FILESYSTEM_TOOL.to_string());
+            let Some(mut change) = Self::with_content(
+                change,
+                &path,
+                options.max_file_size,
+                session.hash_algorithm,
+                &mut HashSet::new(),
+            ) else {
+                continue;
+            };
+            if let Some(mtime) = entry.metadata().ok().as_ref().and_then(Self::mtime) {
+                change = change.with_mtime_after(mtime);
+            }
+            if let Some(indexed) = indexed {
+                change = change.with_mtime_before(DateTime::from_timestamp_nanos(indexed.mtime_ns));
+            }
+
+            // A touched file with the same content only needs its mtime updated
+            let unchanged = indexed.is_some_and(|indexed| {
+                indexed.hash.is_some() && indexed.hash == change.content_hash_after
+            });
+            seen.push(FileIndexEntry {
+                path,
+                mtime_ns,
+                hash: change.content_hash_after.clone(),
+            });
+            if previous.is_some() && !unchanged {
+                info!("File changed while stopped: {:?}", change.path);
+                changes.push(change);
+            }
+        }
+
+        if let Some(previous) = &previous {
+            let seen_paths: HashSet<&PathBuf> = seen.iter().map(|entry| &entry.path).collect();
+            let missing: Vec<PathBuf> = previous
+                .keys()
+                .filter(|path| !seen_paths.contains(path))
+                .cloned()
+                .collect();
+            for path in &missing {
+                // Files that are now ignored weren't deleted, just untracked
+                if path.exists() || Self::should_ignore(path, root_path, &ignore, db_path) {
+                    continue;
+                }
+                let Ok(relative) = session.relative_path(path) else {
+                    continue;
+                };
+                info!("File removed while stopped: {:?}", path);
+                changes.push(
+                    Change::new(ChangeType::Delete, relative, session.id)
+                        .with_tool(FILESYSTEM_TOOL.to_string()),
+                );
+            }
+            storage.remove_from_file_index(root_path, &missing)?;
+        }
+
+        recorded += Self::record_caught_up(storage, session, options, &mut changes)?;
+        storage.save_file_index(root_path, &seen[saved..])?;
+        progress(Progress { done: total, total });
+
+        Ok(recorded)
+    }
+
+    /// Record and clear the changes a catch-up has found so far.
+    fn record_caught_up(
+        storage: &Storage,
+        session: &Session,
+        options: &WatcherOptions,
+        changes: &mut Vec<Change>,
+    ) -> anyhow::Result<usize> {
+        let count = changes.len();
+        for change in changes.drain(..) {
+            let change = Self::with_previous_state(storage, session, change)?;
+            storage.create_captured_change(&change)?;
+            if let Some(metrics) = options.metrics.as_deref() {
+                metrics.record_change();
+            }
+        }
+        Ok(count)
+    }
+
+    /// A file's modification time, if the platform records one.
+    fn mtime(metadata: &Metadata) -> Option<DateTime<Utc>> {
+        metadata.modified().ok().map(DateTime::from)
+    }
+
+    /// Record when a file that still exists was last modified.
+    fn with_current_mtime(change: Change, path: &Path) -> Change {
+        if change.change_type == ChangeType::Delete {
+            return change;
+        }
+        match std::fs::metadata(path).ok().as_ref().and_then(Self::mtime) {
+            Some(mtime) => change.with_mtime_after(mtime),
+            None => change,
+        }
+    }
+
+    /// A file's modification time in nanoseconds since the Unix epoch.
+    fn mtime_ns(metadata: &Metadata) -> Option<i64> {
+        let modified = metadata.modified().ok()?;
+        let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+        i64::try_from(since_epoch.as_nanos()).ok()
+    }
+
+    fn changes_for_event(
+        event: Event,
+        session: &Session,
+        ignore: &IgnoreMatcher,
+        options: &WatcherOptions,
+        vanished: &mut HashSet<PathBuf>,
+    ) -> Vec<Change> {
+        let session_id = session.id;
+        let root_path = &session.root_path;
+        let max_file_size = options.max_file_size;
+        let algorithm = session.hash_algorithm;
+        let mut changes = Vec::new();
+
+        // A move reported as one event, with the old path first
+        if let (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) =
+            (event.kind, &event.paths[..])
+        {
+            let tracked = |path: &Path| {
+                if Self::should_ignore(path, root_path, ignore, options.db_path.as_deref()) {
+                    return None;
+                }
+                session.relative_path(path).ok()
+            };
+            // A move into or out of what's tracked is a create or delete
+            let change = match (tracked(from), tracked(to)) {
+                (Some(old_path), Some(path)) => {
+                    info!("File moved: {:?} -> {:?}", from, to);
+                    Some(Change::new(ChangeType::Rename, path, session_id).with_old_path(old_path))
+                }
+                (None, Some(path)) => {
+                    info!("File moved in: {:?}", to);
+                    Some(Change::new(ChangeType::Create, path, session_id))
+                }
+                (Some(old_path), None) => {
+                    info!("File moved out: {:?}", from);
+                    Some(Change::new(ChangeType::Delete, old_path, session_id))
+                }
+                (None, None) => None,
+            };
+            return change
+                .and_then(|change| match change.change_type {
+                    ChangeType::Delete => Some(change),
+                    _ => Self::with_content(change, to, max_file_size, algorithm, vanished),
+                })
+                .map(|change| {
+                    Self::with_current_mtime(change, to).with_tool(FILESYSTEM_TOOL.to_string())
+                })
+                .into_iter()
+                .collect();
+        }
+
         for path in event.paths {
-            if Self::should_ignore(&path, root_path, ignore_patterns) {
+            if Self::should_ignore(&path, root_path, ignore, options.db_path.as_deref()) {
                 continue;
             }
+            // Changes are stored relative to the content root, as the API
+            // stores them
+            let Ok(relative) = session.relative_path(&path) else {
+                continue;
+            };
 
             let change = match event.kind {
                 EventKind::Create(_) => {
                     info!("File created: {:?}", path);
-                    let content = std::fs::read(&path).ok();
-                    let mut change = Change::new(ChangeType::Create, path.clone(), session_id);
-                    if let Some(content) = content {
-                        change = change.with_content_after(content);
-                    }
-                    Some(change)
+                    let change = Change::new(ChangeType::Create, relative, session_id);
+                    Self::with_content(change, &path, max_file_size, algorithm, vanished)
+                }
+                // Halves of a move whose other half wasn't seen
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                    info!("File moved away: {:?}", path);
+                    Some(Change::new(ChangeType::Delete, relative, session_id))
+                }
+                EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                    info!("File moved here: {:?}", path);
+                    let change = Change::new(ChangeType::Create, relative, session_id);
+                    Self::with_content(change, &path, max_file_size, algorithm, vanished)
                 }
                 EventKind::Modify(_) => {
                     info!("File modified: {:?}", path);
-                    let content_after = std::fs::read(&path).ok();
-                    let mut change = Change::new(ChangeType::Modify, path.clone(), session_id);
-                    if let Some(content) = content_after {
-                        change = change.with_content_after(content);
-                    }
-                    Some(change)
+                    let change = Change::new(ChangeType::Modify, relative, session_id);
+                    Self::with_content(change, &path, max_file_size, algorithm, vanished)
                 }
                 EventKind::Remove(_) => {
                     info!("File removed: {:?}", path);
-                    Some(Change::new(ChangeType::Delete, path.clone(), session_id))
+                    Some(Change::new(ChangeType::Delete, relative, session_id))
                 }
                 _ => None,
             };
 
-            if let Some(change) = change {
-                let storage = storage.lock().unwrap();
-                storage.create_change(&change)?;
+            changes.extend(change.map(|c| {
+                Self::with_current_mtime(c, &path).with_tool(FILESYSTEM_TOOL.to_string())
+            }));
+        }
+
+        changes
+    }
+
+    /// Collapse short-lived files within a batch.
+    ///
+    /// A file created and then deleted leaves no trace, and a file created and
+    /// then modified is recorded as a single create with the final content.
+    /// A delete of a path in `vanished`, gone before it could be read, is
+    /// dropped too, as its create never was recorded.
+    fn coalesce(changes: Vec<Change>, vanished: &HashSet<PathBuf>) -> Vec<Change> {
+        let mut result: Vec<Option<Change>> = Vec::with_capacity(changes.len());
+        let mut created: HashMap<PathBuf, usize> = HashMap::new();
+
+        for change in changes {
+            match (change.change_type, created.get(&change.path).copied()) {
+                (ChangeType::Modify, Some(index)) => {
+                    if let Some(create) = result[index].as_mut() {
+                        create.content_after = change.content_after;
+                        create.content_hash_after = change.content_hash_after;
+                        create.mtime_after = change.mtime_after;
+                        // Including any note on why there's no content
+                        create.metadata = change.metadata;
+                    }
+                }
+                (ChangeType::Delete, Some(index)) => {
+                    info!("Dropping short-lived file: {:?}", change.path);
+                    result[index] = None;
+                    created.remove(&change.path);
+                }
+                (ChangeType::Delete, None) if vanished.contains(&change.path) => {
+                    info!("Dropping short-lived file: {:?}", change.path);
+                }
+                (change_type, _) => {
+                    if change_type == ChangeType::Create {
+                        created.insert(change.path.clone(), result.len());
+                    }
+                    result.push(Some(change));
+                }
             }
         }
 
-        Ok(())
+        result.into_iter().flatten().collect()
     }
 
-    fn should_ignore(path: &Path, root_path: &Path, ignore_patterns: &[String]) -> bool {
-        let relative_path = path.strip_prefix(root_path).unwrap_or(path);
-        let path_str = relative_path.to_string_lossy();
+    /// Give a change the content of the file at `path`.
+    ///
+    /// A file that's gone by the time it's read, or has become a directory,
+    /// was most likely changed again since the event and is left for the
+    /// events that follow, so no change is returned and a created path is added
+    /// to `vanished`. A file that exists but can't be read is recorded without
+    /// content, with a note saying why.
+    fn with_content(
+        change: Change,
+        path: &Path,
+        max_file_size: Option<u64>,
+        algorithm: HashAlgorithm,
+        vanished: &mut HashSet<PathBuf>,
+    ) -> Option<Change> {
+        match Self::read_content(path, max_file_size) {
+            Ok(Some(content)) => Some(change.with_content_after_hashed(content, algorithm)),
+            Ok(None) => Some(change),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::IsADirectory
+                ) =>
+            {
+                debug!("Skipping {:?}, gone before it could be read: {}", path, e);
+                if change.change_type == ChangeType::Create {
+                    vanished.insert(change.path);
+                }
+                None
+            }
+            Err(e) => {
+                warn!(
+                    "Recording {:?} without content, it can't be read: {}",
+                    path, e
+                );
+                Some(change.with_content_missing(e.to_string()))
+            }
+        }
+    }
 
-        for pattern in ignore_patterns {
-            if path_str.contains(pattern) {
-                return true;
+    /// Read a file's content, skipping files over the configured size limit.
+    fn read_content(path: &Path, max_file_size: Option<u64>) -> std::io::Result<Option<Vec<u8>>> {
+        if let Some(limit) = max_file_size {
+            let size = std::fs::metadata(path)?.len();
+            if size > limit {
+                info!(
+                    "Skipping content of {:?} ({} bytes exceeds limit of {})",
+                    path, size, limit
+                );
+                return Ok(None);
             }
         }
 
-        false
+        std::fs::read(path).map(Some)
+    }
+
+    fn should_ignore(
+        path: &Path,
+        root_path: &Path,
+        ignore: &IgnoreMatcher,
+        db_path: Option<&Path>,
+    ) -> bool {
+        if db_path.is_some_and(|db_path| Self::is_database_file(path, db_path)) {
+            return true;
+        }
+
+        let relative_path = path.strip_prefix(root_path).unwrap_or(path);
+        ignore.is_ignored(relative_path)
+    }
+
+    /// Whether `path` is the SQLite database at `db_path` or one of the
+    /// journal files SQLite keeps next to it.
+    fn is_database_file(path: &Path, db_path: &Path) -> bool {
+        let (Some(name), Some(db_name)) = (path.file_name(), db_path.file_name()) else {
+            return false;
+        };
+        if path.parent() != db_path.parent() {
+            return false;
+        }
+
+        let db_name = db_name.to_string_lossy();
+        ["", "-wal", "-shm", "-journal"]
+            .iter()
+            .any(|suffix| *name.to_string_lossy() == format!("{}{}", db_name, suffix))
+    }
+}
+
+/// The watcher for the active session, swapped out when a client starts a
+/// new session through the API.
+pub struct ActiveWatcher {
+    options: WatcherOptions,
+    current: Mutex<Option<FileWatcher>>,
+}
+
+impl ActiveWatcher {
+    /// No watcher yet; ones started later use `options`.
+    pub fn new(options: WatcherOptions) -> Self {
+        Self {
+            options,
+            current: Mutex::new(None),
+        }
+    }
+
+    /// Start out with `watcher` running.
+    pub fn with_watcher(self, watcher: FileWatcher) -> Self {
+        *self.current.lock().unwrap() = Some(watcher);
+        self
+    }
+
+    /// Stop the running watcher, if any, and start watching `session`,
+    /// catching up on its files first when the file index is kept.
+    pub async fn start(&self, session: &Session, storage: &StorageHandle) -> anyhow::Result<()> {
+        // Stopped first, so its last events aren't recorded in the new session
+        self.current.lock().unwrap().take();
+
+        if self.options.file_index {
+            let caught_up = storage
+                .call({
+                    let session = session.clone();
+                    let options = self.options.clone();
+                    move |storage| FileWatcher::catch_up_reported(&session, storage, &options)
+                })
+                .await?;
+            info!("Recorded {} change(s) made while stopped", caught_up);
+        }
+
+        let watcher = FileWatcher::with_options(session, storage.clone(), self.options.clone())?;
+        *self.current.lock().unwrap() = Some(watcher);
+        Ok(())
+    }
+
+    /// The session the running watcher records into.
+    pub fn session_id(&self) -> Option<Uuid> {
+        self.current
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(FileWatcher::session_id)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use gitent_core::Session;
-    use std::path::PathBuf;
+    use gitent_core::{Session, Storage};
+    use notify::event::{CreateKind, ModifyKind, RemoveKind};
     use tempfile::TempDir;
 
+    fn event(kind: EventKind, path: &Path) -> Event {
+        Event::new(kind).add_path(path.to_path_buf())
+    }
+
     #[tokio::test]
     async fn test_file_watcher_creation() {
         let temp_dir = TempDir::new().unwrap();
         let session = Session::new(temp_dir.path().to_path_buf());
-        let storage = Arc::new(Mutex::new(Storage::in_memory().unwrap()));
+        let storage = Storage::in_memory().unwrap();
+        storage.create_session(&session).unwrap();
+        let storage = StorageHandle::spawn(storage);
 
-        storage.lock().unwrap().create_session(&session).unwrap();
+        let watcher = FileWatcher::new(&session, storage).unwrap();
+        assert!(!watcher.is_polling());
+    }
 
-        let _watcher = FileWatcher::new(&session, storage).unwrap();
+    #[tokio::test]
+    async fn test_poll_watcher_selected_when_configured() {
+        let temp_dir = TempDir::new().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        let storage = Storage::in_memory().unwrap();
+        storage.create_session(&session).unwrap();
+        let storage = StorageHandle::spawn(storage);
 
-        // Just verify it doesn't panic
+        let options = WatcherOptions {
+            poll_interval: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let watcher = FileWatcher::with_options(&session, storage, options).unwrap();
+        assert!(watcher.is_polling());
     }
 
     #[test]
     fn test_should_ignore() {
         let root = PathBuf::from("/test");
-        let ignore_patterns = vec!["target".to_string(), ".git".to_string()];
+        let ignore = IgnoreMatcher::new(&[
+            "target".to_string(),
+            ".git".to_string(),
+            "/build/".to_string(),
+        ])
+        .unwrap();
 
         assert!(FileWatcher::should_ignore(
             &PathBuf::from("/test/target/debug"),
             &root,
-            &ignore_patterns
+            &ignore,
+            None
         ));
 
         assert!(FileWatcher::should_ignore(
             &PathBuf::from("/test/.git/config"),
             &root,
-            &ignore_patterns
+            &ignore,
+            None
         ));
 
         assert!(!FileWatcher::should_ignore(
             &PathBuf::from("/test/src/main.rs"),
             &root,
-            &ignore_patterns
+            &ignore,
+            None
+        ));
+
+        assert!(FileWatcher::should_ignore(
+            &PathBuf::from("/test/build/out.o"),
+            &root,
+            &ignore,
+            None
+        ));
+
+        assert!(!FileWatcher::should_ignore(
+            &PathBuf::from("/test/src/build/helpers.rs"),
+            &root,
+            &ignore,
+            None
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_then_delete_in_one_batch() {
+        let temp_dir = TempDir::new().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        let storage = Storage::in_memory().unwrap();
+        storage.create_session(&session).unwrap();
+        let storage = StorageHandle::spawn(storage);
+
+        let temp_file = temp_dir.path().join("scratch.tmp");
+        let kept_file = temp_dir.path().join("kept.txt");
+        std::fs::write(&kept_file, b"final").unwrap();
+
+        let events = vec![
+            event(EventKind::Create(CreateKind::File), &temp_file),
+            event(EventKind::Create(CreateKind::File), &kept_file),
+            event(EventKind::Remove(RemoveKind::File), &temp_file),
+            event(EventKind::Modify(ModifyKind::Any), &kept_file),
+        ];
+        FileWatcher::handle_events(
+            events,
+            &session,
+            &IgnoreMatcher::new(&[]).unwrap(),
+            &storage,
+            &WatcherOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let changes = storage
+            .call(move |storage| storage.get_uncommitted_changes(&session.id))
+            .await
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].change_type, ChangeType::Create);
+        assert_eq!(changes[0].path, PathBuf::from("kept.txt"));
+        assert_eq!(changes[0].content_after.as_deref(), Some(&b"final"[..]));
+    }
+
+    // The unreadable file is a symlink loop
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        let ignore = IgnoreMatcher::new(&[]).unwrap();
+        let options = WatcherOptions::default();
+        let changes = |kind, path: &Path| {
+            FileWatcher::changes_for_event(
+                event(kind, path),
+                &session,
+                &ignore,
+                &options,
+                &mut HashSet::new(),
+            )
+        };
+
+        // Gone by the time the event is handled: nothing is recorded
+        let vanished = temp_dir.path().join("vanished.txt");
+        assert!(changes(EventKind::Create(CreateKind::File), &vanished).is_empty());
+        assert!(changes(EventKind::Modify(ModifyKind::Any), &vanished).is_empty());
+        let dir = temp_dir.path().join("dir");
+        std::fs::create_dir(&dir).unwrap();
+        assert!(changes(EventKind::Create(CreateKind::Folder), &dir).is_empty());
+
+        // There but unreadable: recorded with a note instead of as empty. A
+        // symlink loop can't be read even with root's permissions
+        let looped = temp_dir.path().join("looped");
+        std::os::unix::fs::symlink(temp_dir.path().join("loop"), &looped).unwrap();
+        std::os::unix::fs::symlink(&looped, temp_dir.path().join("loop")).unwrap();
+        let recorded = changes(EventKind::Modify(ModifyKind::Any), &looped);
+        assert_eq!(recorded.len(), 1);
+        assert!(recorded[0].content_after.is_none());
+        assert!(recorded[0].content_missing().is_some());
+
+        let readable = temp_dir.path().join("readable.txt");
+        std::fs::write(&readable, b"content").unwrap();
+        let recorded = changes(EventKind::Create(CreateKind::File), &readable);
+        assert_eq!(recorded[0].content_after.as_deref(), Some(&b"content"[..]));
+        assert!(recorded[0].content_missing().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_batch_recorded_together() {
+        let temp_dir = TempDir::new().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        let storage = Storage::in_memory().unwrap();
+        storage.create_session(&session).unwrap();
+        let storage = StorageHandle::spawn(storage);
+        let options = WatcherOptions {
+            file_index: true,
+            ..Default::default()
+        };
+
+        let events: Vec<_> = (0..50)
+            .map(|i| {
+                let path = temp_dir.path().join(format!("{}.txt", i));
+                std::fs::write(&path, i.to_string()).unwrap();
+                event(EventKind::Create(CreateKind::File), &path)
+            })
+            .collect();
+        FileWatcher::handle_events(
+            events,
+            &session,
+            &IgnoreMatcher::new(&[]).unwrap(),
+            &storage,
+            &options,
+        )
+        .await
+        .unwrap();
+
+        let root = session.root_path.clone();
+        let (changes, indexed) = storage
+            .call(move |storage| {
+                (
+                    storage.get_uncommitted_changes(&session.id).unwrap(),
+                    storage.load_file_index(&root).unwrap().unwrap_or_default(),
+                )
+            })
+            .await;
+        assert_eq!(changes.len(), 50);
+        assert_eq!(indexed.len(), 50);
+    }
+
+    #[tokio::test]
+    async fn test_move_with_edit_is_one_rename() {
+        let temp_dir = TempDir::new().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        let storage = Storage::in_memory().unwrap();
+        storage.create_session(&session).unwrap();
+        let storage = StorageHandle::spawn(storage);
+        let ignore = IgnoreMatcher::new(&[]).unwrap();
+        let options = WatcherOptions::default();
+
+        let old_path = temp_dir.path().join("src").join("a.rs");
+        std::fs::create_dir_all(old_path.parent().unwrap()).unwrap();
+        std::fs::write(&old_path, b"fn a() {}\nfn b() {}\n").unwrap();
+        let events = vec![event(EventKind::Create(CreateKind::File), &old_path)];
+        FileWatcher::handle_events(events, &session, &ignore, &storage, &options)
+            .await
+            .unwrap();
+
+        let new_path = temp_dir.path().join("lib").join("b.rs");
+        std::fs::create_dir_all(new_path.parent().unwrap()).unwrap();
+        std::fs::rename(&old_path, &new_path).unwrap();
+        std::fs::write(&new_path, b"fn a() {}\nfn c() {}\n").unwrap();
+        let moved = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(old_path)
+            .add_path(new_path);
+        FileWatcher::handle_events(vec![moved], &session, &ignore, &storage, &options)
+            .await
+            .unwrap();
+
+        let changes = storage
+            .call(move |storage| storage.get_uncommitted_changes(&session.id))
+            .await
+            .unwrap();
+        assert_eq!(changes.len(), 2);
+        let rename = &changes[0];
+        assert_eq!(rename.change_type, ChangeType::Rename);
+        assert_eq!(rename.path, PathBuf::from("lib/b.rs"));
+        assert_eq!(rename.old_path, Some(PathBuf::from("src/a.rs")));
+
+        let diff = gitent_core::diff::FileDiff::from_change(rename).unwrap();
+        assert_eq!(
+            diff.format_unified(0),
+            "--- src/a.rs\n+++ lib/b.rs\n@@ -2 +2 @@\n-fn b() {}\n+fn c() {}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_and_create_pair_as_rename() {
+        let temp_dir = TempDir::new().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        let storage = Storage::in_memory().unwrap();
+        storage.create_session(&session).unwrap();
+        let storage = StorageHandle::spawn(storage);
+        let ignore = IgnoreMatcher::new(&[]).unwrap();
+        let options = WatcherOptions::default();
+
+        let old_path = temp_dir.path().join("old.rs");
+        std::fs::write(&old_path, b"fn a() {}\nfn b() {}\nfn c() {}\n").unwrap();
+        let events = vec![event(EventKind::Create(CreateKind::File), &old_path)];
+        FileWatcher::handle_events(events, &session, &ignore, &storage, &options)
+            .await
+            .unwrap();
+
+        // A move seen as a remove and a create, as some platforms report it
+        let new_path = temp_dir.path().join("new.rs");
+        std::fs::rename(&old_path, &new_path).unwrap();
+        let events = vec![
+            event(EventKind::Remove(RemoveKind::File), &old_path),
+            event(EventKind::Create(CreateKind::File), &new_path),
+        ];
+        FileWatcher::handle_events(events, &session, &ignore, &storage, &options)
+            .await
+            .unwrap();
+
+        let changes = storage
+            .call(move |storage| storage.get_uncommitted_changes(&session.id))
+            .await
+            .unwrap();
+        let (deletes, creates): (Vec<Change>, Vec<Change>) = changes
+            .into_iter()
+            .filter(|c| c.change_type != ChangeType::Modify)
+            .partition(|c| c.change_type == ChangeType::Delete);
+        assert_eq!(
+            deletes[0].content_before.as_deref(),
+            Some(&b"fn a() {}\nfn b() {}\nfn c() {}\n"[..])
+        );
+        let creates: Vec<Change> = creates
+            .into_iter()
+            .filter(|c| c.path == Path::new("new.rs"))
+            .collect();
+        let renames = gitent_core::diff::detect_renames(&deletes, &creates);
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].0.path, PathBuf::from("old.rs"));
+        assert_eq!(renames[0].1.path, PathBuf::from("new.rs"));
+    }
+
+    #[tokio::test]
+    async fn test_database_inside_root_is_not_tracked() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("data").join("history.db");
+        std::fs::create_dir_all(db_path.parent().unwrap()).unwrap();
+
+        let session = Session::new(temp_dir.path().to_path_buf());
+        let storage = Storage::new(&db_path).unwrap();
+        storage.create_session(&session).unwrap();
+        let storage = StorageHandle::spawn(storage);
+
+        let options = WatcherOptions {
+            db_path: Some(db_path.clone()),
+            ..Default::default()
+        };
+        let wal_path = db_path.with_file_name("history.db-wal");
+        std::fs::write(&wal_path, b"wal").unwrap();
+        let events = vec![
+            event(EventKind::Modify(ModifyKind::Any), &db_path),
+            event(EventKind::Create(CreateKind::File), &wal_path),
+        ];
+        FileWatcher::handle_events(
+            events,
+            &session,
+            &IgnoreMatcher::for_session(&session).unwrap(),
+            &storage,
+            &options,
+        )
+        .await
+        .unwrap();
+
+        let changes = storage
+            .call(move |storage| storage.get_uncommitted_changes(&session.id))
+            .await
+            .unwrap();
+        assert!(changes.is_empty());
+
+        assert!(!FileWatcher::should_ignore(
+            &temp_dir.path().join("data").join("history.dbx"),
+            temp_dir.path(),
+            &IgnoreMatcher::new(&[]).unwrap(),
+            Some(&db_path)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_paths_relative_to_content_root() {
+        let temp_dir = TempDir::new().unwrap();
+        let build_dir = temp_dir.path().join("build").join("out");
+        std::fs::create_dir_all(&build_dir).unwrap();
+        let session =
+            Session::new(build_dir.clone()).with_content_root(temp_dir.path().to_path_buf());
+        let storage = Storage::in_memory().unwrap();
+        storage.create_session(&session).unwrap();
+        let storage = StorageHandle::spawn(storage);
+
+        let artifact = build_dir.join("app.js");
+        std::fs::write(&artifact, b"run()").unwrap();
+        FileWatcher::handle_events(
+            vec![event(EventKind::Create(CreateKind::File), &artifact)],
+            &session,
+            &IgnoreMatcher::new(&[]).unwrap(),
+            &storage,
+            &WatcherOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let changes = storage
+            .call(move |storage| storage.get_uncommitted_changes(&session.id))
+            .await
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, PathBuf::from("build/out/app.js"));
+    }
+
+    #[tokio::test]
+    async fn test_api_and_watcher_paths_unify() {
+        use crate::api::{create_router, AppState};
+        use axum::body::Body;
+        use axum::http::{header, Request, StatusCode};
+        use tower::ServiceExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        let storage = Storage::in_memory().unwrap();
+        storage.create_session(&session).unwrap();
+        let storage = StorageHandle::spawn(storage);
+        let router = create_router(AppState::new(storage.clone()));
+
+        let announce = |path: &str| {
+            let body = serde_json::json!({
+                "change_type": "modify",
+                "path": path,
+                "content_before": "fn main() {}",
+                "content_after": "fn main() { run() }",
+            });
+            router.clone().oneshot(
+                Request::post("/changes")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+        };
+
+        // The SDK announces a relative path, the watcher sees an absolute one
+        assert_eq!(
+            announce("src/main.rs").await.unwrap().status(),
+            StatusCode::OK
+        );
+        let main_rs = temp_dir.path().join("src").join("main.rs");
+        std::fs::create_dir_all(main_rs.parent().unwrap()).unwrap();
+        std::fs::write(&main_rs, b"fn main() { run() }").unwrap();
+        FileWatcher::handle_events(
+            vec![event(EventKind::Modify(ModifyKind::Any), &main_rs)],
+            &session,
+            &IgnoreMatcher::new(&[]).unwrap(),
+            &storage,
+            &WatcherOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let changes = storage
+            .call(move |storage| storage.get_uncommitted_changes(&session.id))
+            .await
+            .unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(changes
+            .iter()
+            .all(|change| change.path == Path::new("src/main.rs")));
+
+        for path in ["../../etc/passwd", "/etc/passwd"] {
+            assert_eq!(
+                announce(path).await.unwrap().status(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    fn batch(path: &Path) -> DebounceEventResult {
+        let event = event(EventKind::Modify(ModifyKind::Any), path);
+        Ok(vec![notify_debouncer_full::DebouncedEvent::new(
+            event,
+            std::time::Instant::now(),
+        )])
+    }
+
+    #[test]
+    fn test_full_queue_drops_events() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let capture = CaptureStatus::new();
+        let path = Path::new("/test/file.txt");
+
+        // Nothing drains the queue, yet the sender never blocks
+        for _ in 0..5 {
+            FileWatcher::enqueue(&tx, batch(path), OverflowPolicy::Drop, &capture);
+        }
+
+        assert_eq!(capture.dropped_events(), 3);
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_full_queue_blocks_until_drained() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let capture = Arc::new(CaptureStatus::new());
+        let path = Path::new("/test/file.txt");
+        FileWatcher::enqueue(&tx, batch(path), OverflowPolicy::Block, &capture);
+
+        let sender = std::thread::spawn({
+            let capture = Arc::clone(&capture);
+            move || FileWatcher::enqueue(&tx, batch(path), OverflowPolicy::Block, &capture)
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!sender.is_finished());
+
+        // Draining one batch lets the blocked send through, with nothing lost
+        assert!(rx.blocking_recv().is_some());
+        sender.join().unwrap();
+        assert!(rx.blocking_recv().is_some());
+        assert_eq!(capture.dropped_events(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_storage_full_pauses_capture() {
+        let temp_dir = TempDir::new().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        let storage = Storage::in_memory().unwrap();
+        storage.create_session(&session).unwrap();
+        storage.set_max_size(1).unwrap();
+        let storage = StorageHandle::spawn(storage);
+        let ignore = IgnoreMatcher::new(&[]).unwrap();
+        let options = WatcherOptions::default();
+
+        let big_file = temp_dir.path().join("big.bin");
+        std::fs::write(&big_file, vec![7; 1 << 20]).unwrap();
+        let events = vec![event(EventKind::Create(CreateKind::File), &big_file)];
+        let err = FileWatcher::handle_events(events.clone(), &session, &ignore, &storage, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::StorageFull(_))
         ));
+        assert!(options.capture.is_paused());
+        assert!(options.capture.degraded_reason().is_some());
+
+        // While paused, events are dropped instead of failing again
+        FileWatcher::handle_events(events, &session, &ignore, &storage, &options)
+            .await
+            .unwrap();
+        let changes = storage
+            .call(move |storage| storage.get_uncommitted_changes(&session.id))
+            .await
+            .unwrap();
+        assert!(changes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resume_catches_up_on_dropped_events() {
+        let temp_dir = TempDir::new().unwrap();
+        let session = Session::new(temp_dir.path().to_path_buf());
+        let storage = Storage::in_memory().unwrap();
+        storage.create_session(&session).unwrap();
+        let options = WatcherOptions {
+            file_index: true,
+            ..Default::default()
+        };
+        let seen = temp_dir.path().join("seen.txt");
+        std::fs::write(&seen, b"seen").unwrap();
+        FileWatcher::catch_up(&session, &storage, &options, |_| {}).unwrap();
+        storage.set_max_size(1).unwrap();
+        let storage = StorageHandle::spawn(storage);
+        let ignore = IgnoreMatcher::new(&[]).unwrap();
+
+        let big_file = temp_dir.path().join("big.bin");
+        std::fs::write(&big_file, vec![7; 1 << 20]).unwrap();
+        let events = vec![event(EventKind::Create(CreateKind::File), &big_file)];
+        FileWatcher::handle_events(events, &session, &ignore, &storage, &options)
+            .await
+            .unwrap_err();
+
+        let missed = temp_dir.path().join("missed.txt");
+        std::fs::write(&missed, b"missed").unwrap();
+        let events = vec![event(EventKind::Create(CreateKind::File), &missed)];
+        FileWatcher::handle_events(events, &session, &ignore, &storage, &options)
+            .await
+            .unwrap();
+        assert_eq!(options.capture.dropped_events(), 1);
+
+        // Space is freed and the backoff runs out, so the next batch goes
+        // through and the scan after it finds what was dropped
+        std::fs::remove_file(&big_file).unwrap();
+        storage
+            .call(|storage| storage.set_max_size(1 << 30))
+            .await
+            .unwrap();
+        options.capture.resume();
+        std::fs::write(&seen, b"seen again").unwrap();
+        let events = vec![event(EventKind::Modify(ModifyKind::Any), &seen)];
+        FileWatcher::handle_events(events, &session, &ignore, &storage, &options)
+            .await
+            .unwrap();
+
+        let paths: Vec<PathBuf> = storage
+            .call(move |storage| storage.get_uncommitted_changes(&session.id))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|change| change.path)
+            .collect();
+        assert!(paths.contains(&PathBuf::from("seen.txt")));
+        assert!(paths.contains(&PathBuf::from("missed.txt")));
+        assert!(!options.capture.take_catch_up_due());
+    }
+
+    #[test]
+    fn test_catch_up_records_offline_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_path_buf();
+        std::fs::write(root.join("a.txt"), b"a").unwrap();
+        std::fs::write(root.join("b.txt"), b"b").unwrap();
+        let storage = Storage::in_memory().unwrap();
+        let options = WatcherOptions {
+            file_index: true,
+            ..Default::default()
+        };
+
+        // The first start only builds the index
+        let first = Session::new(root.clone());
+        storage.create_session(&first).unwrap();
+        assert_eq!(
+            FileWatcher::catch_up(&first, &storage, &options, |_| {}).unwrap(),
+            0
+        );
+
+        // Edit one file while the server is stopped, making sure its mtime moves
+        let b = std::fs::File::options()
+            .write(true)
+            .truncate(true)
+            .open(root.join("b.txt"))
+            .unwrap();
+        std::io::Write::write_all(&mut &b, b"b2").unwrap();
+        let edited_at = std::time::SystemTime::now() + Duration::from_secs(10);
+        b.set_modified(edited_at).unwrap();
+        let edited_at: DateTime<Utc> = edited_at.into();
+        drop(b);
+
+        let second = Session::new(root.clone());
+        storage.create_session(&second).unwrap();
+        assert_eq!(
+            FileWatcher::catch_up(&second, &storage, &options, |_| {}).unwrap(),
+            1
+        );
+
+        let changes = storage.get_uncommitted_changes(&second.id).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].change_type, ChangeType::Modify);
+        assert_eq!(changes[0].path, PathBuf::from("b.txt"));
+        assert_eq!(changes[0].content_after.as_deref(), Some(&b"b2"[..]));
+        assert_eq!(changes[0].mtime_after, Some(edited_at));
+        assert!(changes[0]
+            .mtime_before
+            .is_some_and(|before| before < edited_at));
+
+        // Nothing changed since, so a third start records nothing
+        let third = Session::new(root);
+        storage.create_session(&third).unwrap();
+        assert_eq!(
+            FileWatcher::catch_up(&third, &storage, &options, |_| {}).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_catch_up_reports_progress() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_path_buf();
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        for name in ["a.txt", "b.txt", "src/c.rs", "src/d.rs", "src/e.rs"] {
+            std::fs::write(root.join(name), name).unwrap();
+        }
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new(root);
+        storage.create_session(&session).unwrap();
+        let options = WatcherOptions {
+            file_index: true,
+            ..Default::default()
+        };
+
+        let mut seen = Vec::new();
+        FileWatcher::catch_up(&session, &storage, &options, |progress| seen.push(progress))
+            .unwrap();
+        assert!(seen.iter().all(|progress| progress.total == 5));
+        assert!(seen.windows(2).all(|pair| pair[0].done < pair[1].done));
+        assert_eq!(seen.last(), Some(&Progress { done: 5, total: 5 }));
+
+        // Reported at /health only while it runs
+        FileWatcher::catch_up_reported(&session, &storage, &options).unwrap();
+        assert_eq!(options.capture.catch_up_progress(), None);
+    }
+
+    #[test]
+    fn test_include_patterns() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_path_buf();
+        std::fs::create_dir_all(root.join("src/nested")).unwrap();
+        std::fs::write(root.join("src/nested/lib.rs"), b"fn f() {}").unwrap();
+        std::fs::write(root.join("src/notes.txt"), b"notes").unwrap();
+        std::fs::write(root.join("README.md"), b"readme").unwrap();
+
+        let storage = Storage::in_memory().unwrap();
+        let session =
+            Session::new(root.clone()).with_include_patterns(vec!["src/**/*.rs".to_string()]);
+        storage.create_session(&session).unwrap();
+        let session = storage.get_session(&session.id).unwrap();
+        assert_eq!(session.include_patterns, vec!["src/**/*.rs"]);
+
+        let ignore = IgnoreMatcher::for_session(&session).unwrap();
+        let options = WatcherOptions {
+            file_index: true,
+            ..Default::default()
+        };
+        let changes = |path: &Path| {
+            FileWatcher::changes_for_event(
+                event(EventKind::Modify(ModifyKind::Any), path),
+                &session,
+                &ignore,
+                &options,
+                &mut HashSet::new(),
+            )
+        };
+        assert_eq!(changes(&root.join("src/nested/lib.rs")).len(), 1);
+        assert!(changes(&root.join("src/notes.txt")).is_empty());
+        assert!(changes(&root.join("README.md")).is_empty());
+
+        // Catching up walks into directories to find included files
+        FileWatcher::catch_up(&session, &storage, &options, |_| {}).unwrap();
+        let index = storage.load_file_index(&root).unwrap().unwrap();
+        let mut indexed: Vec<_> = index.keys().cloned().collect();
+        indexed.sort();
+        assert_eq!(indexed, vec![root.join("src/nested/lib.rs")]);
     }
 }
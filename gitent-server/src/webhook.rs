@@ -0,0 +1,287 @@
+//! Outbound webhooks, posted after each commit so teams can trigger CI or
+//! send notifications.
+//!
+//! Only commits made through the API are posted: the CLI writes commits to
+//! the database directly, without the server hearing of them.
+//!
+//! Delivery happens in the background: a receiver that is down or slow is
+//! retried a few times and then logged, and never holds up the commit.
+
+use crate::store::StorageHandle;
+use chrono::{DateTime, Utc};
+use gitent_core::diff::{DiffLineType, FileDiff};
+use gitent_core::{Change, Commit};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Attempts made to deliver each payload before giving up.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry, doubled for each one after.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How long a receiver has to answer each attempt.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a webhook receives for each commit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitPayload {
+    /// Always `"commit"`, so receivers can tell events apart if more are added
+    pub event: String,
+    pub commit_id: Uuid,
+    pub session_id: Uuid,
+    pub parent: Option<Uuid>,
+    pub message: String,
+    pub agent_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub files: Vec<PathBuf>,
+    pub stats: CommitStats,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitStats {
+    pub changes: usize,
+    pub files: usize,
+    /// Lines added across text files
+    pub additions: usize,
+    /// Lines removed across text files
+    pub deletions: usize,
+}
+
+impl CommitPayload {
+    pub fn new(commit: &Commit, changes: &[Change]) -> Self {
+        let mut files: Vec<PathBuf> = changes.iter().map(|c| c.path.clone()).collect();
+        files.sort();
+        files.dedup();
+
+        let mut stats = CommitStats {
+            changes: changes.len(),
+            files: files.len(),
+            ..Default::default()
+        };
+        for change in changes {
+            let (additions, deletions) = line_counts(change);
+            stats.additions += additions;
+            stats.deletions += deletions;
+        }
+
+        Self {
+            event: "commit".to_string(),
+            commit_id: commit.id,
+            session_id: commit.session_id,
+            parent: commit.parent,
+            message: commit.message.clone(),
+            agent_id: commit.agent_id.clone(),
+            timestamp: commit.timestamp,
+            files,
+            stats,
+        }
+    }
+}
+
+/// Lines added and removed by a change, with a missing side counted as empty.
+/// Binary content counts for nothing.
+fn line_counts(change: &Change) -> (usize, usize) {
+    let lines = |content: &Option<Vec<u8>>| {
+        content
+            .as_deref()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+            .map_or(0, |text| text.lines().count())
+    };
+
+    match (&change.content_before, &change.content_after) {
+        (Some(_), Some(_)) => FileDiff::iter_lines(change).filter_map(Result::ok).fold(
+            (0, 0),
+            |(added, removed), line| match line.line_type {
+                DiffLineType::Addition => (added + 1, removed),
+                DiffLineType::Deletion => (added, removed + 1),
+                DiffLineType::Context => (added, removed),
+            },
+        ),
+        _ => (lines(&change.content_after), lines(&change.content_before)),
+    }
+}
+
+/// Posts commit payloads to the configured URLs.
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    urls: Arc<Vec<String>>,
+    retry_backoff: Duration,
+}
+
+impl Webhooks {
+    pub fn new(urls: Vec<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to build webhook client");
+        Self {
+            client,
+            urls: Arc::new(urls),
+            retry_backoff: RETRY_BACKOFF,
+        }
+    }
+
+    /// Wait `backoff` before the first retry instead of the default.
+    pub fn with_retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Announce `commit` to every URL in the background.
+    pub fn notify_commit(&self, storage: StorageHandle, commit: Commit) {
+        let webhooks = self.clone();
+        tokio::spawn(async move {
+            let changes = storage
+                .call({
+                    let ids = commit.changes.clone();
+                    move |storage| {
+                        ids.iter()
+                            .filter_map(|id| storage.get_change(id).ok())
+                            .collect::<Vec<_>>()
+                    }
+                })
+                .await;
+            let payload = CommitPayload::new(&commit, &changes);
+            for url in webhooks.urls.iter() {
+                webhooks.deliver(url, &payload).await;
+            }
+        });
+    }
+
+    /// Post `payload` to `url`, retrying failures and error responses.
+    async fn deliver(&self, url: &str, payload: &CommitPayload) {
+        let mut backoff = self.retry_backoff;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let result = self
+                .client
+                .post(url)
+                .json(payload)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => {
+                    info!("Delivered commit {} to {}", payload.commit_id, url);
+                    return;
+                }
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    warn!(
+                        "Webhook {} failed (attempt {}/{}): {}",
+                        url, attempt, MAX_ATTEMPTS, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => warn!(
+                    "Giving up on webhook {} for commit {}: {}",
+                    url, payload.commit_id, e
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{create_router, AppState};
+    use axum::body::Body;
+    use axum::http::{header, Request, StatusCode};
+    use gitent_core::{ChangeType, Session, Storage};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+
+    /// Serve a receiver that fails its first `failures` requests and sends
+    /// every payload it accepts down the returned channel.
+    async fn receiver(failures: usize) -> (String, mpsc::UnboundedReceiver<CommitPayload>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let app = axum::Router::new().route(
+            "/hook",
+            axum::routing::post(move |axum::Json(payload): axum::Json<CommitPayload>| {
+                let tx = tx.clone();
+                let seen = seen.clone();
+                async move {
+                    if seen.fetch_add(1, Ordering::SeqCst) < failures {
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                    tx.send(payload).unwrap();
+                    StatusCode::OK
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn test_commit_is_posted_to_webhook() {
+        let storage = Storage::in_memory().unwrap();
+        let session = Session::new("/test".into());
+        storage.create_session(&session).unwrap();
+        let create = Change::new(ChangeType::Create, "a.txt".into(), session.id)
+            .with_content_after(b"one\ntwo\n".to_vec());
+        let modify = Change::new(ChangeType::Modify, "b.txt".into(), session.id)
+            .with_content_before(b"old\nsame\n".to_vec())
+            .with_content_after(b"new\nsame\n".to_vec());
+        storage.create_change(&create).unwrap();
+        storage.create_change(&modify).unwrap();
+
+        let (url, mut payloads) = receiver(1).await;
+        let webhooks = Webhooks::new(vec![url]).with_retry_backoff(Duration::from_millis(10));
+        let router =
+            create_router(AppState::new(StorageHandle::spawn(storage)).with_webhooks(webhooks));
+
+        let body = serde_json::json!({
+            "message": "Add a, edit b",
+            "agent_id": "agent",
+            "change_ids": [create.id.to_string(), modify.id.to_string()],
+        });
+        let response = router
+            .oneshot(
+                Request::post("/commits")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        // Answered before the receiver has accepted anything
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let commit: Commit = serde_json::from_slice(&body).unwrap();
+
+        let payload = tokio::time::timeout(Duration::from_secs(5), payloads.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload.event, "commit");
+        assert_eq!(payload.commit_id, commit.id);
+        assert_eq!(payload.message, "Add a, edit b");
+        assert_eq!(payload.agent_id, "agent");
+        assert_eq!(
+            payload.files,
+            vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")]
+        );
+        assert_eq!(
+            payload.stats,
+            CommitStats {
+                changes: 2,
+                files: 2,
+                additions: 3,
+                deletions: 1,
+            }
+        );
+    }
+}
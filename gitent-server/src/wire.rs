@@ -0,0 +1,125 @@
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Media type for MessagePack bodies.
+pub const MSGPACK: &str = "application/msgpack";
+
+/// How a request or response body is encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+impl WireFormat {
+    /// The format named by an `Accept` or `Content-Type` header, falling
+    /// back to JSON when the header is absent or names anything else.
+    pub fn from_header(value: Option<&HeaderValue>) -> Self {
+        let is_msgpack = value
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| {
+                value.split(',').any(|media_type| {
+                    let essence = media_type.split(';').next().unwrap_or_default().trim();
+                    essence.eq_ignore_ascii_case(MSGPACK)
+                        || essence.eq_ignore_ascii_case("application/x-msgpack")
+                })
+            });
+        if is_msgpack {
+            WireFormat::MsgPack
+        } else {
+            WireFormat::Json
+        }
+    }
+}
+
+tokio::task_local! {
+    /// Format responses should be encoded in, set by [`negotiate`].
+    static RESPONSE_FORMAT: WireFormat;
+}
+
+/// Picks the response format for [`Wire`] bodies from the `Accept` header.
+pub async fn negotiate(req: Request, next: Next) -> Response {
+    let format = WireFormat::from_header(req.headers().get(header::ACCEPT));
+    RESPONSE_FORMAT.scope(format, next.run(req)).await
+}
+
+/// A body in whichever format the client asked for.
+///
+/// As an extractor it decodes the request body according to its
+/// `Content-Type`; as a response it encodes according to the request's
+/// `Accept` header. JSON is used when neither names MessagePack.
+pub struct Wire<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Wire<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match WireFormat::from_header(req.headers().get(header::CONTENT_TYPE)) {
+            WireFormat::Json => Json::<T>::from_request(req, state)
+                .await
+                .map(|Json(value)| Wire(value))
+                .map_err(IntoResponse::into_response),
+            WireFormat::MsgPack => {
+                let body = Bytes::from_request(req, state)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                from_msgpack(&body).map(Wire).map_err(|e| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        format!("Invalid MessagePack body: {}", e),
+                    )
+                        .into_response()
+                })
+            }
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Wire<T> {
+    fn into_response(self) -> Response {
+        let format = RESPONSE_FORMAT
+            .try_with(|format| *format)
+            .unwrap_or_default();
+        match format {
+            WireFormat::Json => Json(self.0).into_response(),
+            WireFormat::MsgPack => match to_msgpack(&self.0) {
+                Ok(body) => (
+                    [(header::CONTENT_TYPE, HeaderValue::from_static(MSGPACK))],
+                    body,
+                )
+                    .into_response(),
+                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+            },
+        }
+    }
+}
+
+/// Encode `value` as MessagePack with named fields and ids and timestamps as
+/// strings, so it decodes to the same shape as the JSON form.
+pub fn to_msgpack<T: Serialize>(value: &T) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    let mut body = Vec::new();
+    let mut serializer = rmp_serde::Serializer::new(&mut body)
+        .with_struct_map()
+        .with_human_readable();
+    value.serialize(&mut serializer)?;
+    Ok(body)
+}
+
+/// Decode MessagePack written by [`to_msgpack`].
+pub fn from_msgpack<T: DeserializeOwned>(body: &[u8]) -> Result<T, rmp_serde::decode::Error> {
+    let mut deserializer = rmp_serde::Deserializer::new(body).with_human_readable();
+    T::deserialize(&mut deserializer)
+}